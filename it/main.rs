@@ -0,0 +1,136 @@
+//! End-to-end tests bringing up a full container with a stub warp-svc.
+//!
+//! These need root, iproute2, iptables, util-linux and danted on the host, and use the same
+//! veth and subnet as a real instance, so they are ignored by default. Run them on a rootful CI
+//! runner with `cargo test --test it -- --ignored --test-threads=1`.
+
+use bubblewarp::{Container, Type};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use strum::IntoEnumIterator;
+
+struct Scratch {
+    base_dir: PathBuf,
+}
+
+impl Scratch {
+    fn new(name: &str) -> Self {
+        let base_dir =
+            std::env::temp_dir().join(format!("bubblewarp-it-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&base_dir).unwrap();
+        Scratch { base_dir }
+    }
+
+    fn bubblewarp(&self, args: &[&str]) -> Output {
+        let fake_warp = Path::new(env!("CARGO_MANIFEST_DIR")).join("it/fake-warp-svc");
+        let out = Command::new(env!("CARGO_BIN_EXE_bubblewarp"))
+            .arg("--base-dir")
+            .arg(&self.base_dir)
+            .arg("--fake-warp")
+            .arg(fake_warp)
+            .args(args)
+            .env("RUST_LOG", "bubblewarp=debug")
+            .output()
+            .unwrap();
+        eprintln!("{}", String::from_utf8_lossy(&out.stderr));
+        out
+    }
+
+    fn ns(&self, ns_type: Type) -> PathBuf {
+        Container::with_base_dir(&self.base_dir).mount_point(ns_type)
+    }
+
+    fn in_net_ns(&self, args: &[&str]) -> Output {
+        Command::new("nsenter")
+            .arg(format!("--net={}", self.ns(Type::Net).display()))
+            .args(args)
+            .output()
+            .unwrap()
+    }
+}
+
+impl Drop for Scratch {
+    fn drop(&mut self) {
+        let _ = self.bubblewarp(&["down"]);
+        let _ = std::fs::remove_dir_all(&self.base_dir);
+    }
+}
+
+fn is_nsfs_mounted(path: &Path) -> bool {
+    let mountinfo = std::fs::read_to_string("/proc/self/mountinfo").unwrap();
+    let path = path.to_string_lossy();
+    mountinfo
+        .lines()
+        .any(|l| l.contains(" nsfs ") && l.split(' ').nth(4) == Some(&path))
+}
+
+fn host_has_iface(name: &str) -> bool {
+    Path::new("/sys/class/net").join(name).exists()
+}
+
+fn nat_rules() -> String {
+    let out = Command::new("iptables")
+        .args(["-t", "nat", "-S", "POSTROUTING"])
+        .output()
+        .unwrap();
+    String::from_utf8_lossy(&out.stdout).into_owned()
+}
+
+#[test]
+#[ignore]
+fn up_and_down() {
+    let scratch = Scratch::new("up-down");
+    assert!(scratch.bubblewarp(&["up"]).status.success());
+
+    for ns_type in Type::iter() {
+        // Optional namespaces are only created when asked for
+        assert_eq!(
+            is_nsfs_mounted(&scratch.ns(ns_type)),
+            !ns_type.is_optional(),
+            "{ns_type} namespace"
+        );
+    }
+    assert!(host_has_iface("veth-warp"));
+    assert!(nat_rules().contains("MASQUERADE"));
+
+    let links = scratch.in_net_ns(&["ip", "-o", "link"]);
+    let links = String::from_utf8_lossy(&links.stdout);
+    assert!(links.contains("veth-warp-ns"));
+    assert!(links.contains("CloudflareWARP"));
+
+    let route = scratch.in_net_ns(&["ip", "route", "show", "default"]);
+    assert!(String::from_utf8_lossy(&route.stdout).contains("via 10.200.0.1"));
+
+    let resolv =
+        std::fs::read_to_string(scratch.base_dir.join("etc_overlay/extra_lower/resolv.conf"))
+            .unwrap();
+    assert!(resolv.contains("127.0.2.2"));
+
+    assert!(scratch.bubblewarp(&["down"]).status.success());
+    for ns_type in Type::iter() {
+        assert!(
+            !is_nsfs_mounted(&scratch.ns(ns_type)),
+            "{ns_type} namespace still mounted"
+        );
+    }
+    assert!(!host_has_iface("veth-warp"));
+    assert!(!nat_rules().contains("10.200.0.0/24"));
+}
+
+#[test]
+#[ignore]
+fn up_is_idempotent() {
+    let scratch = Scratch::new("idempotent");
+    assert!(scratch.bubblewarp(&["up"]).status.success());
+    assert!(scratch.bubblewarp(&["up"]).status.success());
+
+    let rules = nat_rules();
+    assert_eq!(rules.matches("MASQUERADE").count(), 1, "{rules}");
+}
+
+#[test]
+#[ignore]
+fn down_without_up() {
+    let scratch = Scratch::new("down-only");
+    assert!(scratch.bubblewarp(&["down"]).status.success());
+}
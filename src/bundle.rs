@@ -0,0 +1,202 @@
+use crate::compat::{self, warp_cli_op, Op};
+use crate::config::{self, Config};
+use crate::exec::Executor;
+use crate::inspect::inspect;
+use crate::lsm;
+use crate::oplog;
+use crate::paths::Binary;
+use crate::pause::warp_cli;
+use anyhow::{Context, Result};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+/// Operations log lines in the bundle, enough to cover the last few commands
+const HISTORY_LINES: usize = 2000;
+const SHOWN_DENIALS: usize = 50;
+const REDACTED: &str = "<redacted>";
+/// Config values that may hold secrets or identify the host. For tables, only the values go.
+const REDACTED_KEYS: &[&str] = &["env", "args", "endpoint", "upstream", "snat-to", "hosts"];
+
+/// The default bundle name, which sorts by when it was made
+pub fn default_file() -> PathBuf {
+    let now = chrono::Local::now().format("%Y%m%d-%H%M%S");
+    PathBuf::from(format!("bubblewarp-debug-{now}.tar.gz"))
+}
+
+fn redact(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                *value = toml::Value::String(REDACTED.to_owned());
+            }
+        }
+        value => *value = toml::Value::String(REDACTED.to_owned()),
+    }
+}
+
+/// The config file as written, with what may be secret replaced
+fn sanitize(table: &mut toml::Table) {
+    for (key, value) in table.iter_mut() {
+        if REDACTED_KEYS.contains(&key.as_str()) {
+            redact(value);
+            continue;
+        }
+        match value {
+            toml::Value::Table(table) => sanitize(table),
+            toml::Value::Array(items) => {
+                for item in items {
+                    if let toml::Value::Table(table) = item {
+                        sanitize(table);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+fn sanitized_config(config_file: Option<&Path>) -> Result<String> {
+    let path = match config_file {
+        Some(path) => path.to_owned(),
+        None => config::default_path()?,
+    };
+    let data = match std::fs::read_to_string(&path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound && config_file.is_none() => {
+            return Ok("# No config file, the defaults are used\n".to_owned())
+        }
+        data => data.with_context(|| format!("Reading {}", path.display()))?,
+    };
+    let mut table: toml::Table = toml::from_str(&data)?;
+    sanitize(&mut table);
+    Ok(format!(
+        "# {}\n{}",
+        path.display(),
+        toml::to_string(&table)?
+    ))
+}
+
+fn host_info(ex: &dyn Executor, config: &Config) -> String {
+    let mut info = compat::report(ex, config);
+    for file in [
+        "/proc/version",
+        "/etc/os-release",
+        "/sys/kernel/security/lsm",
+    ] {
+        let contents = std::fs::read_to_string(file).unwrap_or_else(|e| format!("{e}\n"));
+        info += &format!("== {file}\n{}\n", contents.trim_end());
+    }
+    info
+}
+
+fn warp_diagnostics(ex: &dyn Executor, base_dir: &Path) -> String {
+    let mut diagnostics = String::new();
+    let outputs = [
+        ("status", warp_cli(ex, base_dir, &["status"])),
+        ("settings", warp_cli_op(ex, base_dir, Op::ShowSettings)),
+    ];
+    for (what, output) in outputs {
+        let output = output.unwrap_or_else(|e| format!("{e:#}"));
+        diagnostics += &format!("== warp-cli {what}\n{}\n", output.trim_end());
+    }
+    diagnostics
+}
+
+fn logs(base_dir: &Path) -> Result<String> {
+    let mut logs = format!("== {}\n", oplog::path(base_dir).display());
+    for line in oplog::tail(base_dir, HISTORY_LINES)? {
+        logs += &format!("{line}\n");
+    }
+    logs += "== Denials of our processes\n";
+    match lsm::recent_denials(SHOWN_DENIALS) {
+        Ok(denials) => denials
+            .iter()
+            .for_each(|denial| logs += &format!("{denial}\n")),
+        Err(e) => logs += &format!("{e:#}\n"),
+    }
+    // Kept by service::supervise
+    if let Ok(entries) = std::fs::read_dir(base_dir.join("services")) {
+        logs += "== Service exits\n";
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "exit") {
+                let status = std::fs::read_to_string(&path)?;
+                let name = path.file_stem().unwrap_or_default().to_string_lossy();
+                logs += &format!("{name}: {}\n", status.trim());
+            }
+        }
+    }
+    Ok(logs)
+}
+
+/// Collects what an issue report needs into a tar.gz: inspect's output, the recent logs,
+/// what warp-cli says, the host's details and the config with its secrets left out.
+/// Each part holds its error instead when it can't be collected.
+pub fn debug_bundle(
+    ex: &dyn Executor,
+    config: &Config,
+    config_file: Option<&Path>,
+    file: &Path,
+) -> Result<()> {
+    let base_dir = config.base_dir.as_path();
+    let dir = std::env::temp_dir().join(format!("bubblewarp-debug-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let or_error = |part: Result<String>| part.unwrap_or_else(|e| format!("{e:#}\n"));
+    let parts = [
+        ("inspect.json", or_error(inspect(ex, config))),
+        ("logs.txt", or_error(logs(base_dir))),
+        ("warp-cli.txt", warp_diagnostics(ex, base_dir)),
+        ("host.txt", host_info(ex, config)),
+        ("config.toml", or_error(sanitized_config(config_file))),
+    ];
+    let archived = parts
+        .iter()
+        .try_for_each(|(name, contents)| {
+            debug!("Adding {name} to the bundle");
+            std::fs::write(dir.join(name), contents)
+        })
+        .map_err(anyhow::Error::from)
+        .and_then(|()| {
+            ex.status(
+                ex.command(Binary::Tar)?
+                    .arg("-C")
+                    .arg(&dir)
+                    .arg("-czf")
+                    .arg(file)
+                    .arg("."),
+            )?
+            .exit_ok()
+            .context("Archiving the debug bundle")
+        });
+    std::fs::remove_dir_all(&dir)?;
+    archived?;
+    info!(
+        "Wrote {}, check it before attaching it to an issue",
+        file.display()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_secrets_out() {
+        let mut table: toml::Table = toml::from_str(
+            "[gateway]\nenabled = true\nendpoint = \"203.0.113.7\"\n\
+             [nat]\nsnat-to = \"198.51.100.4\"\n[hosts]\nnas = \"192.168.1.20\"\n\
+             [services.warp-svc]\nenv = { TOKEN = \"hunter2\" }\n\
+             [[services.extra]]\nname = \"exporter\"\ncommand = \"/bin/exporter\"\nargs = [\"--key=abc\"]\n",
+        )
+        .unwrap();
+        sanitize(&mut table);
+        let sanitized = toml::to_string(&table).unwrap();
+        assert!(!sanitized.contains("203.0.113.7"));
+        assert!(!sanitized.contains("hunter2"));
+        assert!(!sanitized.contains("--key=abc"));
+        assert!(!sanitized.contains("198.51.100.4"));
+        assert!(!sanitized.contains("192.168.1.20"));
+        assert!(sanitized.contains("TOKEN"));
+        assert!(sanitized.contains("exporter"));
+    }
+}
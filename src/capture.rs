@@ -0,0 +1,223 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::namespace::{nsenter_cmd, Type};
+use crate::net::in_container_net;
+use crate::paths::Binary;
+use crate::pause::ensure_up;
+use crate::pidfd::PidFd;
+use anyhow::{bail, Result};
+use nix::sys::signal::Signal;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, info};
+
+/// Packets are written without their link-layer header, so both the tunnel's and the veth's
+/// look the same
+const LINKTYPE_RAW: u32 = 101;
+const SNAPLEN: u32 = 65535;
+/// How often the fallback capture checks whether its duration is up
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// When a capture stops. Without either, it runs until interrupted.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Limits {
+    pub count: Option<u64>,
+    pub duration: Option<Duration>,
+}
+
+fn pcap_header() -> Vec<u8> {
+    let mut header = Vec::with_capacity(24);
+    header.extend(0xa1b2c3d4u32.to_le_bytes());
+    header.extend(2u16.to_le_bytes());
+    header.extend(4u16.to_le_bytes());
+    // Timezone offset and timestamp accuracy, always 0
+    header.extend([0; 8]);
+    header.extend(SNAPLEN.to_le_bytes());
+    header.extend(LINKTYPE_RAW.to_le_bytes());
+    header
+}
+
+fn pcap_record(since_epoch: Duration, packet: &[u8], orig_len: usize) -> Vec<u8> {
+    let mut record = Vec::with_capacity(16 + packet.len());
+    record.extend((since_epoch.as_secs() as u32).to_le_bytes());
+    record.extend(since_epoch.subsec_micros().to_le_bytes());
+    record.extend((packet.len() as u32).to_le_bytes());
+    record.extend((orig_len as u32).to_le_bytes());
+    record.extend(packet);
+    record
+}
+
+/// An AF_PACKET socket bound to the interface, giving IP packets without their link header
+fn packet_socket(iface: &str) -> Result<OwnedFd> {
+    let name = std::ffi::CString::new(iface)?;
+    // SAFETY: name is a nul-terminated string that outlives the call
+    let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+    if index == 0 {
+        bail!("The container has no {iface} interface")
+    }
+    let protocol = (libc::ETH_P_ALL as u16).to_be();
+    // SAFETY: socket takes no pointers, and we own the fd it returns
+    let fd = unsafe { libc::socket(libc::AF_PACKET, libc::SOCK_DGRAM, protocol as i32) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    // SAFETY: a zeroed sockaddr_ll is valid, the fields bind reads are set below
+    let mut addr: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+    addr.sll_family = libc::AF_PACKET as u16;
+    addr.sll_protocol = protocol;
+    addr.sll_ifindex = index as i32;
+    // SAFETY: addr is a sockaddr_ll, and its size is passed along with it
+    let ret = unsafe {
+        libc::bind(
+            socket.as_raw_fd(),
+            std::ptr::addr_of!(addr).cast(),
+            std::mem::size_of::<libc::sockaddr_ll>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    let timeout = libc::timeval {
+        tv_sec: 0,
+        tv_usec: POLL_INTERVAL.as_micros() as libc::suseconds_t,
+    };
+    // SAFETY: timeout is a timeval, and its size is passed along with it
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_RCVTIMEO,
+            std::ptr::addr_of!(timeout).cast(),
+            std::mem::size_of::<libc::timeval>() as u32,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(socket)
+}
+
+/// Captures without tcpdump, keeping only IPv4 and IPv6 packets. Each packet is written as it
+/// arrives, so the file stays readable when the capture is interrupted.
+fn capture_af_packet(base_dir: &Path, iface: String, file: PathBuf, limits: Limits) -> Result<u64> {
+    let mut out = File::create(&file)?;
+    out.write_all(&pcap_header())?;
+    in_container_net(base_dir, move || {
+        let socket = packet_socket(&iface)?;
+        let deadline = limits.duration.map(|duration| Instant::now() + duration);
+        let mut buf = vec![0u8; SNAPLEN as usize];
+        let mut captured = 0;
+        while limits.count.is_none_or(|count| captured < count)
+            && deadline.is_none_or(|deadline| Instant::now() < deadline)
+        {
+            // SAFETY: a zeroed sockaddr_ll is valid
+            let mut from: libc::sockaddr_ll = unsafe { std::mem::zeroed() };
+            let mut from_len = std::mem::size_of::<libc::sockaddr_ll>() as u32;
+            // SAFETY: buf and from are valid for the lengths passed along with them
+            let len = unsafe {
+                libc::recvfrom(
+                    socket.as_raw_fd(),
+                    buf.as_mut_ptr().cast(),
+                    buf.len(),
+                    libc::MSG_TRUNC,
+                    std::ptr::addr_of_mut!(from).cast(),
+                    &mut from_len,
+                )
+            };
+            if len < 0 {
+                let err = std::io::Error::last_os_error();
+                match err.kind() {
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::Interrupted => continue,
+                    _ => return Err(err.into()),
+                }
+            }
+            let protocol = u16::from_be(from.sll_protocol) as i32;
+            if protocol != libc::ETH_P_IP && protocol != libc::ETH_P_IPV6 {
+                continue;
+            }
+            let orig_len = len as usize;
+            let packet = &buf[..orig_len.min(buf.len())];
+            let now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+            out.write_all(&pcap_record(now, packet, orig_len))?;
+            captured += 1;
+        }
+        Ok(captured)
+    })
+}
+
+/// Runs tcpdump in the container's network namespace, where it sees the host's files
+fn capture_tcpdump(
+    ex: &dyn Executor,
+    ns_pid: u32,
+    tcpdump: PathBuf,
+    iface: &str,
+    file: &Path,
+    limits: Limits,
+) -> Result<()> {
+    let mut cmd = Command::new(tcpdump);
+    // Some distros have tcpdump drop to its own user, which can't write where root can
+    cmd.args(["-i", iface, "-U", "-Z", "root", "-w"]).arg(file);
+    if let Some(count) = limits.count {
+        cmd.args(["-c", &count.to_string()]);
+    }
+    let mut child = ex.spawn(&mut nsenter_cmd(ex, ns_pid, &[Type::Net], &cmd)?)?;
+    if let Some(duration) = limits.duration {
+        let process = PidFd::open(child.id() as i32)?;
+        if !process.wait_exit(duration)? {
+            debug!("Capture duration is up, stopping tcpdump");
+            // nsenter execs tcpdump when no PID namespace is joined, tcpdump flushes on SIGINT
+            ex.kill(&process, Signal::SIGINT)?;
+        }
+    }
+    child.wait()?.exit_ok()?;
+    Ok(())
+}
+
+/// Captures the packets on one of the container's interfaces into a pcap file. Uses tcpdump when
+/// the host has it, and a plain AF_PACKET socket otherwise.
+pub fn capture(
+    ex: &dyn Executor,
+    config: &Config,
+    iface: &str,
+    file: &Path,
+    limits: Limits,
+) -> Result<()> {
+    let ns_pid = ensure_up(&config.base_dir)?;
+    match ex.command(Binary::Tcpdump) {
+        Ok(cmd) => {
+            let tcpdump = PathBuf::from(cmd.get_program());
+            capture_tcpdump(ex, ns_pid, tcpdump, iface, file, limits)
+        }
+        Err(e) => {
+            debug!("{e:#}");
+            info!("Capturing on {iface} without tcpdump, only IP packets are kept");
+            let captured =
+                capture_af_packet(&config.base_dir, iface.to_owned(), file.to_owned(), limits)?;
+            info!("Captured {captured} packets to {}", file.display());
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_pcap() {
+        let header = pcap_header();
+        assert_eq!(header.len(), 24);
+        assert_eq!(header[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+        assert_eq!(header[20..], [101, 0, 0, 0]);
+        let record = pcap_record(Duration::from_micros(1_500_000), &[0x45, 0], 60);
+        assert_eq!(
+            record,
+            [1, 0, 0, 0, 0x20, 0xa1, 7, 0, 2, 0, 0, 0, 60, 0, 0, 0, 0x45, 0]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use crate::config::ContainerConfig;
+use crate::exec::Executor;
+use crate::pidfd::PidFd;
+use anyhow::{bail, Context, Result};
+use nix::sys::signal::Signal;
+use std::fs::OpenOptions;
+use std::os::fd::{AsRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+/// Holds the cgroups of all instances
+const PARENT: &str = "bubblewarp";
+/// The period cpu.max quotas are expressed in, same as the kernel's default
+const CPU_PERIOD_US: u64 = 100_000;
+/// How long services get to exit after SIGTERM before the whole cgroup is killed
+const KILL_GRACE: Duration = Duration::from_secs(1);
+
+fn is_v2() -> bool {
+    Path::new(CGROUP_ROOT).join("cgroup.controllers").exists()
+}
+
+/// One cgroup per instance, named after its whole base directory
+pub fn path(base_dir: &Path) -> PathBuf {
+    let base_dir = base_dir
+        .canonicalize()
+        .unwrap_or_else(|_| base_dir.to_owned());
+    Path::new(CGROUP_ROOT).join(PARENT).join(escape(&base_dir))
+}
+
+/// Escapes a path into a single name the way systemd-escape --path does
+fn escape(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    match path.trim_matches('/') {
+        "" => "-".to_owned(),
+        path => path
+            .replace('\\', "\\x5c")
+            .replace('-', "\\x2d")
+            .replace('/', "-"),
+    }
+}
+
+fn cpu_max(cpus: f64) -> String {
+    let quota = (cpus * CPU_PERIOD_US as f64).round() as u64;
+    format!("{quota} {CPU_PERIOD_US}")
+}
+
+/// Creates the instance's cgroup and applies the configured limits.
+/// Returns None on hosts without the unified hierarchy, where we can only run without one.
+pub fn create(base_dir: &Path, config: &ContainerConfig) -> Result<Option<PathBuf>> {
+    let has_limits = config.memory_max.is_some() || config.cpus.is_some();
+    if !is_v2() {
+        if has_limits {
+            bail!("Resource limits need the cgroup v2 hierarchy mounted on {CGROUP_ROOT}")
+        }
+        warn!("No cgroup v2 hierarchy on {CGROUP_ROOT}, container processes won't get their own cgroup");
+        return Ok(None);
+    }
+
+    let path = path(base_dir);
+    debug!("Creating cgroup {}", path.display());
+    std::fs::create_dir_all(&path).context("Creating the container's cgroup")?;
+
+    let mut limits = Vec::new();
+    if let Some(memory_max) = &config.memory_max {
+        limits.push(("memory", "memory.max", memory_max.clone()));
+    }
+    if let Some(cpus) = config.cpus {
+        limits.push(("cpu", "cpu.max", cpu_max(cpus)));
+    }
+    for (controller, file, value) in limits {
+        // Limits only apply with the controller enabled all the way down
+        for cgroup in [Path::new(CGROUP_ROOT), &Path::new(CGROUP_ROOT).join(PARENT)] {
+            enable_controller(cgroup, controller)?;
+        }
+        std::fs::write(path.join(file), &value)
+            .with_context(|| format!("Setting {file} of the container's cgroup to {value}"))?;
+    }
+    Ok(Some(path))
+}
+
+fn enable_controller(cgroup: &Path, controller: &str) -> Result<()> {
+    let subtree_control = cgroup.join("cgroup.subtree_control");
+    let enabled = std::fs::read_to_string(&subtree_control)?;
+    if enabled.split_whitespace().any(|c| c == controller) {
+        return Ok(());
+    }
+    debug!("Enabling the {controller} cgroup controller");
+    std::fs::write(&subtree_control, format!("+{controller}"))
+        .with_context(|| format!("Enabling the {controller} cgroup controller"))
+}
+
+/// The cgroup a process is in, on hosts with the unified hierarchy
+pub fn of_process(pid: u32) -> Result<Option<PathBuf>> {
+    if !is_v2() {
+        return Ok(None);
+    }
+    let cgroups = procfs::process::Process::new(pid as i32)?.cgroups()?;
+    Ok(cgroups
+        .into_iter()
+        .find(|cg| cg.hierarchy == 0)
+        .map(|cg| Path::new(CGROUP_ROOT).join(cg.pathname.trim_start_matches('/'))))
+}
+
+/// Moves the command into `cgroup` between fork and exec, so it never runs outside of it
+pub fn join_on_exec(cmd: &mut Command, cgroup: &Path) -> Result<()> {
+    let procs: OwnedFd = OpenOptions::new()
+        .write(true)
+        .open(cgroup.join("cgroup.procs"))
+        .with_context(|| format!("Opening {}", cgroup.display()))?
+        .into();
+    // SAFETY: write(2) is async-signal-safe, and the closure doesn't allocate
+    unsafe {
+        cmd.pre_exec(move || {
+            nix::unistd::write(procs.as_raw_fd(), b"0")?;
+            Ok(())
+        });
+    }
+    Ok(())
+}
+
+fn is_populated(cgroup: &Path) -> Result<bool> {
+    let events = std::fs::read_to_string(cgroup.join("cgroup.events"))?;
+    Ok(events.lines().any(|l| l == "populated 1"))
+}
+
+fn wait_until_empty(cgroup: &Path, timeout: Duration) -> Result<bool> {
+    let start = Instant::now();
+    while is_populated(cgroup)? {
+        if start.elapsed() > timeout {
+            return Ok(false);
+        }
+        std::thread::sleep(Duration::from_millis(25));
+    }
+    Ok(true)
+}
+
+/// Kills whatever is still running in the instance's cgroup, then removes it
+pub fn remove(ex: &dyn Executor, base_dir: &Path) -> Result<()> {
+    let path = path(base_dir);
+    if !path.exists() {
+        return Ok(());
+    }
+    if !wait_until_empty(&path, KILL_GRACE)? {
+        debug!("Killing the processes left in cgroup {}", path.display());
+        let kill_file = path.join("cgroup.kill");
+        if kill_file.exists() {
+            std::fs::write(kill_file, "1").context("Writing cgroup.kill")?;
+        } else {
+            // cgroup.kill is only available since Linux 5.14
+            for pid in std::fs::read_to_string(path.join("cgroup.procs"))?.lines() {
+                let Ok(process) = PidFd::open(pid.parse()?) else {
+                    continue;
+                };
+                // The PID may have been reused after we read it
+                if of_process(process.pid() as u32).ok().flatten().as_ref() == Some(&path) {
+                    let _ = ex.kill(&process, Signal::SIGKILL);
+                }
+            }
+        }
+        if !wait_until_empty(&path, KILL_GRACE)? {
+            bail!("Processes in cgroup {} survived SIGKILL", path.display())
+        }
+    }
+    debug!("Removing cgroup {}", path.display());
+    std::fs::remove_dir(&path).with_context(|| format!("Removing cgroup {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cgroup_named_after_base_dir() {
+        assert_eq!(
+            path(Path::new("/root/.local/share/bubblewarp")),
+            Path::new("/sys/fs/cgroup/bubblewarp/root-.local-share-bubblewarp")
+        );
+        // Same last component
+        assert_ne!(
+            path(Path::new("/root/.local/share/bubblewarp")),
+            path(Path::new("/srv/x/bubblewarp"))
+        );
+        assert_ne!(escape(Path::new("/srv/a-b")), escape(Path::new("/srv/a/b")));
+        assert_eq!(escape(Path::new("/srv/a-b")), "srv-a\\x2db");
+        assert_eq!(escape(Path::new("/")), "-");
+    }
+
+    #[test]
+    fn cpu_quota() {
+        assert_eq!(cpu_max(1.0), "100000 100000");
+        assert_eq!(cpu_max(0.5), "50000 100000");
+    }
+}
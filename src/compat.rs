@@ -0,0 +1,248 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::fetch::compare_versions;
+use crate::firewall;
+use crate::paths::Binary;
+use crate::pause::warp_cli;
+use crate::rootfs;
+use anyhow::{bail, Context, Result};
+use std::cmp::Ordering;
+use std::path::Path;
+use std::sync::OnceLock;
+use tracing::{debug, warn};
+
+/// The WARP releases bubblewarp was tested with, by year.month. Cloudflare changes warp-cli's
+/// commands between releases, so others may not work.
+const TESTED_FIRST: &str = "2023.9";
+const TESTED_LAST: &str = "2025.9";
+
+/// The version in a `--version` output like "warp-cli 2024.6.497.0"
+fn parse_version(output: &str) -> Option<&str> {
+    output
+        .split_whitespace()
+        .find(|word| word.starts_with(|c: char| c.is_ascii_digit()))
+}
+
+fn is_tested(version: &str) -> bool {
+    let release: Vec<&str> = version.splitn(3, '.').take(2).collect();
+    let release = release.join(".");
+    compare_versions(&release, TESTED_FIRST) != Ordering::Less
+        && compare_versions(&release, TESTED_LAST) != Ordering::Greater
+}
+
+/// warp-cli's commands changed in 2024, when they were grouped under registration, mode and proxy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Dialect {
+    /// `register`, `account`, `set-mode` and `set-proxy-port`
+    Legacy,
+    Current,
+}
+
+/// What bubblewarp asks of warp-cli, whichever way it is spelled
+#[derive(Debug, Copy, Clone)]
+pub enum Op<'a> {
+    Register,
+    ShowRegistration,
+    ShowSettings,
+    Mode(&'a str),
+    ProxyPort(u16),
+}
+
+impl Dialect {
+    /// Tells from warp-cli's help, which lists the subcommands it has
+    fn detect(help: &str) -> Dialect {
+        let has_command = |name: &str| {
+            help.lines()
+                .any(|line| line.split_whitespace().next() == Some(name))
+        };
+        if has_command("registration") || !has_command("register") {
+            Dialect::Current
+        } else {
+            Dialect::Legacy
+        }
+    }
+
+    fn args(self, op: Op) -> Vec<String> {
+        let args: Vec<&str> = match (self, op) {
+            (Dialect::Legacy, Op::Register) => vec!["register"],
+            (Dialect::Legacy, Op::ShowRegistration) => vec!["account"],
+            (Dialect::Legacy, Op::ShowSettings) => vec!["settings"],
+            (Dialect::Legacy, Op::Mode(mode)) => vec!["set-mode", mode],
+            (Dialect::Legacy, Op::ProxyPort(_)) => vec!["set-proxy-port"],
+            (Dialect::Current, Op::Register) => vec!["registration", "new"],
+            (Dialect::Current, Op::ShowRegistration) => vec!["registration", "show"],
+            (Dialect::Current, Op::ShowSettings) => vec!["settings", "list"],
+            (Dialect::Current, Op::Mode(mode)) => vec!["mode", mode],
+            (Dialect::Current, Op::ProxyPort(_)) => vec!["proxy", "port"],
+        };
+        let mut args: Vec<String> = args.into_iter().map(str::to_owned).collect();
+        if let Op::ProxyPort(port) = op {
+            args.push(port.to_string());
+        }
+        args
+    }
+}
+
+/// The installed warp-cli's dialect, asked once per run
+fn dialect(ex: &dyn Executor, base_dir: &Path) -> Result<Dialect> {
+    static DIALECT: OnceLock<Dialect> = OnceLock::new();
+    if let Some(dialect) = DIALECT.get() {
+        return Ok(*dialect);
+    }
+    let dialect = Dialect::detect(&warp_cli(ex, base_dir, &["--help"])?);
+    debug!("warp-cli uses the {dialect:?} commands");
+    Ok(*DIALECT.get_or_init(|| dialect))
+}
+
+/// Runs an operation with the commands the installed warp-cli understands
+pub fn warp_cli_op(ex: &dyn Executor, base_dir: &Path, op: Op) -> Result<String> {
+    let args = dialect(ex, base_dir)?.args(op);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    warp_cli(ex, base_dir, &args)
+}
+
+pub fn version(ex: &dyn Executor, bin: Binary) -> Result<String> {
+    let out = ex.output(ex.command(bin)?.arg("--version"))?;
+    out.status.exit_ok()?;
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    parse_version(&stdout)
+        .map(str::to_owned)
+        .with_context(|| format!("No version in {}", stdout.trim()))
+}
+
+/// The first line a program prints about its version, some print it to stderr
+fn version_line(ex: &dyn Executor, bin: Binary, arg: &str) -> Result<String> {
+    let out = ex.output(ex.command(bin)?.arg(arg))?;
+    let printed = [out.stdout, out.stderr].concat();
+    String::from_utf8_lossy(&printed)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .map(str::to_owned)
+        .with_context(|| format!("{} {arg} printed nothing", bin.name()))
+}
+
+/// What `version --full` prints, the versions of everything bubblewarp runs
+pub fn report(ex: &dyn Executor, config: &Config) -> String {
+    let mut lines = vec![(
+        "bubblewarp".to_owned(),
+        env!("CARGO_PKG_VERSION").to_owned(),
+    )];
+    let or_error = |version: Result<String>| version.unwrap_or_else(|e| format!("unknown ({e:#})"));
+    for bin in [Binary::WarpSvc, Binary::WarpCli] {
+        lines.push((bin.name().to_owned(), or_error(version(ex, bin))));
+    }
+    lines.push((
+        "danted".to_owned(),
+        or_error(version_line(ex, Binary::Danted, "-v")),
+    ));
+    lines.push((
+        "util-linux".to_owned(),
+        or_error(version_line(ex, Binary::Nsenter, "--version")),
+    ));
+    lines.push((
+        "iptables".to_owned(),
+        or_error(version_line(ex, Binary::Iptables, "--version")),
+    ));
+    let backend = firewall::resolve_backend(ex, config.firewall.backend);
+    lines.push(("firewall".to_owned(), format!("{backend:?}").to_lowercase()));
+    let kernel = std::fs::read_to_string("/proc/sys/kernel/osrelease");
+    lines.push((
+        "kernel".to_owned(),
+        or_error(
+            kernel
+                .map(|release| release.trim().to_owned())
+                .map_err(Into::into),
+        ),
+    ));
+    lines
+        .iter()
+        .map(|(what, version)| format!("{what:<12}{version}\n"))
+        .collect()
+}
+
+/// Warns about WARP versions bubblewarp wasn't tested with, or fails with `strict`.
+/// An image rootfs brings its own WARP, which can't be run before the container is.
+pub fn check_versions(ex: &dyn Executor, config: &Config) -> Result<()> {
+    if config.container.rootfs == rootfs::Kind::Image {
+        debug!("WARP comes from the image, not checking its version");
+        return Ok(());
+    }
+    let complain = |message: String| -> Result<()> {
+        if config.strict {
+            bail!("{message}")
+        }
+        warn!("{message}");
+        Ok(())
+    };
+    let mut versions = Vec::new();
+    for bin in [Binary::WarpSvc, Binary::WarpCli] {
+        match version(ex, bin) {
+            Ok(version) if is_tested(&version) => {
+                debug!("{} {version}", bin.name());
+                versions.push(version);
+            }
+            Ok(version) => {
+                complain(format!(
+                    "{} {version} is untested, bubblewarp works with {TESTED_FIRST} to {TESTED_LAST}",
+                    bin.name()
+                ))?;
+                versions.push(version);
+            }
+            Err(e) => complain(format!("Couldn't tell the {} version: {e:#}", bin.name()))?,
+        }
+    }
+    if let [svc, cli] = &versions[..] {
+        if svc != cli {
+            complain(format!(
+                "warp-svc {svc} and warp-cli {cli} are from different releases"
+            ))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::{MockExecutor, Reply};
+
+    #[test]
+    fn tested_versions() {
+        assert_eq!(
+            parse_version("warp-cli 2024.6.497.0\n"),
+            Some("2024.6.497.0")
+        );
+        assert!(is_tested("2023.9.301"));
+        assert!(is_tested("2025.9.558.0"));
+        assert!(!is_tested("2023.3.470"));
+        assert!(!is_tested("2025.10.186.0"));
+    }
+
+    #[test]
+    fn translates_to_dialect() {
+        let legacy = "Commands:\n  register        Register with Cloudflare\n  set-mode        Set the mode\n";
+        let current = "Commands:\n  registration    Registration subcommands\n  mode            Set the mode\n";
+        assert_eq!(Dialect::detect(legacy), Dialect::Legacy);
+        assert_eq!(Dialect::detect(current), Dialect::Current);
+        assert_eq!(
+            Dialect::Legacy.args(Op::ProxyPort(40000)),
+            ["set-proxy-port", "40000"]
+        );
+        assert_eq!(
+            Dialect::Current.args(Op::ProxyPort(40000)),
+            ["proxy", "port", "40000"]
+        );
+        assert_eq!(Dialect::Legacy.args(Op::ShowRegistration), ["account"]);
+    }
+
+    #[test]
+    fn strict_refuses_untested() {
+        let ex =
+            MockExecutor::with_handler(|args| Reply::ok(&format!("{} 2022.12.583.0", args[0])));
+        let mut config = Config::default();
+        check_versions(&ex, &config).unwrap();
+        config.strict = true;
+        assert!(check_versions(&ex, &config).is_err());
+    }
+}
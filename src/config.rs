@@ -0,0 +1,335 @@
+use crate::dns::{self, DnsConfig};
+use crate::extra;
+use crate::firewall::{FirewallConfig, NatConfig};
+use crate::gateway::GatewayConfig;
+use crate::health::HealthConfig;
+use crate::net::Network;
+use crate::paths::PathOverrides;
+use crate::proxy::{self, ProxyConfig};
+use crate::readiness::TimeoutsConfig;
+use crate::relay::RelayConfig;
+use crate::rootfs;
+use crate::service::ServicesConfig;
+use crate::up::Parts;
+use crate::watchdog::WatchdogConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+/// Everything describing one container instance
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    #[serde(skip)]
+    pub base_dir: PathBuf,
+    #[serde(skip)]
+    pub network: Network,
+    /// Set from up's flags
+    #[serde(skip)]
+    pub parts: Parts,
+    /// Set from up's --strict flag
+    #[serde(skip)]
+    pub strict: bool,
+    /// Set from up's --egress-iface flag
+    #[serde(skip)]
+    pub egress_iface: Option<String>,
+    /// The config file as written, which up records for reload to compare against
+    #[serde(skip)]
+    pub file: toml::Table,
+    pub paths: PathOverrides,
+    pub firewall: FirewallConfig,
+    pub nat: NatConfig,
+    pub gateway: GatewayConfig,
+    pub container: ContainerConfig,
+    pub services: ServicesConfig,
+    pub proxy: ProxyConfig,
+    pub dns: DnsConfig,
+    pub health: HealthConfig,
+    pub watchdog: WatchdogConfig,
+    pub timeouts: TimeoutsConfig,
+    pub relay: RelayConfig,
+    /// Added to /etc/hosts inside the container, by name. Names with dots need quotes,
+    /// like `"git.corp.example" = "10.0.0.5"`, or TOML reads them as tables.
+    pub hosts: BTreeMap<String, IpAddr>,
+}
+
+/// The `[container]` section of the config file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ContainerConfig {
+    pub hostname: String,
+    /// Written as-is to memory.max, e.g. "512M"
+    pub memory_max: Option<String>,
+    /// How many CPUs worth of time the container's processes may use
+    pub cpus: Option<f64>,
+    /// Creates a time namespace with the monotonic and boot clocks shifted by this many seconds
+    pub time_offset: Option<i64>,
+    pub rootfs: rootfs::Kind,
+    /// Image for the image rootfs, pinned with `name@sha256:<digest>`
+    pub image: Option<String>,
+    /// Covered with an empty read-only directory or file inside the container, with the host rootfs
+    pub mask_paths: Vec<PathBuf>,
+    /// Remounts the host filesystem read-only inside the container, with the host rootfs.
+    /// Only /etc, /tmp, /run and the WARP state stay writable.
+    pub read_only: bool,
+}
+
+/// Where users keep their secrets, none of which the container needs
+const DEFAULT_MASK_PATHS: &[&str] = &[
+    "/home",
+    "/root/.ssh",
+    "/root/.gnupg",
+    "/etc/shadow",
+    "/etc/gshadow",
+    "/etc/ssh",
+];
+
+impl Default for ContainerConfig {
+    fn default() -> Self {
+        ContainerConfig {
+            hostname: "warp-container".to_owned(),
+            memory_max: None,
+            cpus: None,
+            time_offset: None,
+            rootfs: rootfs::Kind::Host,
+            image: None,
+            mask_paths: DEFAULT_MASK_PATHS.iter().map(PathBuf::from).collect(),
+            read_only: false,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config file, if any. An explicitly requested file must exist.
+    pub fn load(path: Option<&Path>, base_dir: PathBuf) -> Result<Self> {
+        let (path, required) = match path {
+            Some(path) => (path.to_owned(), true),
+            None => (default_path()?, false),
+        };
+        let data = match std::fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => String::new(),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Reading config file {}", path.display()))
+            }
+        };
+        let invalid = || format!("Invalid config file {}", path.display());
+        let mut config: Config = toml::from_str(&data).with_context(invalid)?;
+        let file = toml::from_str(&data).with_context(invalid)?;
+        config.base_dir = base_dir;
+        config.file = file;
+        Ok(config)
+    }
+
+    /// Refuses settings that don't go together, before anything is set up. Only looks at the
+    /// config, what depends on the host is checked by up.
+    pub fn check(&self) -> Result<()> {
+        if self.gateway.enabled && self.proxy.backend == proxy::Backend::Warp {
+            bail!("The WireGuard gateway needs WARP's tunnel interface, which the warp proxy backend doesn't create")
+        }
+        self.gateway.check(&self.network)?;
+        proxy::check(self)?;
+        dns::check(&self.dns)?;
+        extra::start_order(&self.services.extra)?;
+        Ok(())
+    }
+}
+
+/// Checks the config file like up would before setting anything up, for the file loaded as
+/// `config`. Syntax errors and unknown keys already failed the load, with where they are.
+pub fn validate(config: &Config, path: Option<&Path>) -> Result<String> {
+    config.check()?;
+    let path = path_or_default(path)?;
+    Ok(match path.exists() {
+        true => format!("{} is valid\n", path.display()),
+        false => format!("There's no {}, the defaults apply\n", path.display()),
+    })
+}
+
+/// A key as TOML takes it, quoted when it isn't bare like host names with dots
+fn toml_key(key: &str) -> String {
+    let bare = key
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    match bare && !key.is_empty() {
+        true => key.to_owned(),
+        false => toml::Value::String(key.to_owned()).to_string(),
+    }
+}
+
+/// Writes a table's values, each with where it came from, then its subtables under their
+/// headers. Tables without values of their own get no header, nor do array entries whose
+/// header is already written.
+fn write_effective(
+    out: &mut String,
+    table: &toml::Table,
+    path: &[&str],
+    source: &dyn Fn(&[&str]) -> String,
+    headed: bool,
+) {
+    let is_table = |value: &toml::Value| match value {
+        toml::Value::Table(_) => true,
+        toml::Value::Array(items) => items.first().is_some_and(toml::Value::is_table),
+        _ => false,
+    };
+    if !headed && !path.is_empty() && table.values().any(|value| !is_table(value)) {
+        let header: Vec<String> = path.iter().map(|key| toml_key(key)).collect();
+        let _ = writeln!(out, "\n[{}]", header.join("."));
+    }
+    for (key, value) in table.iter().filter(|(_, value)| !is_table(value)) {
+        let key_path = [path, &[key.as_str()]].concat();
+        let _ = writeln!(out, "{} = {value}  # {}", toml_key(key), source(&key_path));
+    }
+    for (key, value) in table.iter().filter(|(_, value)| is_table(value)) {
+        let key_path = [path, &[key.as_str()]].concat();
+        match value {
+            toml::Value::Table(table) => write_effective(out, table, &key_path, source, false),
+            toml::Value::Array(items) => {
+                let header: Vec<String> = key_path.iter().map(|key| toml_key(key)).collect();
+                for (i, item) in items.iter().enumerate() {
+                    let _ = writeln!(out, "\n[[{}]]  # {}", header.join("."), source(&key_path));
+                    let Some(item) = item.as_table() else {
+                        continue;
+                    };
+                    // Looked up in the file by the entry's index
+                    let index = i.to_string();
+                    let entry_source = |sub_path: &[&str]| {
+                        let rest = &sub_path[key_path.len()..];
+                        source(&[&key_path, &[index.as_str()][..], rest].concat())
+                    };
+                    write_effective(out, item, &key_path, &entry_source, true);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// The config file as written, or with `effective` every setting up would use, each annotated
+/// with whether it's a default, from the file, or from one of the `flags` that override the
+/// section or key they're paired with
+pub fn show(
+    config: &Config,
+    path: Option<&Path>,
+    effective: bool,
+    flags: &[(&str, &str)],
+) -> Result<String> {
+    let path = path_or_default(path)?;
+    if !effective {
+        return Ok(match std::fs::read_to_string(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                format!("# There's no {}, the defaults apply\n", path.display())
+            }
+            Err(e) => return Err(e.into()),
+        });
+    }
+    let source = |key_path: &[&str]| {
+        let dotted = key_path.join(".");
+        let flag = flags
+            .iter()
+            .find(|(key, _)| dotted == *key || dotted.starts_with(&format!("{key}.")));
+        if let Some((_, flag)) = flag {
+            return flag.to_string();
+        }
+        let (first, rest) = key_path.split_first().expect("a value has a key");
+        let mut in_file = config.file.get(*first);
+        for key in rest {
+            in_file = in_file.and_then(|value| match value {
+                toml::Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+                _ => value.get(*key),
+            });
+        }
+        match in_file.is_some() {
+            true => "config file".to_owned(),
+            false => "default".to_owned(),
+        }
+    };
+    let mut out = format!(
+        "# Flags over {} over the defaults\n# The veth subnet {} is built in\n",
+        path.display(),
+        config.network.subnet()
+    );
+    write_effective(
+        &mut out,
+        &toml::Table::try_from(config)?,
+        &[],
+        &source,
+        false,
+    );
+    Ok(out)
+}
+
+fn path_or_default(path: Option<&Path>) -> Result<PathBuf> {
+    match path {
+        Some(path) => Ok(path.to_owned()),
+        None => default_path(),
+    }
+}
+
+/// Running setuid root, the config file picks what runs as root: the binaries in [paths], the
+/// extra services. So only one that just root can write is used.
+pub fn check_root_only(path: &Path) -> Result<()> {
+    let meta = match std::fs::metadata(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        meta => meta?,
+    };
+    if meta.uid() != 0 || meta.mode() & 0o022 != 0 {
+        bail!(
+            "Running setuid root, but {} can be written by users other than root. Have root own it, or run bubblewarp as root",
+            path.display()
+        )
+    }
+    Ok(())
+}
+
+pub fn default_path() -> Result<PathBuf> {
+    let project_dirs = directories::ProjectDirs::from("", "", "bubblewarp")
+        .ok_or_else(|| anyhow!("Failed to get the path of our config directory"))?;
+    Ok(project_dirs.config_dir().join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn shows_where_settings_come_from() {
+        let data = "[dns]\nmode = \"custom\"\nservers = [\"9.9.9.9\"]\n\n\
+                    [[services.extra]]\nname = \"exporter\"\ncommand = \"/bin/exporter\"\n";
+        let mut config: Config = toml::from_str(data).unwrap();
+        config.file = toml::from_str(data).unwrap();
+        let flags = [("timeouts", "--wait-timeout")];
+        let shown = show(&config, Some(Path::new("/config.toml")), true, &flags).unwrap();
+        for line in [
+            "mode = \"custom\"  # config file",
+            "expose = false  # default",
+            "warp-ready = 5.0  # --wait-timeout",
+            "[[services.extra]]  # config file",
+            "name = \"exporter\"  # config file",
+            "restart = \"no\"  # default",
+        ] {
+            assert!(shown.lines().any(|shown| shown == line), "{line}");
+        }
+        // What it shows is a config file that gives the same settings
+        let reparsed: Config = toml::from_str(&shown).unwrap();
+        assert_eq!(reparsed.dns.servers, config.dns.servers);
+        assert_eq!(reparsed.services.extra[0].name, "exporter");
+    }
+
+    #[test]
+    fn setuid_refuses_config_others_can_write() {
+        let dir = TempDir::new("config-owner");
+        let path = dir.join("config.toml");
+        check_root_only(&path).unwrap();
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o666)).unwrap();
+        assert!(check_root_only(&path).is_err());
+    }
+}
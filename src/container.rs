@@ -0,0 +1,207 @@
+use anyhow::{anyhow, bail, Context, Result};
+use nix::sys::statfs::{fstatfs, NSFS_MAGIC};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::File;
+use std::os::fd::OwnedFd;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use strum::IntoEnumIterator;
+use strum_macros::EnumIter;
+
+/// Written and read by the names Display and FromStr use, in flags, files and JSON alike
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, EnumIter, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum Type {
+    User,
+    Pid,
+    Mount,
+    Net,
+    Uts,
+    Ipc,
+    Cgroup,
+    /// Only created on request, see [`Type::is_optional`]
+    Time,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Type::User => "user",
+            Type::Pid => "pid",
+            Type::Mount => "mount",
+            Type::Net => "net",
+            Type::Uts => "uts",
+            Type::Ipc => "ipc",
+            Type::Cgroup => "cgroup",
+            Type::Time => "time",
+        })
+    }
+}
+
+impl FromStr for Type {
+    type Err = anyhow::Error;
+
+    /// Takes the names Display gives, and mnt like /proc/<pid>/ns has it
+    fn from_str(name: &str) -> Result<Self> {
+        match Type::iter().find(|ns_type| ns_type.to_string() == name) {
+            Some(ns_type) => Ok(ns_type),
+            None if name == "mnt" => Ok(Type::Mount),
+            None => bail!(
+                "Unknown namespace {name}, expected one of {}",
+                Type::iter()
+                    .map(|t| t.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }
+    }
+}
+
+impl TryFrom<String> for Type {
+    type Error = anyhow::Error;
+
+    fn try_from(name: String) -> Result<Self> {
+        name.parse()
+    }
+}
+
+impl From<Type> for String {
+    fn from(ns_type: Type) -> String {
+        ns_type.to_string()
+    }
+}
+
+impl Type {
+    /// Containers are complete without these namespaces
+    pub fn is_optional(self) -> bool {
+        matches!(self, Type::Time)
+    }
+
+    /// The name of its link in /proc/<pid>/ns
+    pub fn proc_name(self) -> String {
+        match self {
+            Type::Mount => "mnt".to_owned(),
+            ns_type => ns_type.to_string(),
+        }
+    }
+}
+
+/// A bubblewarp container, found by the directory its namespaces are mounted in
+#[derive(Debug, Clone)]
+pub struct Container {
+    base_dir: PathBuf,
+}
+
+impl Container {
+    /// The container bubblewarp runs without --base-dir
+    pub fn new() -> Result<Self> {
+        let project_dirs = directories::ProjectDirs::from("", "", "bubblewarp")
+            .ok_or_else(|| anyhow!("Failed to get the path of our data directory"))?;
+        Ok(Container::with_base_dir(project_dirs.data_dir()))
+    }
+
+    /// The container run with this --base-dir
+    pub fn with_base_dir(base_dir: impl Into<PathBuf>) -> Self {
+        Container {
+            base_dir: base_dir.into(),
+        }
+    }
+
+    pub fn base_dir(&self) -> &Path {
+        &self.base_dir
+    }
+
+    /// Where the namespace is bind mounted while the container is up
+    pub fn mount_point(&self, ns_type: Type) -> PathBuf {
+        let mut path = self.base_dir.clone();
+        match ns_type {
+            Type::User => path.push("user"),
+            Type::Pid => path.push("pid"),
+            Type::Mount => path.push("mount"),
+            Type::Net => path.push("net"),
+            Type::Uts => path.push("uts"),
+            Type::Ipc => path.push("ipc"),
+            Type::Cgroup => path.push("cgroup"),
+            Type::Time => path.push("time"),
+        };
+        path
+    }
+
+    /// A file descriptor for each of the container's namespaces, to pass to setns(2). Optional
+    /// namespaces the container was created without are left out. They come in the order to
+    /// join them in, the user namespace first.
+    ///
+    /// Fails if the container isn't up. A mount point that holds no namespace is only the empty
+    /// file it's mounted on, which setns would refuse.
+    pub fn ns_fds(&self) -> Result<Vec<(Type, OwnedFd)>> {
+        let mut fds = Vec::new();
+        for ns_type in Type::iter() {
+            let path = self.mount_point(ns_type);
+            let file = match File::open(&path) {
+                Ok(file) => Some(file),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Opening {}", path.display()));
+                }
+            };
+            let is_ns = match &file {
+                Some(file) => fstatfs(file)?.filesystem_type() == NSFS_MAGIC,
+                None => false,
+            };
+            match file.filter(|_| is_ns) {
+                Some(file) => fds.push((ns_type, file.into())),
+                None if ns_type.is_optional() => (),
+                None => bail!("The container isn't up, its {ns_type} namespace isn't mounted"),
+            }
+        }
+        Ok(fds)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn parses_types() {
+        for ns_type in Type::iter() {
+            assert_eq!(ns_type.to_string().parse::<Type>().unwrap(), ns_type);
+        }
+        assert_eq!("mnt".parse::<Type>().unwrap(), Type::Mount);
+        assert!("network".parse::<Type>().is_err());
+
+        #[derive(Debug, PartialEq, Deserialize, Serialize)]
+        struct Listed {
+            ns: Vec<Type>,
+        }
+        let listed: Listed = toml::from_str("ns = [\"net\", \"mnt\"]").unwrap();
+        assert_eq!(listed.ns, [Type::Net, Type::Mount]);
+        assert_eq!(
+            toml::to_string(&listed).unwrap(),
+            "ns = [\"net\", \"mount\"]\n"
+        );
+        assert!(toml::from_str::<Listed>("ns = [\"network\"]").is_err());
+    }
+
+    #[test]
+    fn down_container_has_no_ns_fds() {
+        let base_dir = TempDir::new("ns-fds");
+        let container = Container::with_base_dir(&*base_dir);
+        let err = container.ns_fds().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The container isn't up, its user namespace isn't mounted"
+        );
+        // What's left of a container that was taken down, the empty files namespaces mount on
+        for ns_type in Type::iter() {
+            File::create(container.mount_point(ns_type)).unwrap();
+        }
+        let err = container.ns_fds().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "The container isn't up, its user namespace isn't mounted"
+        );
+    }
+}
@@ -0,0 +1,583 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::net::{forward_to_loopback_inside, in_container_net};
+use crate::paths::Binary;
+use crate::up::SOCKS_PORT;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// warp-svc's resolver inside the container, which sends queries over DoH through the tunnel
+pub const WARP_RESOLVER: Ipv4Addr = Ipv4Addr::new(127, 0, 2, 2);
+
+/// Which resolver lookups inside the container go to
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// warp-svc's, which resolves over DoH through the tunnel
+    #[default]
+    Warp,
+    /// The host's resolvers, reached through the tunnel
+    Host,
+    /// The configured servers, reached through the tunnel
+    Custom,
+}
+
+/// The `[dns]` section of the config file
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct DnsConfig {
+    pub mode: Mode,
+    /// The resolvers of the custom mode
+    pub servers: Vec<IpAddr>,
+    /// Serves the container's resolver on its address, so the host can use it for DNS
+    pub expose: bool,
+    /// Points the host's DNS at the exposed resolver while the container is up, implies expose
+    pub manage_host: bool,
+}
+
+impl DnsConfig {
+    pub fn exposed(&self) -> bool {
+        self.expose || self.manage_host
+    }
+}
+
+/// Refuses settings that don't go together, before anything is set up
+pub fn check(dns: &DnsConfig) -> Result<()> {
+    match dns.mode {
+        Mode::Custom if dns.servers.is_empty() => {
+            bail!("The custom DNS mode needs servers to send lookups to")
+        }
+        Mode::Warp | Mode::Host if !dns.servers.is_empty() => {
+            bail!("DNS servers are only used with the custom mode")
+        }
+        Mode::Host if dns.manage_host => {
+            bail!("The host mode would have the container use the host's resolver, which manage-host points back at the container")
+        }
+        Mode::Host | Mode::Custom if dns.exposed() => {
+            bail!("Only warp-svc's resolver can be exposed, which needs the warp DNS mode")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// What the container's resolv.conf has before its nameservers, it replaces the base's
+const CONTAINER_RESOLV_HEADER: &str =
+    "# This is an overlay of resolv.conf in the WARP container, set by dns.mode\n";
+
+/// systemd-resolved's list of the servers its stub forwards to
+const RESOLVED_UPSTREAMS: &str = "/run/systemd/resolve/resolv.conf";
+
+/// The host's resolv.conf, or the servers behind systemd-resolved's stub since the container
+/// can't reach the stub on its own loopback
+fn host_resolv_conf() -> Result<String> {
+    let path = match host_uses_stub_resolver() {
+        true => RESOLVED_UPSTREAMS,
+        false => RESOLV_CONF,
+    };
+    std::fs::read_to_string(path).with_context(|| format!("Reading {path} for the host DNS mode"))
+}
+
+/// The container's resolv.conf for the DNS mode. The host mode copies the host's when the
+/// container is created, later changes on the host need a restart.
+pub fn container_resolv_conf(dns: &DnsConfig) -> Result<String> {
+    let mut resolv = CONTAINER_RESOLV_HEADER.to_owned();
+    match dns.mode {
+        Mode::Warp => {
+            resolv += "nameserver 127.0.2.2\nnameserver 127.0.2.3\n";
+            resolv += "nameserver fd01:db8:1111::2\nnameserver fd01:db8:1111::3\n";
+        }
+        Mode::Host => {
+            debug!("Copying the host's resolvers to the container");
+            resolv += &host_resolv_conf()?;
+        }
+        Mode::Custom => {
+            for server in &dns.servers {
+                resolv += &format!("nameserver {server}\n");
+            }
+        }
+    }
+    Ok(resolv)
+}
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+
+/// Starts the resolv.conf we write, so we never restore over someone else's
+const MANAGED_MARKER: &str = "# Managed by bubblewarp, restored by bubblewarp down";
+
+fn resolv_conf_backup(base_dir: &Path) -> PathBuf {
+    base_dir.join("resolv.conf.orig")
+}
+
+/// systemd-resolved only serves the host if resolv.conf points at one of its files
+fn resolved_manages_host() -> bool {
+    std::fs::read_link(RESOLV_CONF).is_ok_and(|target| target.starts_with("/run/systemd/resolve"))
+}
+
+/// Where systemd-resolved's stub listens, which the container's own loopback doesn't have
+const RESOLVED_STUB: &str = "127.0.0.53";
+
+/// Whether the host resolves through systemd-resolved's stub, from a symlink or a copy of its file
+pub fn host_uses_stub_resolver() -> bool {
+    resolved_manages_host()
+        || std::fs::read_to_string(RESOLV_CONF).is_ok_and(|resolv| {
+            resolv
+                .lines()
+                .any(|line| line.split_whitespace().eq(["nameserver", RESOLVED_STUB]))
+        })
+}
+
+/// The container looks names up in its hosts file, then asks WARP's resolver
+const CONTAINER_HOSTS_SOURCES: &[&str] = &["files", "dns"];
+
+/// A base nsswitch.conf with its hosts line made to use only the hosts file and resolv.conf, or
+/// None if it already does. nss-resolve, mymachines and myhostname answer from the host's
+/// systemd, whose sockets may or may not be reachable from the container.
+pub fn container_nsswitch(base: &str) -> Option<String> {
+    let hosts_line = format!("hosts: {}", CONTAINER_HOSTS_SOURCES.join(" "));
+    let mut found = false;
+    let mut nsswitch = String::new();
+    for line in base.lines() {
+        match line.trim_start().strip_prefix("hosts:") {
+            Some(sources) => {
+                let sources: Vec<&str> = sources
+                    .split('#')
+                    .next()
+                    .unwrap_or_default()
+                    .split_whitespace()
+                    .collect();
+                if sources == CONTAINER_HOSTS_SOURCES {
+                    return None;
+                }
+                found = true;
+                nsswitch += &hosts_line;
+            }
+            None => nsswitch += line,
+        }
+        nsswitch.push('\n');
+    }
+    if !found {
+        nsswitch += &format!("{hosts_line}\n");
+    }
+    Some(nsswitch)
+}
+
+/// Sends the host's lookups to the container's resolver. With systemd-resolved, the setting is on
+/// our veth and goes away with it. Otherwise resolv.conf is replaced and backed up in the base dir.
+pub fn manage_host(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let net = &config.network;
+    if resolved_manages_host() {
+        debug!("Pointing systemd-resolved at the container's resolver");
+        let ns_addr = net.ns_addr.to_string();
+        for args in [
+            ["dns", &net.host_iface, &ns_addr],
+            ["domain", &net.host_iface, "~."],
+        ] {
+            ex.status(ex.command(Binary::Resolvectl)?.args(args))?
+                .exit_ok()?;
+        }
+        return Ok(());
+    }
+    debug!("Replacing {RESOLV_CONF} to use the container's resolver");
+    rewrite_resolv_conf(
+        Path::new(RESOLV_CONF),
+        &resolv_conf_backup(&config.base_dir),
+        net.ns_addr,
+    )
+}
+
+/// Undoes manage_host, if it did anything
+pub fn restore_host(ex: &dyn Executor, config: &Config) -> Result<()> {
+    if resolved_manages_host() {
+        // Fails when the veth is already gone, which took the setting with it
+        let _ = ex.status(
+            ex.command(Binary::Resolvectl)?
+                .args(["revert", &config.network.host_iface])
+                .stderr(Stdio::null()),
+        );
+    }
+    restore_resolv_conf(
+        Path::new(RESOLV_CONF),
+        &resolv_conf_backup(&config.base_dir),
+    )
+}
+
+/// Puts back the resolv.conf a container that went away without down, e.g. in a crash, left
+/// pointing at its resolver. Up calls it before creating a new container, which manages it again
+/// if configured to.
+pub fn restore_stale_host(config: &Config) -> Result<()> {
+    let backup = resolv_conf_backup(&config.base_dir);
+    if backup.symlink_metadata().is_err() {
+        return Ok(());
+    }
+    info!("{RESOLV_CONF} still points at the previous container, restoring it");
+    restore_resolv_conf(Path::new(RESOLV_CONF), &backup)
+}
+
+fn rewrite_resolv_conf(path: &Path, backup: &Path, nameserver: Ipv4Addr) -> Result<()> {
+    let current = std::fs::read_to_string(path).unwrap_or_default();
+    // Already ours when up runs again, the backup has the original
+    if !current.starts_with(MANAGED_MARKER) {
+        let _ = std::fs::remove_file(backup);
+        match std::fs::read_link(path) {
+            Ok(target) => std::os::unix::fs::symlink(target, backup)?,
+            Err(_) => {
+                std::fs::copy(path, backup)?;
+            }
+        }
+    }
+    // Renamed into place so the file is never seen half-written, or with a partial backup
+    let new = path.with_extension("conf.bubblewarp");
+    std::fs::write(&new, format!("{MANAGED_MARKER}\nnameserver {nameserver}\n"))?;
+    std::fs::rename(&new, path)?;
+    Ok(())
+}
+
+fn restore_resolv_conf(path: &Path, backup: &Path) -> Result<()> {
+    if backup.symlink_metadata().is_err() {
+        return Ok(());
+    }
+    let current = std::fs::read_to_string(path).unwrap_or_default();
+    if !current.starts_with(MANAGED_MARKER) {
+        warn!(
+            "{} was changed since bubblewarp replaced it, keeping it. The original is at {}",
+            path.display(),
+            backup.display()
+        );
+        return Ok(());
+    }
+    debug!("Restoring {}", path.display());
+    std::fs::rename(backup, path)?;
+    Ok(())
+}
+
+/// Programs `client-config` writes a config snippet for
+#[derive(Debug, Copy, Clone, clap::ValueEnum)]
+pub enum Client {
+    /// A drop-in for /etc/systemd/resolved.conf.d, using the exposed resolver
+    Resolved,
+    /// Preferences for user.js, sending Firefox's traffic and lookups through the proxy
+    Firefox,
+}
+
+/// Lets the host query warp-svc's resolver at the container's address, which check only allows
+/// in the warp mode
+pub fn expose(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let resolver = SocketAddr::from((WARP_RESOLVER, 53));
+    for proto in ["udp", "tcp"] {
+        forward_to_loopback_inside(ex, &config.base_dir, &config.network, proto, 53, resolver)?;
+    }
+    Ok(())
+}
+
+pub fn client_config(config: &Config, client: Client) -> Result<String> {
+    let ns_addr = config.network.ns_addr;
+    Ok(match client {
+        Client::Resolved => {
+            if !config.dns.exposed() {
+                bail!("The resolver isn't exposed, set expose = true in the [dns] section of the config file")
+            }
+            format!(
+                "# /etc/systemd/resolved.conf.d/bubblewarp.conf
+[Resolve]
+DNS={ns_addr}
+Domains=~.
+"
+            )
+        }
+        Client::Firefox => format!(
+            "// Add to user.js in the Firefox profile directory
+user_pref(\"network.proxy.type\", 1);
+user_pref(\"network.proxy.socks\", \"{ns_addr}\");
+user_pref(\"network.proxy.socks_port\", {SOCKS_PORT});
+user_pref(\"network.proxy.socks_version\", 5);
+user_pref(\"network.proxy.socks_remote_dns\", true);
+user_pref(\"network.trr.mode\", 5);
+"
+        ),
+    })
+}
+
+/// Record types the dns command can ask for
+#[derive(Debug, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+#[clap(rename_all = "UPPER")]
+pub enum RecordType {
+    A,
+    Aaaa,
+    Cname,
+    Mx,
+    Ns,
+    Txt,
+}
+
+impl RecordType {
+    fn code(self) -> u16 {
+        match self {
+            RecordType::A => 1,
+            RecordType::Ns => 2,
+            RecordType::Cname => 5,
+            RecordType::Mx => 15,
+            RecordType::Txt => 16,
+            RecordType::Aaaa => 28,
+        }
+    }
+}
+
+/// How long to wait for the resolver, which may itself wait on the tunnel
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Asks WARP's resolver from inside the container, so a failure here but not through the proxy
+/// points at DNS rather than the tunnel. Returns the answers, one per line, and the latency.
+pub fn query(config: &Config, name: &str, rtype: RecordType) -> Result<String> {
+    let socket = in_container_net(&config.base_dir, || {
+        Ok(UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))?)
+    })?;
+    socket.set_read_timeout(Some(QUERY_TIMEOUT))?;
+    socket.connect((WARP_RESOLVER, 53))?;
+    let id = std::process::id() as u16;
+    let request = build_query(id, name, rtype)?;
+    let started = Instant::now();
+    socket.send(&request)?;
+    let mut reply = [0u8; 4096];
+    let len = loop {
+        let len = socket
+            .recv(&mut reply)
+            .with_context(|| format!("No answer from {WARP_RESOLVER} in the container"))?;
+        if len >= 2 && u16::from_be_bytes([reply[0], reply[1]]) == id {
+            break len;
+        }
+    };
+    let elapsed = started.elapsed();
+    let mut out = parse_reply(&reply[..len])?;
+    out += &format!(
+        ";; {} ms via {WARP_RESOLVER} in the container\n",
+        elapsed.as_millis()
+    );
+    Ok(out)
+}
+
+fn build_query(id: u16, name: &str, rtype: RecordType) -> Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(512);
+    packet.extend(id.to_be_bytes());
+    // Recursion desired, one question
+    packet.extend([0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            bail!("Invalid name {name}")
+        }
+        packet.push(label.len() as u8);
+        packet.extend(label.as_bytes());
+    }
+    packet.push(0);
+    packet.extend(rtype.code().to_be_bytes());
+    packet.extend(1u16.to_be_bytes());
+    Ok(packet)
+}
+
+/// Reads a possibly compressed name at `pos`, returns it and the position after it
+fn read_name(packet: &[u8], mut pos: usize) -> Result<(String, usize)> {
+    let mut name = String::new();
+    let mut end = None;
+    // Pointers only go backwards in valid packets, this also stops loops
+    for _ in 0..packet.len() {
+        let len = *packet.get(pos).context("Truncated name")? as usize;
+        if len & 0xc0 == 0xc0 {
+            let low = *packet.get(pos + 1).context("Truncated name")? as usize;
+            end.get_or_insert(pos + 2);
+            pos = (len & 0x3f) << 8 | low;
+            continue;
+        }
+        if len == 0 {
+            if name.is_empty() {
+                name.push('.');
+            }
+            return Ok((name, end.unwrap_or(pos + 1)));
+        }
+        let label = packet
+            .get(pos + 1..pos + 1 + len)
+            .context("Truncated name")?;
+        name += &String::from_utf8_lossy(label);
+        name.push('.');
+        pos += 1 + len;
+    }
+    bail!("Name compression loop")
+}
+
+fn read_u16(packet: &[u8], pos: usize) -> Result<u16> {
+    let bytes = packet.get(pos..pos + 2).context("Truncated reply")?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+fn format_rdata(packet: &[u8], rtype: u16, start: usize, rdata: &[u8]) -> Result<String> {
+    Ok(match rtype {
+        1 if rdata.len() == 4 => format!(
+            "A {}",
+            Ipv4Addr::new(rdata[0], rdata[1], rdata[2], rdata[3])
+        ),
+        28 if rdata.len() == 16 => {
+            let octets: [u8; 16] = rdata.try_into()?;
+            format!("AAAA {}", Ipv6Addr::from(octets))
+        }
+        2 => format!("NS {}", read_name(packet, start)?.0),
+        5 => format!("CNAME {}", read_name(packet, start)?.0),
+        15 => format!(
+            "MX {} {}",
+            read_u16(packet, start)?,
+            read_name(packet, start + 2)?.0
+        ),
+        16 => {
+            let mut strings = Vec::new();
+            let mut rest = rdata;
+            while let Some((&len, tail)) = rest.split_first() {
+                let text = tail.get(..len as usize).context("Truncated TXT")?;
+                strings.push(format!("{:?}", String::from_utf8_lossy(text)));
+                rest = &tail[len as usize..];
+            }
+            format!("TXT {}", strings.join(" "))
+        }
+        _ => format!(
+            "TYPE{rtype} {}",
+            rdata.iter().map(|b| format!("{b:02x}")).collect::<String>()
+        ),
+    })
+}
+
+fn parse_reply(packet: &[u8]) -> Result<String> {
+    let flags = read_u16(packet, 2)?;
+    let questions = read_u16(packet, 4)?;
+    let answers = read_u16(packet, 6)?;
+    let rcode = match flags & 0xf {
+        0 => "NOERROR",
+        1 => "FORMERR",
+        2 => "SERVFAIL",
+        3 => "NXDOMAIN",
+        4 => "NOTIMP",
+        5 => "REFUSED",
+        _ => "error",
+    };
+    let mut out = format!(";; {rcode}, {answers} answers\n");
+    let mut pos = 12;
+    for _ in 0..questions {
+        pos = read_name(packet, pos)?.1 + 4;
+    }
+    for _ in 0..answers {
+        let (name, after) = read_name(packet, pos)?;
+        let rtype = read_u16(packet, after)?;
+        let ttl = packet
+            .get(after + 4..after + 8)
+            .context("Truncated reply")?;
+        let ttl = u32::from_be_bytes(ttl.try_into()?);
+        let len = read_u16(packet, after + 8)? as usize;
+        let start = after + 10;
+        let rdata = packet.get(start..start + len).context("Truncated reply")?;
+        out += &format!(
+            "{name} {ttl} IN {}\n",
+            format_rdata(packet, rtype, start, rdata)?
+        );
+        pos = start + len;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::MockExecutor;
+    use crate::test_utils::TempDir;
+    use std::path::PathBuf;
+
+    #[test]
+    fn dns_modes() {
+        let custom: DnsConfig =
+            toml::from_str("mode = \"custom\"\nservers = [\"9.9.9.9\"]\n").unwrap();
+        check(&custom).unwrap();
+        assert!(container_resolv_conf(&custom)
+            .unwrap()
+            .ends_with("\nnameserver 9.9.9.9\n"));
+        let warp = DnsConfig::default();
+        assert!(container_resolv_conf(&warp)
+            .unwrap()
+            .contains("nameserver 127.0.2.2\n"));
+        assert!(check(&toml::from_str("mode = \"custom\"").unwrap()).is_err());
+        assert!(check(&toml::from_str("mode = \"host\"\nmanage-host = true").unwrap()).is_err());
+        assert!(check(
+            &toml::from_str("mode = \"custom\"\nservers = [\"9.9.9.9\"]\nexpose = true").unwrap()
+        )
+        .is_err());
+        check(&toml::from_str("expose = true").unwrap()).unwrap();
+    }
+
+    #[test]
+    fn container_resolves_with_warp() {
+        let resolved = "passwd: files systemd\nhosts: mymachines resolve [!UNAVAIL=return] files myhostname dns\n";
+        assert_eq!(
+            container_nsswitch(resolved).unwrap(),
+            "passwd: files systemd\nhosts: files dns\n"
+        );
+        assert_eq!(container_nsswitch("hosts:   files dns # default\n"), None);
+        assert_eq!(container_nsswitch("").unwrap(), "hosts: files dns\n");
+    }
+
+    #[test]
+    fn exposes_resolver() {
+        let ex = MockExecutor::without_rules();
+        let mut config = Config {
+            base_dir: PathBuf::from("/base"),
+            ..Default::default()
+        };
+        expose(&ex, &config).unwrap();
+        assert_eq!(
+            ex.commands().last().unwrap(),
+            "nsenter --net=/base/net iptables -t nat -A PREROUTING -i veth-warp-ns -p tcp \
+             --dport 53 -j DNAT --to-destination 127.0.2.2:53"
+        );
+
+        assert!(client_config(&config, Client::Resolved).is_err());
+        config.dns.expose = true;
+        assert!(client_config(&config, Client::Resolved)
+            .unwrap()
+            .contains("DNS=10.200.0.2\n"));
+    }
+
+    #[test]
+    fn replaces_and_restores_resolv_conf() {
+        let dir = TempDir::new("dns");
+        let (path, backup) = (dir.join("resolv.conf"), dir.join("resolv.conf.orig"));
+        std::os::unix::fs::symlink("../run/resolvconf/resolv.conf", &path).unwrap();
+        let nameserver = Ipv4Addr::new(10, 200, 0, 2);
+        rewrite_resolv_conf(&path, &backup, nameserver).unwrap();
+        // Running up again keeps the original backup
+        rewrite_resolv_conf(&path, &backup, nameserver).unwrap();
+        let managed = std::fs::read_to_string(&path).unwrap();
+        restore_resolv_conf(&path, &backup).unwrap();
+        let restored = std::fs::read_link(&path).unwrap();
+        assert!(managed.ends_with("nameserver 10.200.0.2\n"));
+        assert_eq!(restored, Path::new("../run/resolvconf/resolv.conf"));
+    }
+
+    #[test]
+    fn parses_answers() {
+        let query = build_query(0x1234, "example.com", RecordType::A).unwrap();
+        assert_eq!(query[12..25], *b"\x07example\x03com\x00");
+        // The query, turned into a reply with a CNAME then an A record, names compressed
+        let mut reply = query.clone();
+        reply[2..8].copy_from_slice(&[0x81, 0x80, 0, 1, 0, 2]);
+        reply.extend([
+            0xc0, 12, 0, 5, 0, 1, 0, 0, 0, 60, 0, 6, 3, b'w', b'w', b'w', 0xc0, 12,
+        ]);
+        reply.extend([0xc0, 41, 0, 1, 0, 1, 0, 0, 1, 44, 0, 4, 93, 184, 216, 34]);
+        assert_eq!(
+            parse_reply(&reply).unwrap(),
+            ";; NOERROR, 2 answers\n\
+             example.com. 60 IN CNAME www.example.com.\n\
+             www.example.com. 300 IN A 93.184.216.34\n"
+        );
+        reply[3] = 0x83;
+        reply[7] = 0;
+        assert_eq!(parse_reply(&reply).unwrap(), ";; NXDOMAIN, 0 answers\n");
+    }
+}
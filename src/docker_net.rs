@@ -0,0 +1,171 @@
+use crate::exec::Executor;
+use crate::namespace::{self, is_mounted, Type};
+use anyhow::{bail, Context, Result};
+use nix::mount::{MntFlags, MsFlags};
+use std::fs::File;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+/// Where iproute2 and container runtimes look for named network namespaces
+const NETNS_DIR: &str = "/run/netns";
+
+fn netns_path(netns_dir: &Path, name: &str) -> Result<PathBuf> {
+    if name.is_empty() || name.contains('/') || name == "." || name == ".." {
+        bail!("Invalid network namespace name '{name}'")
+    }
+    Ok(netns_dir.join(name))
+}
+
+/// Publishes the container's network namespace under /run/netns, so other containers can join it
+pub fn create(ex: &dyn Executor, base_dir: &Path, name: &str) -> Result<()> {
+    create_in(ex, base_dir, Path::new(NETNS_DIR), name)
+}
+
+fn create_in(ex: &dyn Executor, base_dir: &Path, netns_dir: &Path, name: &str) -> Result<()> {
+    if !is_mounted(base_dir, Type::Net)? {
+        bail!("The container's network namespace isn't mounted, run the up command first")
+    }
+    let path = netns_path(netns_dir, name)?;
+    if path.exists() {
+        if points_to_container(base_dir, &path) {
+            info!("{} is already registered", path.display());
+            print_usage(&path);
+            return Ok(());
+        }
+        bail!("{} already exists", path.display())
+    }
+
+    debug!(
+        "Bind mounting the container's network namespace on {}",
+        path.display()
+    );
+    std::fs::create_dir_all(netns_dir)?;
+    File::create(&path).context("Creating network namespace mount point")?;
+    let ns_mount_point = namespace::mount_point(base_dir, Type::Net);
+    if let Err(e) = ex.mount(Some(&ns_mount_point), &path, None, MsFlags::MS_BIND, None) {
+        let _ = std::fs::remove_file(&path);
+        return Err(e).context("Bind mounting the network namespace");
+    }
+    print_usage(&path);
+    Ok(())
+}
+
+pub fn remove(ex: &dyn Executor, base_dir: &Path, name: &str) -> Result<()> {
+    remove_in(ex, base_dir, Path::new(NETNS_DIR), name)
+}
+
+fn remove_in(ex: &dyn Executor, base_dir: &Path, netns_dir: &Path, name: &str) -> Result<()> {
+    let path = netns_path(netns_dir, name)?;
+    if !points_to_container(base_dir, &path) {
+        bail!(
+            "{} is not the container's network namespace",
+            path.display()
+        )
+    }
+    unregister(ex, &path)
+}
+
+/// Called on down, since a registered path keeps the namespace alive after we unmount ours
+pub fn remove_all(ex: &dyn Executor, base_dir: &Path) -> Result<()> {
+    remove_all_in(ex, base_dir, Path::new(NETNS_DIR))
+}
+
+fn remove_all_in(ex: &dyn Executor, base_dir: &Path, netns_dir: &Path) -> Result<()> {
+    let Ok(entries) = std::fs::read_dir(netns_dir) else {
+        return Ok(());
+    };
+    for entry in entries {
+        let path = entry?.path();
+        if points_to_container(base_dir, &path) {
+            unregister(ex, &path)?;
+        }
+    }
+    Ok(())
+}
+
+fn unregister(ex: &dyn Executor, path: &Path) -> Result<()> {
+    debug!("Removing network namespace registration {}", path.display());
+    ex.umount(path, MntFlags::MNT_DETACH)
+        .with_context(|| format!("Unmounting {}", path.display()))?;
+    std::fs::remove_file(path)?;
+    Ok(())
+}
+
+/// Mounted namespace files share the inode number of the namespace itself
+fn points_to_container(base_dir: &Path, path: &Path) -> bool {
+    let ns_mount_point = namespace::mount_point(base_dir, Type::Net);
+    let (Ok(ours), Ok(theirs)) = (ns_mount_point.metadata(), path.metadata()) else {
+        return false;
+    };
+    ours.dev() == theirs.dev() && ours.ino() == theirs.ino()
+}
+
+fn print_usage(path: &Path) {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    println!(
+        "The WARP container's network is available as {}",
+        path.display()
+    );
+    println!("  podman run --network ns:{} ...", path.display());
+    println!("  ip netns exec {name} <command>");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::{Call, MockExecutor};
+    use crate::test_utils::TempDir;
+
+    /// A base dir whose network namespace file is registered as `warp`, next to an unrelated `other`
+    fn registered(dir: &TempDir) -> (PathBuf, PathBuf) {
+        let (base_dir, netns_dir) = (dir.join("base"), dir.join("netns"));
+        std::fs::create_dir_all(&base_dir).unwrap();
+        std::fs::create_dir_all(&netns_dir).unwrap();
+        let ns_file = namespace::mount_point(&base_dir, Type::Net);
+        File::create(&ns_file).unwrap();
+        // Same inode, like a bind mount of the namespace
+        std::fs::hard_link(&ns_file, netns_dir.join("warp")).unwrap();
+        File::create(netns_dir.join("other")).unwrap();
+        (base_dir, netns_dir)
+    }
+
+    #[test]
+    fn removes_only_the_containers_registrations() {
+        let dir = TempDir::new("docker-net");
+        let (base_dir, netns_dir) = registered(&dir);
+        let ex = MockExecutor::new();
+        remove_all_in(&ex, &base_dir, &netns_dir).unwrap();
+        assert_eq!(ex.calls(), [Call::Umount(netns_dir.join("warp"))]);
+        assert!(!netns_dir.join("warp").exists());
+        assert!(netns_dir.join("other").exists());
+    }
+
+    #[test]
+    fn refuses_to_remove_what_isnt_there() {
+        let dir = TempDir::new("docker-net-missing");
+        let (base_dir, netns_dir) = registered(&dir);
+        let ex = MockExecutor::new();
+        for name in ["other", "missing", "../base", ""] {
+            assert!(
+                remove_in(&ex, &base_dir, &netns_dir, name).is_err(),
+                "{name}"
+            );
+        }
+        remove_in(&ex, &base_dir, &netns_dir, "warp").unwrap();
+        assert!(remove_in(&ex, &base_dir, &netns_dir, "warp").is_err());
+        assert_eq!(ex.calls(), [Call::Umount(netns_dir.join("warp"))]);
+        // Nothing to clean up on down without a netns directory
+        remove_all_in(&ex, &base_dir, &dir.join("none")).unwrap();
+    }
+
+    #[test]
+    fn only_creates_for_a_container_that_is_up() {
+        let dir = TempDir::new("docker-net-down");
+        let (base_dir, netns_dir) = registered(&dir);
+        let ex = MockExecutor::new();
+        assert!(create_in(&ex, &base_dir, &netns_dir, "podman").is_err());
+        assert!(ex.calls().is_empty());
+        assert!(!netns_dir.join("podman").exists());
+    }
+}
@@ -1,32 +1,97 @@
-use crate::namespace::{self, all_ns_processes, is_mounted, run_inside_namespace, Type};
-use crate::net::default_route_iface_name;
+use crate::cgroup;
+use crate::config::Config;
+use crate::dns;
+use crate::docker_net;
+use crate::exec::Executor;
+use crate::firewall;
+use crate::gateway;
+use crate::init;
+use crate::inspect::dir_size;
+use crate::mtu;
+use crate::namespace::{self, all_ns_processes, run_inside_namespace, Mounts, Type};
+use crate::net::{
+    default_route_iface_name, forget_uplinks, recorded_uplinks, remove_egress_route,
+    restore_rp_filter, Network,
+};
+use crate::paths::Binary;
+use crate::pause;
+use crate::pidfd::PidFd;
+use crate::reload;
+use crate::rootfs;
+use crate::service;
+use crate::state;
+use crate::up::{etc_overlay_dir, Parts, VAR_LIB, WARP_STATE_DIR};
 use anyhow::{bail, Context, Result};
-use nix::sys::signal::{kill, Signal};
-use nix::unistd::Pid;
-use std::path::Path;
-use std::process::{Command, Stdio};
+use nix::mount::MntFlags;
+use nix::sys::signal::Signal;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 use strum::IntoEnumIterator;
-use tracing::debug;
+use tracing::{debug, info};
 
-pub fn down() -> Result<()> {
-    let base_dir = namespace::base_dir()?;
+pub fn down(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let base_dir = &config.base_dir;
+    let net = &config.network;
+    state::migrate(base_dir)?;
 
-    kill_ns_processes(&base_dir)?;
+    // Namespaces stay mounted until the very end
+    let mounts = Mounts::snapshot()?;
+    if mounts.is_mounted(base_dir, Type::Pid) {
+        kill_ns_processes(ex, base_dir)?;
+    }
+    cgroup::remove(ex, base_dir)?;
 
-    if is_mounted(&base_dir, Type::Mount)? {
-        clean_mount_namespace(&base_dir)?;
+    if mounts.is_mounted(base_dir, Type::Mount) {
+        clean_mount_namespace(ex, config)?;
     }
-    if is_mounted(&base_dir, Type::Net)? {
-        cleanup_external_networking()?;
+    let net_mounted = mounts.is_mounted(base_dir, Type::Net);
+    // Forward rules up didn't add may be the user's own
+    if net_mounted && Parts::load(base_dir)?.external {
+        cleanup_external_networking(ex, base_dir, net)?;
     }
-    cleanup_private_networking(&base_dir)?;
+    gateway::teardown(ex, config)?;
+    firewall::close_recorded(ex, base_dir)?;
+    restore_rp_filter(ex, base_dir)?;
+    remove_egress_route(ex, base_dir)?;
+    // Whether or not the config still blocks it, the rules may be from an earlier up
+    firewall::unblock_ipv6(ex, net)?;
+    dns::restore_host(ex, config)?;
+    cleanup_private_networking(ex, base_dir, net, net_mounted)?;
+    docker_net::remove_all(ex, base_dir)?;
+
+    unmount_namespaces(ex, base_dir, &mounts)?;
+    init::remove_id(base_dir)?;
+    namespace::forget_created(base_dir)?;
+    Parts::forget(base_dir)?;
+    mtu::forget(base_dir)?;
+    reload::forget(base_dir)?;
+    pause::forget(base_dir)?;
+    service::forget_all(base_dir)?;
+    rootfs::remove(base_dir)?;
+    let _ = ex.umount(base_dir, MntFlags::empty());
+    Ok(())
+}
 
-    unmount_namespaces(&base_dir)?;
-    let _ = nix::mount::umount(&base_dir);
+/// Removes what was written to /etc inside the container, once it is down
+pub fn purge_etc_overlay(base_dir: &Path) -> Result<()> {
+    if namespace::status(base_dir)? != namespace::Status::None {
+        bail!("The container is still up, the /etc overlay can only go once it is down")
+    }
+    let overlay_dir = etc_overlay_dir(base_dir);
+    if !overlay_dir.exists() {
+        return Ok(());
+    }
+    let size = dir_size(&overlay_dir)?;
+    std::fs::remove_dir_all(&overlay_dir)?;
+    info!(
+        "Removed the /etc overlay in {}, freeing {} KiB",
+        overlay_dir.display(),
+        size / 1024
+    );
     Ok(())
 }
 
-fn kill_ns_processes(base_dir: &Path) -> Result<()> {
+pub fn kill_ns_processes(ex: &dyn Executor, base_dir: &Path) -> Result<()> {
     let ns_procs = all_ns_processes(base_dir)?;
     for proc in ns_procs {
         if !proc.is_alive() {
@@ -37,49 +102,56 @@ fn kill_ns_processes(base_dir: &Path) -> Result<()> {
         } else {
             debug!("Killing pid {} (failed to get cmdline)", proc.pid);
         };
-        let _ = kill(Pid::from_raw(proc.pid), Signal::SIGTERM);
+        let Ok(stat) = proc.stat() else { continue };
+        // The process may have exited and its PID been reused since the scan
+        if let Ok(Some(process)) = PidFd::open_if_started_at(proc.pid, stat.starttime) {
+            let _ = ex.kill(&process, Signal::SIGTERM);
+        }
     }
     Ok(())
 }
 
-fn cleanup_external_networking() -> Result<()> {
-    let iface_name = default_route_iface_name()?;
-    delete_iptables_rule(&format!(
-        "POSTROUTING -t nat -s 10.200.0.0/24 -o {iface_name} -j MASQUERADE"
-    ));
-    delete_iptables_rule(&format!("FORWARD -i {iface_name} -o veth-warp -j ACCEPT"));
-    delete_iptables_rule(&format!("FORWARD -o {iface_name} -i veth-warp -j ACCEPT"));
-    Ok(())
-}
-
-fn delete_iptables_rule(rule: &str) {
-    let rule_words: Vec<&str> = rule.split(' ').collect();
-    loop {
-        let status = Command::new("/usr/sbin/iptables")
-            .arg("-D")
-            .args(&rule_words)
-            .stderr(Stdio::null())
-            .status()
-            .unwrap();
-        if !status.success() {
-            break;
-        }
+/// Removes the forward through the uplinks up recorded, and the default route's interface
+pub fn cleanup_external_networking(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    net: &Network,
+) -> Result<()> {
+    let mut uplinks = recorded_uplinks(base_dir)?;
+    match default_route_iface_name(ex) {
+        Ok(uplink) if !uplinks.contains(&uplink) => uplinks.push(uplink),
+        Ok(_) => (),
+        Err(e) if uplinks.is_empty() => return Err(e),
+        Err(e) => debug!("{e:#}"),
+    }
+    for uplink in &uplinks {
+        firewall::remove_forward(ex, net, uplink)?;
     }
+    forget_uplinks(base_dir)
 }
 
-fn cleanup_private_networking(base_dir: &Path) -> Result<()> {
-    if is_mounted(base_dir, Type::Net)? {
+fn cleanup_private_networking(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    net: &Network,
+    net_mounted: bool,
+) -> Result<()> {
+    if net_mounted {
         let _ = run_inside_namespace(
+            ex,
             base_dir,
             Type::Mount,
-            Command::new("ip").args(["link", "delete", "dev", "veth-warp-ns"]),
+            ex.command(Binary::Ip)?
+                .args(["link", "delete", "dev", &net.ns_iface]),
         );
     }
 
-    if nix::ifaddrs::getifaddrs()?.any(|dev| dev.interface_name == "veth-warp") {
-        let out = Command::new("ip")
-            .args(["link", "delete", "dev", "veth-warp"])
-            .output()?;
+    if nix::ifaddrs::getifaddrs()?.any(|dev| dev.interface_name == net.host_iface) {
+        let out =
+            ex.output(
+                ex.command(Binary::Ip)?
+                    .args(["link", "delete", "dev", &net.host_iface]),
+            )?;
         if !out.status.success() {
             bail!(
             "Failed to delete private veth network interface, returned {}\nstdout: {}\nstderr: {}",
@@ -92,23 +164,65 @@ fn cleanup_private_networking(base_dir: &Path) -> Result<()> {
     Ok(())
 }
 
-fn clean_mount_namespace(base_dir: &Path) -> Result<()> {
-    let _ = run_inside_namespace(base_dir, Type::Mount, Command::new("umount").arg("/proc"));
-    let _ = run_inside_namespace(base_dir, Type::Mount, Command::new("umount").arg("/etc"));
+fn clean_mount_namespace(ex: &dyn Executor, config: &Config) -> Result<()> {
+    // Masks go first, some of them sit on top of the /etc overlay
+    let masks = config
+        .container
+        .mask_paths
+        .iter()
+        .filter_map(|path| path.canonicalize().ok());
+    let mounts = ["/proc", "/etc", "/tmp", "/run", WARP_STATE_DIR, VAR_LIB].map(PathBuf::from);
+    for target in masks.chain(mounts) {
+        let _ = run_inside_namespace(
+            ex,
+            &config.base_dir,
+            Type::Mount,
+            Command::new("umount").arg(target),
+        );
+    }
     Ok(())
 }
 
-pub fn unmount_namespaces(base_dir: &Path) -> Result<()> {
+pub fn unmount_namespaces(ex: &dyn Executor, base_dir: &Path, mounts: &Mounts) -> Result<()> {
     for ns_type in Type::iter() {
-        if is_mounted(base_dir, ns_type)? {
-            unmount_one_namespace(base_dir, ns_type)?;
+        if mounts.is_mounted(base_dir, ns_type) {
+            unmount_one_namespace(ex, base_dir, ns_type)?;
         }
     }
     Ok(())
 }
 
-pub fn unmount_one_namespace(base_dir: &Path, ns_type: Type) -> Result<()> {
+pub fn unmount_one_namespace(ex: &dyn Executor, base_dir: &Path, ns_type: Type) -> Result<()> {
     let ns_mount_point = namespace::mount_point(base_dir, ns_type);
-    nix::mount::umount(&ns_mount_point).context("Unmounting persistent namespace")?;
+    ex.umount(&ns_mount_point, MntFlags::empty())
+        .context("Unmounting persistent namespace")?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::{Call, MockExecutor, Reply};
+
+    #[test]
+    fn external_cleanup_uses_default_route_iface() {
+        let ex = MockExecutor::with_handler(|_| Reply::fail());
+        cleanup_external_networking(&ex, Path::new("/nonexistent"), &Network::default()).unwrap();
+        assert_eq!(
+            ex.commands()[..4],
+            [
+                "iptables -D POSTROUTING -t nat -s 10.200.0.0/24 -o eth0 -j MASQUERADE",
+                "iptables -t nat -S POSTROUTING",
+                "iptables -S FORWARD",
+                "iptables -D FORWARD -o eth0 -i veth-warp -j ACCEPT",
+            ]
+        );
+    }
+
+    #[test]
+    fn unmounts_namespace_mount_point() {
+        let ex = MockExecutor::new();
+        unmount_one_namespace(&ex, Path::new("/base"), Type::Net).unwrap();
+        assert_eq!(ex.calls(), [Call::Umount("/base/net".into())]);
+    }
+}
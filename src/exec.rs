@@ -0,0 +1,299 @@
+use crate::netlink::{self, DefaultRoute};
+use crate::oplog::OpLog;
+use crate::paths::{Binary, Paths};
+use crate::pidfd::PidFd;
+use nix::mount::{MntFlags, MsFlags};
+use nix::sys::signal::Signal;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Output};
+use tracing::trace;
+
+/// Every external command and privileged syscall made by the orchestration code goes through here,
+/// so that it can be swapped out for a recording implementation in tests.
+/// Shared between the threads that run independent setup steps side by side.
+pub trait Executor: Sync {
+    /// Where to find one of the programs we run
+    fn which(&self, bin: Binary) -> anyhow::Result<PathBuf>;
+    fn command(&self, bin: Binary) -> anyhow::Result<Command> {
+        Ok(Command::new(self.which(bin)?))
+    }
+    fn output(&self, cmd: &mut Command) -> io::Result<Output>;
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus>;
+    fn spawn(&self, cmd: &mut Command) -> io::Result<Child>;
+    fn mount(
+        &self,
+        source: Option<&Path>,
+        target: &Path,
+        fstype: Option<&str>,
+        flags: MsFlags,
+        data: Option<&str>,
+    ) -> nix::Result<()>;
+    fn umount(&self, target: &Path, flags: MntFlags) -> nix::Result<()>;
+    fn kill(&self, process: &PidFd, signal: Signal) -> io::Result<()>;
+    /// The host's default routes, best ones first
+    fn default_routes(&self) -> anyhow::Result<Vec<DefaultRoute>>;
+}
+
+pub struct RealExecutor {
+    paths: Paths,
+    oplog: OpLog,
+}
+
+impl RealExecutor {
+    pub fn new(paths: Paths, base_dir: &Path) -> Self {
+        RealExecutor {
+            paths,
+            oplog: OpLog::new(base_dir),
+        }
+    }
+}
+
+impl Executor for RealExecutor {
+    fn which(&self, bin: Binary) -> anyhow::Result<PathBuf> {
+        self.paths.get(bin, self)
+    }
+
+    fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+        let argv = cmd_argv(cmd).join(" ");
+        trace!("Running '{argv}'");
+        let op = self.oplog.start(format_args!("run {argv}"));
+        let output = cmd.output();
+        self.oplog
+            .finish(op, &output.as_ref().map(|out| out.status));
+        output
+    }
+
+    fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+        let argv = cmd_argv(cmd).join(" ");
+        trace!("Running '{argv}'");
+        let op = self.oplog.start(format_args!("run {argv}"));
+        let status = cmd.status();
+        self.oplog.finish(op, &status);
+        status
+    }
+
+    fn spawn(&self, cmd: &mut Command) -> io::Result<Child> {
+        let argv = cmd_argv(cmd).join(" ");
+        trace!("Spawning '{argv}'");
+        let op = self.oplog.start(format_args!("spawn {argv}"));
+        let child = cmd.spawn();
+        self.oplog
+            .finish(op, &child.as_ref().map(|c| format!("pid {}", c.id())));
+        child
+    }
+
+    fn mount(
+        &self,
+        source: Option<&Path>,
+        target: &Path,
+        fstype: Option<&str>,
+        flags: MsFlags,
+        data: Option<&str>,
+    ) -> nix::Result<()> {
+        let op = self.oplog.start(format_args!(
+            "mount {} on {} type {} {flags:?} {}",
+            source.map_or("none".into(), |s| s.display().to_string()),
+            target.display(),
+            fstype.unwrap_or("none"),
+            data.unwrap_or_default(),
+        ));
+        let result = nix::mount::mount(source, target, fstype, flags, data);
+        self.oplog.finish(op, &result.map(|()| "ok"));
+        result
+    }
+
+    fn umount(&self, target: &Path, flags: MntFlags) -> nix::Result<()> {
+        let op = self
+            .oplog
+            .start(format_args!("umount {} {flags:?}", target.display()));
+        let result = nix::mount::umount2(target, flags);
+        self.oplog.finish(op, &result.map(|()| "ok"));
+        result
+    }
+
+    fn kill(&self, process: &PidFd, signal: Signal) -> io::Result<()> {
+        let op = self
+            .oplog
+            .start(format_args!("kill {} {signal}", process.pid()));
+        let result = process.send_signal(signal);
+        self.oplog.finish(op, &result.as_ref().map(|()| "ok"));
+        result
+    }
+
+    fn default_routes(&self) -> anyhow::Result<Vec<DefaultRoute>> {
+        netlink::default_routes()
+    }
+}
+
+pub fn cmd_argv(cmd: &Command) -> Vec<String> {
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|s| s.to_string_lossy().into_owned())
+        .collect()
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::{cmd_argv, Executor};
+    use crate::netlink::DefaultRoute;
+    use crate::paths::Binary;
+    use crate::pidfd::PidFd;
+    use nix::mount::{MntFlags, MsFlags};
+    use nix::sys::signal::Signal;
+    use std::io;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::{Path, PathBuf};
+    use std::process::{Child, Command, ExitStatus, Output};
+    use std::sync::Mutex;
+
+    #[derive(Debug, Clone, Eq, PartialEq)]
+    pub enum Call {
+        Run(Vec<String>),
+        Spawn(Vec<String>),
+        Mount { target: PathBuf, flags: MsFlags },
+        Umount(PathBuf),
+        Kill(i32, Signal),
+    }
+
+    pub struct Reply {
+        pub code: i32,
+        pub stdout: Vec<u8>,
+    }
+
+    impl Reply {
+        pub fn ok(stdout: &str) -> Self {
+            Reply {
+                code: 0,
+                stdout: stdout.as_bytes().to_owned(),
+            }
+        }
+
+        pub fn fail() -> Self {
+            Reply {
+                code: 1,
+                stdout: Vec::new(),
+            }
+        }
+    }
+
+    type Handler = Box<dyn Fn(&[String]) -> Reply + Send + Sync>;
+
+    /// Records every call instead of running it. Commands succeed with no output, unless the
+    /// handler says otherwise. Spawns start a harmless `true` process, since a `Child` can't be
+    /// faked. Binaries are never looked up, they are run by bare name.
+    pub struct MockExecutor {
+        calls: Mutex<Vec<Call>>,
+        handler: Handler,
+    }
+
+    impl MockExecutor {
+        pub fn new() -> Self {
+            Self::with_handler(|_| Reply::ok(""))
+        }
+
+        pub fn with_handler(handler: impl Fn(&[String]) -> Reply + Send + Sync + 'static) -> Self {
+            MockExecutor {
+                calls: Mutex::new(Vec::new()),
+                handler: Box::new(handler),
+            }
+        }
+
+        /// Every `-C` check for a firewall rule fails, as when none were added yet
+        pub fn without_rules() -> Self {
+            Self::with_handler(|argv| match argv.iter().any(|arg| arg == "-C") {
+                true => Reply::fail(),
+                false => Reply::ok(""),
+            })
+        }
+
+        pub fn calls(&self) -> Vec<Call> {
+            self.calls.lock().unwrap().clone()
+        }
+
+        /// The argv of all commands that were run, joined with spaces for easy comparison
+        pub fn commands(&self) -> Vec<String> {
+            self.calls
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|c| match c {
+                    Call::Run(argv) | Call::Spawn(argv) => Some(argv.join(" ")),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        fn run(&self, cmd: &Command) -> Reply {
+            let argv = cmd_argv(cmd);
+            let reply = (self.handler)(&argv);
+            self.calls.lock().unwrap().push(Call::Run(argv));
+            reply
+        }
+    }
+
+    impl Executor for MockExecutor {
+        fn which(&self, bin: Binary) -> anyhow::Result<PathBuf> {
+            Ok(bin.name().into())
+        }
+
+        fn output(&self, cmd: &mut Command) -> io::Result<Output> {
+            let reply = self.run(cmd);
+            Ok(Output {
+                status: ExitStatus::from_raw(reply.code << 8),
+                stdout: reply.stdout,
+                stderr: Vec::new(),
+            })
+        }
+
+        fn status(&self, cmd: &mut Command) -> io::Result<ExitStatus> {
+            Ok(ExitStatus::from_raw(self.run(cmd).code << 8))
+        }
+
+        fn spawn(&self, cmd: &mut Command) -> io::Result<Child> {
+            self.calls.lock().unwrap().push(Call::Spawn(cmd_argv(cmd)));
+            Command::new("true").spawn()
+        }
+
+        fn mount(
+            &self,
+            _source: Option<&Path>,
+            target: &Path,
+            _fstype: Option<&str>,
+            flags: MsFlags,
+            _data: Option<&str>,
+        ) -> nix::Result<()> {
+            self.calls.lock().unwrap().push(Call::Mount {
+                target: target.to_owned(),
+                flags,
+            });
+            Ok(())
+        }
+
+        fn umount(&self, target: &Path, _flags: MntFlags) -> nix::Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(Call::Umount(target.to_owned()));
+            Ok(())
+        }
+
+        fn kill(&self, process: &PidFd, signal: Signal) -> io::Result<()> {
+            self.calls
+                .lock()
+                .unwrap()
+                .push(Call::Kill(process.pid(), signal));
+            Ok(())
+        }
+
+        /// A single uplink, like most hosts have
+        fn default_routes(&self) -> anyhow::Result<Vec<DefaultRoute>> {
+            Ok(vec![DefaultRoute {
+                iface: "eth0".to_owned(),
+                gateway: Some([192, 168, 1, 1].into()),
+                metric: 100,
+                ipv6: false,
+            }])
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::readiness::wait_for_service;
+use crate::service::{self, ServiceConfig};
+use crate::up::spawn_service_inside;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// Names taken by what bubblewarp runs itself
+const CORE_SERVICES: &[&str] = &["init", "warp-svc", "danted"];
+
+/// When an extra service gets started again after it exits
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Restart {
+    #[default]
+    No,
+    /// Unless it exited successfully
+    OnFailure,
+    Always,
+}
+
+/// A `[[services.extra]]` entry, a long-running process of the user's own started in the
+/// container once WARP is up. Takes the same settings as the other services, args included.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(try_from = "toml::Table", rename_all = "kebab-case")]
+pub struct ExtraService {
+    pub name: String,
+    /// The program, as a path inside the container
+    pub command: PathBuf,
+    /// Services to start before this one, by name. warp-svc and danted always come first.
+    pub after: Vec<String>,
+    pub restart: Restart,
+    #[serde(flatten)]
+    pub service: ServiceConfig,
+}
+
+/// What is only about extra services, the rest of an entry is a regular service's settings
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct ExtraFields {
+    name: String,
+    command: PathBuf,
+    #[serde(default)]
+    after: Vec<String>,
+    #[serde(default)]
+    restart: Restart,
+}
+
+impl TryFrom<toml::Table> for ExtraService {
+    type Error = toml::de::Error;
+
+    fn try_from(mut table: toml::Table) -> Result<Self, Self::Error> {
+        let mut own = toml::Table::new();
+        for key in ["name", "command", "after", "restart"] {
+            if let Some(value) = table.remove(key) {
+                own.insert(key.to_owned(), value);
+            }
+        }
+        let own: ExtraFields = own.try_into()?;
+        Ok(ExtraService {
+            name: own.name,
+            command: own.command,
+            after: own.after,
+            restart: own.restart,
+            service: table.try_into()?,
+        })
+    }
+}
+
+/// The extra services in an order that starts each after those it comes after
+pub fn start_order(extras: &[ExtraService]) -> Result<Vec<&ExtraService>> {
+    for extra in extras {
+        let name = &extra.name;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            bail!("Invalid extra service name '{name}', use letters, digits, '-' and '_'")
+        }
+        if CORE_SERVICES.contains(&name.as_str()) {
+            bail!("{name} is one of bubblewarp's own services, name the extra service differently")
+        }
+        if extras.iter().filter(|other| other.name == *name).count() > 1 {
+            bail!("There are several extra services named {name}")
+        }
+        for dep in &extra.after {
+            if !CORE_SERVICES.contains(&dep.as_str()) && !extras.iter().any(|e| e.name == *dep) {
+                bail!("{name} comes after {dep}, which isn't a service")
+            }
+        }
+    }
+
+    let mut order: Vec<&ExtraService> = Vec::new();
+    while order.len() < extras.len() {
+        let ready = extras.iter().find(|extra| {
+            !order.iter().any(|started| started.name == extra.name)
+                && extra.after.iter().all(|dep| {
+                    CORE_SERVICES.contains(&dep.as_str())
+                        || order.iter().any(|started| started.name == *dep)
+                })
+        });
+        match ready {
+            Some(extra) => order.push(extra),
+            None => {
+                let stuck: Vec<&str> = extras
+                    .iter()
+                    .filter(|extra| !order.iter().any(|started| started.name == extra.name))
+                    .map(|extra| extra.name.as_str())
+                    .collect();
+                bail!(
+                    "The extra services {} come after each other",
+                    stuck.join(", ")
+                )
+            }
+        }
+    }
+    Ok(order)
+}
+
+fn start(ex: &dyn Executor, base_dir: &Path, extra: &ExtraService, ns_pid: u32) -> Result<()> {
+    if service::running(base_dir, &extra.name)?.is_some() {
+        debug!("{} is already running", extra.name);
+        return Ok(());
+    }
+    spawn_service_inside(
+        ex,
+        base_dir,
+        &extra.name,
+        &extra.command,
+        &extra.service,
+        ns_pid,
+    )
+}
+
+/// Starts the extra services that aren't running, in order. Those others come after get to pass
+/// their readiness check first.
+pub fn start_all(ex: &dyn Executor, config: &Config, ns_pid: u32) -> Result<()> {
+    let extras = &config.services.extra;
+    for extra in start_order(extras)? {
+        start(ex, &config.base_dir, extra, ns_pid)?;
+        if extras.iter().any(|other| other.after.contains(&extra.name)) {
+            let timeout = config.timeouts.service_ready;
+            if let Err(e) = wait_for_service(ex, &extra.name, &extra.service, ns_pid, timeout) {
+                warn!("{e:#}, starting the services after it anyway");
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Stops the extra services, those started last first
+pub fn stop_all(ex: &dyn Executor, config: &Config) -> Result<()> {
+    for extra in start_order(&config.services.extra)?.iter().rev() {
+        service::stop(ex, &config.base_dir, &extra.name)?;
+    }
+    Ok(())
+}
+
+/// Whether the service exited and its restart policy wants it back. An exit status is only known
+/// while the process that started the service runs, an unknown one doesn't count as a failure.
+fn needs_restart(base_dir: &Path, extra: &ExtraService) -> Result<bool> {
+    if service::running(base_dir, &extra.name)?.is_some() {
+        return Ok(false);
+    }
+    Ok(match extra.restart {
+        Restart::No => false,
+        Restart::OnFailure => service::exited_with_failure(base_dir, &extra.name),
+        Restart::Always => true,
+    })
+}
+
+/// Starts exited extra services again, as their restart policy says
+pub fn restart_exited(ex: &dyn Executor, config: &Config, ns_pid: u32) -> Result<()> {
+    let base_dir = &config.base_dir;
+    for extra in start_order(&config.services.extra)? {
+        if !needs_restart(base_dir, extra)? {
+            continue;
+        }
+        info!(
+            event = "service_restart",
+            service = extra.name,
+            "{} exited, starting it again",
+            extra.name
+        );
+        if let Err(e) = start(ex, base_dir, extra, ns_pid) {
+            warn!("Starting {} again failed: {e:#}", extra.name);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::service::ServicesConfig;
+    use crate::test_utils::TempDir;
+
+    fn parse(toml: &str) -> ServicesConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn orders_by_dependencies() {
+        let services = parse(
+            "[[extra]]\nname = \"exporter\"\ncommand = \"/usr/bin/exporter\"\nafter = [\"privoxy\"]\n\
+             [[extra]]\nname = \"privoxy\"\ncommand = \"/usr/sbin/privoxy\"\nafter = [\"danted\"]\n\
+             args = [\"--no-daemon\"]\nrestart = \"on-failure\"\n",
+        );
+        let order: Vec<&str> = start_order(&services.extra)
+            .unwrap()
+            .iter()
+            .map(|extra| extra.name.as_str())
+            .collect();
+        assert_eq!(order, ["privoxy", "exporter"]);
+        assert_eq!(services.extra[1].service.args, ["--no-daemon"]);
+        assert_eq!(services.extra[1].restart, Restart::OnFailure);
+    }
+
+    #[test]
+    fn rejects_bad_services() {
+        let cycle = parse(
+            "[[extra]]\nname = \"a\"\ncommand = \"/a\"\nafter = [\"b\"]\n\
+             [[extra]]\nname = \"b\"\ncommand = \"/b\"\nafter = [\"a\"]\n",
+        );
+        assert!(start_order(&cycle.extra).is_err());
+        let unknown = parse("[[extra]]\nname = \"a\"\ncommand = \"/a\"\nafter = [\"b\"]\n");
+        assert!(start_order(&unknown.extra).is_err());
+        let core = parse("[[extra]]\nname = \"danted\"\ncommand = \"/a\"\n");
+        assert!(start_order(&core.extra).is_err());
+        assert!(toml::from_str::<ServicesConfig>(
+            "[[extra]]\nname = \"a\"\ncommand = \"/a\"\nrestart-policy = \"always\"\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn restarts_on_failure_only_after_failed_exit() {
+        let dir = TempDir::new("extra");
+        std::fs::create_dir(dir.join("services")).unwrap();
+        let services = parse(
+            "[[extra]]\nname = \"a\"\ncommand = \"/a\"\nrestart = \"on-failure\"\n\
+             [[extra]]\nname = \"b\"\ncommand = \"/b\"\nrestart = \"always\"\n",
+        );
+        let (on_failure, always) = (&services.extra[0], &services.extra[1]);
+        assert!(!needs_restart(&dir, on_failure).unwrap());
+        assert!(needs_restart(&dir, always).unwrap());
+        std::fs::write(dir.join("services/a.exit"), "exit status: 0\n").unwrap();
+        assert!(!needs_restart(&dir, on_failure).unwrap());
+        std::fs::write(dir.join("services/a.exit"), "exit status: 3\n").unwrap();
+        assert!(needs_restart(&dir, on_failure).unwrap());
+    }
+}
@@ -0,0 +1,418 @@
+use crate::exec::Executor;
+use crate::paths::Binary;
+use anyhow::{anyhow, bail, Context, Result};
+use std::cmp::Ordering;
+use std::path::{Path, PathBuf};
+use tracing::{debug, info};
+
+/// Cloudflare's apt repository. The binaries in the .deb run on any glibc distro.
+const REPO: &str = "https://pkg.cloudflareclient.com";
+const PACKAGE: &str = "cloudflare-warp";
+/// Used on hosts that aren't Debian or Ubuntu based
+const DEFAULT_CODENAME: &str = "bookworm";
+/// The repository's signing key, as the end of its fingerprint. The key served next to the
+/// repository is only trusted if it is this one, a rotated key has to be added here.
+const SIGNING_KEYS: &[&str] = &["6E2DD2174FA1C3BA"];
+
+/// Where fetched WARP releases are unpacked, one directory per version
+pub fn managed_dir(base_dir: &Path) -> PathBuf {
+    base_dir.join("warp")
+}
+
+/// A binary of the release fetch last installed, preferred over a system-wide install
+pub fn installed(base_dir: &Path, name: &str) -> Option<PathBuf> {
+    let path = managed_dir(base_dir).join("current").join(name);
+    path.exists().then_some(path)
+}
+
+fn deb_arch() -> Result<&'static str> {
+    match std::env::consts::ARCH {
+        "x86_64" => Ok("amd64"),
+        "aarch64" => Ok("arm64"),
+        arch => bail!("Cloudflare doesn't publish WARP packages for {arch}"),
+    }
+}
+
+fn os_release_codename(os_release: &str) -> Option<String> {
+    let field = |key: &str| {
+        os_release.lines().find_map(|line| {
+            let value = line.strip_prefix(key)?.strip_prefix('=')?;
+            Some(value.trim_matches('"').to_owned())
+        })
+    };
+    let ids = format!(
+        "{} {}",
+        field("ID").unwrap_or_default(),
+        field("ID_LIKE").unwrap_or_default()
+    );
+    if !ids.contains("debian") && !ids.contains("ubuntu") {
+        return None;
+    }
+    // Ubuntu derivatives have their own VERSION_CODENAME
+    field("UBUNTU_CODENAME")
+        .or_else(|| field("VERSION_CODENAME"))
+        .filter(|c| !c.is_empty())
+}
+
+fn codename() -> String {
+    std::fs::read_to_string("/etc/os-release")
+        .ok()
+        .and_then(|os_release| os_release_codename(&os_release))
+        .unwrap_or_else(|| DEFAULT_CODENAME.to_owned())
+}
+
+fn download(ex: &dyn Executor, url: &str, dest: &Path) -> Result<()> {
+    debug!("Downloading {url}");
+    ex.status(
+        ex.command(Binary::Curl)?
+            .args(["-fsSL", "--proto", "=https", "-o"])
+            .arg(dest)
+            .arg(url),
+    )?
+    .exit_ok()
+    .with_context(|| format!("Downloading {url}"))?;
+    Ok(())
+}
+
+fn verify_sha256(ex: &dyn Executor, file: &Path, expected: &str) -> Result<()> {
+    let out = ex.output(ex.command(Binary::Sha256sum)?.arg(file))?;
+    out.status.exit_ok()?;
+    let out = String::from_utf8(out.stdout)?;
+    let actual = out.split_whitespace().next().unwrap_or_default();
+    if !actual.eq_ignore_ascii_case(expected) {
+        bail!(
+            "Checksum mismatch for {}, expected {expected} but got {actual}",
+            file.display()
+        )
+    }
+    Ok(())
+}
+
+/// The fingerprints of the primary keys in gpg's --with-colons listing
+fn primary_fingerprints(listing: &str) -> Vec<String> {
+    let mut fingerprints = Vec::new();
+    let mut record = "";
+    for line in listing.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields[0] {
+            "fpr" if record == "pub" => {
+                fingerprints.extend(fields.get(9).map(|fpr| fpr.to_ascii_uppercase()))
+            }
+            "fpr" => {}
+            other => record = other,
+        }
+    }
+    fingerprints
+}
+
+fn check_signing_key(ex: &dyn Executor, key: &Path) -> Result<()> {
+    let out = ex.output(
+        ex.command(Binary::Gpg)?
+            .args(["--batch", "--with-colons", "--show-keys"])
+            .arg(key),
+    )?;
+    out.status
+        .exit_ok()
+        .context("Reading Cloudflare's signing key")?;
+    let fingerprints = primary_fingerprints(&String::from_utf8_lossy(&out.stdout));
+    if fingerprints.is_empty() {
+        bail!("The repository's key file has no keys")
+    }
+    for fingerprint in fingerprints {
+        if !SIGNING_KEYS.iter().any(|pin| fingerprint.ends_with(pin)) {
+            bail!("The repository is signed with an unknown key {fingerprint}, not trusting it")
+        }
+    }
+    Ok(())
+}
+
+/// The SHA256 of `name` in the signed Release file
+fn release_sha256(release: &str, name: &str) -> Option<String> {
+    release
+        .lines()
+        .skip_while(|line| *line != "SHA256:")
+        .skip(1)
+        .take_while(|line| line.starts_with(' '))
+        .find_map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            (parts.len() == 3 && parts[2] == name).then(|| parts[0].to_owned())
+        })
+}
+
+struct Package {
+    version: String,
+    filename: String,
+    sha256: String,
+}
+
+/// Compares runs of digits numerically, which is all the WARP version numbers need
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let chunks = |v: &str| -> Vec<(bool, String)> {
+        let mut chunks: Vec<(bool, String)> = Vec::new();
+        for c in v.chars() {
+            let digit = c.is_ascii_digit();
+            match chunks.last_mut() {
+                Some((d, s)) if *d == digit => s.push(c),
+                _ => chunks.push((digit, c.to_string())),
+            }
+        }
+        chunks
+    };
+    for (x, y) in chunks(a).into_iter().zip(chunks(b)) {
+        let ord = match (x, y) {
+            ((true, x), (true, y)) => x
+                .parse::<u64>()
+                .unwrap_or(0)
+                .cmp(&y.parse::<u64>().unwrap_or(0)),
+            ((_, x), (_, y)) => x.cmp(&y),
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// The newest WARP release listed in a Packages index
+fn latest_package(packages: &str) -> Option<Package> {
+    packages
+        .split("\n\n")
+        .filter_map(|stanza| {
+            let field = |key: &str| {
+                stanza.lines().find_map(|line| {
+                    Some(
+                        line.strip_prefix(key)?
+                            .strip_prefix(": ")?
+                            .trim()
+                            .to_owned(),
+                    )
+                })
+            };
+            (field("Package")? == PACKAGE).then_some(())?;
+            Some(Package {
+                version: field("Version")?,
+                filename: field("Filename")?,
+                sha256: field("SHA256")?,
+            })
+        })
+        .max_by(|a, b| compare_versions(&a.version, &b.version))
+}
+
+/// Downloads the latest WARP release from Cloudflare's repository and installs its binaries
+/// into our managed directory. The Packages index is checked against the signed Release file,
+/// and the package against the index.
+pub fn fetch(ex: &dyn Executor, base_dir: &Path) -> Result<()> {
+    let managed = managed_dir(base_dir);
+    let work = managed.join(".download");
+    if work.exists() {
+        std::fs::remove_dir_all(&work)?;
+    }
+    std::fs::create_dir_all(&work)?;
+    let result = fetch_into(ex, &managed, &work);
+    let _ = std::fs::remove_dir_all(&work);
+    result
+}
+
+fn fetch_into(ex: &dyn Executor, managed: &Path, work: &Path) -> Result<()> {
+    let arch = deb_arch()?;
+    let dist = format!("{REPO}/dists/{}", codename());
+
+    let armored_key = work.join("pubkey.gpg");
+    download(ex, &format!("{REPO}/pubkey.gpg"), &armored_key)?;
+    check_signing_key(ex, &armored_key)?;
+    let keyring = work.join("keyring.gpg");
+    ex.status(
+        ex.command(Binary::Gpg)?
+            .args(["--batch", "--yes", "--dearmor", "-o"])
+            .arg(&keyring)
+            .arg(&armored_key),
+    )?
+    .exit_ok()
+    .context("Converting Cloudflare's signing key")?;
+
+    let in_release = work.join("InRelease");
+    download(ex, &format!("{dist}/InRelease"), &in_release)?;
+    let release = work.join("Release");
+    ex.status(
+        ex.command(Binary::Gpgv)?
+            .arg("--keyring")
+            .arg(&keyring)
+            .arg("--output")
+            .arg(&release)
+            .arg(&in_release),
+    )?
+    .exit_ok()
+    .context("Verifying the signature of the repository's Release file")?;
+
+    let index_name = format!("main/binary-{arch}/Packages");
+    let index_sha256 = release_sha256(&std::fs::read_to_string(&release)?, &index_name)
+        .ok_or_else(|| anyhow!("The repository's Release file doesn't list {index_name}"))?;
+    let index = work.join("Packages");
+    download(ex, &format!("{dist}/{index_name}"), &index)?;
+    verify_sha256(ex, &index, &index_sha256)?;
+
+    let package = latest_package(&std::fs::read_to_string(&index)?)
+        .ok_or_else(|| anyhow!("No {PACKAGE} package for {arch} in the repository"))?;
+    let version_dir = managed.join(&package.version);
+    if version_dir.join("warp-svc").exists() {
+        info!("WARP {} is already fetched", package.version);
+    } else {
+        info!("Fetching WARP {}", package.version);
+        let deb = work.join("package.deb");
+        download(ex, &format!("{REPO}/{}", package.filename), &deb)?;
+        verify_sha256(ex, &deb, &package.sha256)?;
+        unpack(ex, work, &deb, managed, &package.version)?;
+    }
+
+    // Swapped in with a rename, so up never sees a half-updated link
+    let current = managed.join("current");
+    let new_link = managed.join(".current.new");
+    let _ = std::fs::remove_file(&new_link);
+    std::os::unix::fs::symlink(&package.version, &new_link)?;
+    std::fs::rename(&new_link, &current)?;
+    println!(
+        "WARP {} installed in {}, up will use it",
+        package.version,
+        version_dir.display()
+    );
+    Ok(())
+}
+
+/// Where a version is copied to before it's renamed into place, versions have dots in them
+fn staging_dir(managed: &Path, version: &str) -> PathBuf {
+    managed.join(format!("{version}.partial"))
+}
+
+fn unpack(ex: &dyn Executor, work: &Path, deb: &Path, managed: &Path, version: &str) -> Result<()> {
+    let extracted = work.join("deb");
+    std::fs::create_dir_all(&extracted)?;
+    ex.status(
+        ex.command(Binary::Ar)?
+            .arg("x")
+            .arg(deb)
+            .current_dir(&extracted),
+    )?
+    .exit_ok()
+    .context("Extracting the package")?;
+    let data_tar = std::fs::read_dir(&extracted)?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| {
+            p.file_name()
+                .is_some_and(|n| n.to_string_lossy().starts_with("data.tar"))
+        })
+        .ok_or_else(|| anyhow!("The package has no data archive"))?;
+    let data = work.join("data");
+    std::fs::create_dir_all(&data)?;
+    ex.status(
+        ex.command(Binary::Tar)?
+            .arg("-C")
+            .arg(&data)
+            .arg("--no-same-owner")
+            .arg("-xf")
+            .arg(&data_tar),
+    )?
+    .exit_ok()
+    .context("Extracting the package's files")?;
+
+    let bin_dir = ["bin", "usr/bin"]
+        .iter()
+        .map(|dir| data.join(dir))
+        .find(|dir| dir.join("warp-svc").exists())
+        .ok_or_else(|| anyhow!("The package doesn't contain warp-svc"))?;
+    let staging = staging_dir(managed, version);
+    // Left over by a fetch that failed, it may hold files of another package
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+    for entry in std::fs::read_dir(&bin_dir)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with("warp-") {
+            std::fs::copy(entry.path(), staging.join(entry.file_name()))?;
+        }
+    }
+    let version_dir = managed.join(version);
+    if version_dir.exists() {
+        std::fs::remove_dir_all(&version_dir)?;
+    }
+    std::fs::rename(&staging, &version_dir)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codename_from_os_release() {
+        let ubuntu = "ID=linuxmint\nID_LIKE=\"ubuntu debian\"\nVERSION_CODENAME=virginia\nUBUNTU_CODENAME=jammy\n";
+        assert_eq!(os_release_codename(ubuntu).as_deref(), Some("jammy"));
+        let debian = "ID=debian\nVERSION_CODENAME=bookworm\n";
+        assert_eq!(os_release_codename(debian).as_deref(), Some("bookworm"));
+        assert_eq!(os_release_codename("ID=arch\n"), None);
+    }
+
+    #[test]
+    fn stages_each_version_apart() {
+        let managed = Path::new("/base/warp");
+        assert_eq!(
+            staging_dir(managed, "2023.10.120"),
+            Path::new("/base/warp/2023.10.120.partial")
+        );
+        assert_ne!(
+            staging_dir(managed, "2023.10.120"),
+            staging_dir(managed, "2023.10.121")
+        );
+    }
+
+    #[test]
+    fn fingerprints_of_primary_keys() {
+        let listing = "pub:-:4096:1:6E2DD2174FA1C3BA:1700000000:::-:::scSC::::::23::0:
+fpr:::::::::1111222233334444555566666E2DD2174FA1C3BA:
+uid:-::::1700000000::AAAA::CloudFlare Software Packaging <help@cloudflare.com>::::::::::0:
+sub:-:4096:1:0123456789ABCDEF:1700000000::::::e::::::23:
+fpr:::::::::aaaabbbbccccddddeeeeffff0123456789abcdef:
+";
+        assert_eq!(
+            primary_fingerprints(listing),
+            ["1111222233334444555566666E2DD2174FA1C3BA"]
+        );
+    }
+
+    #[test]
+    fn index_hash_from_release() {
+        let release = "Origin: Cloudflare
+SHA256:
+ aaaa 123 main/binary-arm64/Packages
+ bbbb 456 main/binary-amd64/Packages
+";
+        assert_eq!(
+            release_sha256(release, "main/binary-amd64/Packages").as_deref(),
+            Some("bbbb")
+        );
+        assert_eq!(release_sha256(release, "main/binary-i386/Packages"), None);
+    }
+
+    #[test]
+    fn picks_latest_package() {
+        let packages = "Package: cloudflare-warp
+Version: 2023.9.301
+Filename: pool/bookworm/main/c/cloudflare-warp/cloudflare-warp_2023.9.301_amd64.deb
+SHA256: 1111
+
+Package: cloudflare-warp
+Version: 2023.10.120
+Filename: pool/bookworm/main/c/cloudflare-warp/cloudflare-warp_2023.10.120_amd64.deb
+SHA256: 2222
+
+Package: something-else
+Version: 9999.1
+Filename: pool/x.deb
+SHA256: 3333
+";
+        let package = latest_package(packages).unwrap();
+        assert_eq!(package.version, "2023.10.120");
+        assert_eq!(package.sha256, "2222");
+    }
+}
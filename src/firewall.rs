@@ -0,0 +1,1084 @@
+use crate::exec::Executor;
+use crate::namespace::{run_inside_namespace, Type};
+use crate::net::Network;
+use crate::paths::Binary;
+use crate::readiness::WARP_IFACE;
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::str::FromStr;
+use tracing::{debug, warn};
+
+/// Docker and libvirt jump to these from the top of FORWARD, and they end up dropping traffic
+/// they don't know about (Docker also sets the FORWARD policy to DROP), so we have to go first.
+const FORWARD_GATEKEEPER_CHAINS: &[&str] = &[
+    "DOCKER-USER",
+    "DOCKER-FORWARD",
+    "DOCKER",
+    "LIBVIRT_FWX",
+    "LIBVIRT_FWI",
+    "LIBVIRT_FWO",
+];
+
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// firewalld if it is running, plain iptables otherwise
+    #[default]
+    Auto,
+    Iptables,
+    /// Rules added behind firewalld's back are lost whenever it reloads, so let it own them
+    Firewalld,
+}
+
+/// The `[firewall]` section of the config file
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FirewallConfig {
+    pub backend: Backend,
+    /// Host ports to open while the container is up, like "1080/tcp", for listeners exposed on
+    /// the LAN such as a relay. down closes them, even if the config changed meanwhile.
+    pub open: Vec<Opening>,
+    /// Container ports that may take new connections forwarded from the uplink, like "8080/tcp",
+    /// for hosts on the LAN that route the veth subnet through this one. Otherwise only replies to
+    /// the container's own connections are let in.
+    pub inbound: Vec<Opening>,
+    /// Lets the container's IPv6 out. Without it, IPv6 only leaves the container through WARP's
+    /// tunnel, and the host doesn't forward any from or to the veth.
+    pub ipv6: bool,
+    /// The interfaces the container's traffic may leave through, most preferred first, like
+    /// ["eth0", "wlan0"]. Each gets its rules up front, so traffic keeps flowing when the host's
+    /// default route fails over to another one. Defaults to the default route's interface.
+    pub uplinks: Vec<String>,
+}
+
+/// The `[nat]` section of the config file
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct NatConfig {
+    /// The host address the container's traffic leaves from on its way to WARP, instead of the
+    /// one MASQUERADE picks. Instances with their own config files can each have their own.
+    pub snat_to: Option<Ipv4Addr>,
+}
+
+impl NatConfig {
+    /// SNAT needs an address the host has, replies to any other never come back
+    pub fn check(&self) -> Result<()> {
+        let Some(addr) = self.snat_to else {
+            return Ok(());
+        };
+        let on_host = nix::ifaddrs::getifaddrs()?.any(|dev| {
+            dev.address
+                .and_then(|a| a.as_sockaddr_in().map(|sin| sin.ip()))
+                .is_some_and(|ip| Ipv4Addr::from(ip) == addr)
+        });
+        if !on_host {
+            bail!("nat.snat-to is {addr}, which none of the host's interfaces has")
+        }
+        Ok(())
+    }
+
+    /// The NAT for one of several uplinks. SNAT only goes on those that have the address, the
+    /// others masquerade, since replies to it wouldn't come back through them.
+    fn on(&self, uplink: &str) -> Result<NatConfig> {
+        let Some(addr) = self.snat_to else {
+            return Ok(self.clone());
+        };
+        let has_addr = nix::ifaddrs::getifaddrs()?.any(|dev| {
+            dev.interface_name == uplink
+                && dev
+                    .address
+                    .and_then(|a| a.as_sockaddr_in().map(|sin| sin.ip()))
+                    .is_some_and(|ip| Ipv4Addr::from(ip) == addr)
+        });
+        Ok(match has_addr {
+            true => self.clone(),
+            false => NatConfig::default(),
+        })
+    }
+
+    fn target(&self) -> Vec<String> {
+        match self.snat_to {
+            Some(addr) => ["-j", "SNAT", "--to-source", &addr.to_string()]
+                .map(str::to_owned)
+                .to_vec(),
+            None => vec!["-j".to_owned(), "MASQUERADE".to_owned()],
+        }
+    }
+}
+
+/// A port and protocol to accept incoming traffic on
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Opening {
+    pub port: u16,
+    pub proto: String,
+}
+
+impl FromStr for Opening {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (port, proto) = s.split_once('/').unwrap_or((s, "tcp"));
+        if proto != "tcp" && proto != "udp" {
+            bail!("Unknown protocol in {s}, expected tcp or udp")
+        }
+        Ok(Opening {
+            port: port
+                .parse()
+                .with_context(|| format!("Invalid port in {s}"))?,
+            proto: proto.to_owned(),
+        })
+    }
+}
+
+impl TryFrom<String> for Opening {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Opening> for String {
+    fn from(opening: Opening) -> String {
+        opening.to_string()
+    }
+}
+
+impl fmt::Display for Opening {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.port, self.proto)
+    }
+}
+
+/// What open_configured opened, one per line, for down to close
+fn openings_path(base_dir: &Path) -> PathBuf {
+    base_dir.join("firewall-openings")
+}
+
+fn recorded_openings(base_dir: &Path) -> Result<Vec<Opening>> {
+    match std::fs::read_to_string(openings_path(base_dir)) {
+        Ok(data) => data.lines().map(str::parse).collect(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Opens the configured ports, recording each before opening it
+pub fn open_configured(ex: &dyn Executor, base_dir: &Path, config: &FirewallConfig) -> Result<()> {
+    let mut recorded = recorded_openings(base_dir)?;
+    for opening in &config.open {
+        if !recorded.contains(opening) {
+            recorded.push(opening.clone());
+            let data: String = recorded.iter().map(|o| format!("{o}\n")).collect();
+            std::fs::write(openings_path(base_dir), data)?;
+        }
+        open_port(ex, config.backend, &opening.proto, opening.port)?;
+    }
+    Ok(())
+}
+
+/// Closes what open_configured opened
+pub fn close_recorded(ex: &dyn Executor, base_dir: &Path) -> Result<()> {
+    for opening in recorded_openings(base_dir)? {
+        close_port(ex, &opening.proto, opening.port)?;
+    }
+    match std::fs::remove_file(openings_path(base_dir)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn firewalld_running(ex: &dyn Executor) -> bool {
+    let Ok(mut cmd) = ex.command(Binary::FirewallCmd) else {
+        return false;
+    };
+    ex.status(
+        cmd.arg("--state")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null()),
+    )
+    .is_ok_and(|status| status.success())
+}
+
+pub fn resolve_backend(ex: &dyn Executor, backend: Backend) -> Backend {
+    match backend {
+        Backend::Auto if firewalld_running(ex) => Backend::Firewalld,
+        Backend::Auto => Backend::Iptables,
+        backend => backend,
+    }
+}
+
+/// The iptables rules that are about the container, found by its subnet and veth, and the ports
+/// open_configured opened. Rules in firewalld's own chains are left out.
+pub fn installed_rules(ex: &dyn Executor, base_dir: &Path, net: &Network) -> Result<Vec<String>> {
+    let openings: Vec<String> = recorded_openings(base_dir)?
+        .iter()
+        .map(|o| format!("-p {} -m {} --dport {} -j ACCEPT", o.proto, o.proto, o.port))
+        .collect();
+    let mut rules = Vec::new();
+    for table in ["filter", "nat"] {
+        let out = ex.output(ex.command(Binary::Iptables)?.args(["-t", table, "-S"]))?;
+        out.status.exit_ok()?;
+        let listed = String::from_utf8(out.stdout)?;
+        rules.extend(
+            listed
+                .lines()
+                .filter(|rule| {
+                    rule.contains(&net.subnet())
+                        || rule.contains(&net.ns_cidr())
+                        || rule.contains(&net.host_iface)
+                        || openings.iter().any(|opening| rule.ends_with(opening))
+                })
+                .map(|rule| format!("-t {table} {rule}")),
+        );
+    }
+    Ok(rules)
+}
+
+/// Lets the container reach the outside world through the uplink interface, and lets back in
+/// only the replies and the configured inbound ports
+pub fn add_forward(
+    ex: &dyn Executor,
+    config: &FirewallConfig,
+    nat: &NatConfig,
+    net: &Network,
+    uplinks: &[String],
+) -> Result<()> {
+    match resolve_backend(ex, config.backend) {
+        Backend::Firewalld => {
+            if nat.snat_to.is_some() {
+                bail!("nat.snat-to needs the iptables firewall backend, firewalld's policy can only masquerade")
+            }
+            if !config.inbound.is_empty() {
+                warn!("firewall.inbound only applies with the iptables backend, firewalld's policy only lets replies in");
+            }
+            firewalld_add_forward(ex, net, uplinks)
+        }
+        _ => {
+            for uplink in uplinks {
+                let nat = match uplinks.len() {
+                    1 => nat.clone(),
+                    _ => nat.on(uplink)?,
+                };
+                iptables_add_forward(ex, net, &nat, uplink, &config.inbound)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Removes the forward from whichever backend has it, the config may have changed since `up`
+pub fn remove_forward(ex: &dyn Executor, net: &Network, uplink: &str) -> Result<()> {
+    iptables_remove_forward(ex, net, uplink)?;
+    if firewalld_running(ex) {
+        firewalld_remove_forward(ex, net)?;
+    }
+    Ok(())
+}
+
+pub fn has_forward(
+    ex: &dyn Executor,
+    backend: Backend,
+    nat: &NatConfig,
+    net: &Network,
+    uplink: &str,
+) -> Result<bool> {
+    match resolve_backend(ex, backend) {
+        Backend::Firewalld => firewalld_has(ex, "--get-policies", &firewalld_names(net).1),
+        _ => {
+            let status = ex.status(
+                ex.command(Binary::Iptables)?
+                    .args(["-t", "nat", "-C", "POSTROUTING", "-s", &net.ns_cidr()])
+                    .args(["-o", uplink])
+                    .args(nat.target())
+                    .stderr(Stdio::null()),
+            )?;
+            Ok(status.success())
+        }
+    }
+}
+
+/// Accepts incoming traffic to a port on the host, for listeners we expose
+pub fn open_port(ex: &dyn Executor, backend: Backend, proto: &str, port: u16) -> Result<()> {
+    debug!("Opening host port {port}/{proto}");
+    match resolve_backend(ex, backend) {
+        Backend::Firewalld => {
+            let port_arg = format!("--add-port={port}/{proto}");
+            firewall_cmd(ex, &[&port_arg])?;
+            firewall_cmd(ex, &["--permanent", &port_arg])?;
+        }
+        _ => {
+            let port = port.to_string();
+            let rule = ["INPUT", "-p", proto, "--dport", &port, "-j", "ACCEPT"];
+            let exists = ex
+                .status(
+                    ex.command(Binary::Iptables)?
+                        .arg("-C")
+                        .args(rule)
+                        .stderr(Stdio::null()),
+                )?
+                .success();
+            if !exists {
+                ex.status(ex.command(Binary::Iptables)?.arg("-I").args(rule))?
+                    .exit_ok()?;
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn close_port(ex: &dyn Executor, proto: &str, port: u16) -> Result<()> {
+    delete_iptables_rule(ex, &format!("INPUT -p {proto} --dport {port} -j ACCEPT"))?;
+    if firewalld_running(ex) {
+        let port_arg = format!("--remove-port={port}/{proto}");
+        // These fail if the port wasn't open, which is fine
+        let _ = firewall_cmd(ex, &[&port_arg]);
+        let _ = firewall_cmd(ex, &["--permanent", &port_arg]);
+    }
+    Ok(())
+}
+
+/// Whether FORWARD jumps to a chain that would see our traffic before an appended rule does
+fn forward_has_gatekeeper(ex: &dyn Executor) -> Result<bool> {
+    let out = ex.output(ex.command(Binary::Iptables)?.args(["-S", "FORWARD"]))?;
+    if !out.status.success() {
+        return Ok(false);
+    }
+    let rules = String::from_utf8_lossy(&out.stdout);
+    Ok(rules.lines().any(|rule| {
+        let mut words = rule.split(' ').skip_while(|&w| w != "-j").skip(1);
+        words
+            .next()
+            .is_some_and(|target| FORWARD_GATEKEEPER_CHAINS.contains(&target))
+    }))
+}
+
+/// One of the rules forwarding the container's traffic through an uplink
+struct ForwardRule {
+    /// In the nat table, the filter table otherwise
+    nat: bool,
+    chain: &'static str,
+    rule: Vec<String>,
+}
+
+impl ForwardRule {
+    fn new(nat: bool, chain: &'static str, rule: &[&str]) -> Self {
+        ForwardRule {
+            nat,
+            chain,
+            rule: rule.iter().map(|&word| word.to_owned()).collect(),
+        }
+    }
+
+    fn table(&self) -> &'static [&'static str] {
+        match self.nat {
+            true => &["-t", "nat"],
+            false => &[],
+        }
+    }
+}
+
+impl fmt::Display for ForwardRule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let table = if self.nat { "-t nat " } else { "" };
+        write!(f, "{table}-A {} {}", self.chain, self.rule.join(" "))
+    }
+}
+
+fn forward_rules(
+    net: &Network,
+    nat: &NatConfig,
+    uplink: &str,
+    inbound: &[Opening],
+) -> Vec<ForwardRule> {
+    let veth = net.host_iface.as_str();
+    let mut masquerade = vec![
+        "-s".to_owned(),
+        net.ns_cidr(),
+        "-o".to_owned(),
+        uplink.to_owned(),
+    ];
+    masquerade.extend(nat.target());
+    let mut rules = vec![
+        ForwardRule {
+            nat: true,
+            chain: "POSTROUTING",
+            rule: masquerade,
+        },
+        ForwardRule::new(
+            false,
+            "FORWARD",
+            &[
+                "-i",
+                uplink,
+                "-o",
+                veth,
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "ESTABLISHED,RELATED",
+                "-j",
+                "ACCEPT",
+            ],
+        ),
+    ];
+    let ns_addr = net.ns_addr.to_string();
+    for opening in inbound {
+        let port = opening.port.to_string();
+        rules.push(ForwardRule::new(
+            false,
+            "FORWARD",
+            &[
+                "-i",
+                uplink,
+                "-o",
+                veth,
+                "-d",
+                &ns_addr,
+                "-p",
+                &opening.proto,
+                "--dport",
+                &port,
+                "-m",
+                "conntrack",
+                "--ctstate",
+                "NEW",
+                "-j",
+                "ACCEPT",
+            ],
+        ));
+    }
+    rules.push(ForwardRule::new(
+        false,
+        "FORWARD",
+        &["-o", uplink, "-i", veth, "-j", "ACCEPT"],
+    ));
+    rules
+}
+
+fn iptables_has_rule(ex: &dyn Executor, rule: &ForwardRule) -> Result<bool> {
+    Ok(ex
+        .status(
+            ex.command(Binary::Iptables)?
+                .args(rule.table())
+                .args(["-C", rule.chain])
+                .args(&rule.rule)
+                .stderr(Stdio::null()),
+        )?
+        .success())
+}
+
+fn iptables_add_forward(
+    ex: &dyn Executor,
+    net: &Network,
+    nat: &NatConfig,
+    uplink: &str,
+    inbound: &[Opening],
+) -> Result<()> {
+    debug!("Adding iptables forward rules for interface {uplink}");
+    let forward_op: &[&str] = if forward_has_gatekeeper(ex)? {
+        debug!("FORWARD is managed by Docker or libvirt, inserting our rules ahead of theirs");
+        &["-I", "FORWARD", "1"]
+    } else {
+        &["-A", "FORWARD"]
+    };
+    for rule in forward_rules(net, nat, uplink, inbound) {
+        // Adding the forward again only puts back what went missing, like after a flush
+        if iptables_has_rule(ex, &rule)? {
+            continue;
+        }
+        let mut cmd = ex.command(Binary::Iptables)?;
+        cmd.args(rule.table());
+        match rule.nat {
+            true => cmd.args(["-A", rule.chain]),
+            false => cmd.args(forward_op),
+        };
+        ex.status(cmd.args(&rule.rule))?.exit_ok()?;
+    }
+    Ok(())
+}
+
+/// The forward's rules that aren't there, as iptables -S lists them
+pub fn missing_forward_rules(
+    ex: &dyn Executor,
+    config: &FirewallConfig,
+    nat: &NatConfig,
+    net: &Network,
+    uplinks: &[String],
+) -> Result<Vec<String>> {
+    let mut missing = Vec::new();
+    match resolve_backend(ex, config.backend) {
+        Backend::Firewalld => {
+            let policy = firewalld_names(net).1;
+            if !firewalld_has(ex, "--get-policies", &policy)? {
+                missing.push(format!("firewalld policy {policy}"));
+            }
+        }
+        _ => {
+            for uplink in uplinks {
+                let nat = match uplinks.len() {
+                    1 => nat.clone(),
+                    _ => nat.on(uplink)?,
+                };
+                for rule in forward_rules(net, &nat, uplink, &config.inbound) {
+                    if !iptables_has_rule(ex, &rule)? {
+                        missing.push(rule.to_string());
+                    }
+                }
+            }
+        }
+    }
+    Ok(missing)
+}
+
+fn iptables_remove_forward(ex: &dyn Executor, net: &Network, uplink: &str) -> Result<()> {
+    let subnet = net.subnet();
+    let veth = &net.host_iface;
+    delete_iptables_rule(
+        ex,
+        &format!("POSTROUTING -t nat -s {subnet} -o {uplink} -j MASQUERADE"),
+    )?;
+    // With any address, nat.snat-to may have changed since
+    let out = ex.output(
+        ex.command(Binary::Iptables)?
+            .args(["-t", "nat", "-S", "POSTROUTING"]),
+    )?;
+    if out.status.success() {
+        let snat = format!("-A POSTROUTING -s {subnet} -o {uplink} -j SNAT ");
+        for rule in String::from_utf8_lossy(&out.stdout).lines() {
+            if let Some(rule) = rule.strip_prefix("-A ").filter(|_| rule.starts_with(&snat)) {
+                delete_iptables_rule(ex, &format!("{rule} -t nat"))?;
+            }
+        }
+    }
+    // The inbound ports may have changed since, and older versions accepted everything inbound
+    let out = ex.output(ex.command(Binary::Iptables)?.args(["-S", "FORWARD"]))?;
+    if out.status.success() {
+        let inbound = format!("-A FORWARD -i {uplink} -o {veth} ");
+        for rule in String::from_utf8_lossy(&out.stdout).lines() {
+            if let Some(rule) = rule
+                .strip_prefix("-A ")
+                .filter(|_| rule.starts_with(&inbound))
+            {
+                delete_iptables_rule(ex, rule)?;
+            }
+        }
+    }
+    delete_iptables_rule(ex, &format!("FORWARD -o {uplink} -i {veth} -j ACCEPT"))?;
+    Ok(())
+}
+
+fn delete_iptables_rule(ex: &dyn Executor, rule: &str) -> Result<()> {
+    delete_rule(ex, Binary::Iptables, rule)
+}
+
+fn delete_rule(ex: &dyn Executor, iptables: Binary, rule: &str) -> Result<()> {
+    let rule_words: Vec<&str> = rule.split(' ').collect();
+    loop {
+        let status = ex.status(
+            ex.command(iptables)?
+                .arg("-D")
+                .args(&rule_words)
+                .stderr(Stdio::null()),
+        )?;
+        if !status.success() {
+            return Ok(());
+        }
+    }
+}
+
+/// Drops IPv6 leaving the container other than through the tunnel, which the IPv4-only NAT
+/// would otherwise let out if IPv6 forwarding gets enabled. Rules inside the container go away
+/// with it, those on the host are in ip6tables' own FORWARD, which firewalld's reloads keep.
+pub fn block_ipv6_leaks(ex: &dyn Executor, base_dir: &Path, net: &Network) -> Result<()> {
+    debug!("Dropping IPv6 that doesn't go through the tunnel");
+    let in_ns = |cmd: &mut Command| run_inside_namespace(ex, base_dir, Type::Net, cmd);
+    for (chain, rule) in [
+        ("OUTPUT", vec!["-o", "lo", "-j", "ACCEPT"]),
+        ("OUTPUT", vec!["-o", WARP_IFACE, "-j", "ACCEPT"]),
+        ("OUTPUT", vec!["-j", "DROP"]),
+        ("FORWARD", vec!["-o", WARP_IFACE, "-j", "ACCEPT"]),
+        ("FORWARD", vec!["-j", "DROP"]),
+    ] {
+        let exists = in_ns(
+            ex.command(Binary::Ip6tables)?
+                .args(["-C", chain])
+                .args(&rule)
+                .stderr(Stdio::null()),
+        )
+        .is_ok();
+        if !exists {
+            in_ns(
+                ex.command(Binary::Ip6tables)?
+                    .args(["-A", chain])
+                    .args(&rule),
+            )?;
+        }
+    }
+    for rule in ipv6_host_rules(net) {
+        if !ip6tables_has_rule(ex, &rule)? {
+            ex.status(ex.command(Binary::Ip6tables)?.arg("-I").args(rule))?
+                .exit_ok()?;
+        }
+    }
+    Ok(())
+}
+
+fn ipv6_host_rules(net: &Network) -> [[&str; 5]; 2] {
+    ["-i", "-o"].map(|direction| ["FORWARD", direction, &net.host_iface, "-j", "DROP"])
+}
+
+fn ip6tables_has_rule(ex: &dyn Executor, rule: &[&str]) -> Result<bool> {
+    Ok(ex
+        .status(
+            ex.command(Binary::Ip6tables)?
+                .arg("-C")
+                .args(rule)
+                .stderr(Stdio::null()),
+        )?
+        .success())
+}
+
+/// block_ipv6_leaks' rules on the host that aren't there, as ip6tables -S lists them
+pub fn missing_ipv6_rules(ex: &dyn Executor, net: &Network) -> Result<Vec<String>> {
+    let mut missing = Vec::new();
+    for rule in ipv6_host_rules(net) {
+        if !ip6tables_has_rule(ex, &rule)? {
+            missing.push(format!("-A {}", rule.join(" ")));
+        }
+    }
+    Ok(missing)
+}
+
+/// Removes block_ipv6_leaks' rules on the host, if ip6tables is there to have any
+pub fn unblock_ipv6(ex: &dyn Executor, net: &Network) -> Result<()> {
+    if ex.command(Binary::Ip6tables).is_err() {
+        return Ok(());
+    }
+    let veth = &net.host_iface;
+    delete_rule(ex, Binary::Ip6tables, &format!("FORWARD -i {veth} -j DROP"))?;
+    delete_rule(ex, Binary::Ip6tables, &format!("FORWARD -o {veth} -j DROP"))
+}
+
+/// The veth gets its own zone, and a policy from that zone to the uplink's zone does the NAT
+fn firewalld_names(net: &Network) -> (String, String) {
+    (net.host_iface.clone(), format!("{}-out", net.host_iface))
+}
+
+fn firewall_cmd(ex: &dyn Executor, args: &[&str]) -> Result<String> {
+    let out = ex.output(ex.command(Binary::FirewallCmd)?.args(args))?;
+    if !out.status.success() {
+        bail!(
+            "firewall-cmd {} returned {}\nstderr: {}",
+            args.join(" "),
+            out.status,
+            String::from_utf8_lossy(&out.stderr),
+        )
+    }
+    Ok(String::from_utf8_lossy(&out.stdout).trim().to_owned())
+}
+
+fn firewalld_has(ex: &dyn Executor, list_arg: &str, name: &str) -> Result<bool> {
+    let names = firewall_cmd(ex, &["--permanent", list_arg])?;
+    Ok(names.split_whitespace().any(|n| n == name))
+}
+
+fn firewalld_add_forward(ex: &dyn Executor, net: &Network, uplinks: &[String]) -> Result<()> {
+    let (zone, policy) = firewalld_names(net);
+    if firewalld_has(ex, "--get-policies", &policy)? {
+        debug!("firewalld policy {policy} already exists, keeping it");
+        return Ok(());
+    }
+
+    let mut uplink_zones = Vec::new();
+    for uplink in uplinks {
+        let uplink_zone = match firewall_cmd(ex, &[&format!("--get-zone-of-interface={uplink}")]) {
+            Ok(uplink_zone) => uplink_zone,
+            Err(_) => firewall_cmd(ex, &["--get-default-zone"])?,
+        };
+        if !uplink_zones.contains(&uplink_zone) {
+            uplink_zones.push(uplink_zone);
+        }
+    }
+    debug!(
+        "Adding firewalld zone {zone} and policy {policy} towards zones {}",
+        uplink_zones.join(", ")
+    );
+
+    if !firewalld_has(ex, "--get-zones", &zone)? {
+        firewall_cmd(ex, &["--permanent", &format!("--new-zone={zone}")])?;
+    }
+    firewall_cmd(
+        ex,
+        &[
+            "--permanent",
+            &format!("--zone={zone}"),
+            &format!("--add-interface={}", net.host_iface),
+        ],
+    )?;
+    firewall_cmd(ex, &["--permanent", &format!("--new-policy={policy}")])?;
+    let policy_arg = format!("--policy={policy}");
+    let egress = uplink_zones
+        .iter()
+        .map(|uplink_zone| format!("--add-egress-zone={uplink_zone}"));
+    for setting in std::iter::once(format!("--add-ingress-zone={zone}"))
+        .chain(egress)
+        .chain([
+            "--set-target=ACCEPT".to_owned(),
+            "--add-masquerade".to_owned(),
+        ])
+    {
+        firewall_cmd(ex, &["--permanent", &policy_arg, &setting])?;
+    }
+    firewall_cmd(ex, &["--reload"])?;
+    Ok(())
+}
+
+fn firewalld_remove_forward(ex: &dyn Executor, net: &Network) -> Result<()> {
+    let (zone, policy) = firewalld_names(net);
+    let has_policy = firewalld_has(ex, "--get-policies", &policy)?;
+    let has_zone = firewalld_has(ex, "--get-zones", &zone)?;
+    if has_policy {
+        debug!("Deleting firewalld policy {policy}");
+        firewall_cmd(ex, &["--permanent", &format!("--delete-policy={policy}")])?;
+    }
+    if has_zone {
+        debug!("Deleting firewalld zone {zone}");
+        firewall_cmd(ex, &["--permanent", &format!("--delete-zone={zone}")])?;
+    }
+    if has_policy || has_zone {
+        firewall_cmd(ex, &["--reload"])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::{MockExecutor, Reply};
+    use crate::test_utils::TempDir;
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    fn uplink() -> Vec<String> {
+        vec!["eth0".to_owned()]
+    }
+
+    fn iptables() -> FirewallConfig {
+        FirewallConfig {
+            backend: Backend::Iptables,
+            ..Default::default()
+        }
+    }
+
+    fn is_check(argv: &[String]) -> bool {
+        argv.iter().any(|arg| arg == "-C")
+    }
+
+    /// What was added, leaving out the checks for whether it was already there
+    fn added(ex: &MockExecutor) -> Vec<String> {
+        ex.commands()
+            .into_iter()
+            .filter(|c| !c.contains(" -C "))
+            .collect()
+    }
+
+    #[test]
+    fn iptables_forward_rules() {
+        let ex = MockExecutor::without_rules();
+        let config = FirewallConfig {
+            inbound: vec!["8080".parse().unwrap()],
+            ..iptables()
+        };
+        add_forward(
+            &ex,
+            &config,
+            &NatConfig::default(),
+            &Network::default(),
+            &uplink(),
+        )
+        .unwrap();
+        assert_eq!(
+            added(&ex),
+            [
+                "iptables -S FORWARD",
+                "iptables -t nat -A POSTROUTING -s 10.200.0.2/24 -o eth0 -j MASQUERADE",
+                "iptables -A FORWARD -i eth0 -o veth-warp -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT",
+                "iptables -A FORWARD -i eth0 -o veth-warp -d 10.200.0.2 -p tcp --dport 8080 -m conntrack --ctstate NEW -j ACCEPT",
+                "iptables -A FORWARD -o eth0 -i veth-warp -j ACCEPT",
+            ]
+        );
+    }
+
+    #[test]
+    fn restores_missing_forward_rules() {
+        // Someone flushed the filter table, the NAT rule is still there
+        let ex = MockExecutor::with_handler(|argv| match is_check(argv) && argv[1] != "-t" {
+            true => Reply::fail(),
+            false => Reply::ok(""),
+        });
+        add_forward(
+            &ex,
+            &iptables(),
+            &NatConfig::default(),
+            &Network::default(),
+            &uplink(),
+        )
+        .unwrap();
+        assert_eq!(
+            added(&ex)[1..],
+            [
+                "iptables -A FORWARD -i eth0 -o veth-warp -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT",
+                "iptables -A FORWARD -o eth0 -i veth-warp -j ACCEPT",
+            ]
+        );
+    }
+
+    #[test]
+    fn snat_to_configured_address() {
+        let ex = MockExecutor::without_rules();
+        let nat = NatConfig {
+            snat_to: Some("203.0.113.7".parse().unwrap()),
+        };
+        add_forward(&ex, &iptables(), &nat, &Network::default(), &uplink()).unwrap();
+        assert_eq!(
+            added(&ex)[1],
+            "iptables -t nat -A POSTROUTING -s 10.200.0.2/24 -o eth0 -j SNAT --to-source 203.0.113.7"
+        );
+        let ex = MockExecutor::with_handler(|argv| match argv.get(3).map(String::as_str) {
+            Some("-S") => Reply::ok(
+                "-P POSTROUTING ACCEPT\n\
+                 -A POSTROUTING -s 10.200.0.0/24 -o eth0 -j SNAT --to-source 203.0.113.7\n",
+            ),
+            _ => Reply::fail(),
+        });
+        remove_forward(&ex, &Network::default(), "eth0").unwrap();
+        assert_eq!(
+            ex.commands()[2],
+            "iptables -D POSTROUTING -s 10.200.0.0/24 -o eth0 -j SNAT --to-source 203.0.113.7 -t nat"
+        );
+    }
+
+    #[test]
+    fn removes_inbound_forward_rules() {
+        let ex = MockExecutor::with_handler(|argv| {
+            match argv[1].as_str() {
+            "-S" => Reply::ok(
+                "-P FORWARD ACCEPT\n-A FORWARD -i eth0 -o veth-warp -j ACCEPT\n\
+                 -A FORWARD -i eth0 -o veth-warp -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT\n\
+                 -A FORWARD -i eth1 -o veth-other -j ACCEPT\n",
+            ),
+            _ => Reply::fail(),
+        }
+        });
+        remove_forward(&ex, &Network::default(), "eth0").unwrap();
+        assert_eq!(
+            ex.commands()[2..6],
+            [
+                "iptables -S FORWARD",
+                "iptables -D FORWARD -i eth0 -o veth-warp -j ACCEPT",
+                "iptables -D FORWARD -i eth0 -o veth-warp -m conntrack --ctstate RELATED,ESTABLISHED -j ACCEPT",
+                "iptables -D FORWARD -o eth0 -i veth-warp -j ACCEPT",
+            ]
+        );
+    }
+
+    /// Docker's DOCKER-USER/DOCKER-FORWARD chains and its DROP policy would eat our forwarded
+    /// traffic if our rules were appended after them.
+    #[test]
+    fn forward_rules_go_before_docker() {
+        let ex = MockExecutor::with_handler(|argv| {
+            if argv[1] == "-S" {
+                Reply::ok(
+                    "-P FORWARD DROP\n-A FORWARD -j DOCKER-USER\n-A FORWARD -j DOCKER-FORWARD\n",
+                )
+            } else if is_check(argv) {
+                Reply::fail()
+            } else {
+                Reply::ok("")
+            }
+        });
+        add_forward(
+            &ex,
+            &iptables(),
+            &NatConfig::default(),
+            &Network::default(),
+            &uplink(),
+        )
+        .unwrap();
+        assert_eq!(
+            added(&ex)[2..],
+            [
+                "iptables -I FORWARD 1 -i eth0 -o veth-warp -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT",
+                "iptables -I FORWARD 1 -o eth0 -i veth-warp -j ACCEPT",
+            ]
+        );
+    }
+
+    /// libvirt's LIBVIRT_FW* chains reject anything not going to or from its own networks
+    #[test]
+    fn forward_rules_go_before_libvirt() {
+        let ex = MockExecutor::with_handler(|argv| {
+            if argv[1] == "-S" {
+                Reply::ok(
+                    "-P FORWARD ACCEPT\n-A FORWARD -j LIBVIRT_FWX\n-A FORWARD -j LIBVIRT_FWI\n\
+                     -A FORWARD -j LIBVIRT_FWO\n",
+                )
+            } else if is_check(argv) {
+                Reply::fail()
+            } else {
+                Reply::ok("")
+            }
+        });
+        add_forward(
+            &ex,
+            &iptables(),
+            &NatConfig::default(),
+            &Network::default(),
+            &uplink(),
+        )
+        .unwrap();
+        assert!(added(&ex)[2..]
+            .iter()
+            .all(|c| c.starts_with("iptables -I FORWARD 1 ")));
+    }
+
+    #[test]
+    fn unrelated_forward_rules_are_appended_after() {
+        let ex = MockExecutor::with_handler(|argv| {
+            if argv[1] == "-S" {
+                Reply::ok("-P FORWARD ACCEPT\n-A FORWARD -i br0 -j DOCKERISH-ACCEPT\n")
+            } else if is_check(argv) {
+                Reply::fail()
+            } else {
+                Reply::ok("")
+            }
+        });
+        add_forward(
+            &ex,
+            &iptables(),
+            &NatConfig::default(),
+            &Network::default(),
+            &uplink(),
+        )
+        .unwrap();
+        assert!(added(&ex)[2..]
+            .iter()
+            .all(|c| c.starts_with("iptables -A FORWARD ")));
+    }
+
+    #[test]
+    fn ipv6_leak_rules() {
+        let ex = MockExecutor::without_rules();
+        block_ipv6_leaks(&ex, Path::new("/base"), &Network::default()).unwrap();
+        let added: Vec<String> = ex
+            .commands()
+            .into_iter()
+            .filter(|c| !c.contains(" -C "))
+            .collect();
+        assert_eq!(
+            added,
+            [
+                "nsenter --net=/base/net ip6tables -A OUTPUT -o lo -j ACCEPT",
+                "nsenter --net=/base/net ip6tables -A OUTPUT -o CloudflareWARP -j ACCEPT",
+                "nsenter --net=/base/net ip6tables -A OUTPUT -j DROP",
+                "nsenter --net=/base/net ip6tables -A FORWARD -o CloudflareWARP -j ACCEPT",
+                "nsenter --net=/base/net ip6tables -A FORWARD -j DROP",
+                "ip6tables -I FORWARD -i veth-warp -j DROP",
+                "ip6tables -I FORWARD -o veth-warp -j DROP",
+            ]
+        );
+    }
+
+    #[test]
+    fn delete_iptables_rule_removes_all_duplicates() {
+        let remaining = AtomicU32::new(3);
+        let ex = MockExecutor::with_handler(move |_| {
+            if remaining.load(Ordering::Relaxed) == 0 {
+                return Reply::fail();
+            }
+            remaining.fetch_sub(1, Ordering::Relaxed);
+            Reply::ok("")
+        });
+        delete_iptables_rule(&ex, "FORWARD -i eth0 -o veth-warp -j ACCEPT").unwrap();
+        let cmds = ex.commands();
+        assert_eq!(cmds.len(), 4);
+        assert!(cmds
+            .iter()
+            .all(|c| c == "iptables -D FORWARD -i eth0 -o veth-warp -j ACCEPT"));
+    }
+
+    #[test]
+    fn auto_backend_prefers_running_firewalld() {
+        let ex = MockExecutor::new();
+        assert_eq!(resolve_backend(&ex, Backend::Auto), Backend::Firewalld);
+        let ex = MockExecutor::with_handler(|_| Reply::fail());
+        assert_eq!(resolve_backend(&ex, Backend::Auto), Backend::Iptables);
+    }
+
+    #[test]
+    fn firewalld_forward_policy() {
+        let ex = MockExecutor::with_handler(|argv| match argv[1].as_str() {
+            "--get-zone-of-interface=eth0" => Reply::ok("public\n"),
+            "--permanent" if argv[2] == "--get-zones" => Reply::ok("block dmz public\n"),
+            _ => Reply::ok(""),
+        });
+        let config = FirewallConfig {
+            backend: Backend::Firewalld,
+            ..Default::default()
+        };
+        add_forward(
+            &ex,
+            &config,
+            &NatConfig::default(),
+            &Network::default(),
+            &uplink(),
+        )
+        .unwrap();
+        assert_eq!(
+            ex.commands(),
+            [
+                "firewall-cmd --permanent --get-policies",
+                "firewall-cmd --get-zone-of-interface=eth0",
+                "firewall-cmd --permanent --get-zones",
+                "firewall-cmd --permanent --new-zone=veth-warp",
+                "firewall-cmd --permanent --zone=veth-warp --add-interface=veth-warp",
+                "firewall-cmd --permanent --new-policy=veth-warp-out",
+                "firewall-cmd --permanent --policy=veth-warp-out --add-ingress-zone=veth-warp",
+                "firewall-cmd --permanent --policy=veth-warp-out --add-egress-zone=public",
+                "firewall-cmd --permanent --policy=veth-warp-out --set-target=ACCEPT",
+                "firewall-cmd --permanent --policy=veth-warp-out --add-masquerade",
+                "firewall-cmd --reload",
+            ]
+        );
+    }
+
+    #[test]
+    fn closes_recorded_openings() {
+        let dir = TempDir::new("firewall");
+        let config: FirewallConfig =
+            toml::from_str("backend = \"iptables\"\nopen = [\"1080\", \"53/udp\"]").unwrap();
+        let ex = MockExecutor::without_rules();
+        open_configured(&ex, &dir, &config).unwrap();
+        assert_eq!(
+            ex.commands()[1],
+            "iptables -I INPUT -p tcp --dport 1080 -j ACCEPT"
+        );
+
+        let ex = MockExecutor::with_handler(|_| Reply::fail());
+        close_recorded(&ex, &dir).unwrap();
+        assert_eq!(
+            ex.commands()[..2],
+            [
+                "iptables -D INPUT -p tcp --dport 1080 -j ACCEPT",
+                "firewall-cmd --state",
+            ]
+        );
+        assert!(!openings_path(&dir).exists());
+        assert!("53/sctp".parse::<Opening>().is_err());
+    }
+}
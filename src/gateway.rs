@@ -0,0 +1,544 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::firewall;
+use crate::namespace::{mount_point, run_inside_namespace, Type};
+use crate::net::{self, default_route_iface_name, Network};
+use crate::paths::Binary;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::net::{Ipv4Addr, UdpSocket};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::{debug, info};
+
+/// The `[gateway]` section of the config file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct GatewayConfig {
+    pub enabled: bool,
+    pub iface: String,
+    /// Pinned UDP port, otherwise the first free one from 51820 is picked and kept for the container
+    pub listen_port: Option<u16>,
+    /// Our address on the WireGuard subnet, peers get the following ones
+    pub address: Ipv4Addr,
+    pub prefix_len: u8,
+    /// What peers connect to, defaults to our address on the uplink interface
+    pub endpoint: Option<String>,
+    /// Handed to peers, WARP's own resolver is only reachable from inside the container
+    pub dns: String,
+}
+
+impl Default for GatewayConfig {
+    fn default() -> Self {
+        GatewayConfig {
+            enabled: false,
+            iface: "wg-warp".to_owned(),
+            listen_port: None,
+            address: Ipv4Addr::new(10, 210, 0, 1),
+            prefix_len: 24,
+            endpoint: None,
+            dns: "1.1.1.1".to_owned(),
+        }
+    }
+}
+
+impl GatewayConfig {
+    fn netmask(&self) -> u32 {
+        net::netmask(self.prefix_len)
+    }
+
+    fn subnet(&self) -> String {
+        net::subnet(self.address, self.prefix_len)
+    }
+
+    /// Refuses a subnet without room for a peer, or one the host would route to the veth too
+    pub fn check(&self, net: &Network) -> Result<()> {
+        if !self.enabled {
+            return Ok(());
+        }
+        if self.prefix_len > 30 {
+            bail!(
+                "gateway.prefix-len is {}, the WireGuard subnet needs room for a peer so /30 at most",
+                self.prefix_len
+            )
+        }
+        let shortest = self.prefix_len.min(net.prefix_len);
+        let mask = net::netmask(shortest);
+        if u32::from(self.address) & mask == u32::from(net.host_addr) & mask {
+            bail!(
+                "The gateway's subnet {} overlaps the container's {}",
+                self.subnet(),
+                net.subnet()
+            )
+        }
+        Ok(())
+    }
+}
+
+struct Peer {
+    name: String,
+    public_key: String,
+    address: Ipv4Addr,
+}
+
+/// Where automatic port allocation starts, WireGuard's usual port
+const FIRST_PORT: u16 = 51820;
+
+fn gateway_dir(base_dir: &Path) -> PathBuf {
+    base_dir.join("gateway")
+}
+
+/// The port allocated to this container, so several containers don't fight over one
+fn port_path(base_dir: &Path) -> PathBuf {
+    gateway_dir(base_dir).join("port")
+}
+
+fn recorded_port(base_dir: &Path) -> Result<Option<u16>> {
+    match std::fs::read_to_string(port_path(base_dir)) {
+        Ok(port) => Ok(Some(port.trim().parse().with_context(|| {
+            format!("Invalid port in {}", port_path(base_dir).display())
+        })?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The port setup opened in the host's firewall, for down to close whatever the config says then
+fn opened_port_path(base_dir: &Path) -> PathBuf {
+    gateway_dir(base_dir).join("opened-port")
+}
+
+/// The first port from `first` nothing on the host listens on yet
+fn free_udp_port(first: u16) -> Result<u16> {
+    (first..=u16::MAX)
+        .find(|&port| UdpSocket::bind((Ipv4Addr::UNSPECIFIED, port)).is_ok())
+        .ok_or_else(|| anyhow!("No free UDP port from {first}"))
+}
+
+/// The pinned port, or the one allocated to this container, allocating it the first time
+pub fn listen_port(config: &Config) -> Result<u16> {
+    let base_dir = &config.base_dir;
+    if let Some(port) = config.gateway.listen_port {
+        return Ok(port);
+    }
+    if let Some(port) = recorded_port(base_dir)? {
+        return Ok(port);
+    }
+    let port = free_udp_port(FIRST_PORT)?;
+    debug!("Allocated UDP port {port} for the WireGuard gateway");
+    std::fs::create_dir_all(gateway_dir(base_dir))?;
+    std::fs::write(port_path(base_dir), format!("{port}\n"))?;
+    Ok(port)
+}
+
+fn server_key_path(base_dir: &Path) -> PathBuf {
+    gateway_dir(base_dir).join("server.key")
+}
+
+/// One peer per line, as "name public-key address"
+fn peers_path(base_dir: &Path) -> PathBuf {
+    gateway_dir(base_dir).join("peers")
+}
+
+fn read_peers(base_dir: &Path) -> Result<Vec<Peer>> {
+    let data = match std::fs::read_to_string(peers_path(base_dir)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    data.lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(|line| {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let [name, public_key, address] = parts[..] else {
+                bail!("Invalid line in {}: {line}", peers_path(base_dir).display())
+            };
+            Ok(Peer {
+                name: name.to_owned(),
+                public_key: public_key.to_owned(),
+                address: address.parse()?,
+            })
+        })
+        .collect()
+}
+
+fn write_peers(base_dir: &Path, peers: &[Peer]) -> Result<()> {
+    let data: String = peers
+        .iter()
+        .map(|p| format!("{} {} {}\n", p.name, p.public_key, p.address))
+        .collect();
+    std::fs::create_dir_all(gateway_dir(base_dir))?;
+    std::fs::write(peers_path(base_dir), data)?;
+    Ok(())
+}
+
+fn write_secret(path: &Path, data: &str) -> Result<()> {
+    let mut f = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?;
+    f.write_all(data.as_bytes())?;
+    Ok(())
+}
+
+fn run_with_stdin(ex: &dyn Executor, cmd: &mut Command, input: &str) -> Result<String> {
+    let mut child = ex.spawn(
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit()),
+    )?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input.as_bytes())?;
+    }
+    let out = child.wait_with_output()?;
+    out.status.exit_ok()?;
+    Ok(String::from_utf8(out.stdout)?)
+}
+
+fn gen_private_key(ex: &dyn Executor) -> Result<String> {
+    let out = ex.output(ex.command(Binary::Wg)?.arg("genkey"))?;
+    out.status.exit_ok()?;
+    Ok(String::from_utf8(out.stdout)?.trim().to_owned())
+}
+
+fn public_key(ex: &dyn Executor, private_key: &str) -> Result<String> {
+    let out = run_with_stdin(ex, ex.command(Binary::Wg)?.arg("pubkey"), private_key)?;
+    Ok(out.trim().to_owned())
+}
+
+fn ensure_server_key(ex: &dyn Executor, base_dir: &Path) -> Result<String> {
+    let path = server_key_path(base_dir);
+    if let Ok(key) = std::fs::read_to_string(&path) {
+        return Ok(key.trim().to_owned());
+    }
+    debug!("Generating WireGuard gateway key");
+    std::fs::create_dir_all(gateway_dir(base_dir))?;
+    let key = gen_private_key(ex)?;
+    write_secret(&path, &key)?;
+    Ok(key)
+}
+
+fn iface_is_up(ex: &dyn Executor, config: &Config) -> Result<bool> {
+    let Ok(out) = run_inside_namespace(
+        ex,
+        &config.base_dir,
+        Type::Net,
+        ex.command(Binary::Ip)?
+            .args(["-o", "link", "show", "dev", &config.gateway.iface]),
+    ) else {
+        return Ok(false);
+    };
+    Ok(net::link_is_up(&String::from_utf8_lossy(&out.stdout)))
+}
+
+/// Creates the WireGuard interface on the host, so it listens on the host's network, then moves it
+/// into the container where the traffic it decrypts gets routed into the WARP tunnel.
+pub fn setup(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let gw = &config.gateway;
+    let base_dir = &config.base_dir;
+    if iface_is_up(ex, config)? {
+        debug!("{} already exists in the container, keeping it", gw.iface);
+        return Ok(());
+    }
+
+    ensure_server_key(ex, base_dir)?;
+    debug!("Creating WireGuard gateway interface {}", gw.iface);
+    ex.status(
+        ex.command(Binary::Ip)?
+            .args(["link", "add", &gw.iface, "type", "wireguard"]),
+    )?
+    .exit_ok()?;
+    if let Err(e) = configure_iface(ex, config) {
+        let _ = ex.status(ex.command(Binary::Ip)?.args(["link", "delete", &gw.iface]));
+        return Err(e);
+    }
+
+    let in_ns = |cmd: &mut Command| run_inside_namespace(ex, base_dir, Type::Net, cmd);
+    let addr = format!("{}/{}", gw.address, gw.prefix_len);
+    in_ns(
+        ex.command(Binary::Ip)?
+            .args(["addr", "add", &addr, "dev", &gw.iface]),
+    )?;
+    in_ns(
+        ex.command(Binary::Ip)?
+            .args(["link", "set", &gw.iface, "up"]),
+    )?;
+    in_ns(
+        ex.command(Binary::Sysctl)?
+            .args(["-w", "net.ipv4.ip_forward=1"]),
+    )?;
+    in_ns(
+        ex.command(Binary::Iptables)?
+            .args(["-t", "nat", "-A", "POSTROUTING", "-s", &gw.subnet()])
+            .args(["-o", "CloudflareWARP", "-j", "MASQUERADE"]),
+    )?;
+
+    let port = listen_port(config)?;
+    // Recorded first, so down closes it even if opening it fails halfway
+    std::fs::write(opened_port_path(base_dir), format!("{port}\n"))?;
+    firewall::open_port(ex, config.firewall.backend, "udp", port)?;
+    info!("WireGuard gateway listening on port {port}, add peers with the wg add-peer command");
+    Ok(())
+}
+
+fn configure_iface(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let gw = &config.gateway;
+    ex.status(
+        ex.command(Binary::Wg)?
+            .args([
+                "set",
+                &gw.iface,
+                "listen-port",
+                &listen_port(config)?.to_string(),
+            ])
+            .arg("private-key")
+            .arg(server_key_path(&config.base_dir)),
+    )?
+    .exit_ok()?;
+    for peer in read_peers(&config.base_dir)? {
+        ex.status(
+            ex.command(Binary::Wg)?
+                .args(["set", &gw.iface, "peer", &peer.public_key])
+                .args(["allowed-ips", &format!("{}/32", peer.address)]),
+        )?
+        .exit_ok()?;
+    }
+    let net_ns = mount_point(&config.base_dir, Type::Net);
+    ex.status(
+        ex.command(Binary::Ip)?
+            .args(["link", "set", &gw.iface, "netns"])
+            .arg(net_ns),
+    )?
+    .exit_ok()?;
+    Ok(())
+}
+
+/// The interface and its NAT rule go away with the container's network namespace
+pub fn teardown(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let path = opened_port_path(&config.base_dir);
+    let port = match std::fs::read_to_string(&path) {
+        Ok(port) => port
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid port in {}", path.display()))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    firewall::close_port(ex, "udp", port)?;
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!("Peer names can only contain letters, digits, '-' and '_'")
+    }
+    Ok(())
+}
+
+fn allocate_address(gw: &GatewayConfig, peers: &[Peer]) -> Result<Ipv4Addr> {
+    let net = u32::from(gw.address) & gw.netmask();
+    let broadcast = net | !gw.netmask();
+    (net + 1..broadcast)
+        .map(Ipv4Addr::from)
+        .find(|addr| *addr != gw.address && !peers.iter().any(|p| p.address == *addr))
+        .ok_or_else(|| anyhow!("No free address left in {}", gw.subnet()))
+}
+
+fn default_endpoint(ex: &dyn Executor, config: &Config) -> Result<String> {
+    let uplink = default_route_iface_name(ex)?;
+    let addr = nix::ifaddrs::getifaddrs()?
+        .filter(|dev| dev.interface_name == uplink)
+        .find_map(|dev| {
+            dev.address?
+                .as_sockaddr_in()
+                .map(|a| Ipv4Addr::from(a.ip()))
+        })
+        .ok_or_else(|| {
+            anyhow!("{uplink} has no IPv4 address, set gateway.endpoint in the config file")
+        })?;
+    Ok(format!("{}:{}", addr, listen_port(config)?))
+}
+
+pub fn add_peer(ex: &dyn Executor, config: &Config, name: &str, qr: bool) -> Result<()> {
+    validate_name(name)?;
+    let gw = &config.gateway;
+    let base_dir = &config.base_dir;
+    let mut peers = read_peers(base_dir)?;
+    if peers.iter().any(|p| p.name == name) {
+        bail!("There is already a peer named {name}")
+    }
+
+    let server_public_key = public_key(ex, &ensure_server_key(ex, base_dir)?)?;
+    let endpoint = match &gw.endpoint {
+        Some(endpoint) => endpoint.clone(),
+        None => default_endpoint(ex, config)?,
+    };
+    let private_key = gen_private_key(ex)?;
+    let peer = Peer {
+        name: name.to_owned(),
+        public_key: public_key(ex, &private_key)?,
+        address: allocate_address(gw, &peers)?,
+    };
+
+    if iface_is_up(ex, config)? {
+        run_inside_namespace(
+            ex,
+            base_dir,
+            Type::Net,
+            ex.command(Binary::Wg)?
+                .args(["set", &gw.iface, "peer", &peer.public_key])
+                .args(["allowed-ips", &format!("{}/32", peer.address)]),
+        )?;
+    }
+
+    let client_config = format!(
+        "[Interface]
+PrivateKey = {private_key}
+Address = {}/32
+DNS = {}
+
+[Peer]
+PublicKey = {server_public_key}
+Endpoint = {endpoint}
+AllowedIPs = 0.0.0.0/0
+PersistentKeepalive = 25
+",
+        peer.address, gw.dns
+    );
+    peers.push(peer);
+    write_peers(base_dir, &peers)?;
+
+    if qr {
+        let code = run_with_stdin(
+            ex,
+            ex.command(Binary::Qrencode)?.args(["-t", "ansiutf8"]),
+            &client_config,
+        )
+        .context("Rendering QR code")?;
+        print!("{code}");
+    } else {
+        print!("{client_config}");
+    }
+    Ok(())
+}
+
+pub fn remove_peer(ex: &dyn Executor, config: &Config, name: &str) -> Result<()> {
+    let gw = &config.gateway;
+    let mut peers = read_peers(&config.base_dir)?;
+    let Some(pos) = peers.iter().position(|p| p.name == name) else {
+        bail!("There is no peer named {name}")
+    };
+    let peer = peers.remove(pos);
+    if iface_is_up(ex, config)? {
+        run_inside_namespace(
+            ex,
+            &config.base_dir,
+            Type::Net,
+            ex.command(Binary::Wg)?
+                .args(["set", &gw.iface, "peer", &peer.public_key, "remove"]),
+        )?;
+    }
+    write_peers(&config.base_dir, &peers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::{MockExecutor, Reply};
+    use crate::test_utils::TempDir;
+
+    fn peer(address: &str) -> Peer {
+        Peer {
+            name: address.to_owned(),
+            public_key: String::new(),
+            address: address.parse().unwrap(),
+        }
+    }
+
+    #[test]
+    fn allocates_first_free_address() {
+        let gw = GatewayConfig::default();
+        assert_eq!(gw.subnet(), "10.210.0.0/24");
+        let peers = [peer("10.210.0.2"), peer("10.210.0.4")];
+        assert_eq!(
+            allocate_address(&gw, &peers).unwrap(),
+            Ipv4Addr::new(10, 210, 0, 3)
+        );
+    }
+
+    #[test]
+    fn checks_subnet() {
+        let net = Network::default();
+        let mut gw = GatewayConfig {
+            enabled: true,
+            ..Default::default()
+        };
+        assert!(gw.check(&net).is_ok());
+        gw.address = Ipv4Addr::new(10, 200, 0, 9);
+        assert!(gw.check(&net).is_err());
+        gw.address = Ipv4Addr::new(10, 0, 0, 1);
+        gw.prefix_len = 8;
+        assert!(gw.check(&net).is_err());
+        gw.prefix_len = 31;
+        assert!(gw.check(&net).is_err());
+        gw.enabled = false;
+        assert!(gw.check(&net).is_ok());
+    }
+
+    #[test]
+    fn full_subnet_is_an_error() {
+        let gw = GatewayConfig {
+            prefix_len: 30,
+            ..Default::default()
+        };
+        assert!(allocate_address(&gw, &[peer("10.210.0.2")]).is_err());
+    }
+
+    #[test]
+    fn teardown_closes_the_opened_port() {
+        // Deleting the rule fails once it's gone, here right away
+        let ex = MockExecutor::with_handler(|_| Reply::fail());
+        let dir = TempDir::new("gateway-teardown");
+        let config = Config {
+            base_dir: dir.to_path_buf(),
+            ..Default::default()
+        };
+        teardown(&ex, &config).unwrap();
+        assert!(ex.commands().is_empty());
+        assert!(!port_path(&dir).exists());
+
+        std::fs::create_dir_all(gateway_dir(&dir)).unwrap();
+        std::fs::write(opened_port_path(&dir), "51821\n").unwrap();
+        // Closed even with the gateway disabled and another port pinned since
+        let config = Config {
+            gateway: GatewayConfig {
+                listen_port: Some(51900),
+                ..Default::default()
+            },
+            ..config
+        };
+        teardown(&ex, &config).unwrap();
+        assert!(ex
+            .commands()
+            .iter()
+            .any(|c| c.contains("INPUT -p udp --dport 51821 -j ACCEPT")));
+        assert!(!opened_port_path(&dir).exists());
+    }
+
+    #[test]
+    fn skips_ports_in_use() {
+        let taken = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert_ne!(free_udp_port(port).unwrap(), port);
+    }
+}
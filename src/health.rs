@@ -0,0 +1,272 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::paths::Binary;
+use crate::pause::warp_cli;
+use crate::readiness::wait_for_proxy;
+use crate::service;
+use crate::summary::warp_colo;
+use crate::up::{find_pid_ns_init_process, Parts};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+/// How long a client of the HTTP endpoints gets to send its request
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A check of one part of a running container, from its processes to traffic through WARP
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Probe {
+    /// The container's init, which holds it together
+    Init,
+    WarpSvc,
+    /// warp-svc reports its tunnel as connected
+    Tunnel,
+    /// The proxy answers a SOCKS5 greeting
+    Socks,
+    /// Cloudflare sees a request through the proxy coming from WARP
+    Trace,
+}
+
+impl Probe {
+    pub fn name(self) -> &'static str {
+        match self {
+            Probe::Init => "init",
+            Probe::WarpSvc => "warp-svc",
+            Probe::Tunnel => "tunnel",
+            Probe::Socks => "socks",
+            Probe::Trace => "trace",
+        }
+    }
+
+    fn needs_proxy(self) -> bool {
+        matches!(self, Probe::Socks | Probe::Trace)
+    }
+}
+
+/// The `[health]` section of the config file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct HealthConfig {
+    /// Which probes to run, in order
+    pub probes: Vec<Probe>,
+    /// Where watch answers /healthz and /readyz over HTTP, for supervisors that probe that way
+    pub listen: Option<SocketAddr>,
+}
+
+impl Default for HealthConfig {
+    fn default() -> Self {
+        HealthConfig {
+            probes: vec![
+                Probe::Init,
+                Probe::WarpSvc,
+                Probe::Tunnel,
+                Probe::Socks,
+                Probe::Trace,
+            ],
+            listen: None,
+        }
+    }
+}
+
+fn run(ex: &dyn Executor, config: &Config, probe: Probe) -> Result<()> {
+    let base_dir = &config.base_dir;
+    match probe {
+        Probe::Init => {
+            if find_pid_ns_init_process(base_dir)?.is_none() {
+                bail!("not running")
+            }
+        }
+        Probe::WarpSvc => {
+            if service::running(base_dir, Binary::WarpSvc.name())?.is_none() {
+                bail!("not running")
+            }
+        }
+        Probe::Tunnel => {
+            let status = warp_cli(ex, base_dir, &["status"])?;
+            if !status.contains("Connected") {
+                bail!("{}", status.trim())
+            }
+        }
+        // Once, the caller decides how long to keep trying
+        Probe::Socks => wait_for_proxy(&config.network, Duration::ZERO)?,
+        Probe::Trace => {
+            if warp_colo(ex, config)?.is_none() {
+                bail!("traffic through the proxy doesn't go through WARP")
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Runs the configured probes. Those for parts the container was brought up without are skipped.
+pub fn check(ex: &dyn Executor, config: &Config) -> Result<Vec<(Probe, Option<Result<()>>)>> {
+    let parts = Parts::load(&config.base_dir)?;
+    Ok(config
+        .health
+        .probes
+        .iter()
+        .map(|&probe| {
+            let skip = probe.needs_proxy() && !parts.proxy;
+            (probe, (!skip).then(|| run(ex, config, probe)))
+        })
+        .collect())
+}
+
+fn result_line(probe: Probe, result: &Option<Result<()>>) -> String {
+    match result {
+        Some(Ok(())) => format!("[PASS] {}", probe.name()),
+        Some(Err(e)) => format!("[FAIL] {}: {e:#}", probe.name()),
+        None => format!("[SKIP] {}: not part of this container", probe.name()),
+    }
+}
+
+/// Prints each probe's result, and fails if any probe did
+pub fn health(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let results = check(ex, config)?;
+    let mut failures = 0;
+    for (probe, result) in &results {
+        failures += matches!(result, Some(Err(_))) as usize;
+        println!("{}", result_line(*probe, result));
+    }
+    if failures > 0 {
+        bail!("{failures} of {} health probes failed", results.len());
+    }
+    Ok(())
+}
+
+/// The watchdog's latest check, which the HTTP endpoints answer from
+#[derive(Debug, Default)]
+pub struct Latest {
+    at: Option<Instant>,
+    failed: Vec<Probe>,
+    report: String,
+}
+
+impl Latest {
+    pub fn record(&mut self, results: &[(Probe, Option<Result<()>>)]) {
+        self.at = Some(Instant::now());
+        self.failed = results
+            .iter()
+            .filter(|(_, result)| matches!(result, Some(Err(_))))
+            .map(|&(probe, _)| probe)
+            .collect();
+        self.report = results
+            .iter()
+            .map(|(probe, result)| result_line(*probe, result) + "\n")
+            .collect();
+    }
+
+    /// The watchdog still checks, and the container's processes run. A stuck tunnel is for the
+    /// watchdog to recover, not a reason to kill it.
+    fn is_live(&self, max_age: Duration) -> bool {
+        self.at.is_some_and(|at| at.elapsed() <= max_age)
+            && !self
+                .failed
+                .iter()
+                .any(|probe| matches!(probe, Probe::Init | Probe::WarpSvc))
+    }
+
+    fn is_ready(&self) -> bool {
+        self.at.is_some() && self.failed.is_empty()
+    }
+
+    /// The status line and body for a request's path
+    fn respond(&self, path: &str, max_age: Duration) -> (&'static str, String) {
+        let ok = match path.split('?').next() {
+            Some("/healthz") => self.is_live(max_age),
+            Some("/readyz") => self.is_ready(),
+            _ => return ("404 Not Found", "Not found\n".to_owned()),
+        };
+        let report = match self.at {
+            Some(_) => self.report.clone(),
+            None => "Not checked yet\n".to_owned(),
+        };
+        (
+            if ok {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            },
+            report,
+        )
+    }
+}
+
+fn answer(mut stream: TcpStream, latest: &Mutex<Latest>, max_age: Duration) -> Result<()> {
+    stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+    let mut request = [0; 1024];
+    let len = stream.read(&mut request)?;
+    let request = String::from_utf8_lossy(&request[..len]);
+    let path = request.split_whitespace().nth(1).unwrap_or_default();
+    let (status, body) = latest.lock().unwrap().respond(path, max_age);
+    write!(
+        stream,
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    Ok(())
+}
+
+/// Answers /healthz and /readyz on a thread of its own, from what the watchdog last recorded.
+/// The latest check counts as stuck once older than max_age.
+pub fn serve(listen: SocketAddr, latest: Arc<Mutex<Latest>>, max_age: Duration) -> Result<()> {
+    let listener = TcpListener::bind(listen).with_context(|| format!("Listening on {listen}"))?;
+    info!("Answering /healthz and /readyz on {listen}");
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let answered = stream
+                .map_err(anyhow::Error::from)
+                .and_then(|stream| answer(stream, &latest, max_age));
+            if let Err(e) = answered {
+                debug!("Health request failed: {e:#}");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn configured_probes() {
+        let health: HealthConfig = toml::from_str("probes = [\"warp-svc\", \"socks\"]").unwrap();
+        assert_eq!(health.probes, [Probe::WarpSvc, Probe::Socks]);
+        assert!(toml::from_str::<HealthConfig>("probes = [\"ping\"]").is_err());
+    }
+
+    #[test]
+    fn answers_probes() {
+        let max_age = Duration::from_secs(90);
+        let mut latest = Latest::default();
+        assert_eq!(
+            latest.respond("/healthz", max_age).0,
+            "503 Service Unavailable"
+        );
+        latest.record(&[
+            (Probe::Init, Some(Ok(()))),
+            (Probe::Tunnel, Some(Err(anyhow::anyhow!("Disconnected")))),
+            (Probe::Socks, None),
+        ]);
+        assert_eq!(latest.respond("/healthz", max_age).0, "200 OK");
+        let (status, body) = latest.respond("/readyz?verbose", max_age);
+        assert_eq!(status, "503 Service Unavailable");
+        assert_eq!(
+            body,
+            "[PASS] init\n[FAIL] tunnel: Disconnected\n[SKIP] socks: not part of this container\n"
+        );
+        assert_eq!(latest.respond("/metrics", max_age).0, "404 Not Found");
+        latest.record(&[(Probe::WarpSvc, Some(Err(anyhow::anyhow!("not running"))))]);
+        assert_eq!(
+            latest.respond("/healthz", max_age).0,
+            "503 Service Unavailable"
+        );
+    }
+}
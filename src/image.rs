@@ -0,0 +1,105 @@
+use crate::exec::Executor;
+use crate::paths::Binary;
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, warn};
+
+/// An OCI layout holding every image the instance pulled. Layers are shared between tags,
+/// so pulling a new version of an image only downloads what changed.
+pub fn cache_dir(base_dir: &Path) -> PathBuf {
+    base_dir.join("images")
+}
+
+/// OCI layout tags only allow a restricted character set
+fn tag(reference: &str) -> String {
+    reference
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '_' | '-' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// The digest a reference like `registry/name@sha256:<hex>` pins
+fn pinned_digest(reference: &str) -> Option<&str> {
+    let (_, digest) = reference.rsplit_once('@')?;
+    digest.starts_with("sha256:").then_some(digest)
+}
+
+fn layout(base_dir: &Path, reference: &str) -> String {
+    format!("{}:{}", cache_dir(base_dir).display(), tag(reference))
+}
+
+/// The manifest digest of the cached image, if it was pulled before
+fn cached_digest(ex: &dyn Executor, base_dir: &Path, reference: &str) -> Result<Option<String>> {
+    let out = ex.output(
+        ex.command(Binary::Skopeo)?
+            .args(["inspect", "--format", "{{.Digest}}"])
+            .arg(format!("oci:{}", layout(base_dir, reference))),
+    )?;
+    if !out.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(String::from_utf8(out.stdout)?.trim().to_owned()))
+}
+
+/// Makes sure the image is in the cache. Pinned images are only downloaded once,
+/// skopeo checks what it downloads against the pinned digest.
+pub fn pull(ex: &dyn Executor, base_dir: &Path, reference: &str) -> Result<()> {
+    let pinned = pinned_digest(reference);
+    let cached = cached_digest(ex, base_dir, reference)?;
+    if pinned.is_some() && cached.as_deref() == pinned {
+        debug!("{reference} is already cached");
+        return Ok(());
+    }
+    if pinned.is_none() {
+        warn!("{reference} isn't pinned to a digest, it may change under us between pulls");
+    }
+
+    info!("Pulling {reference}");
+    std::fs::create_dir_all(cache_dir(base_dir))?;
+    ex.status(
+        ex.command(Binary::Skopeo)?
+            .args(["copy", "--quiet"])
+            .arg(format!("docker://{reference}"))
+            .arg(format!("oci:{}", layout(base_dir, reference))),
+    )?
+    .exit_ok()
+    .with_context(|| format!("Pulling {reference}"))?;
+
+    let Some(digest) = cached_digest(ex, base_dir, reference)? else {
+        bail!("{reference} is missing from the image cache after pulling it")
+    };
+    if pinned.is_none() {
+        info!("Pulled {reference} at {digest}, append @{digest} to container.image to pin it");
+    }
+    Ok(())
+}
+
+/// Applies the image's layers to a fresh directory, which must not exist yet
+pub fn unpack(ex: &dyn Executor, base_dir: &Path, reference: &str, dest: &Path) -> Result<()> {
+    debug!("Unpacking {reference} to {}", dest.display());
+    ex.status(
+        ex.command(Binary::Umoci)?
+            .args(["raw", "unpack", "--image"])
+            .arg(layout(base_dir, reference))
+            .arg(dest),
+    )?
+    .exit_ok()
+    .with_context(|| format!("Unpacking {reference}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn references() {
+        let pinned = "ghcr.io/me/warp@sha256:0123abcd";
+        assert_eq!(pinned_digest(pinned), Some("sha256:0123abcd"));
+        assert_eq!(tag(pinned), "ghcr.io_me_warp_sha256_0123abcd");
+        assert_eq!(pinned_digest("docker.io/me/warp:latest"), None);
+    }
+}
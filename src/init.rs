@@ -0,0 +1,110 @@
+use anyhow::Result;
+use nix::sys::signal::{kill, SigSet, Signal};
+use nix::sys::wait::{waitpid, WaitPidFlag, WaitStatus};
+use nix::unistd::Pid;
+use std::fs::File;
+use std::io::Write;
+use std::os::fd::{FromRawFd, RawFd};
+use std::path::{Path, PathBuf};
+
+/// Hidden subcommand that runs the init, which also tells it apart from other processes
+pub const MARKER: &str = "bubblewarp-init";
+
+/// Stop the container once passed on, like they would stop a regular init's child
+const STOP_SIGNALS: &[Signal] = &[Signal::SIGTERM, Signal::SIGINT, Signal::SIGHUP];
+
+/// Set in the init's environment to the ID of the instance it belongs to
+pub const ID_ENV: &str = "BUBBLEWARP_INIT_ID";
+
+/// Holds the write end of a pipe, closed after writing to it once the init runs
+pub const READY_FD_ENV: &str = "BUBBLEWARP_INIT_READY_FD";
+
+fn id_path(base_dir: &Path) -> PathBuf {
+    base_dir.join("init-id")
+}
+
+/// A fresh ID for a new container's init, stored in the base dir to find the init again later
+pub fn new_id(base_dir: &Path) -> Result<String> {
+    let id = std::fs::read_to_string("/proc/sys/kernel/random/uuid")?
+        .trim()
+        .to_owned();
+    std::fs::write(id_path(base_dir), &id)?;
+    Ok(id)
+}
+
+pub fn remove_id(base_dir: &Path) -> Result<()> {
+    match std::fs::remove_file(id_path(base_dir)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Whether a process of the container's PID namespace is its init.
+/// Matched by ID rather than by command line, which any process in there can imitate.
+pub fn is_init(base_dir: &Path, proc: &procfs::process::Process) -> bool {
+    let Ok(id) = std::fs::read_to_string(id_path(base_dir)) else {
+        return false;
+    };
+    let Ok(environ) = proc.environ() else {
+        return false;
+    };
+    environ
+        .get(std::ffi::OsStr::new(ID_ENV))
+        .is_some_and(|value| *value == *id.trim())
+}
+
+/// Whether a process is the tini that containers brought up before the built-in init still run.
+/// Those have no ID, so only look for it among the processes of the container's PID namespace.
+pub fn is_legacy_init(base_dir: &Path, proc: &procfs::process::Process) -> bool {
+    if id_path(base_dir).exists() {
+        return false;
+    }
+    proc.cmdline().is_ok_and(|cmdline| {
+        cmdline
+            .first()
+            .is_some_and(|arg| Path::new(arg).ends_with("tini"))
+    })
+}
+
+/// PID 1 of the container. Keeps the PID namespace alive, reaps the orphans reparented to it,
+/// and passes signals on to every other process in the container.
+pub fn run() -> ! {
+    let signals = SigSet::all();
+    signals
+        .thread_block()
+        .expect("Failed to block signals in the init process");
+    notify_ready();
+    loop {
+        let Ok(signal) = signals.wait() else { continue };
+        if signal == Signal::SIGCHLD {
+            reap();
+            continue;
+        }
+        // From PID 1, this reaches every other process in the PID namespace
+        let _ = kill(Pid::from_raw(-1), signal);
+        if STOP_SIGNALS.contains(&signal) {
+            std::process::exit(0);
+        }
+    }
+}
+
+fn notify_ready() {
+    let Some(fd) = std::env::var(READY_FD_ENV)
+        .ok()
+        .and_then(|fd| fd.parse::<RawFd>().ok())
+    else {
+        return;
+    };
+    // SAFETY: up passes us this fd for our own use
+    let mut ready = unsafe { File::from_raw_fd(fd) };
+    let _ = ready.write_all(b"1");
+}
+
+fn reap() {
+    loop {
+        match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::StillAlive) | Err(_) => return,
+            Ok(_) => continue,
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::firewall;
+use crate::logging::write_json_str;
+use crate::namespace::{self, all_ns_processes, mount_point, run_inside_namespace, Type};
+use crate::paths::Binary;
+use crate::pidfd::PidFd;
+use crate::rootfs;
+use crate::up::{etc_overlay_dir, find_pid_ns_init_process, warp_state_dir};
+use anyhow::{bail, Context, Result};
+use std::fmt::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use strum::IntoEnumIterator;
+
+/// Just enough JSON to print a document, without pulling in a serializer
+enum Json {
+    Null,
+    Bool(bool),
+    Number(u64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(&'static str, Json)>),
+    /// Already JSON, like what `ip -j` prints
+    Raw(String),
+}
+
+impl Json {
+    fn str(s: impl Into<String>) -> Json {
+        Json::String(s.into())
+    }
+
+    fn write(&self, out: &mut impl Write, indent: usize) -> fmt::Result {
+        let pad = "  ".repeat(indent + 1);
+        match self {
+            Json::Null => out.write_str("null"),
+            Json::Bool(b) => write!(out, "{b}"),
+            Json::Number(n) => write!(out, "{n}"),
+            Json::String(s) => write_json_str(out, s),
+            Json::Raw(raw) => out.write_str(raw.trim()),
+            Json::Array(items) if items.is_empty() => out.write_str("[]"),
+            Json::Array(items) => {
+                out.write_str("[\n")?;
+                for (i, item) in items.iter().enumerate() {
+                    out.write_str(&pad)?;
+                    item.write(out, indent + 1)?;
+                    out.write_str(if i + 1 < items.len() { ",\n" } else { "\n" })?;
+                }
+                write!(out, "{}]", "  ".repeat(indent))
+            }
+            Json::Object(members) if members.is_empty() => out.write_str("{}"),
+            Json::Object(members) => {
+                out.write_str("{\n")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    out.write_str(&pad)?;
+                    write_json_str(out, key)?;
+                    out.write_str(": ")?;
+                    value.write(out, indent + 1)?;
+                    out.write_str(if i + 1 < members.len() { ",\n" } else { "\n" })?;
+                }
+                write!(out, "{}}}", "  ".repeat(indent))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+/// A part of the document, which holds its error instead when it can't be told
+fn section(part: impl FnOnce() -> Result<Json>) -> Json {
+    part().unwrap_or_else(|e| Json::Object(vec![("error", Json::str(format!("{e:#}")))]))
+}
+
+fn namespaces(base_dir: &Path) -> Result<Json> {
+    let mounts = namespace::Mounts::snapshot()?;
+    Ok(Json::Array(
+        Type::iter()
+            .map(|ns_type| {
+                let path = mount_point(base_dir, ns_type);
+                let mounted = mounts.is_mounted(base_dir, ns_type);
+                let inode = match mounted {
+                    true => std::fs::metadata(&path).map_or(Json::Null, |m| Json::Number(m.ino())),
+                    false => Json::Null,
+                };
+                Json::Object(vec![
+                    ("type", Json::str(ns_type.to_string())),
+                    ("mount_point", Json::str(path.to_string_lossy())),
+                    ("mounted", Json::Bool(mounted)),
+                    ("inode", inode),
+                ])
+            })
+            .collect(),
+    ))
+}
+
+fn init(init: &procfs::process::Process) -> Result<Json> {
+    let pidfd = PidFd::open(init.pid)?;
+    let ns_pids = pidfd.ns_pids()?;
+    Ok(Json::Object(vec![
+        ("pid", Json::Number(init.pid as u64)),
+        ("start_time", Json::Number(init.stat()?.starttime)),
+        (
+            "pidfd_ns_pids",
+            Json::Array(
+                ns_pids
+                    .iter()
+                    .map(|&pid| Json::Number(pid as u64))
+                    .collect(),
+            ),
+        ),
+    ]))
+}
+
+fn processes(base_dir: &Path) -> Result<Json> {
+    Ok(Json::Array(
+        all_ns_processes(base_dir)?
+            .map(|proc| {
+                let cmdline = proc.cmdline().unwrap_or_default();
+                Json::Object(vec![
+                    ("pid", Json::Number(proc.pid as u64)),
+                    (
+                        "cmdline",
+                        Json::Array(cmdline.into_iter().map(Json::String).collect()),
+                    ),
+                ])
+            })
+            .collect(),
+    ))
+}
+
+fn mounts(init: &procfs::process::Process) -> Result<Json> {
+    Ok(Json::Array(
+        init.mountinfo()?
+            .into_iter()
+            .map(|mount| {
+                let mut options: Vec<String> = mount
+                    .mount_options
+                    .iter()
+                    .map(|(key, value)| match value {
+                        Some(value) => format!("{key}={value}"),
+                        None => key.clone(),
+                    })
+                    .collect();
+                options.sort();
+                Json::Object(vec![
+                    (
+                        "mount_point",
+                        Json::str(mount.mount_point.to_string_lossy()),
+                    ),
+                    ("fs_type", Json::str(mount.fs_type)),
+                    (
+                        "source",
+                        mount.mount_source.map_or(Json::Null, Json::String),
+                    ),
+                    ("root", Json::str(mount.root)),
+                    ("options", Json::str(options.join(","))),
+                ])
+            })
+            .collect(),
+    ))
+}
+
+/// What `ip -j` says about the container's network namespace
+fn ip_json(ex: &dyn Executor, base_dir: &Path, args: &[&str]) -> Result<Json> {
+    let out = run_inside_namespace(
+        ex,
+        base_dir,
+        Type::Net,
+        ex.command(Binary::Ip)?.arg("-j").args(args),
+    )?;
+    let out = String::from_utf8(out.stdout)?;
+    if !out.trim_start().starts_with('[') {
+        bail!("ip printed no JSON, it may be too old for -j")
+    }
+    Ok(Json::Raw(out))
+}
+
+/// Bytes taken by the files under a directory, without following symlinks
+pub fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    let mut pending = vec![dir.to_owned()];
+    while let Some(path) = pending.pop() {
+        let meta = path.symlink_metadata()?;
+        size += meta.len();
+        if meta.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                pending.push(entry?.path());
+            }
+        }
+    }
+    Ok(size)
+}
+
+fn overlay_sizes(base_dir: &Path) -> Result<Json> {
+    let overlay_dir = etc_overlay_dir(base_dir);
+    let mut dirs = vec![rootfs::path(base_dir), warp_state_dir(base_dir)];
+    if overlay_dir.exists() {
+        for entry in std::fs::read_dir(&overlay_dir)? {
+            dirs.push(entry?.path());
+        }
+    }
+    dirs.sort();
+    Ok(Json::Array(
+        dirs.iter()
+            .filter(|dir| dir.exists())
+            .map(|dir| {
+                Json::Object(vec![
+                    ("path", Json::str(dir.to_string_lossy())),
+                    ("bytes", section(|| Ok(Json::Number(dir_size(dir)?)))),
+                ])
+            })
+            .collect(),
+    ))
+}
+
+/// Everything there is to know about the container, as JSON for bug reports. Parts that can't be
+/// told, like those of a container that is down, hold their error instead.
+pub fn inspect(ex: &dyn Executor, config: &Config) -> Result<String> {
+    let base_dir = config.base_dir.as_path();
+    let status = namespace::status(base_dir)?;
+    let init_proc = match status {
+        namespace::Status::None => None,
+        _ => find_pid_ns_init_process(base_dir)?,
+    };
+    let with_init = |part: fn(&procfs::process::Process) -> Result<Json>| {
+        section(|| {
+            part(
+                init_proc
+                    .as_ref()
+                    .context("The container's init isn't running")?,
+            )
+        })
+    };
+
+    let doc = Json::Object(vec![
+        ("version", Json::str(env!("CARGO_PKG_VERSION"))),
+        ("base_dir", Json::str(base_dir.to_string_lossy())),
+        (
+            "status",
+            Json::str(match status {
+                namespace::Status::Ready => "ready",
+                namespace::Status::Partial(_) => "partial",
+                namespace::Status::None => "none",
+            }),
+        ),
+        ("namespaces", section(|| namespaces(base_dir))),
+        ("init", with_init(init)),
+        ("processes", section(|| processes(base_dir))),
+        ("mounts", with_init(mounts)),
+        ("addresses", section(|| ip_json(ex, base_dir, &["addr"]))),
+        (
+            "routes",
+            section(|| ip_json(ex, base_dir, &["route", "show", "table", "all"])),
+        ),
+        (
+            "firewall_rules",
+            section(|| {
+                let rules = firewall::installed_rules(ex, base_dir, &config.network)?;
+                Ok(Json::Array(rules.into_iter().map(Json::String).collect()))
+            }),
+        ),
+        ("overlay_sizes", section(|| overlay_sizes(base_dir))),
+    ]);
+    Ok(format!("{doc}\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prints_json() {
+        let doc = Json::Object(vec![
+            ("name", Json::str("a \"b\"")),
+            ("ids", Json::Array(vec![Json::Number(1), Json::Null])),
+            ("empty", Json::Object(Vec::new())),
+            ("raw", Json::Raw("[{\"ifname\":\"lo\"}]\n".to_owned())),
+        ]);
+        assert_eq!(
+            doc.to_string(),
+            "{\n  \"name\": \"a \\\"b\\\"\",\n  \"ids\": [\n    1,\n    null\n  ],\n  \"empty\": {},\n  \"raw\": [{\"ifname\":\"lo\"}]\n}"
+        );
+    }
+}
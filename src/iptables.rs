@@ -0,0 +1,132 @@
+use crate::exec::Executor;
+use crate::paths::{self, resolve, Binary, PathOverrides};
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::debug;
+
+/// When both iptables backends are installed, rules added through the one the host's firewall
+/// doesn't use still "succeed", but never see any traffic.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Variant {
+    Nft,
+    Legacy,
+}
+
+impl Variant {
+    fn binary_name(self) -> &'static str {
+        match self {
+            Variant::Nft => "iptables-nft",
+            Variant::Legacy => "iptables-legacy",
+        }
+    }
+
+    fn save_name(self) -> &'static str {
+        match self {
+            Variant::Nft => "iptables-nft-save",
+            Variant::Legacy => "iptables-legacy-save",
+        }
+    }
+}
+
+/// Finds the iptables binary for the backend that owns the current ruleset.
+pub fn detect(ex: &dyn Executor) -> Result<PathBuf> {
+    let (nft, legacy) = match (
+        paths::find(Variant::Nft.binary_name()),
+        paths::find(Variant::Legacy.binary_name()),
+    ) {
+        (Some(nft), Some(legacy)) => (nft, legacy),
+        _ => {
+            debug!("Only one iptables backend is installed, using the default iptables");
+            return resolve(&PathOverrides::default(), None, Binary::Iptables);
+        }
+    };
+
+    let nft_rules = count_rules(ex, &nft, Variant::Nft);
+    let legacy_rules = count_rules(ex, &legacy, Variant::Legacy);
+    let default = resolve(&PathOverrides::default(), None, Binary::Iptables)
+        .ok()
+        .and_then(|path| default_variant(ex, &path));
+    let variant = pick(nft_rules, legacy_rules, default);
+    debug!(
+        "Using {} ({nft_rules} nft rules, {legacy_rules} legacy rules, default is {default:?})",
+        variant.binary_name()
+    );
+    Ok(match variant {
+        Variant::Nft => nft,
+        Variant::Legacy => legacy,
+    })
+}
+
+/// Prefers whichever backend already has rules, since that's the one the host's firewall uses
+fn pick(nft_rules: usize, legacy_rules: usize, default: Option<Variant>) -> Variant {
+    if legacy_rules > nft_rules {
+        Variant::Legacy
+    } else if nft_rules > legacy_rules {
+        Variant::Nft
+    } else {
+        default.unwrap_or(Variant::Nft)
+    }
+}
+
+fn count_rules(ex: &dyn Executor, iptables: &Path, variant: Variant) -> usize {
+    // The save command lives next to the iptables binary
+    let save = iptables.with_file_name(variant.save_name());
+    let Ok(out) = ex.output(&mut Command::new(save)) else {
+        return 0;
+    };
+    if !out.status.success() {
+        return 0;
+    }
+    String::from_utf8_lossy(&out.stdout)
+        .lines()
+        .filter(|l| l.starts_with("-A "))
+        .count()
+}
+
+/// Which backend a plain `iptables` uses, from e.g. "iptables v1.8.9 (nf_tables)"
+fn default_variant(ex: &dyn Executor, iptables: &Path) -> Option<Variant> {
+    let out = ex.output(Command::new(iptables).arg("--version")).ok()?;
+    let version = String::from_utf8_lossy(&out.stdout);
+    if version.contains("(nf_tables)") {
+        Some(Variant::Nft)
+    } else if version.contains("(legacy)") {
+        Some(Variant::Legacy)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::{MockExecutor, Reply};
+
+    #[test]
+    fn picks_backend_with_rules() {
+        assert_eq!(pick(12, 0, Some(Variant::Legacy)), Variant::Nft);
+        assert_eq!(pick(0, 3, Some(Variant::Nft)), Variant::Legacy);
+        assert_eq!(pick(0, 0, Some(Variant::Legacy)), Variant::Legacy);
+        assert_eq!(pick(4, 4, None), Variant::Nft);
+    }
+
+    #[test]
+    fn counts_appended_rules() {
+        let ex = MockExecutor::with_handler(|_| {
+            Reply::ok(
+                "*filter\n:INPUT ACCEPT [0:0]\n:DOCKER-USER - [0:0]\n-A FORWARD -j DOCKER-USER\n\
+                 -A DOCKER-USER -j RETURN\nCOMMIT\n",
+            )
+        });
+        let path = Path::new("/usr/sbin/iptables-legacy");
+        assert_eq!(count_rules(&ex, path, Variant::Legacy), 2);
+        assert_eq!(ex.commands(), ["/usr/sbin/iptables-legacy-save"]);
+    }
+
+    #[test]
+    fn parses_default_variant() {
+        let ex = MockExecutor::with_handler(|_| Reply::ok("iptables v1.8.9 (nf_tables)\n"));
+        let variant = default_variant(&ex, Path::new("iptables"));
+        assert_eq!(variant, Some(Variant::Nft));
+    }
+}
@@ -0,0 +1,7 @@
+//! Access to bubblewarp's containers for other programs, like joining one's namespaces without
+//! going through `bubblewarp exec` or knowing how its base directory is laid out.
+
+mod container;
+pub use container::{Container, Type};
+#[cfg(test)]
+mod test_utils;
@@ -0,0 +1,422 @@
+use nix::fcntl::{fcntl, FcntlArg, SealFlag};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+use std::fmt::{self, Write};
+use std::fs::File;
+use std::io::{self, Write as _};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixDatagram, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::field::{Field, Visit};
+use tracing::{span, Event, Level, Subscriber};
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::time::{FormatTime, SystemTime};
+use tracing_subscriber::fmt::{FmtContext, FormatEvent, FormatFields, FormattedFields};
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+const JOURNAL_STDOUT: &str = "/run/systemd/journal/stdout";
+
+/// Set once our logs go to the journal, so the services we start send their output there too
+static LOGGING_TO_JOURNAL: AtomicBool = AtomicBool::new(false);
+
+/// How log lines are written
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum Format {
+    /// For people
+    #[default]
+    Text,
+    /// One JSON object per line, for log shippers
+    Json,
+    /// Entries with their priority and fields, sent straight to the systemd journal
+    Journald,
+}
+
+impl Format {
+    /// The journal when systemd connected stderr to it, text otherwise
+    pub fn detect() -> Self {
+        match stderr_is_journal() {
+            true => Format::Journald,
+            false => Format::Text,
+        }
+    }
+}
+
+/// systemd sets JOURNAL_STREAM to the device and inode of the stream it connects to the journal
+fn stderr_is_journal() -> bool {
+    let Some((dev, ino)) = std::env::var("JOURNAL_STREAM").ok().and_then(|stream| {
+        let (dev, ino) = stream.split_once(':')?;
+        Some((dev.parse::<u64>().ok()?, ino.parse::<u64>().ok()?))
+    }) else {
+        return false;
+    };
+    nix::sys::stat::fstat(libc::STDERR_FILENO)
+        .is_ok_and(|stat| stat.st_dev == dev && stat.st_ino == ino)
+}
+
+pub fn write_json_str(out: &mut impl Write, s: &str) -> fmt::Result {
+    out.write_char('"')?;
+    for c in s.chars() {
+        match c {
+            '"' => out.write_str("\\\"")?,
+            '\\' => out.write_str("\\\\")?,
+            '\n' => out.write_str("\\n")?,
+            '\r' => out.write_str("\\r")?,
+            '\t' => out.write_str("\\t")?,
+            c if c.is_control() => write!(out, "\\u{:04x}", c as u32)?,
+            c => out.write_char(c)?,
+        }
+    }
+    out.write_char('"')
+}
+
+/// Writes fields as the members of a JSON object
+struct JsonVisitor<'a, W: Write> {
+    out: &'a mut W,
+    first: bool,
+    result: fmt::Result,
+}
+
+impl<W: Write> JsonVisitor<'_, W> {
+    fn member(&mut self, field: &Field, value: impl FnOnce(&mut W) -> fmt::Result) {
+        if self.result.is_err() {
+            return;
+        }
+        let separator = if self.first { "" } else { "," };
+        self.first = false;
+        self.result = write!(self.out, "{separator}")
+            .and_then(|()| write_json_str(self.out, field.name()))
+            .and_then(|()| self.out.write_char(':'))
+            .and_then(|()| value(self.out));
+    }
+}
+
+impl<W: Write> Visit for JsonVisitor<'_, W> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.member(field, |out| write_json_str(out, &format!("{value:?}")));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.member(field, |out| write_json_str(out, value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.member(field, |out| write!(out, "{value}"));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.member(field, |out| write!(out, "{value}"));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.member(field, |out| write!(out, "{value}"));
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        match value.is_finite() {
+            true => self.member(field, |out| write!(out, "{value}")),
+            false => self.member(field, |out| out.write_str("null")),
+        }
+    }
+}
+
+/// Formats the fields of events and spans as JSON objects
+pub struct JsonFields;
+
+impl<'w> FormatFields<'w> for JsonFields {
+    fn format_fields<R: tracing_subscriber::field::RecordFields>(
+        &self,
+        mut writer: Writer<'w>,
+        fields: R,
+    ) -> fmt::Result {
+        writer.write_char('{')?;
+        let mut visitor = JsonVisitor {
+            out: &mut writer,
+            first: true,
+            result: Ok(()),
+        };
+        fields.record(&mut visitor);
+        visitor.result?;
+        writer.write_char('}')
+    }
+
+    /// Merges the recorded fields into the span's object, instead of appending a second one
+    fn add_fields(
+        &self,
+        current: &'w mut FormattedFields<Self>,
+        fields: &span::Record<'_>,
+    ) -> fmt::Result {
+        let existing = std::mem::take(&mut current.fields);
+        let mut added = String::new();
+        added.write_char('{')?;
+        let mut visitor = JsonVisitor {
+            out: &mut added,
+            first: true,
+            result: Ok(()),
+        };
+        fields.record(&mut visitor);
+        visitor.result?;
+        current.fields = match (existing.strip_suffix('}'), visitor.first) {
+            (Some(members), false) if members != "{" => format!("{members},{}}}", &added[1..]),
+            (Some(_), false) | (None, _) => added + "}",
+            (Some(_), true) => existing,
+        };
+        Ok(())
+    }
+}
+
+/// One JSON object per event, with its time, level, target, fields, and the spans it's in
+pub struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        writer.write_str("{\"timestamp\":\"")?;
+        SystemTime.format_time(&mut writer)?;
+        write!(writer, "\",\"level\":\"{}\",\"target\":", meta.level())?;
+        write_json_str(&mut writer, meta.target())?;
+        writer.write_str(",\"fields\":")?;
+        ctx.format_fields(writer.by_ref(), event)?;
+        writer.write_str(",\"spans\":[")?;
+        if let Some(scope) = ctx.event_scope() {
+            for (i, span) in scope.from_root().enumerate() {
+                if i > 0 {
+                    writer.write_char(',')?;
+                }
+                writer.write_str("{\"name\":")?;
+                write_json_str(&mut writer, span.name())?;
+                let extensions = span.extensions();
+                if let Some(fields) = extensions.get::<FormattedFields<JsonFields>>() {
+                    if !fields.fields.is_empty() {
+                        write!(writer, ",\"fields\":{}", fields.fields)?;
+                    }
+                }
+                writer.write_char('}')?;
+            }
+        }
+        writeln!(writer, "]}}")
+    }
+}
+
+/// Sends each event to the journal as its own entry. The message and fields keep their own
+/// journal fields, and the level maps to the entry's priority.
+pub struct Journald {
+    socket: UnixDatagram,
+}
+
+impl Journald {
+    pub fn new() -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(JOURNAL_SOCKET)?;
+        LOGGING_TO_JOURNAL.store(true, Ordering::Relaxed);
+        Ok(Journald { socket })
+    }
+
+    /// Entries too large for a datagram are passed in a sealed memfd, which journald reads instead
+    fn send_in_memfd(&self, entry: &[u8]) -> io::Result<()> {
+        let flags = MemFdCreateFlag::MFD_ALLOW_SEALING | MemFdCreateFlag::MFD_CLOEXEC;
+        let fd = memfd_create(c"bubblewarp-journal-entry", flags)?;
+        // SAFETY: memfd_create just returned this fd, nothing else owns it
+        let mut memfd = unsafe { File::from_raw_fd(fd) };
+        memfd.write_all(entry)?;
+        fcntl(fd, FcntlArg::F_ADD_SEALS(SealFlag::all()))?;
+        sendmsg::<()>(
+            self.socket.as_raw_fd(),
+            &[],
+            &[ControlMessage::ScmRights(&[fd])],
+            MsgFlags::empty(),
+            None,
+        )?;
+        Ok(())
+    }
+}
+
+fn priority(level: &Level) -> &'static str {
+    match *level {
+        Level::ERROR => "3",
+        Level::WARN => "4",
+        Level::INFO => "6",
+        Level::DEBUG | Level::TRACE => "7",
+    }
+}
+
+/// Journal field names only have uppercase letters, digits and underscores,
+/// and those starting with an underscore are reserved to journald
+fn journal_field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' => c.to_ascii_uppercase(),
+            _ => '_',
+        })
+        .collect();
+    name.trim_start_matches(|c: char| c == '_' || c.is_ascii_digit())
+        .to_owned()
+}
+
+/// Values with newlines need the length-prefixed form of the native protocol
+fn put_journal_field(entry: &mut Vec<u8>, name: &str, value: &[u8]) {
+    entry.extend_from_slice(name.as_bytes());
+    if value.contains(&b'\n') {
+        entry.push(b'\n');
+        entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        entry.push(b'=');
+    }
+    entry.extend_from_slice(value);
+    entry.push(b'\n');
+}
+
+struct JournalVisitor<'a>(&'a mut Vec<u8>);
+
+impl Visit for JournalVisitor<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{value:?}"));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let name = journal_field_name(field.name());
+        if !name.is_empty() {
+            put_journal_field(self.0, &name, value.as_bytes());
+        }
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> tracing_subscriber::Layer<S> for Journald {
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let meta = event.metadata();
+        let mut entry = Vec::new();
+        put_journal_field(&mut entry, "PRIORITY", priority(meta.level()).as_bytes());
+        put_journal_field(&mut entry, "SYSLOG_IDENTIFIER", b"bubblewarp");
+        put_journal_field(&mut entry, "TARGET", meta.target().as_bytes());
+        if let Some(file) = meta.file() {
+            put_journal_field(&mut entry, "CODE_FILE", file.as_bytes());
+        }
+        if let Some(line) = meta.line() {
+            put_journal_field(&mut entry, "CODE_LINE", line.to_string().as_bytes());
+        }
+        if let Some(scope) = ctx.event_scope(event) {
+            let spans: Vec<&str> = scope.from_root().map(|span| span.name()).collect();
+            put_journal_field(&mut entry, "SPANS", spans.join(":").as_bytes());
+        }
+        event.record(&mut JournalVisitor(&mut entry));
+        // Nowhere left to report a failure to log
+        if let Err(e) = self.socket.send(&entry) {
+            if e.raw_os_error() == Some(libc::EMSGSIZE) {
+                let _ = self.send_in_memfd(&entry);
+            }
+        }
+    }
+}
+
+/// A stream into the journal for a service's output, tagged with its name,
+/// if our own logs go to the journal
+pub fn journal_stream(identifier: &str) -> Option<UnixStream> {
+    if !LOGGING_TO_JOURNAL.load(Ordering::Relaxed) {
+        return None;
+    }
+    let mut stream = UnixStream::connect(JOURNAL_STDOUT).ok()?;
+    // Identifier, unit, priority, level prefix, and forwarding to syslog, kmsg and console
+    stream
+        .write_all(format!("{identifier}\n\n6\n0\n0\n0\n0\n").as_bytes())
+        .ok()?;
+    stream.shutdown(std::net::Shutdown::Read).ok()?;
+    Some(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn json_lines() {
+        let buf = Arc::new(Mutex::new(Vec::new()));
+        let writer = buf.clone();
+        let layer = tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .with_writer(move || WriteTo(writer.clone()));
+        let subscriber = tracing_subscriber::registry().with(layer);
+        tracing::subscriber::with_default(subscriber, || {
+            let span =
+                tracing::info_span!("service", name = "warp-svc", pid = tracing::field::Empty);
+            span.record("pid", 42);
+            span.in_scope(|| tracing::warn!(code = 1, "exited \"early\""));
+        });
+        let line = String::from_utf8(buf.lock().unwrap().clone()).unwrap();
+        assert!(line.starts_with("{\"timestamp\":\""));
+        assert!(line.ends_with(concat!(
+            "\"level\":\"WARN\",\"target\":\"bubblewarp::logging::tests\",",
+            "\"fields\":{\"message\":\"exited \\\"early\\\"\",\"code\":1},",
+            "\"spans\":[{\"name\":\"service\",\"fields\":{\"name\":\"warp-svc\",\"pid\":42}}]}\n"
+        )));
+    }
+
+    #[test]
+    fn journal_fields() {
+        assert_eq!(journal_field_name("message"), "MESSAGE");
+        assert_eq!(journal_field_name("_pid"), "PID");
+        assert_eq!(journal_field_name("exit.code"), "EXIT_CODE");
+        let mut entry = Vec::new();
+        put_journal_field(&mut entry, "MESSAGE", b"one line");
+        put_journal_field(&mut entry, "STDERR", b"a\nb");
+        let mut expected = b"MESSAGE=one line\nSTDERR\n".to_vec();
+        expected.extend(3u64.to_le_bytes());
+        expected.extend(b"a\nb\n");
+        assert_eq!(entry, expected);
+    }
+
+    #[test]
+    fn large_entries_go_in_a_memfd() {
+        use nix::sys::socket::{recvmsg, ControlMessageOwned};
+        use std::io::{IoSliceMut, Read, Seek};
+        let (socket, journal) = UnixDatagram::pair().unwrap();
+        let journald = Journald { socket };
+        let entry = format!("MESSAGE={}\n", "x".repeat(1 << 20));
+        journald.send_in_memfd(entry.as_bytes()).unwrap();
+
+        let mut cmsg = nix::cmsg_space!([std::os::fd::RawFd; 1]);
+        let mut buf = [0u8; 1];
+        let mut iov = [IoSliceMut::new(&mut buf)];
+        let msg = recvmsg::<()>(
+            journal.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::empty(),
+        )
+        .unwrap();
+        let Some(ControlMessageOwned::ScmRights(fds)) = msg.cmsgs().next() else {
+            panic!("No fd passed with the entry")
+        };
+        // SAFETY: the fd was just received, nothing else owns it
+        let mut memfd = unsafe { File::from_raw_fd(fds[0]) };
+        memfd.rewind().unwrap();
+        let mut received = String::new();
+        memfd.read_to_string(&mut received).unwrap();
+        assert_eq!(received, entry);
+        assert!(memfd.write_all(b"more").is_err());
+    }
+
+    struct WriteTo(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for WriteTo {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
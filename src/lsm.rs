@@ -0,0 +1,152 @@
+use anyhow::{bail, Result};
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use tracing::debug;
+
+const SELINUX_XATTR: &str = "security.selinux";
+const AUDIT_LOG: &str = "/var/log/audit/audit.log";
+/// Denials of these are likely ours
+const OUR_PROCESSES: &[&str] = &["warp-svc", "warp-cli", "danted", "bubblewarp"];
+
+/// The security modules the kernel runs, like "capability,landlock,selinux"
+pub fn active() -> Vec<String> {
+    std::fs::read_to_string("/sys/kernel/security/lsm")
+        .unwrap_or_default()
+        .trim()
+        .split(',')
+        .filter(|lsm| !lsm.is_empty() && *lsm != "capability")
+        .map(str::to_owned)
+        .collect()
+}
+
+/// Enforcing or permissive, files get labels either way
+pub fn selinux_enabled() -> bool {
+    Path::new("/sys/fs/selinux/enforce").exists()
+}
+
+pub fn selinux_enforcing() -> bool {
+    std::fs::read_to_string("/sys/fs/selinux/enforce").is_ok_and(|mode| mode.trim() == "1")
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    Ok(CString::new(path.as_os_str().as_bytes())?)
+}
+
+fn label_of(path: &Path) -> Result<String> {
+    let path = c_path(path)?;
+    let name = CString::new(SELINUX_XATTR)?;
+    let mut buf = vec![0u8; 256];
+    // SAFETY: the buffer's length is passed along with it
+    let len = unsafe {
+        libc::lgetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            buf.as_mut_ptr().cast(),
+            buf.len(),
+        )
+    };
+    if len < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    buf.truncate(len as usize);
+    // The kernel includes the terminating nul
+    Ok(String::from_utf8(buf)?.trim_end_matches('\0').to_owned())
+}
+
+fn set_label(path: &Path, label: &str) -> Result<()> {
+    let path = c_path(path)?;
+    let name = CString::new(SELINUX_XATTR)?;
+    // SAFETY: the value's length is passed along with it
+    let ret = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            label.as_ptr().cast(),
+            label.len(),
+            0,
+        )
+    };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error().into());
+    }
+    Ok(())
+}
+
+/// Labels `dir` and everything in it like `reference`. Files created under the base dir get its
+/// label, which SELinux policy doesn't let warp-svc or danted use in place of their usual ones.
+pub fn label_like(dir: &Path, reference: &Path) -> Result<()> {
+    if !selinux_enabled() {
+        return Ok(());
+    }
+    let label = label_of(reference)?;
+    debug!("Labeling {} as {label}", dir.display());
+    let mut pending = vec![dir.to_owned()];
+    while let Some(path) = pending.pop() {
+        set_label(&path, &label)?;
+        if path.symlink_metadata()?.is_dir() {
+            for entry in std::fs::read_dir(&path)? {
+                pending.push(entry?.path());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Mount options giving an overlay the label of the directory it covers
+pub fn overlay_context(covered: &Path) -> Result<Option<String>> {
+    if !selinux_enabled() {
+        return Ok(None);
+    }
+    Ok(Some(format!("context=\"{}\"", label_of(covered)?)))
+}
+
+/// Whether an audit record is an SELinux or AppArmor denial of one of our processes
+fn is_our_denial(record: &str) -> bool {
+    let denial = (record.contains("type=AVC") && record.contains("denied"))
+        || record.contains("apparmor=\"DENIED\"");
+    denial
+        && OUR_PROCESSES
+            .iter()
+            .any(|comm| record.contains(&format!("comm=\"{comm}\"")))
+}
+
+/// The latest denials of our processes in the audit log
+pub fn recent_denials(max: usize) -> Result<Vec<String>> {
+    let log = match std::fs::read_to_string(AUDIT_LOG) {
+        Ok(log) => log,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            bail!("{AUDIT_LOG} doesn't exist, is auditd running?")
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let denials: Vec<String> = log
+        .lines()
+        .filter(|record| is_our_denial(record))
+        .map(str::to_owned)
+        .collect();
+    Ok(denials[denials.len().saturating_sub(max)..].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_our_denials() {
+        assert!(is_our_denial(
+            "type=AVC msg=audit(1700000000.123:456): avc:  denied  { write } for  pid=1234 \
+             comm=\"warp-svc\" name=\"conf.json\" scontext=system_u:system_r:unconfined_service_t:s0"
+        ));
+        assert!(is_our_denial(
+            "type=AVC msg=audit(1700000000.123:457): apparmor=\"DENIED\" operation=\"open\" \
+             comm=\"danted\" name=\"/etc/danted.conf\""
+        ));
+        assert!(!is_our_denial(
+            "type=AVC msg=audit(1700000000.123:458): avc:  denied  { read } comm=\"sshd\""
+        ));
+        assert!(!is_our_denial(
+            "type=SYSCALL msg=audit(1700000000.123:456): comm=\"warp-svc\" success=yes"
+        ));
+    }
+}
@@ -4,19 +4,96 @@ mod up;
 use crate::up::up;
 mod down;
 use crate::down::down;
+mod bundle;
+mod capture;
+mod cgroup;
+mod compat;
+mod config;
+use crate::config::Config;
+mod dns;
+mod docker_net;
+mod exec;
+use crate::exec::RealExecutor;
+mod extra;
+mod fetch;
+mod firewall;
+mod gateway;
+mod health;
+mod image;
+mod init;
+mod inspect;
+mod iptables;
+mod logging;
+mod lsm;
+mod mtu;
 mod namespace;
 mod net;
+mod netlink;
+mod oplog;
+mod paths;
+mod pause;
+use crate::paths::Paths;
+mod pidfd;
+mod preflight;
+mod progress;
+mod proxy;
+mod readiness;
+mod registration;
+mod relay;
+mod reload;
+mod restart;
+mod rollback;
+mod rootfs;
+mod seccomp;
+mod selftest;
+use crate::selftest::selftest;
+mod service;
+mod speedtest;
+mod state;
+mod status;
+mod summary;
+mod systemd;
+#[cfg(test)]
+mod test_utils;
+mod timings;
+mod watchdog;
 
 use anyhow::{bail, Result};
 use clap::Parser;
 use nix::unistd;
 use nix::unistd::ROOT;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 use tracing::debug;
+use tracing_subscriber::filter::filter_fn;
 use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
 
 #[derive(Parser)]
 struct Args {
+    /// Config file to use instead of the default one
+    #[clap(long, global = true)]
+    config: Option<PathBuf>,
+    /// Directory holding the persistent namespaces and container state
+    #[clap(long, global = true)]
+    base_dir: Option<PathBuf>,
+    /// Only log warnings and errors
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Log more details, twice to log every command run. RUST_LOG overrides this.
+    #[clap(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+    /// How to write logs, to the journal by default when systemd connected us to it
+    #[clap(long, global = true, value_name = "FORMAT")]
+    log_format: Option<logging::Format>,
+    /// Wait up to SECONDS for each step of the startup, instead of the configured timeouts
+    #[clap(long, global = true, value_name = "SECONDS", value_parser = readiness::parse_seconds)]
+    wait_timeout: Option<Duration>,
+    /// Test support: run this executable instead of warp-svc inside the container
+    #[clap(long, global = true, hide = true)]
+    fake_warp: Option<PathBuf>,
     #[clap(subcommand)]
     command: Command,
 }
@@ -24,41 +101,528 @@ struct Args {
 #[derive(clap::Subcommand)]
 enum Command {
     /// Start warp in a container
-    Up,
+    Up {
+        /// Give the container its own monotonic and boot clocks, shifted by SECONDS
+        #[clap(long, value_name = "SECONDS", num_args = 0..=1, default_missing_value = "0", allow_hyphen_values = true)]
+        time_ns: Option<i64>,
+        /// Print how long each phase of the startup took
+        #[clap(long)]
+        timings: bool,
+        /// Show each step of the startup as it runs
+        #[clap(long, value_name = "WHEN", num_args = 0..=1, default_value = "auto", default_missing_value = "always")]
+        progress: progress::When,
+        /// Don't start the SOCKS proxy, for use with warp-cli's own proxy mode or commands run inside
+        #[clap(long)]
+        no_proxy: bool,
+        /// Don't add NAT and forward rules, for hosts whose firewall is managed separately
+        #[clap(long)]
+        no_external: bool,
+        /// Refuse to start WARP versions bubblewarp wasn't tested with, instead of warning
+        #[clap(long)]
+        strict: bool,
+        /// Send the container's traffic out this interface instead of the default route's, like
+        /// a WireGuard tunnel to run WARP over
+        #[clap(long, value_name = "IFACE", conflicts_with = "no_external")]
+        egress_iface: Option<String>,
+    },
     /// Stop warp and cleanup the container
-    Down,
+    Down {
+        /// Also remove what was written to /etc inside the container, which is kept across runs
+        #[clap(long)]
+        purge_overlay: bool,
+    },
+    /// Restart warp, returning once the proxy serves again
+    Restart {
+        /// Only cycle warp-svc and danted inside the running container, instead of a full down and up
+        #[clap(long)]
+        services_only: bool,
+    },
+    /// Apply the config file's changes that don't need a restart, and list those that do
+    Reload,
+    /// Disconnect the tunnel, keeping the container for a quick resume
+    Pause {
+        /// Stop the SOCKS proxy as well, so nothing connects directly while paused
+        #[clap(long)]
+        stop_proxy: bool,
+    },
+    /// Reconnect after pause, returning once the proxy serves again
+    Resume,
+    /// Register the container with WARP, when it has no registration yet
+    Register,
+    /// Show whether the container is up, and how to use it
+    Status {
+        /// Check each resource up manages against the config, listing what drifted and failing
+        /// if anything did
+        #[clap(long)]
+        verify: bool,
+    },
+    /// Show the last privileged operations bubblewarp ran, to see how the container got into its state
+    History {
+        #[clap(long, short = 'n', default_value = "50")]
+        lines: usize,
+    },
+    /// Print shell exports that send proxy-aware programs through the container
+    Env,
+    /// Run the configured health probes, failing if any of them does
+    Health,
+    /// Manage a systemd service for the container
+    Systemd {
+        #[clap(subcommand)]
+        action: SystemdAction,
+    },
+    /// Relay a host port to the proxy, bringing the container up or resuming it on the first
+    /// connection. Takes its listening socket from systemd socket activation, or listens on --listen.
+    Relay {
+        #[clap(long, value_name = "ADDR")]
+        listen: Option<std::net::SocketAddr>,
+    },
+    /// Keep checking the container's health, and recover it when WARP stops working
+    Watch,
+    /// Fetch a plain HTTP URL through the proxy, printing the body, then the status and timings
+    #[clap(alias = "fetch-url")]
+    Curl { url: String },
+    /// Measure latency and throughput through WARP, and directly from the host for comparison
+    Speedtest,
+    /// Find the largest packets that get through WARP's tunnel, and the veth MTU and TCP MSS
+    /// clamp to match
+    MtuProbe {
+        /// The IPv4 address to ping through the tunnel
+        #[clap(long, default_value = "1.1.1.1")]
+        target: std::net::IpAddr,
+        /// Set the veth MTU and MSS clamp until the next down
+        #[clap(long)]
+        apply: bool,
+    },
+    /// Look up a name with WARP's resolver in the container, to tell DNS issues from tunnel ones
+    Dns {
+        name: String,
+        #[clap(
+            long = "type",
+            short = 't',
+            value_name = "TYPE",
+            value_enum,
+            ignore_case = true,
+            default_value = "A"
+        )]
+        rtype: dns::RecordType,
+    },
+    /// Print a config snippet that makes a program use the container
+    ClientConfig {
+        #[clap(value_enum)]
+        client: dns::Client,
+    },
+    /// Bring up and tear down a throwaway container to check that this host supports bubblewarp
+    Selftest,
+    /// Check the kernel's features and the security modules, and show their recent denials
+    Doctor,
+    /// Print where a namespace is mounted, for tools like `nsenter --net=$(bubblewarp ns-path net)`
+    NsPath {
+        /// A namespace like net, or all for nsenter's flags joining every one
+        #[clap(value_name = "TYPE|all")]
+        ns: String,
+    },
+    /// Run a command in the container, with the terminal
+    Exec {
+        /// Only join these namespaces, like net,mount. All of them by default.
+        #[clap(long, value_name = "TYPES", value_delimiter = ',')]
+        ns: Vec<namespace::Type>,
+        #[clap(required = true, trailing_var_arg = true, allow_hyphen_values = true)]
+        command: Vec<String>,
+    },
+    /// Capture packets on one of the container's interfaces, with tcpdump if the host has it
+    Capture {
+        #[clap(long, default_value = readiness::WARP_IFACE)]
+        iface: String,
+        /// The pcap file to write
+        #[clap(short = 'w', long = "write", value_name = "FILE")]
+        file: PathBuf,
+        /// Stop after this many packets
+        #[clap(short = 'c', long)]
+        count: Option<u64>,
+        /// Stop after this many seconds
+        #[clap(long, value_name = "SECONDS", value_parser = readiness::parse_seconds)]
+        duration: Option<Duration>,
+    },
+    /// Print bubblewarp's version
+    Version {
+        /// Also print the versions of WARP and the tools bubblewarp runs, and the kernel's
+        #[clap(long)]
+        full: bool,
+    },
+    /// Check or print the config file, without touching the container
+    Config {
+        #[clap(subcommand)]
+        action: ConfigAction,
+    },
+    /// Print everything about the container as JSON, to attach to bug reports
+    Inspect,
+    /// Collect inspect's output, logs, WARP's diagnostics and the sanitized config into a tar.gz
+    DebugBundle {
+        /// Where to write the bundle, bubblewarp-debug-<date>-<time>.tar.gz by default
+        file: Option<PathBuf>,
+    },
+    /// Share the container's network with other containers
+    DockerNet {
+        #[clap(subcommand)]
+        action: DockerNetAction,
+    },
+    /// Archive the container's WARP registration, to back it up or move it to another machine
+    ExportRegistration { file: PathBuf },
+    /// Restore a registration archived by export-registration
+    ImportRegistration { file: PathBuf },
+    /// Download the latest WARP release from Cloudflare for up to use, instead of a system-wide install
+    Fetch,
+    /// Let LAN devices use the WARP tunnel through a WireGuard gateway
+    Wg {
+        #[clap(subcommand)]
+        action: WgAction,
+    },
+    /// Runs as PID 1 of the container, started by up
+    #[clap(name = init::MARKER, hide = true)]
+    Init,
+}
+
+#[derive(clap::Subcommand)]
+enum DockerNetAction {
+    /// Register the container's network namespace as /run/netns/<NAME>
+    Create {
+        #[clap(default_value = "bubblewarp")]
+        name: String,
+    },
+    /// Remove a registration made by create
+    Remove {
+        #[clap(default_value = "bubblewarp")]
+        name: String,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum ConfigAction {
+    /// Fail on settings up would refuse, like unknown keys, malformed addresses or overlapping subnets
+    Validate,
+    /// Print the config file
+    Show {
+        /// Print every setting in effect instead, and whether it's a default or comes from the
+        /// file or a flag
+        #[clap(long)]
+        effective: bool,
+    },
+}
+
+#[derive(clap::Subcommand)]
+enum SystemdAction {
+    /// Install and enable a service bringing the container up at boot
+    Install {
+        /// Only keep the container up during this window, like "Mon..Fri 09:00-18:00"
+        #[clap(long)]
+        schedule: Option<String>,
+        /// Also relay this port on 127.0.0.1 to the proxy, bringing the container up on demand
+        #[clap(long, value_name = "PORT")]
+        relay: Option<u16>,
+    },
+    /// Remove what install added
+    Uninstall,
+    /// Fails outside of the schedule's window, for the service's ExecCondition
+    #[clap(hide = true)]
+    CheckSchedule { schedule: String },
+}
+
+#[derive(clap::Subcommand)]
+enum WgAction {
+    /// Add a peer and print its client config
+    AddPeer {
+        name: String,
+        /// Print the client config as a QR code, for the mobile apps
+        #[clap(long)]
+        qr: bool,
+    },
+    /// Remove a peer added by add-peer
+    RemovePeer { name: String },
 }
 
 fn main() -> Result<()> {
+    let cli = Args::parse();
+    if let Command::Init = cli.command {
+        init::run();
+    }
+
+    let progress = match cli.command {
+        Command::Up { progress, .. } if progress.enabled() => Some(progress::Progress::default()),
+        _ => None,
+    };
+    // Progress replaces the info logs, unless asked for them
+    let default_filter = match (cli.quiet, cli.verbose) {
+        (true, _) => "bubblewarp=warn",
+        (false, 0) if progress.is_some() => "bubblewarp=warn",
+        (false, 0) => "bubblewarp=info",
+        (false, 1) => "bubblewarp=debug",
+        (false, _) => "bubblewarp=trace",
+    };
+    // Phases are timed whatever the log level, so the recorder gets its own filter
+    let timings = timings::Recorder::default();
+    let is_phase = || filter_fn(|meta| meta.target() == timings::TARGET);
+    let fmt_layer = match cli.log_format.unwrap_or_else(logging::Format::detect) {
+        logging::Format::Journald => match logging::Journald::new() {
+            Ok(journald) => journald.boxed(),
+            Err(e) => {
+                eprintln!("Can't log to the journal, logging to stdout instead: {e}");
+                tracing_subscriber::fmt::layer().boxed()
+            }
+        },
+        logging::Format::Text => tracing_subscriber::fmt::layer().boxed(),
+        logging::Format::Json => tracing_subscriber::fmt::layer()
+            .fmt_fields(logging::JsonFields)
+            .event_format(logging::JsonFormat)
+            .boxed(),
+    };
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "bubblewarp=info".into()),
-        ))
-        .with(tracing_subscriber::fmt::layer())
+        .with(fmt_layer.with_filter(tracing_subscriber::EnvFilter::new(
+            std::env::var("RUST_LOG").unwrap_or_else(|_| default_filter.into()),
+        )))
+        .with(timings.clone().with_filter(is_phase()))
+        .with(progress.clone().with_filter(is_phase()))
         .init();
 
-    let cli = Args::parse();
-    ensure_root()?;
+    let setuid = ensure_root()?;
+    if setuid {
+        // The caller mustn't choose the binaries or the config that run as root
+        if cli.config.is_some() || cli.base_dir.is_some() || cli.fake_warp.is_some() {
+            bail!("Running setuid root, only root can pass --config, --base-dir and --fake-warp")
+        }
+        config::check_root_only(&config::default_path()?)?;
+        std::env::set_var("PATH", paths::FALLBACK_DIRS.join(":"));
+    }
 
+    let base_dir = match cli.base_dir {
+        Some(base_dir) => base_dir,
+        None => namespace::base_dir()?,
+    };
+    let mut config = Config::load(cli.config.as_deref(), base_dir)?;
+    // What the flags override, for config show
+    let mut flagged = Vec::new();
+    if let Some(fake_warp) = cli.fake_warp {
+        config.paths.warp_svc = Some(fake_warp);
+        flagged.push(("paths.warp-svc", "--fake-warp"));
+    }
+    if let Some(timeout) = cli.wait_timeout {
+        config.timeouts.set_all(timeout);
+        flagged.push(("timeouts", "--wait-timeout"));
+    }
+    let ex = RealExecutor::new(
+        // Fetched releases are in a base dir the caller may own
+        Paths::new(config.paths.clone(), (!setuid).then_some(&*config.base_dir)),
+        &config.base_dir,
+    );
     match cli.command {
-        Command::Up => {
-            up()?;
+        Command::Up {
+            time_ns,
+            timings: print_timings,
+            no_proxy,
+            no_external,
+            strict,
+            egress_iface,
+            ..
+        } => {
+            if time_ns.is_some() {
+                config.container.time_offset = time_ns;
+            }
+            config.parts = up::Parts {
+                proxy: !no_proxy,
+                external: !no_external,
+            };
+            config.strict = strict;
+            if let Some(iface) = &egress_iface {
+                config.firewall.uplinks = vec![iface.clone()];
+            }
+            config.egress_iface = egress_iface;
+            let started = Instant::now();
+            if let Some(progress) = &progress {
+                progress.start();
+            }
+            let result = up(&ex, &config);
+            if let Some(progress) = &progress {
+                progress.finish(&match result {
+                    Ok(()) => format!(
+                        "bubblewarp is up after {:.1} s",
+                        started.elapsed().as_secs_f64()
+                    ),
+                    Err(_) => "✗ up failed".to_owned(),
+                });
+            }
+            if print_timings {
+                print!("{}", timings.report());
+            }
+            result?;
+            print!("{}", summary::summary(&ex, &config));
+        }
+        Command::Down { purge_overlay } => {
+            down(&ex, &config)?;
+            if purge_overlay {
+                down::purge_etc_overlay(&config.base_dir)?;
+            }
+        }
+        Command::Restart { services_only } => {
+            restart::restart(&ex, &config, services_only)?;
+        }
+        Command::Reload => {
+            reload::reload(&ex, &config)?;
+        }
+        Command::Pause { stop_proxy } => {
+            pause::pause(&ex, &config, stop_proxy)?;
+        }
+        Command::Resume => {
+            pause::resume(&ex, &config)?;
         }
-        Command::Down => {
-            down()?;
+        Command::Register => {
+            print!(
+                "{}",
+                compat::warp_cli_op(&ex, &config.base_dir, compat::Op::Register)?
+            );
         }
+        Command::Status { verify } => {
+            status::status(&ex, &config, verify)?;
+        }
+        Command::History { lines } => {
+            for line in oplog::tail(&config.base_dir, lines)? {
+                println!("{line}");
+            }
+        }
+        Command::Env => {
+            print!("{}", summary::env(&config));
+        }
+        Command::Health => {
+            health::health(&ex, &config)?;
+        }
+        Command::Systemd { action } => match action {
+            SystemdAction::Install { schedule, relay } => systemd::install(
+                &ex,
+                &config,
+                cli.config.as_deref(),
+                schedule.as_deref(),
+                relay,
+            )?,
+            SystemdAction::Uninstall => systemd::uninstall(&ex)?,
+            SystemdAction::CheckSchedule { schedule } => systemd::check_schedule(&schedule)?,
+        },
+        Command::Relay { listen } => {
+            relay::relay(&ex, &config, listen)?;
+        }
+        Command::Watch => {
+            watchdog::watch(&ex, &config)?;
+        }
+        Command::Curl { url } => {
+            let fetched = proxy::fetch(&config, &url)?;
+            std::io::stdout().write_all(&fetched.body)?;
+            if !fetched.body.is_empty() && !fetched.body.ends_with(b"\n") {
+                println!();
+            }
+            println!(
+                "HTTP {}, connected in {} ms, first byte after {} ms, done after {} ms",
+                fetched.status,
+                fetched.connect.as_millis(),
+                fetched.first_byte.as_millis(),
+                fetched.total.as_millis()
+            );
+        }
+        Command::Speedtest => {
+            print!("{}", speedtest::speedtest(&ex, &config)?);
+        }
+        Command::MtuProbe { target, apply } => {
+            print!("{}", mtu::mtu_probe(&ex, &config, target, apply)?);
+        }
+        Command::Dns { name, rtype } => {
+            print!("{}", dns::query(&config, &name, rtype)?);
+        }
+        Command::ClientConfig { client } => {
+            print!("{}", dns::client_config(&config, client)?);
+        }
+        Command::Selftest => {
+            selftest(&config)?;
+        }
+        Command::Doctor => {
+            preflight::doctor(&config)?;
+        }
+        Command::NsPath { ns } => {
+            let ns_type = match ns.as_str() {
+                "all" => None,
+                name => Some(name.parse()?),
+            };
+            println!("{}", namespace::ns_path(&config.base_dir, ns_type)?);
+        }
+        Command::Exec { ns, command } => {
+            if setuid {
+                bail!("exec runs any command as root, only root can use it")
+            }
+            let ns_pid = pause::ensure_up(&config.base_dir)?;
+            let mut cmd = std::process::Command::new(&command[0]);
+            cmd.args(&command[1..]);
+            let status = namespace::exec_inside(&ex, ns_pid, &ns, &cmd)?;
+            std::process::exit(status.code().unwrap_or(1));
+        }
+        Command::Capture {
+            iface,
+            file,
+            count,
+            duration,
+        } => {
+            let limits = capture::Limits { count, duration };
+            capture::capture(&ex, &config, &iface, &file, limits)?;
+        }
+        Command::Version { full: false } => {
+            println!("bubblewarp {}", env!("CARGO_PKG_VERSION"));
+        }
+        Command::Version { full: true } => {
+            print!("{}", compat::report(&ex, &config));
+        }
+        Command::Config { action } => match action {
+            ConfigAction::Validate => {
+                print!("{}", config::validate(&config, cli.config.as_deref())?)
+            }
+            ConfigAction::Show { effective } => print!(
+                "{}",
+                config::show(&config, cli.config.as_deref(), effective, &flagged)?
+            ),
+        },
+        Command::Inspect => {
+            print!("{}", inspect::inspect(&ex, &config)?);
+        }
+        Command::DebugBundle { file } => {
+            let file = file.unwrap_or_else(bundle::default_file);
+            bundle::debug_bundle(&ex, &config, cli.config.as_deref(), &file)?;
+        }
+        Command::DockerNet { action } => match action {
+            DockerNetAction::Create { name } => docker_net::create(&ex, &config.base_dir, &name)?,
+            DockerNetAction::Remove { name } => docker_net::remove(&ex, &config.base_dir, &name)?,
+        },
+        Command::ExportRegistration { file } => {
+            registration::export(&ex, &config.base_dir, &file)?;
+        }
+        Command::ImportRegistration { file } => {
+            registration::import(&ex, &config.base_dir, &file)?;
+        }
+        Command::Fetch => {
+            fetch::fetch(&ex, &config.base_dir)?;
+        }
+        Command::Wg { action } => match action {
+            WgAction::AddPeer { name, qr } => gateway::add_peer(&ex, &config, &name, qr)?,
+            WgAction::RemovePeer { name } => gateway::remove_peer(&ex, &config, &name)?,
+        },
+        Command::Init => unreachable!(),
     }
 
     Ok(())
 }
 
-fn ensure_root() -> Result<()> {
+/// Returns whether we run setuid root for a user that isn't root, who mustn't get to choose
+/// what runs as root
+fn ensure_root() -> Result<bool> {
     if !unistd::geteuid().is_root() {
         bail!("We are not running as root!")
     } else if !unistd::getuid().is_root() {
         // We are not root, but we're suid root. Elevate.
         debug!("Running as setuid root. Continuing happily.");
         unistd::setuid(ROOT).expect("Failed to setuid(0), but we have euid 0!");
+        return Ok(true);
     }
-    Ok(())
+    Ok(false)
 }
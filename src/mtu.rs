@@ -0,0 +1,207 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::namespace::{nsenter_cmd, run_inside_namespace, Type};
+use crate::paths::Binary;
+use crate::pause::ensure_up;
+use crate::readiness::WARP_IFACE;
+use anyhow::{bail, Result};
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::{debug, info};
+
+/// What an ICMP echo adds to its payload over IPv4
+const IPV4_ICMP_OVERHEAD: u16 = 28;
+/// And what TCP and IPv4 headers take out of the MTU
+const IPV4_TCP_OVERHEAD: u16 = 40;
+/// The smallest MTU IPv4 hosts have to take, the search doesn't go lower
+const MIN_PAYLOAD: u16 = 576 - IPV4_ICMP_OVERHEAD;
+/// A full Ethernet frame, the tunnel can't carry more than its uplink
+const MAX_PAYLOAD: u16 = 1500 - IPV4_ICMP_OVERHEAD;
+/// What the veth gets when it's created
+pub const DEFAULT_VETH_MTU: u16 = 1500;
+
+#[derive(Debug, PartialEq)]
+pub struct Probed {
+    /// The largest ICMP payload that made it there and back
+    pub payload: u16,
+}
+
+impl Probed {
+    pub fn path_mtu(&self) -> u16 {
+        self.payload + IPV4_ICMP_OVERHEAD
+    }
+
+    /// Larger TCP segments would have to be fragmented, which DF forbids
+    pub fn mss(&self) -> u16 {
+        self.path_mtu() - IPV4_TCP_OVERHEAD
+    }
+}
+
+/// Whether an echo with this payload and Don't Fragment set gets an answer. Two are sent, so
+/// that one lost packet doesn't pass for a too large one.
+fn probe(ex: &dyn Executor, ns_pid: u32, target: IpAddr, payload: u16) -> Result<bool> {
+    let mut ping = ex.command(Binary::Ping)?;
+    ping.args(["-n", "-q", "-M", "do", "-c", "2", "-W", "1"])
+        .args(["-s", &payload.to_string()])
+        .arg(target.to_string());
+    let fits = ex
+        .status(
+            nsenter_cmd(ex, ns_pid, &[Type::Net], &ping)?
+                .stdout(Stdio::null())
+                .stderr(Stdio::null()),
+        )?
+        .success();
+    debug!(
+        "{payload} bytes {}",
+        if fits { "got through" } else { "didn't" }
+    );
+    Ok(fits)
+}
+
+/// Bisects the largest payload that gets through from inside the container, which routes
+/// through WARP's tunnel
+fn probe_path(ex: &dyn Executor, ns_pid: u32, target: IpAddr) -> Result<Probed> {
+    if !probe(ex, ns_pid, target, MIN_PAYLOAD)? {
+        bail!(
+            "{target} doesn't answer pings of {MIN_PAYLOAD} bytes through the tunnel, \
+             check that WARP is connected or probe another --target"
+        )
+    }
+    let (mut fits, mut too_large) = (MIN_PAYLOAD, MAX_PAYLOAD + 1);
+    while too_large - fits > 1 {
+        let size = fits + (too_large - fits) / 2;
+        if probe(ex, ns_pid, target, size)? {
+            fits = size;
+        } else {
+            too_large = size;
+        }
+    }
+    Ok(Probed { payload: fits })
+}
+
+fn recorded_path(base_dir: &Path) -> PathBuf {
+    base_dir.join("mtu")
+}
+
+/// The veth MTU the container should have, the one last applied until down
+pub fn expected(base_dir: &Path) -> Result<u16> {
+    match std::fs::read_to_string(recorded_path(base_dir)) {
+        Ok(mtu) => Ok(mtu.trim().parse()?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(DEFAULT_VETH_MTU),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn forget(base_dir: &Path) -> Result<()> {
+    match std::fs::remove_file(recorded_path(base_dir)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Sets the veth's MTU on both ends, and clamps the MSS of TCP connections leaving through the
+/// tunnel. Both last until down, the next up starts from the defaults again.
+fn apply(ex: &dyn Executor, config: &Config, probed: &Probed) -> Result<()> {
+    let base_dir = config.base_dir.as_path();
+    let net = &config.network;
+    let mtu = probed.path_mtu().to_string();
+    std::fs::write(recorded_path(base_dir), format!("{mtu}\n"))?;
+    ex.status(
+        ex.command(Binary::Ip)?
+            .args(["link", "set", "dev", &net.host_iface, "mtu", &mtu]),
+    )?
+    .exit_ok()?;
+    let in_ns = |cmd: &mut Command| run_inside_namespace(ex, base_dir, Type::Net, cmd);
+    in_ns(
+        ex.command(Binary::Ip)?
+            .args(["link", "set", "dev", &net.ns_iface, "mtu", &mtu]),
+    )?;
+
+    // A clamp from an earlier probe would apply first
+    let listed = in_ns(
+        ex.command(Binary::Iptables)?
+            .args(["-t", "mangle", "-S", "FORWARD"]),
+    )?;
+    for rule in String::from_utf8(listed.stdout)?.lines() {
+        if let Some(rule) = rule
+            .strip_prefix("-A ")
+            .filter(|rule| rule.contains("TCPMSS"))
+        {
+            in_ns(
+                ex.command(Binary::Iptables)?
+                    .args(["-t", "mangle", "-D"])
+                    .args(rule.split(' ')),
+            )?;
+        }
+    }
+    in_ns(
+        ex.command(Binary::Iptables)?
+            .args(["-t", "mangle", "-A", "FORWARD", "-o", WARP_IFACE])
+            .args(["-p", "tcp", "--tcp-flags", "SYN,RST", "SYN"])
+            .args(["-j", "TCPMSS", "--set-mss", &probed.mss().to_string()]),
+    )?;
+    Ok(())
+}
+
+fn report(probed: &Probed, applied: bool) -> String {
+    let mut report = String::new();
+    let _ = writeln!(
+        report,
+        "Largest payload through the tunnel: {} bytes",
+        probed.payload
+    );
+    let _ = writeln!(report, "Path MTU: {}", probed.path_mtu());
+    let verb = if applied { "Set" } else { "Suggested" };
+    let _ = writeln!(report, "{verb} veth MTU: {}", probed.path_mtu());
+    let _ = writeln!(report, "{verb} TCP MSS clamp: {}", probed.mss());
+    if !applied {
+        report += "Run again with --apply to set them until the next down\n";
+    }
+    report
+}
+
+/// Finds the path MTU through WARP with Don't Fragment pings from inside the container, then
+/// suggests or applies the veth MTU and MSS clamp that avoid fragmentation
+pub fn mtu_probe(
+    ex: &dyn Executor,
+    config: &Config,
+    target: IpAddr,
+    apply_it: bool,
+) -> Result<String> {
+    if target.is_ipv6() {
+        bail!("Only IPv4 targets can be probed")
+    }
+    let ns_pid = ensure_up(&config.base_dir)?;
+    info!("Probing the path MTU to {target}");
+    let probed = probe_path(ex, ns_pid, target)?;
+    if apply_it {
+        apply(ex, config, &probed)?;
+    }
+    Ok(report(&probed, apply_it))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::{MockExecutor, Reply};
+
+    #[test]
+    fn finds_largest_payload() {
+        let ex = MockExecutor::with_handler(|args| {
+            let size = args.iter().skip_while(|&arg| arg != "-s").nth(1);
+            match size.map(|size| size.parse::<u16>().unwrap()) {
+                Some(size) if size > 1252 => Reply::fail(),
+                _ => Reply::ok(""),
+            }
+        });
+        let probed = probe_path(&ex, 1, "1.1.1.1".parse().unwrap()).unwrap();
+        assert_eq!(probed, Probed { payload: 1252 });
+        assert_eq!(probed.path_mtu(), 1280);
+        assert_eq!(probed.mss(), 1240);
+
+        let silent = MockExecutor::with_handler(|_| Reply::fail());
+        assert!(probe_path(&silent, 1, "1.1.1.1".parse().unwrap()).is_err());
+    }
+}
@@ -1,32 +1,29 @@
-use anyhow::{anyhow, bail, Result};
-use std::collections::HashSet;
-use std::ffi::OsStr;
+use crate::cgroup;
+use crate::exec::Executor;
+use crate::logging;
+use crate::paths::{self, Binary};
+use crate::pidfd::PidFd;
+use crate::seccomp;
+use crate::service::ServiceConfig;
+use anyhow::{anyhow, bail, Context, Result};
+use bubblewarp::Container;
+pub use bubblewarp::Type;
+use nix::mount::MsFlags;
+use nix::sched::{self, CloneFlags};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::os::fd::{AsRawFd, OwnedFd};
 use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::process::{Child, Command, Output, Stdio};
-use strum::{EnumCount, IntoEnumIterator};
-use strum_macros::{EnumCount as EnumCountMacro, EnumIter};
-use tracing::trace;
-
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, EnumIter, EnumCountMacro)]
-pub enum Type {
-    User,
-    Pid,
-    Mount,
-    Net,
-}
-
-impl ToString for Type {
-    fn to_string(&self) -> String {
-        match self {
-            Type::User => "user",
-            Type::Pid => "pid",
-            Type::Mount => "mount",
-            Type::Net => "net",
-        }
-        .to_owned()
-    }
-}
+use std::process::{Child, Command, ExitStatus, Output, Stdio};
+use std::time::{Duration, Instant};
+use strum::IntoEnumIterator;
+use tracing::{debug, error, trace};
+
+/// Container IDs 0 to ID_MAP_SIZE-1 map to the same host IDs
+pub const ID_MAP_SIZE: u32 = 1200;
 
 #[derive(Debug, Eq, PartialEq, Clone)]
 pub enum Status {
@@ -36,72 +33,166 @@ pub enum Status {
 }
 
 pub fn base_dir() -> Result<PathBuf> {
-    let project_dirs = directories::ProjectDirs::from("", "", "bubblewarp")
-        .ok_or_else(|| anyhow!("Failed to get the path of our data directory"))?;
-    Ok(project_dirs.data_dir().to_owned())
+    Ok(Container::new()?.base_dir().to_owned())
 }
 
-pub fn status(base_dir: &Path) -> Result<Status> {
-    let mut mounted_set = HashSet::new();
+fn created_path(base_dir: &Path) -> PathBuf {
+    base_dir.join("namespaces.toml")
+}
 
-    for ns_type in Type::iter() {
-        if is_mounted(base_dir, ns_type)? {
-            mounted_set.insert(ns_type);
+/// The namespaces a container was created with, which include the optional ones it asked for
+#[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+struct Created {
+    namespaces: Vec<Type>,
+}
+
+pub fn record_created(base_dir: &Path, namespaces: Vec<Type>) -> Result<()> {
+    std::fs::write(
+        created_path(base_dir),
+        toml::to_string(&Created { namespaces })?,
+    )?;
+    Ok(())
+}
+
+/// Containers created before these were recorded have all but the optional namespaces
+pub fn created(base_dir: &Path) -> Result<Vec<Type>> {
+    match std::fs::read_to_string(created_path(base_dir)) {
+        Ok(data) => Ok(toml::from_str::<Created>(&data)
+            .with_context(|| format!("Invalid {}", created_path(base_dir).display()))?
+            .namespaces),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Ok(Type::iter().filter(|t| !t.is_optional()).collect())
         }
+        Err(e) => Err(e.into()),
     }
+}
 
-    if mounted_set.is_empty() {
-        Ok(Status::None)
-    } else if mounted_set.len() == Type::COUNT {
-        Ok(Status::Ready)
-    } else {
-        Ok(Status::Partial(mounted_set))
+pub fn forget_created(base_dir: &Path) -> Result<()> {
+    match std::fs::remove_file(created_path(base_dir)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
     }
 }
 
-pub fn is_mounted(base_dir: &Path, ns_type: Type) -> Result<bool> {
-    use procfs::process::Process;
+/// Our mount table, read once and then queried for every namespace
+pub struct Mounts {
+    /// Where namespaces are bind mounted
+    nsfs: HashSet<PathBuf>,
+    /// Directories bind mounted onto themselves
+    self_binds: HashSet<PathBuf>,
+}
 
-    let mut ns_mount_point = mount_point(base_dir, ns_type);
-    if !ns_mount_point.exists() {
-        return Ok(false);
-    }
-    if let Ok(canon) = ns_mount_point.canonicalize() {
-        ns_mount_point = canon;
+impl Mounts {
+    pub fn snapshot() -> Result<Self> {
+        let mut mounts = Mounts {
+            nsfs: HashSet::new(),
+            self_binds: HashSet::new(),
+        };
+        // The kernel lists canonical paths
+        for mount in procfs::process::Process::myself()?.mountinfo()? {
+            if mount.fs_type == "nsfs" && mount.mount_source.as_deref() == Some("nsfs") {
+                mounts.nsfs.insert(mount.mount_point);
+            } else if mount.root == mount.mount_point.to_string_lossy() {
+                mounts.self_binds.insert(mount.mount_point);
+            }
+        }
+        Ok(mounts)
     }
 
-    for mount in Process::myself()?.mountinfo()? {
-        if mount.fs_type != "nsfs" || mount.mount_source.as_deref() != Some("nsfs") {
-            continue;
+    pub fn is_mounted(&self, base_dir: &Path, ns_type: Type) -> bool {
+        let Ok(ns_mount_point) = mount_point(base_dir, ns_type).canonicalize() else {
+            return false;
+        };
+        let mounted = self.nsfs.contains(&ns_mount_point);
+        if mounted {
+            trace!(
+                "Found mounted persistent namespace at {}",
+                ns_mount_point.display()
+            );
         }
+        mounted
+    }
 
-        let dst = if let Ok(dst) = std::fs::canonicalize(&mount.mount_point) {
-            dst
+    pub fn status(&self, base_dir: &Path) -> Status {
+        let mounted_set: HashSet<Type> = Type::iter()
+            .filter(|&ns_type| self.is_mounted(base_dir, ns_type))
+            .collect();
+        let all_required = Type::iter()
+            .filter(|t| !t.is_optional())
+            .all(|t| mounted_set.contains(&t));
+        if mounted_set.is_empty() {
+            Status::None
+        } else if all_required {
+            Status::Ready
         } else {
-            continue;
-        };
-        if dst != ns_mount_point {
-            continue;
+            Status::Partial(mounted_set)
         }
+    }
 
-        trace!(
-            "Found mounted persistent namespace at {}",
-            ns_mount_point.display()
-        );
-        return Ok(true);
+    pub fn has_self_bind_mount(&self, dir: &Path) -> bool {
+        dir.canonicalize()
+            .is_ok_and(|dir| self.self_binds.contains(&dir))
     }
-    Ok(false)
+}
+
+pub fn status(base_dir: &Path) -> Result<Status> {
+    Ok(Mounts::snapshot()?.status(base_dir))
+}
+
+pub fn is_mounted(base_dir: &Path, ns_type: Type) -> Result<bool> {
+    Ok(Mounts::snapshot()?.is_mounted(base_dir, ns_type))
 }
 
 pub fn mount_point(base_dir: &Path, ns_type: Type) -> PathBuf {
-    let mut path = base_dir.to_owned();
-    match ns_type {
-        Type::User => path.push("user"),
-        Type::Pid => path.push("pid"),
-        Type::Mount => path.push("mount"),
-        Type::Net => path.push("net"),
+    Container::with_base_dir(base_dir).mount_point(ns_type)
+}
+
+/// The mount point of a namespace, or with None nsenter's flags for all those mounted, like
+/// `--user=<path> --pid=<path> ...`. Fails for namespaces that aren't mounted, so that nsenter
+/// doesn't join the empty file left behind instead.
+pub fn ns_path(base_dir: &Path, ns_type: Option<Type>) -> Result<String> {
+    let mounts = Mounts::snapshot()?;
+    let path = |ns_type| match mounts.is_mounted(base_dir, ns_type) {
+        true => Ok(mount_point(base_dir, ns_type).display().to_string()),
+        false => Err(anyhow!(
+            "The {ns_type} namespace isn't mounted, is the container up?"
+        )),
     };
-    path
+    if let Some(ns_type) = ns_type {
+        return path(ns_type);
+    }
+    let mut flags = Vec::new();
+    for ns_type in Type::iter() {
+        if ns_type.is_optional() && !mounts.is_mounted(base_dir, ns_type) {
+            continue;
+        }
+        flags.push(format!("--{ns_type}={}", path(ns_type)?));
+    }
+    Ok(flags.join(" "))
+}
+
+/// Variables of ours passed on inside, the rest of our environment is the caller's business
+const KEPT_ENV: &[&str] = &["TZ"];
+
+/// What runs inside gets a fixed environment instead of whatever we were started with. Root's
+/// PATH lacks the sbin directories on some hosts, and the caller's variables don't belong inside.
+fn set_container_env(wrapper: &mut Command, cmd: &Command) {
+    wrapper.env_clear();
+    wrapper.env("PATH", paths::FALLBACK_DIRS.join(":"));
+    wrapper.env("HOME", "/root");
+    // warp-cli's output gets parsed
+    wrapper.env("LANG", "C.UTF-8");
+    for var in KEPT_ENV {
+        if let Some(value) = std::env::var_os(var) {
+            wrapper.env(var, value);
+        }
+    }
+    for (var, value) in cmd.get_envs() {
+        match value {
+            Some(value) => wrapper.env(var, value),
+            None => wrapper.env_remove(var),
+        };
+    }
 }
 
 fn wrap_cmd(wrapper: &mut Command, cmd: &Command) {
@@ -110,18 +201,52 @@ fn wrap_cmd(wrapper: &mut Command, cmd: &Command) {
     if let Some(cwd) = cmd.get_current_dir() {
         wrapper.current_dir(cwd);
     }
+    set_container_env(wrapper, cmd);
 }
 
-pub fn run_inside_namespace(base_dir: &Path, ns_type: Type, cmd: &Command) -> Result<Output> {
-    let mut ns_cmd = Command::new("nsenter");
+/// Runs f with the calling thread in the container's mount namespace, for mounting with syscalls.
+/// Only the mount namespace is joined, so f keeps our privileges over the container.
+pub fn with_mount_namespace<T>(base_dir: &Path, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let own = File::open("/proc/thread-self/ns/mnt")?;
+    let container = File::open(mount_point(base_dir, Type::Mount))?;
+    let cwd = std::env::current_dir()?;
+    // Threads sharing their filesystem info with others can't switch mount namespaces
+    sched::unshare(CloneFlags::CLONE_FS)?;
+    sched::setns(container.as_raw_fd(), CloneFlags::CLONE_NEWNS)
+        .context("Joining the container's mount namespace")?;
+    let result = f();
+    let returned = sched::setns(own.as_raw_fd(), CloneFlags::CLONE_NEWNS)
+        .context("Returning to our own mount namespace");
+    if let Err(e) = returned {
+        // What f ran into came first, and is likely why
+        return match result {
+            Ok(_) => Err(e),
+            Err(first) => {
+                error!("{e:#}");
+                Err(first)
+            }
+        };
+    }
+    // Joining a mount namespace moves to its root directory
+    std::env::set_current_dir(cwd)?;
+    result
+}
+
+pub fn run_inside_namespace(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    ns_type: Type,
+    cmd: &Command,
+) -> Result<Output> {
+    let mut ns_cmd = ex.command(Binary::Nsenter)?;
     ns_cmd.arg(format!(
         "--{}={}",
-        ns_type.to_string(),
+        ns_type,
         mount_point(base_dir, ns_type).to_string_lossy()
     ));
     wrap_cmd(&mut ns_cmd, cmd);
 
-    let out = ns_cmd.output()?;
+    let out = ex.output(&mut ns_cmd)?;
     if !out.status.success() {
         bail!(
             "Failed to run command {} inside namespaces, returned {}\nstdout: {}\nstderr: {}",
@@ -134,16 +259,121 @@ pub fn run_inside_namespace(base_dir: &Path, ns_type: Type, cmd: &Command) -> Re
     Ok(out)
 }
 
-pub fn spawn_inside_all_namespaces(cmd: &Command, ns_pid: u32) -> Result<Child> {
-    let mut ns_cmd = Command::new("nsenter");
+/// Where a program is inside the container. That's the same path as on the host,
+/// unless the container runs in an image rootfs that keeps it somewhere else.
+pub fn which_inside(ex: &dyn Executor, ns_pid: u32, bin: Binary) -> Result<PathBuf> {
+    let root = PathBuf::from(format!("/proc/{ns_pid}/root"));
+    let inside = |path: &Path| root.join(path.strip_prefix("/").unwrap_or(path));
+    let host_path = ex.which(bin);
+    if let Ok(path) = &host_path {
+        if inside(path).exists() {
+            return host_path;
+        }
+    }
+    let found = paths::FALLBACK_DIRS.iter().find_map(|dir| {
+        bin.file_names()
+            .iter()
+            .map(|name| Path::new(dir).join(name))
+            .find(|path| inside(path).exists())
+    });
+    match found {
+        Some(path) => Ok(path),
+        None => host_path,
+    }
+}
+
+/// Services get hardened, one-off commands like mount run with the privileges of namespace root
+pub fn spawn_inside_all_namespaces(
+    ex: &dyn Executor,
+    cmd: &Command,
+    ns_pid: u32,
+    service: Option<&ServiceConfig>,
+) -> Result<Child> {
+    let mut ns_cmd = ex.command(Binary::Nsenter)?;
+    if let Some(service) = service {
+        seccomp::apply_on_exec(&mut ns_cmd, service.seccomp);
+    }
     ns_cmd.args(["-a", "-t", &ns_pid.to_string()]);
+    // nsenter joins the cgroup namespace, but leaves the process in our own cgroup
+    if let Some(cgroup) = cgroup::of_process(ns_pid)? {
+        cgroup::join_on_exec(&mut ns_cmd, &cgroup)?;
+    }
     ns_cmd.stdout(Stdio::null());
     ns_cmd.stderr(Stdio::null());
+    // Services log under their own name, when we log to the journal
+    if service.is_some() {
+        let name = Path::new(cmd.get_program()).file_name().unwrap_or_default();
+        if let Some(journal) = logging::journal_stream(&name.to_string_lossy()) {
+            ns_cmd.stdout(OwnedFd::from(journal.try_clone()?));
+            ns_cmd.stderr(OwnedFd::from(journal));
+        }
+    }
+    if let Some(service) = service {
+        ns_cmd
+            .arg(which_inside(ex, ns_pid, Binary::Setpriv)?)
+            .args(service.setpriv_args()?)
+            .arg("--");
+    }
     wrap_cmd(&mut ns_cmd, cmd);
-    let child = ns_cmd.spawn()?;
+    let child = ex.spawn(&mut ns_cmd)?;
     Ok(child)
 }
 
+/// nsenter running a command in some of the container's namespaces, all of them when `types`
+/// is empty. Outside the mount namespace, it sees the host's files and keeps our environment.
+pub fn nsenter_cmd(
+    ex: &dyn Executor,
+    ns_pid: u32,
+    types: &[Type],
+    cmd: &Command,
+) -> Result<Command> {
+    let mut ns_cmd = ex.command(Binary::Nsenter)?;
+    ns_cmd.args(["-t", &ns_pid.to_string()]);
+    let joins_mount = types.is_empty() || types.contains(&Type::Mount);
+    if types.is_empty() {
+        ns_cmd.arg("-a");
+    }
+    for ns_type in types {
+        ns_cmd.arg(format!("--{ns_type}"));
+    }
+    if joins_mount {
+        // The container's root may be a rootfs of its own
+        ns_cmd.args(["--root", "--wd"]);
+        wrap_cmd(&mut ns_cmd, cmd);
+    } else {
+        ns_cmd.arg(cmd.get_program()).args(cmd.get_args());
+    }
+    Ok(ns_cmd)
+}
+
+/// Runs a command with the terminal in some of the container's namespaces, see nsenter_cmd
+pub fn exec_inside(
+    ex: &dyn Executor,
+    ns_pid: u32,
+    types: &[Type],
+    cmd: &Command,
+) -> Result<ExitStatus> {
+    Ok(ex.status(&mut nsenter_cmd(ex, ns_pid, types, cmd)?)?)
+}
+
+/// nsenter forks to enter the PID namespace, what it runs is its only child
+pub fn nsenter_child(nsenter: &Child, timeout: Duration) -> Result<i32> {
+    let nsenter_fd = PidFd::open(nsenter.id() as i32)?;
+    let nsenter = procfs::process::Process::new(nsenter.id() as i32)?;
+    let start = Instant::now();
+    loop {
+        if let Some(child) = nsenter.task_main_thread()?.children()?.first() {
+            return Ok(*child as i32);
+        }
+        if start.elapsed() > timeout {
+            bail!("Timed out waiting for nsenter to start its command")
+        }
+        if nsenter_fd.wait_exit(Duration::from_millis(10))? {
+            bail!("nsenter exited without starting its command")
+        }
+    }
+}
+
 pub fn all_ns_processes(base_dir: &Path) -> Result<impl Iterator<Item = procfs::process::Process>> {
     let pid_ns_id = std::fs::metadata(mount_point(base_dir, Type::Pid))?.ino();
     Ok(procfs::process::all_processes()?.filter_map(move |p| {
@@ -158,3 +388,119 @@ pub fn all_ns_processes(base_dir: &Path) -> Result<impl Iterator<Item = procfs::
         }
     }))
 }
+
+/// Mounts the namespaces of the container's init that are missing from the base dir, after
+/// checking that those still mounted are its own. Optional namespaces are left out, since init
+/// has the host's when the container was created without them.
+pub fn mount_missing(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    mounted: &HashSet<Type>,
+    init: &procfs::process::Process,
+) -> Result<()> {
+    mount_missing_from(ex, base_dir, mounted, &init.namespaces()?)
+}
+
+fn mount_missing_from(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    mounted: &HashSet<Type>,
+    namespaces: &HashMap<OsString, procfs::process::Namespace>,
+) -> Result<()> {
+    for ns_type in Type::iter() {
+        // Kernels before 5.6 have no time namespace to look up
+        if ns_type.is_optional() && !mounted.contains(&ns_type) {
+            continue;
+        }
+        let ns = namespaces
+            .get(OsStr::new(&ns_type.proc_name()))
+            .with_context(|| format!("The container's init has no {ns_type} namespace"))?;
+        let path = mount_point(base_dir, ns_type);
+        if mounted.contains(&ns_type) {
+            if std::fs::metadata(&path)?.ino() != ns.identifier {
+                bail!(
+                    "{} isn't the namespace of the container's init",
+                    path.display()
+                )
+            }
+        } else {
+            debug!("Mounting the container's {ns_type} namespace again");
+            if !path.exists() {
+                File::create(&path)?;
+            }
+            ex.mount(Some(&ns.path), &path, None, MsFlags::MS_BIND, None)
+                .with_context(|| format!("Mounting {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::{Call, MockExecutor};
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn wrapped_commands_get_container_env() {
+        let mut cmd = Command::new("warp-cli");
+        cmd.arg("status").env("WARP_DEBUG", "1");
+        let mut wrapper = Command::new("nsenter");
+        wrap_cmd(&mut wrapper, &cmd);
+        let envs: Vec<_> = wrapper.get_envs().collect();
+        assert!(envs.contains(&(
+            OsStr::new("PATH"),
+            Some(OsStr::new(
+                "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
+            ))
+        )));
+        assert!(envs.contains(&(OsStr::new("WARP_DEBUG"), Some(OsStr::new("1")))));
+    }
+
+    #[test]
+    fn records_created_namespaces() {
+        let dir = TempDir::new("created");
+        assert!(!created(&dir).unwrap().contains(&Type::Time));
+        record_created(&dir, vec![Type::User, Type::Net, Type::Time]).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(created_path(&dir)).unwrap(),
+            "namespaces = [\"user\", \"net\", \"time\"]\n"
+        );
+        assert_eq!(created(&dir).unwrap(), [Type::User, Type::Net, Type::Time]);
+        forget_created(&dir).unwrap();
+        assert_eq!(created(&dir).unwrap().len(), Type::iter().count() - 1);
+    }
+
+    #[test]
+    fn remounts_without_a_time_namespace() {
+        let ex = MockExecutor::new();
+        let dir = TempDir::new("mount-missing");
+        // As on kernels before 5.6, with every namespace mounted but net
+        let mut namespaces = HashMap::new();
+        let mut mounted = HashSet::new();
+        for ns_type in Type::iter().filter(|t| !t.is_optional()) {
+            let path = mount_point(&dir, ns_type);
+            File::create(&path).unwrap();
+            namespaces.insert(
+                OsString::from(ns_type.proc_name()),
+                procfs::process::Namespace {
+                    ns_type: ns_type.proc_name().into(),
+                    path: path.clone(),
+                    identifier: std::fs::metadata(&path).unwrap().ino(),
+                    device_id: 0,
+                },
+            );
+            if ns_type != Type::Net {
+                mounted.insert(ns_type);
+            }
+        }
+        mount_missing_from(&ex, &dir, &mounted, &namespaces).unwrap();
+        assert_eq!(
+            ex.calls(),
+            [Call::Mount {
+                target: mount_point(&dir, Type::Net),
+                flags: MsFlags::MS_BIND,
+            }]
+        );
+    }
+}
@@ -1,120 +1,602 @@
+use crate::exec::Executor;
+use crate::firewall::{self, FirewallConfig, NatConfig};
 use crate::namespace::{mount_point, run_inside_namespace, Type};
-use anyhow::{bail, Result};
-use std::path::Path;
-use std::process::Command;
-use tracing::debug;
+use crate::paths::Binary;
+use anyhow::{anyhow, bail, Context, Result};
+use nix::net::if_::InterfaceFlags;
+use nix::sched::{self, CloneFlags};
+use std::fs::File;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use tracing::{debug, info, warn};
 
-fn parse_iface_name(ip_route_default_stdout: Vec<u8>) -> Result<String> {
-    let out = String::from_utf8(ip_route_default_stdout)?;
-    let parts: Vec<&str> = out.split(' ').collect();
-    if out.is_empty() || parts.is_empty() {
-        bail!("Empty stdout when running ip route show default!")
+/// The veth link between the host and the container
+#[derive(Debug, Clone)]
+pub struct Network {
+    pub host_iface: String,
+    pub ns_iface: String,
+    pub host_addr: Ipv4Addr,
+    pub ns_addr: Ipv4Addr,
+    pub prefix_len: u8,
+}
+
+impl Default for Network {
+    fn default() -> Self {
+        Network {
+            host_iface: "veth-warp".to_owned(),
+            ns_iface: "veth-warp-ns".to_owned(),
+            host_addr: Ipv4Addr::new(10, 200, 0, 1),
+            ns_addr: Ipv4Addr::new(10, 200, 0, 2),
+            prefix_len: 24,
+        }
+    }
+}
+
+impl Network {
+    pub fn host_cidr(&self) -> String {
+        format!("{}/{}", self.host_addr, self.prefix_len)
+    }
+
+    pub fn ns_cidr(&self) -> String {
+        format!("{}/{}", self.ns_addr, self.prefix_len)
     }
-    let pos_dev = parts.iter().position(|&e| e == "dev");
-    if parts[0] != "default" || pos_dev.is_none() || parts.len() <= pos_dev.unwrap() + 1 {
-        bail!("Unexpected output from ip route show default: {}", out)
+
+    /// The network address of the veth subnet, as iptables prints it back
+    pub fn subnet(&self) -> String {
+        subnet(self.host_addr, self.prefix_len)
     }
-    Ok(parts[pos_dev.unwrap() + 1].to_owned())
+}
+
+pub fn netmask(prefix_len: u8) -> u32 {
+    u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0)
+}
+
+/// The network address of the subnet addr is in, with its prefix length
+pub fn subnet(addr: Ipv4Addr, prefix_len: u8) -> String {
+    let net = Ipv4Addr::from(u32::from(addr) & netmask(prefix_len));
+    format!("{}/{}", net, prefix_len)
+}
+
+/// Whether a line of `ip -o link show` has UP among the interface's flags
+pub fn link_is_up(link: &str) -> bool {
+    link.split_whitespace()
+        .find(|word| word.starts_with('<'))
+        .is_some_and(|flags| flags.trim_matches(['<', '>']).split(',').any(|f| f == "UP"))
+}
+
+/// Whether a /proc/net/route table has an IPv4 default route, which has 0 for destination and mask
+fn has_default_route(route_table: &str) -> bool {
+    route_table.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        fields.len() > 7 && fields[1] == "00000000" && fields[7] == "00000000"
+    })
+}
+
+/// Runs `f` on a thread in the container's network namespace, which needs neither a binary nor
+/// a process running in there. Sockets it opens stay in the namespace.
+pub fn in_container_net<T: Send + 'static>(
+    base_dir: &Path,
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let net_ns = File::open(mount_point(base_dir, Type::Net))?;
+    std::thread::spawn(move || -> Result<T> {
+        sched::setns(net_ns.as_raw_fd(), CloneFlags::CLONE_NEWNET)?;
+        f()
+    })
+    .join()
+    .map_err(|_| anyhow!("Thread in the container's network namespace panicked"))?
 }
 
 pub fn container_has_default_route(base_dir: &Path) -> Result<bool> {
-    let out = run_inside_namespace(
-        base_dir,
-        Type::Net,
-        Command::new("ip").args(["route", "show", "default"]),
-    )?;
-    Ok(!out.stdout.is_empty())
+    let route_table = in_container_net(base_dir, || {
+        Ok(std::fs::read_to_string("/proc/thread-self/net/route")?)
+    })?;
+    Ok(has_default_route(&route_table))
 }
 
-pub fn default_route_iface_name() -> Result<String> {
-    let out = Command::new("ip")
-        .args(["route", "show", "default"])
-        .output()?;
-    out.status.exit_ok()?;
-    parse_iface_name(out.stdout)
+/// The uplink the host's IPv4 traffic leaves through, the one with the lowest metric
+pub fn default_route_iface_name(ex: &dyn Executor) -> Result<String> {
+    ex.default_routes()?
+        .into_iter()
+        .find(|route| !route.ipv6)
+        .map(|route| route.iface)
+        .ok_or_else(|| anyhow!("The host has no IPv4 default route"))
 }
 
-pub fn setup_private_networking(base_dir: &Path) -> Result<()> {
+/// The uplinks to forward the container's traffic through, firewall.uplinks if set
+pub fn uplinks(ex: &dyn Executor, firewall: &FirewallConfig) -> Result<Vec<String>> {
+    match firewall.uplinks.is_empty() {
+        true => Ok(vec![default_route_iface_name(ex)?]),
+        false => Ok(firewall.uplinks.clone()),
+    }
+}
+
+/// The uplinks the forward was set up for, one per line, for down to clean up after
+fn uplinks_path(base_dir: &Path) -> PathBuf {
+    base_dir.join("uplinks")
+}
+
+/// The uplinks setup_external_forward recorded, which may no longer be in the config
+pub fn recorded_uplinks(base_dir: &Path) -> Result<Vec<String>> {
+    match std::fs::read_to_string(uplinks_path(base_dir)) {
+        Ok(data) => Ok(data.lines().map(str::to_owned).collect()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn forget_uplinks(base_dir: &Path) -> Result<()> {
+    match std::fs::remove_file(uplinks_path(base_dir)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+pub fn setup_private_networking(ex: &dyn Executor, base_dir: &Path, net: &Network) -> Result<()> {
     debug!("Making sure loopback interface is up");
     run_inside_namespace(
+        ex,
         base_dir,
         Type::Net,
-        Command::new("ip").args(["link", "set", "dev", "lo", "up"]),
+        ex.command(Binary::Ip)?
+            .args(["link", "set", "dev", "lo", "up"]),
     )?;
 
-    if nix::ifaddrs::getifaddrs()?.any(|dev| dev.interface_name == "veth-warp") {
-        debug!("veth-warp iface seems to already exist, not re-creating it");
-        return Ok(());
-    }
-
-    debug!("Setting up veth pair for private networking");
-    let net_ns = mount_point(base_dir, Type::Net);
-    Command::new("ip")
-        .args(["link", "add", "veth-warp", "type", "veth"])
-        .args(["peer", "name", "veth-warp-ns"])
-        .args(["netns", net_ns.to_string_lossy().as_ref()])
-        .status()?
-        .exit_ok()?;
-    Command::new("ip")
-        .args(["addr", "add", "10.200.0.1/24", "dev", "veth-warp"])
-        .status()?
-        .exit_ok()?;
-    Command::new("ip")
-        .args(["link", "set", "veth-warp", "up"])
-        .status()?
+    if nix::ifaddrs::getifaddrs()?.any(|dev| dev.interface_name == net.host_iface) {
+        debug!(
+            "{} iface seems to already exist, only checking its addresses",
+            net.host_iface
+        );
+    } else {
+        debug!("Setting up veth pair for private networking");
+        let net_ns = mount_point(base_dir, Type::Net);
+        ex.status(
+            ex.command(Binary::Ip)?
+                .args(["link", "add", &net.host_iface, "type", "veth"])
+                .args(["peer", "name", &net.ns_iface])
+                .args(["netns", net_ns.to_string_lossy().as_ref()]),
+        )?
         .exit_ok()?;
+    }
+    // Replacing is a no-op for addresses already there, and puts back those removed since
+    ex.status(ex.command(Binary::Ip)?.args([
+        "addr",
+        "replace",
+        &net.host_cidr(),
+        "dev",
+        &net.host_iface,
+    ]))?
+    .exit_ok()?;
+    ex.status(
+        ex.command(Binary::Ip)?
+            .args(["link", "set", &net.host_iface, "up"]),
+    )?
+    .exit_ok()?;
 
     run_inside_namespace(
+        ex,
         base_dir,
         Type::Net,
-        Command::new("ip").args(["addr", "add", "10.200.0.2/24", "dev", "veth-warp-ns"]),
+        ex.command(Binary::Ip)?
+            .args(["addr", "replace", &net.ns_cidr(), "dev", &net.ns_iface]),
     )?;
     run_inside_namespace(
+        ex,
         base_dir,
         Type::Net,
-        Command::new("ip").args(["link", "set", "veth-warp-ns", "up"]),
+        ex.command(Binary::Ip)?
+            .args(["link", "set", &net.ns_iface, "up"]),
     )?;
     Ok(())
 }
 
-pub fn setup_external_networking(base_dir: &Path) -> Result<()> {
-    if container_has_default_route(base_dir)? {
-        debug!(
-            "Container appears to already have default route, keeping external networking as-is"
-        );
-        return Ok(());
+/// Without `forward`, the container only gets its default route and forwarding it is left to
+/// the host's own firewall. For a container that already has its route, the forward's rules that
+/// went missing are added back.
+pub fn setup_external_networking(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    net: &Network,
+    firewall: &FirewallConfig,
+    nat: &NatConfig,
+    forward: bool,
+) -> Result<()> {
+    if !forward {
+        if container_has_default_route(base_dir)? {
+            debug!("Container already has its default route, and the forward isn't ours");
+            return Ok(());
+        }
+        debug!("Not setting up the external forward, as asked");
+        return add_default_route_inside(ex, base_dir, net);
     }
+    let uplinks = uplinks(ex, firewall)?;
+    setup_external_forward(ex, base_dir, net, firewall, nat, &uplinks)?;
+    Ok(())
+}
 
-    let iface_name = default_route_iface_name()?;
-    setup_external_forward(base_dir, &iface_name)?;
+pub fn setup_external_forward(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    net: &Network,
+    firewall: &FirewallConfig,
+    nat: &NatConfig,
+    uplinks: &[String],
+) -> Result<()> {
+    debug!("Setting up external forward for {}", uplinks.join(", "));
+    // Recorded first, so down finds the rules even if adding some of them fails
+    let mut recorded = recorded_uplinks(base_dir)?;
+    for uplink in uplinks {
+        if !recorded.contains(uplink) {
+            recorded.push(uplink.clone());
+        }
+    }
+    let data: String = recorded
+        .iter()
+        .map(|uplink| format!("{uplink}\n"))
+        .collect();
+    std::fs::write(uplinks_path(base_dir), data)?;
+    firewall::add_forward(ex, firewall, nat, net, uplinks)?;
+    add_default_route_inside(ex, base_dir, net)
+}
+
+/// Redirects connections to the container's address to a listener on its loopback, where warp-svc
+/// keeps its proxy and resolver
+pub fn forward_to_loopback_inside(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    net: &Network,
+    proto: &str,
+    port: u16,
+    destination: SocketAddr,
+) -> Result<()> {
+    debug!("Forwarding {proto} port {port} to {destination} inside the container");
+    let in_ns = |cmd: &mut Command| run_inside_namespace(ex, base_dir, Type::Net, cmd);
+    // Routing to 127.0.0.0/8 after DNAT is otherwise refused for packets from outside
+    in_ns(ex.command(Binary::Sysctl)?.args([
+        "-w",
+        &format!("net.ipv4.conf.{}.route_localnet=1", net.ns_iface),
+    ]))?;
+    let port = port.to_string();
+    let destination = destination.to_string();
+    let rule = [
+        "PREROUTING",
+        "-i",
+        &net.ns_iface,
+        "-p",
+        proto,
+        "--dport",
+        &port,
+        "-j",
+        "DNAT",
+        "--to-destination",
+        &destination,
+    ];
+    // Already there when up runs again on a working container
+    let exists = in_ns(
+        ex.command(Binary::Iptables)?
+            .args(["-t", "nat", "-C"])
+            .args(rule),
+    )
+    .is_ok();
+    if !exists {
+        in_ns(
+            ex.command(Binary::Iptables)?
+                .args(["-t", "nat", "-A"])
+                .args(rule),
+        )?;
+    }
     Ok(())
 }
 
-pub fn setup_external_forward(base_dir: &Path, iface_name: &str) -> Result<()> {
-    debug!("Setting up external forward for interface {iface_name}");
-    Command::new("/usr/sbin/iptables")
-        .args(["-t", "nat", "-A", "POSTROUTING", "-s", "10.200.0.2/24"])
-        .args(["-o", iface_name, "-j", "MASQUERADE"])
-        .status()?
-        .exit_ok()?;
-    Command::new("/usr/sbin/iptables")
-        .args(["-A", "FORWARD", "-i", iface_name, "-o", "veth-warp"])
-        .args(["-j", "ACCEPT"])
-        .status()?
+/// The routing table and rule priority that send the container's traffic out the egress
+/// interface, clear of wg-quick's 51820 and its rules
+const EGRESS_TABLE: &str = "5301";
+const EGRESS_RULE_PRIORITY: &str = "5300";
+/// WARP's own packets, a 1280 bytes tunnel MTU and its encapsulation
+const WARP_OUTER_MTU: u32 = 1360;
+
+/// What route_through_egress recorded, for down to remove
+fn egress_path(base_dir: &Path) -> PathBuf {
+    base_dir.join("egress-iface")
+}
+
+/// Checks that an interface can carry the container's traffic, returning the gateway to use
+/// through it. Point-to-point links like WireGuard's need none.
+pub fn check_egress(ex: &dyn Executor, iface: &str) -> Result<Option<IpAddr>> {
+    let addrs: Vec<_> = nix::ifaddrs::getifaddrs()?
+        .filter(|dev| dev.interface_name == iface)
+        .collect();
+    let Some(dev) = addrs.first() else {
+        bail!("There is no {iface} interface to egress through")
+    };
+    if !dev.flags.contains(InterfaceFlags::IFF_UP) {
+        bail!("{iface} is down, bring it up before egressing through it")
+    }
+    let has_ipv4 = addrs
+        .iter()
+        .any(|dev| dev.address.is_some_and(|a| a.as_sockaddr_in().is_some()));
+    if !has_ipv4 {
+        bail!("{iface} has no IPv4 address to masquerade the container's traffic with")
+    }
+    let route = ex
+        .default_routes()?
+        .into_iter()
+        .find(|route| !route.ipv6 && route.iface == iface);
+    let gateway = match route {
+        Some(route) => route.gateway,
+        None if dev.flags.contains(InterfaceFlags::IFF_POINTOPOINT) => None,
+        None => bail!("{iface} has no default route and isn't point-to-point, so there is no route to send the container's traffic through it"),
+    };
+    let mtu_path = Path::new("/sys/class/net").join(iface).join("mtu");
+    if let Ok(mtu) = std::fs::read_to_string(mtu_path) {
+        let mtu: u32 = mtu.trim().parse()?;
+        if mtu < WARP_OUTER_MTU {
+            warn!("{iface} has an MTU of {mtu}, WARP's packets of up to {WARP_OUTER_MTU} bytes will be fragmented on it, which slows the tunnel down");
+        }
+    }
+    Ok(gateway)
+}
+
+/// Sends the container's traffic out the egress interface with a routing table of its own, since
+/// the host's default route goes elsewhere
+pub fn route_through_egress(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    net: &Network,
+    iface: &str,
+) -> Result<()> {
+    let gateway = check_egress(ex, iface)?;
+    debug!("Routing the container's traffic through {iface}");
+    std::fs::write(egress_path(base_dir), iface)?;
+    let mut route = ex.command(Binary::Ip)?;
+    route.args(["-4", "route", "replace", "default"]);
+    if let Some(gateway) = gateway {
+        route.args(["via", &gateway.to_string()]);
+    }
+    ex.status(route.args(["dev", iface, "table", EGRESS_TABLE]))?
         .exit_ok()?;
-    Command::new("/usr/sbin/iptables")
-        .args(["-A", "FORWARD", "-o", iface_name, "-i", "veth-warp"])
-        .args(["-j", "ACCEPT"])
-        .status()?
+    // Replaced, in case up runs again
+    remove_egress_rule(ex)?;
+    ex.status(ex.command(Binary::Ip)?.args([
+        "-4",
+        "rule",
+        "add",
+        "from",
+        &net.subnet(),
+        "lookup",
+        EGRESS_TABLE,
+        "priority",
+        EGRESS_RULE_PRIORITY,
+    ]))?
+    .exit_ok()?;
+    Ok(())
+}
+
+fn remove_egress_rule(ex: &dyn Executor) -> Result<()> {
+    let _ = ex.status(
+        ex.command(Binary::Ip)?
+            .args(["-4", "rule", "del", "priority", EGRESS_RULE_PRIORITY])
+            .stderr(Stdio::null()),
+    )?;
+    Ok(())
+}
+
+/// Undoes route_through_egress, if it ran
+pub fn remove_egress_route(ex: &dyn Executor, base_dir: &Path) -> Result<()> {
+    if !egress_path(base_dir).exists() {
+        return Ok(());
+    }
+    remove_egress_rule(ex)?;
+    let _ = ex.status(
+        ex.command(Binary::Ip)?
+            .args(["-4", "route", "flush", "table", EGRESS_TABLE])
+            .stderr(Stdio::null()),
+    )?;
+    std::fs::remove_file(egress_path(base_dir))?;
+    Ok(())
+}
+
+/// Strict reverse path filtering, which drops packets arriving where the reply wouldn't leave
+const RP_FILTER_STRICT: u8 = 1;
+const RP_FILTER_LOOSE: u8 = 2;
+
+/// What loosen_rp_filter changed, one `iface value` line each with the value it had before
+fn rp_filter_state(base_dir: &Path) -> PathBuf {
+    base_dir.join("rp-filter")
+}
+
+fn rp_filter_path(iface: &str) -> PathBuf {
+    Path::new("/proc/sys/net/ipv4/conf")
+        .join(iface)
+        .join("rp_filter")
+}
+
+fn read_rp_filter(iface: &str) -> Result<u8> {
+    let path = rp_filter_path(iface);
+    let value =
+        std::fs::read_to_string(&path).with_context(|| format!("Reading {}", path.display()))?;
+    Ok(value.trim().parse()?)
+}
+
+fn set_rp_filter(ex: &dyn Executor, iface: &str, value: u8) -> Result<()> {
+    // Slashes, since interface names can have dots
+    let setting = format!("net/ipv4/conf/{iface}/rp_filter={value}");
+    ex.status(ex.command(Binary::Sysctl)?.args(["-q", "-w", &setting]))?
         .exit_ok()?;
+    Ok(())
+}
+
+/// The interfaces to make loose, out of those the container's traffic crosses. The kernel uses
+/// the highest of `all` and the interface's own setting, so a strict `all` makes every one strict
+/// but doesn't stop an interface from being loose.
+fn strict_ifaces(all: u8, ifaces: &[(&str, u8)]) -> Vec<String> {
+    ifaces
+        .iter()
+        .filter(|&&(_, value)| value.max(all) == RP_FILTER_STRICT)
+        .map(|&(iface, _)| iface.to_owned())
+        .collect()
+}
 
+/// Makes reverse path filtering loose on the veth and uplinks where it is strict, so the
+/// container's return traffic isn't dropped as martian. The original values are recorded for
+/// restore_rp_filter.
+pub fn loosen_rp_filter(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    net: &Network,
+    uplinks: &[String],
+) -> Result<()> {
+    let mut ifaces = Vec::new();
+    for iface in std::iter::once(&net.host_iface).chain(uplinks) {
+        // A fallback uplink may be missing until it is needed
+        if rp_filter_path(iface).exists() {
+            ifaces.push((iface.as_str(), read_rp_filter(iface)?));
+        }
+    }
+    let all = read_rp_filter("all")?;
+    let strict = strict_ifaces(all, &ifaces);
+    if strict.is_empty() {
+        debug!("Reverse path filtering is loose or off where the container's traffic goes");
+        return Ok(());
+    }
+    let mut recorded = std::fs::read_to_string(rp_filter_state(base_dir)).unwrap_or_default();
+    for iface in strict {
+        // Already recorded when up runs again, and that value is the original one
+        if !recorded
+            .lines()
+            .any(|line| line.split(' ').next() == Some(&iface))
+        {
+            recorded += &format!("{iface} {}\n", read_rp_filter(&iface)?);
+            std::fs::write(rp_filter_state(base_dir), &recorded)?;
+        }
+        info!("Making reverse path filtering on {iface} loose, it would drop the container's return traffic");
+        set_rp_filter(ex, &iface, RP_FILTER_LOOSE)?;
+    }
+    Ok(())
+}
+
+/// Puts back what loosen_rp_filter changed. The veth's setting went away with it.
+pub fn restore_rp_filter(ex: &dyn Executor, base_dir: &Path) -> Result<()> {
+    let state = rp_filter_state(base_dir);
+    let recorded = match std::fs::read_to_string(&state) {
+        Ok(recorded) => recorded,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+    for line in recorded.lines() {
+        let Some((iface, value)) = line.split_once(' ') else {
+            continue;
+        };
+        if !rp_filter_path(iface).exists() {
+            continue;
+        }
+        debug!("Restoring rp_filter {value} on {iface}");
+        set_rp_filter(ex, iface, value.parse()?)?;
+    }
+    std::fs::remove_file(state)?;
+    Ok(())
+}
+
+fn add_default_route_inside(ex: &dyn Executor, base_dir: &Path, net: &Network) -> Result<()> {
     run_inside_namespace(
+        ex,
         base_dir,
         Type::Net,
-        Command::new("ip")
-            .args(["route", "add", "default"])
-            .args(["via", "10.200.0.1"])
-            .args(["dev", "veth-warp-ns"]),
+        ex.command(Binary::Ip)?
+            .args(["route", "replace", "default"])
+            .args(["via", &net.host_addr.to_string()])
+            .args(["dev", &net.ns_iface]),
     )?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::{MockExecutor, Reply};
+    use crate::firewall::Backend;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn network_subnet() {
+        let mut net = Network::default();
+        assert_eq!(net.subnet(), "10.200.0.0/24");
+        net.host_addr = "10.201.7.1".parse().unwrap();
+        net.prefix_len = 30;
+        assert_eq!(net.subnet(), "10.201.7.0/30");
+    }
+
+    fn iptables() -> FirewallConfig {
+        FirewallConfig {
+            backend: Backend::Iptables,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn external_forward_rules() {
+        let ex = MockExecutor::without_rules();
+        let net = Network::default();
+        let dir = TempDir::new("forward");
+        let uplinks = ["eth0".to_owned(), "wlan0".to_owned()];
+        let nat = NatConfig::default();
+        setup_external_forward(&ex, &dir, &net, &iptables(), &nat, &uplinks).unwrap();
+        assert_eq!(recorded_uplinks(&dir).unwrap(), uplinks);
+        let added: Vec<String> = ex
+            .commands()
+            .into_iter()
+            .filter(|c| !c.contains(" -C ") && !c.contains(" -S "))
+            .collect();
+        let route = format!(
+            "nsenter --net={}/net ip route replace default via 10.200.0.1 dev veth-warp-ns",
+            dir.display()
+        );
+        assert_eq!(
+            added,
+            [
+                "iptables -t nat -A POSTROUTING -s 10.200.0.2/24 -o eth0 -j MASQUERADE",
+                "iptables -A FORWARD -i eth0 -o veth-warp -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT",
+                "iptables -A FORWARD -o eth0 -i veth-warp -j ACCEPT",
+                "iptables -t nat -A POSTROUTING -s 10.200.0.2/24 -o wlan0 -j MASQUERADE",
+                "iptables -A FORWARD -i wlan0 -o veth-warp -m conntrack --ctstate ESTABLISHED,RELATED -j ACCEPT",
+                "iptables -A FORWARD -o wlan0 -i veth-warp -j ACCEPT",
+                &route,
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_default_route() {
+        let header =
+            "Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT\n";
+        let subnet = "veth-warp-ns\t0000C80A\t00000000\t0001\t0\t0\t0\t00FFFFFF\t0\t0\t0\n";
+        let default = "veth-warp-ns\t00000000\t0100C80A\t0003\t0\t0\t0\t00000000\t0\t0\t0\n";
+        assert!(!has_default_route(header));
+        assert!(!has_default_route(&format!("{header}{subnet}")));
+        assert!(has_default_route(&format!("{header}{subnet}{default}")));
+    }
+
+    #[test]
+    fn finds_strict_rp_filter() {
+        let ifaces = [("veth-warp", 0), ("eth0", 1)];
+        assert_eq!(strict_ifaces(0, &ifaces), ["eth0"]);
+        assert_eq!(strict_ifaces(1, &ifaces), ["veth-warp", "eth0"]);
+        // Loose wins over a strict all
+        assert_eq!(strict_ifaces(1, &[("eth0", 2)]), Vec::<String>::new());
+        assert_eq!(strict_ifaces(2, &ifaces), Vec::<String>::new());
+    }
+
+    #[test]
+    fn failing_command_is_an_error() {
+        let ex = MockExecutor::with_handler(|_| Reply::fail());
+        let net = Network::default();
+        let dir = TempDir::new("forward-fail");
+        let uplinks = ["eth0".to_owned()];
+        let nat = NatConfig::default();
+        assert!(setup_external_forward(&ex, &dir, &net, &iptables(), &nat, &uplinks).is_err());
+        // Listing FORWARD and checking for the NAT rule are allowed to fail, adding it isn't
+        assert_eq!(ex.commands().len(), 3);
+    }
+}
@@ -0,0 +1,344 @@
+use anyhow::{bail, Result};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+/// A default route of the main routing table
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct DefaultRoute {
+    pub iface: String,
+    /// None for on-link routes, like those of point-to-point links
+    pub gateway: Option<IpAddr>,
+    pub metric: u32,
+    pub ipv6: bool,
+}
+
+const NLMSG_HDR_LEN: usize = 16;
+const RTMSG_LEN: usize = 12;
+const RTATTR_HDR_LEN: usize = 4;
+const RTNEXTHOP_LEN: usize = 8;
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn u16_at(buf: &[u8], at: usize) -> u16 {
+    u16::from_ne_bytes([buf[at], buf[at + 1]])
+}
+
+fn u32_at(buf: &[u8], at: usize) -> u32 {
+    u32::from_ne_bytes(buf[at..at + 4].try_into().unwrap())
+}
+
+/// A default route as the kernel describes it, with the interface still an index
+#[derive(Debug, Eq, PartialEq)]
+struct RawRoute {
+    oif: u32,
+    gateway: Option<IpAddr>,
+    metric: u32,
+}
+
+/// Splits a buffer of netlink attributes into (type, payload)
+fn attributes(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < RTATTR_HDR_LEN {
+            return None;
+        }
+        let len = u16_at(buf, 0) as usize;
+        if len < RTATTR_HDR_LEN || len > buf.len() {
+            return None;
+        }
+        let attr = (u16_at(buf, 2), &buf[RTATTR_HDR_LEN..len]);
+        buf = &buf[align(len).min(buf.len())..];
+        Some(attr)
+    })
+}
+
+fn parse_addr(family: u8, payload: &[u8]) -> Option<IpAddr> {
+    match family as i32 {
+        libc::AF_INET => Some(Ipv4Addr::from(<[u8; 4]>::try_from(payload).ok()?).into()),
+        libc::AF_INET6 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(payload).ok()?).into()),
+        _ => None,
+    }
+}
+
+/// The default route in one RTM_NEWROUTE payload, if that's what it describes
+fn parse_route(msg: &[u8]) -> Option<RawRoute> {
+    if msg.len() < RTMSG_LEN {
+        return None;
+    }
+    let (family, dst_len, table, kind) = (msg[0], msg[1], msg[4], msg[7]);
+    if dst_len != 0 || kind != libc::RTN_UNICAST {
+        return None;
+    }
+    let mut table = table as u32;
+    let mut route = RawRoute {
+        oif: 0,
+        gateway: None,
+        metric: 0,
+    };
+    for (kind, payload) in attributes(&msg[RTMSG_LEN..]) {
+        match kind {
+            libc::RTA_TABLE if payload.len() == 4 => table = u32_at(payload, 0),
+            libc::RTA_OIF if payload.len() == 4 => route.oif = u32_at(payload, 0),
+            libc::RTA_PRIORITY if payload.len() == 4 => route.metric = u32_at(payload, 0),
+            libc::RTA_GATEWAY => route.gateway = parse_addr(family, payload),
+            // ECMP routes list their hops instead, the first one will do
+            libc::RTA_MULTIPATH if payload.len() >= RTNEXTHOP_LEN && route.oif == 0 => {
+                let hop_len = (u16_at(payload, 0) as usize).min(payload.len());
+                route.oif = u32_at(payload, 4);
+                for (kind, hop_attr) in attributes(&payload[RTNEXTHOP_LEN..hop_len]) {
+                    if kind == libc::RTA_GATEWAY {
+                        route.gateway = parse_addr(family, hop_attr);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    (table == libc::RT_TABLE_MAIN as u32 && route.oif != 0).then_some(route)
+}
+
+/// Adds the default routes of a dump reply to routes, returns whether the dump is done
+fn parse_dump(mut buf: &[u8], routes: &mut Vec<RawRoute>) -> Result<bool> {
+    while buf.len() >= NLMSG_HDR_LEN {
+        let len = u32_at(buf, 0) as usize;
+        if len < NLMSG_HDR_LEN || len > buf.len() {
+            bail!("Truncated netlink message")
+        }
+        let payload = &buf[NLMSG_HDR_LEN..len];
+        match u16_at(buf, 4) as i32 {
+            libc::NLMSG_DONE => return Ok(true),
+            libc::NLMSG_ERROR => {
+                let errno = i32::from_ne_bytes(payload[..4].try_into()?);
+                if errno != 0 {
+                    return Err(io::Error::from_raw_os_error(-errno).into());
+                }
+            }
+            kind if kind == libc::RTM_NEWROUTE as i32 => routes.extend(parse_route(payload)),
+            _ => {}
+        }
+        buf = &buf[align(len).min(buf.len())..];
+    }
+    Ok(false)
+}
+
+fn dump(family: u8) -> Result<Vec<RawRoute>> {
+    // SAFETY: socket takes no pointers, and we own the fd it returns
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(io::Error::last_os_error().into());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let mut request = [0u8; NLMSG_HDR_LEN + RTMSG_LEN];
+    request[0..4].copy_from_slice(&((NLMSG_HDR_LEN + RTMSG_LEN) as u32).to_ne_bytes());
+    request[4..6].copy_from_slice(&libc::RTM_GETROUTE.to_ne_bytes());
+    let flags = (libc::NLM_F_REQUEST | libc::NLM_F_DUMP) as u16;
+    request[6..8].copy_from_slice(&flags.to_ne_bytes());
+    request[8..12].copy_from_slice(&1u32.to_ne_bytes());
+    request[NLMSG_HDR_LEN] = family;
+    // SAFETY: the buffer outlives the call, the kernel is the default destination
+    if unsafe {
+        libc::send(
+            socket.as_raw_fd(),
+            request.as_ptr().cast(),
+            request.len(),
+            0,
+        )
+    } < 0
+    {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let mut routes = Vec::new();
+    let mut buf = vec![0u8; 32 * 1024];
+    loop {
+        // SAFETY: recv writes at most buf.len() bytes into buf
+        let len = unsafe { libc::recv(socket.as_raw_fd(), buf.as_mut_ptr().cast(), buf.len(), 0) };
+        if len < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        if len == 0 || parse_dump(&buf[..len as usize], &mut routes)? {
+            return Ok(routes);
+        }
+    }
+}
+
+fn iface_name(index: u32) -> Option<String> {
+    let mut name = [0 as libc::c_char; libc::IF_NAMESIZE];
+    // SAFETY: if_indextoname writes at most IF_NAMESIZE bytes, NUL included
+    let ret = unsafe { libc::if_indextoname(index, name.as_mut_ptr()) };
+    if ret.is_null() {
+        return None;
+    }
+    let name = unsafe { std::ffi::CStr::from_ptr(name.as_ptr()) };
+    Some(name.to_string_lossy().into_owned())
+}
+
+/// The host's IPv4 and IPv6 default routes, asked straight to the kernel, best ones first
+pub fn default_routes() -> Result<Vec<DefaultRoute>> {
+    let mut routes = Vec::new();
+    for family in [libc::AF_INET, libc::AF_INET6] {
+        for raw in dump(family as u8)? {
+            // The interface can vanish between the dump and now
+            let Some(iface) = iface_name(raw.oif) else {
+                continue;
+            };
+            routes.push(DefaultRoute {
+                iface,
+                gateway: raw.gateway,
+                metric: raw.metric,
+                ipv6: family == libc::AF_INET6,
+            });
+        }
+    }
+    routes.sort_by_key(|r| r.metric);
+    Ok(routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attr(kind: u16, payload: &[u8]) -> Vec<u8> {
+        let mut attr = ((RTATTR_HDR_LEN + payload.len()) as u16)
+            .to_ne_bytes()
+            .to_vec();
+        attr.extend(kind.to_ne_bytes());
+        attr.extend(payload);
+        attr.resize(align(attr.len()), 0);
+        attr
+    }
+
+    fn route_msg(family: i32, dst_len: u8, table: u8, attrs: &[Vec<u8>]) -> Vec<u8> {
+        let mut payload = vec![family as u8, dst_len, 0, 0, table, 0, 0, libc::RTN_UNICAST];
+        payload.extend([0; 4]);
+        payload.extend(attrs.concat());
+        let mut msg = ((NLMSG_HDR_LEN + payload.len()) as u32)
+            .to_ne_bytes()
+            .to_vec();
+        msg.extend(libc::RTM_NEWROUTE.to_ne_bytes());
+        msg.extend([0; 10]);
+        msg.extend(payload);
+        msg
+    }
+
+    fn done() -> Vec<u8> {
+        let mut msg = (NLMSG_HDR_LEN as u32 + 4).to_ne_bytes().to_vec();
+        msg.extend((libc::NLMSG_DONE as u16).to_ne_bytes());
+        msg.extend([0; 14]);
+        msg
+    }
+
+    fn parse(msgs: &[Vec<u8>]) -> Vec<RawRoute> {
+        let mut routes = Vec::new();
+        assert!(parse_dump(&msgs.concat(), &mut routes).unwrap());
+        routes
+    }
+
+    const MAIN: u8 = libc::RT_TABLE_MAIN;
+
+    #[test]
+    fn multiple_defaults() {
+        let routes = parse(&[
+            route_msg(
+                libc::AF_INET,
+                0,
+                MAIN,
+                &[
+                    attr(libc::RTA_GATEWAY, &[192, 168, 1, 1]),
+                    attr(libc::RTA_OIF, &2u32.to_ne_bytes()),
+                    attr(libc::RTA_PRIORITY, &600u32.to_ne_bytes()),
+                ],
+            ),
+            // Not a default route
+            route_msg(
+                libc::AF_INET,
+                24,
+                MAIN,
+                &[attr(libc::RTA_OIF, &2u32.to_ne_bytes())],
+            ),
+            route_msg(
+                libc::AF_INET,
+                0,
+                MAIN,
+                &[
+                    attr(libc::RTA_GATEWAY, &[10, 0, 0, 1]),
+                    attr(libc::RTA_OIF, &3u32.to_ne_bytes()),
+                    attr(libc::RTA_PRIORITY, &100u32.to_ne_bytes()),
+                ],
+            ),
+            // Policy routing table
+            route_msg(
+                libc::AF_INET,
+                0,
+                100,
+                &[attr(libc::RTA_OIF, &4u32.to_ne_bytes())],
+            ),
+            done(),
+        ]);
+        assert_eq!(
+            routes,
+            [
+                RawRoute {
+                    oif: 2,
+                    gateway: Some("192.168.1.1".parse().unwrap()),
+                    metric: 600
+                },
+                RawRoute {
+                    oif: 3,
+                    gateway: Some("10.0.0.1".parse().unwrap()),
+                    metric: 100
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn on_link_default() {
+        let routes = parse(&[
+            route_msg(
+                libc::AF_INET,
+                0,
+                MAIN,
+                &[attr(libc::RTA_OIF, &5u32.to_ne_bytes())],
+            ),
+            done(),
+        ]);
+        assert_eq!(
+            routes,
+            [RawRoute {
+                oif: 5,
+                gateway: None,
+                metric: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn ipv6_default() {
+        let gateway: Ipv6Addr = "fe80::1".parse().unwrap();
+        let routes = parse(&[
+            route_msg(
+                libc::AF_INET6,
+                0,
+                MAIN,
+                &[
+                    attr(libc::RTA_GATEWAY, &gateway.octets()),
+                    attr(libc::RTA_OIF, &2u32.to_ne_bytes()),
+                    attr(libc::RTA_PRIORITY, &1024u32.to_ne_bytes()),
+                ],
+            ),
+            done(),
+        ]);
+        assert_eq!(routes[0].gateway, Some(gateway.into()));
+        assert_eq!(routes[0].metric, 1024);
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use tracing::warn;
+
+/// Past this size, the log is moved aside when a command starts
+const MAX_SIZE: u64 = 1024 * 1024;
+
+pub fn path(base_dir: &Path) -> PathBuf {
+    base_dir.join("operations.log")
+}
+
+/// Append-only record of every privileged operation, written before each one starts and after
+/// it ends. Lines are written as they come, so after a crash the log shows what the last command
+/// did, up to the operation it was in the middle of. The file is synced to disk once the command
+/// is done with it, a power loss can lose what the last command wrote.
+pub struct OpLog {
+    path: PathBuf,
+    file: Mutex<Option<File>>,
+    next_id: AtomicU64,
+}
+
+/// Identifies the line that ends an operation with the one that started it
+pub struct Op(u64);
+
+impl OpLog {
+    pub fn new(base_dir: &Path) -> Self {
+        OpLog {
+            path: path(base_dir),
+            file: Mutex::new(None),
+            next_id: AtomicU64::new(1),
+        }
+    }
+
+    /// Opened on first use, since the base dir may not exist yet when a command starts
+    fn append(&self, line: std::fmt::Arguments) {
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            if self.path.metadata().is_ok_and(|m| m.len() > MAX_SIZE) {
+                let _ = std::fs::rename(&self.path, self.path.with_extension("log.old"));
+            }
+            *file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .ok();
+        }
+        let Some(file) = file.as_mut() else { return };
+        let now = chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ");
+        // One write per line, so concurrent setup steps don't interleave
+        let line = format!("{now} [{}] {line}\n", std::process::id());
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            warn!("Writing to {}: {e}", self.path.display());
+        }
+    }
+
+    pub fn start(&self, operation: impl Display) -> Op {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.append(format_args!("#{id} {operation}"));
+        Op(id)
+    }
+
+    pub fn finish<T: Display, E: Display>(&self, op: Op, result: &Result<T, E>) {
+        match result {
+            Ok(outcome) => self.append(format_args!("#{} -> {outcome}", op.0)),
+            Err(e) => self.append(format_args!("#{} -> failed: {e}", op.0)),
+        }
+    }
+}
+
+impl Drop for OpLog {
+    fn drop(&mut self) {
+        let file = self.file.get_mut().unwrap();
+        if let Some(Err(e)) = file.as_ref().map(File::sync_data) {
+            warn!("Syncing {}: {e}", self.path.display());
+        }
+    }
+}
+
+/// The last lines of the log, oldest first
+pub fn tail(base_dir: &Path, lines: usize) -> std::io::Result<Vec<String>> {
+    let file = match File::open(path(base_dir)) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        file => file?,
+    };
+    let all: Vec<String> = BufReader::new(file).lines().collect::<Result<_, _>>()?;
+    Ok(all[all.len().saturating_sub(lines)..].to_vec())
+}
+
+/// The pid, operation number and whether the line ends the operation
+fn parse_line(line: &str) -> Option<(&str, &str, bool)> {
+    let mut words = line.split_whitespace().skip(1);
+    let (pid, id) = (words.next()?, words.next()?);
+    id.starts_with('#')
+        .then_some((pid, id, words.next() == Some("->")))
+}
+
+/// Operations that started but never ended, because the last command that's gone was killed or
+/// the host went down in the middle of them. Commands still running are left out, and only the
+/// last lines of the log are looked at.
+pub fn unfinished(base_dir: &Path, lines: usize) -> std::io::Result<Vec<String>> {
+    let lines = tail(base_dir, lines)?;
+    let is_running = |pid: &str| {
+        Path::new("/proc")
+            .join(pid.trim_matches(['[', ']']))
+            .exists()
+    };
+    let Some(last) = lines
+        .iter()
+        .rev()
+        .filter_map(|line| parse_line(line))
+        .map(|(pid, _, _)| pid)
+        .find(|pid| !is_running(pid))
+    else {
+        return Ok(Vec::new());
+    };
+    let ended: HashSet<&str> = lines
+        .iter()
+        .filter_map(|line| parse_line(line))
+        .filter(|&(pid, _, end)| pid == last && end)
+        .map(|(_, id, _)| id)
+        .collect();
+    Ok(lines
+        .iter()
+        .filter(|line| match parse_line(line) {
+            Some((pid, id, false)) => pid == last && !ended.contains(id),
+            _ => false,
+        })
+        .cloned()
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn pairs_start_and_result() {
+        let dir = TempDir::new("oplog");
+        let log = OpLog::new(&dir);
+        let op = log.start("mount /base/net");
+        log.finish(op, &Ok::<_, String>("ok"));
+        let op = log.start("run iptables -A FORWARD");
+        log.finish(op, &Err::<String, _>("exit status: 1"));
+        let lines = tail(&dir, 3).unwrap();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].ends_with("#1 -> ok"));
+        assert!(lines[1].ends_with("#2 run iptables -A FORWARD"));
+        assert!(lines[2].ends_with("#2 -> failed: exit status: 1"));
+    }
+
+    #[test]
+    fn finds_interrupted_operations() {
+        let dir = TempDir::new("oplog-crash");
+        std::fs::write(
+            path(&dir),
+            "2024-01-01T00:00:00.000Z [99999999] #1 mount /base/net\n\
+             2024-01-01T00:00:00.001Z [99999999] #1 -> ok\n\
+             2024-01-01T00:00:00.002Z [99999999] #2 run ip link add veth-warp\n",
+        )
+        .unwrap();
+        assert_eq!(
+            unfinished(&dir, 100).unwrap(),
+            ["2024-01-01T00:00:00.002Z [99999999] #2 run ip link add veth-warp"]
+        );
+        // A later command that finished what it started
+        let mut log = OpenOptions::new().append(true).open(path(&dir)).unwrap();
+        log.write_all(
+            b"2024-01-01T00:01:00.000Z [99999998] #1 run ip link del veth-warp\n\
+              2024-01-01T00:01:00.001Z [99999998] #1 -> exit status: 0\n",
+        )
+        .unwrap();
+        assert!(unfinished(&dir, 100).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,308 @@
+use crate::exec::Executor;
+use crate::fetch;
+use crate::iptables;
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Searched after $PATH, since root's $PATH doesn't always include the sbin directories
+pub const FALLBACK_DIRS: &[&str] = &[
+    "/usr/local/sbin",
+    "/usr/local/bin",
+    "/usr/sbin",
+    "/usr/bin",
+    "/sbin",
+    "/bin",
+];
+
+/// The external programs we depend on
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum Binary {
+    WarpSvc,
+    Danted,
+    Iptables,
+    Ip,
+    Nsenter,
+    Unshare,
+    FirewallCmd,
+    Wg,
+    Qrencode,
+    Sysctl,
+    Setpriv,
+    Tar,
+    Busybox,
+    Ldd,
+    PivotRoot,
+    Curl,
+    Gpg,
+    Gpgv,
+    Ar,
+    Sha256sum,
+    Skopeo,
+    Umoci,
+    WarpCli,
+    Resolvectl,
+    Systemctl,
+    Tcpdump,
+    Ping,
+    Ip6tables,
+}
+
+impl Binary {
+    pub fn name(self) -> &'static str {
+        match self {
+            Binary::WarpSvc => "warp-svc",
+            Binary::Danted => "danted",
+            Binary::Iptables => "iptables",
+            Binary::Ip => "ip",
+            Binary::Nsenter => "nsenter",
+            Binary::Unshare => "unshare",
+            Binary::FirewallCmd => "firewall-cmd",
+            Binary::Wg => "wg",
+            Binary::Qrencode => "qrencode",
+            Binary::Sysctl => "sysctl",
+            Binary::Setpriv => "setpriv",
+            Binary::Tar => "tar",
+            Binary::Busybox => "busybox",
+            Binary::Ldd => "ldd",
+            Binary::PivotRoot => "pivot_root",
+            Binary::Curl => "curl",
+            Binary::Gpg => "gpg",
+            Binary::Gpgv => "gpgv",
+            Binary::Ar => "ar",
+            Binary::Sha256sum => "sha256sum",
+            Binary::Skopeo => "skopeo",
+            Binary::Umoci => "umoci",
+            Binary::WarpCli => "warp-cli",
+            Binary::Resolvectl => "resolvectl",
+            Binary::Systemctl => "systemctl",
+            Binary::Tcpdump => "tcpdump",
+            Binary::Ping => "ping",
+            Binary::Ip6tables => "ip6tables",
+        }
+    }
+
+    /// Names the binary is installed under, in order of preference
+    pub fn file_names(self) -> &'static [&'static str] {
+        match self {
+            Binary::WarpSvc => &["warp-svc"],
+            // Arch's dante package only ships it as sockd
+            Binary::Danted => &["danted", "sockd"],
+            Binary::Iptables => &["iptables"],
+            Binary::Ip => &["ip"],
+            Binary::Nsenter => &["nsenter"],
+            Binary::Unshare => &["unshare"],
+            Binary::FirewallCmd => &["firewall-cmd"],
+            Binary::Wg => &["wg"],
+            Binary::Qrencode => &["qrencode"],
+            Binary::Sysctl => &["sysctl"],
+            Binary::Setpriv => &["setpriv"],
+            Binary::Tar => &["tar"],
+            Binary::Busybox => &["busybox"],
+            Binary::Ldd => &["ldd"],
+            Binary::PivotRoot => &["pivot_root"],
+            Binary::Curl => &["curl"],
+            Binary::Gpg => &["gpg"],
+            Binary::Gpgv => &["gpgv"],
+            Binary::Ar => &["ar"],
+            Binary::Sha256sum => &["sha256sum"],
+            Binary::Skopeo => &["skopeo"],
+            Binary::Umoci => &["umoci"],
+            Binary::WarpCli => &["warp-cli"],
+            Binary::Resolvectl => &["resolvectl"],
+            Binary::Systemctl => &["systemctl"],
+            Binary::Tcpdump => &["tcpdump"],
+            Binary::Ping => &["ping"],
+            Binary::Ip6tables => &["ip6tables"],
+        }
+    }
+
+    fn package(self) -> &'static str {
+        match self {
+            Binary::WarpSvc => "cloudflare-warp",
+            Binary::Danted => "dante-server (dante on Arch)",
+            Binary::Iptables => "iptables",
+            Binary::Ip => "iproute2",
+            Binary::Nsenter | Binary::Unshare | Binary::Setpriv | Binary::PivotRoot => "util-linux",
+            Binary::FirewallCmd => "firewalld",
+            Binary::Wg => "wireguard-tools",
+            Binary::Qrencode => "qrencode",
+            Binary::Sysctl => "procps",
+            Binary::Tar => "tar",
+            Binary::Busybox => "busybox",
+            Binary::Ldd => "glibc (libc-bin on Debian)",
+            Binary::Curl => "curl",
+            Binary::Gpg => "gnupg",
+            Binary::Gpgv => "gpgv (gnupg)",
+            Binary::Ar => "binutils",
+            Binary::Sha256sum => "coreutils",
+            Binary::Skopeo => "skopeo",
+            Binary::Umoci => "umoci",
+            Binary::WarpCli => "cloudflare-warp",
+            Binary::Resolvectl => "systemd",
+            Binary::Systemctl => "systemd",
+            Binary::Tcpdump => "tcpdump",
+            Binary::Ping => "iputils-ping (iputils on Arch and Fedora)",
+            Binary::Ip6tables => "iptables",
+        }
+    }
+}
+
+/// The `[paths]` section of the config file
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct PathOverrides {
+    pub warp_svc: Option<PathBuf>,
+    pub danted: Option<PathBuf>,
+    pub iptables: Option<PathBuf>,
+    pub ip: Option<PathBuf>,
+    pub nsenter: Option<PathBuf>,
+    pub unshare: Option<PathBuf>,
+    pub firewall_cmd: Option<PathBuf>,
+    pub wg: Option<PathBuf>,
+    pub qrencode: Option<PathBuf>,
+    pub sysctl: Option<PathBuf>,
+    pub setpriv: Option<PathBuf>,
+    pub tar: Option<PathBuf>,
+    pub busybox: Option<PathBuf>,
+    pub ldd: Option<PathBuf>,
+    pub pivot_root: Option<PathBuf>,
+    pub curl: Option<PathBuf>,
+    pub gpg: Option<PathBuf>,
+    pub gpgv: Option<PathBuf>,
+    pub ar: Option<PathBuf>,
+    pub sha256sum: Option<PathBuf>,
+    pub skopeo: Option<PathBuf>,
+    pub umoci: Option<PathBuf>,
+    pub warp_cli: Option<PathBuf>,
+    pub resolvectl: Option<PathBuf>,
+    pub systemctl: Option<PathBuf>,
+    pub tcpdump: Option<PathBuf>,
+    pub ping: Option<PathBuf>,
+    pub ip6tables: Option<PathBuf>,
+}
+
+impl PathOverrides {
+    fn get(&self, bin: Binary) -> Option<&Path> {
+        match bin {
+            Binary::WarpSvc => &self.warp_svc,
+            Binary::Danted => &self.danted,
+            Binary::Iptables => &self.iptables,
+            Binary::Ip => &self.ip,
+            Binary::Nsenter => &self.nsenter,
+            Binary::Unshare => &self.unshare,
+            Binary::FirewallCmd => &self.firewall_cmd,
+            Binary::Wg => &self.wg,
+            Binary::Qrencode => &self.qrencode,
+            Binary::Sysctl => &self.sysctl,
+            Binary::Setpriv => &self.setpriv,
+            Binary::Tar => &self.tar,
+            Binary::Busybox => &self.busybox,
+            Binary::Ldd => &self.ldd,
+            Binary::PivotRoot => &self.pivot_root,
+            Binary::Curl => &self.curl,
+            Binary::Gpg => &self.gpg,
+            Binary::Gpgv => &self.gpgv,
+            Binary::Ar => &self.ar,
+            Binary::Sha256sum => &self.sha256sum,
+            Binary::Skopeo => &self.skopeo,
+            Binary::Umoci => &self.umoci,
+            Binary::WarpCli => &self.warp_cli,
+            Binary::Resolvectl => &self.resolvectl,
+            Binary::Systemctl => &self.systemctl,
+            Binary::Tcpdump => &self.tcpdump,
+            Binary::Ping => &self.ping,
+            Binary::Ip6tables => &self.ip6tables,
+        }
+        .as_deref()
+    }
+}
+
+/// Resolves binaries on first use, so a missing one only matters to the commands that need it
+pub struct Paths {
+    overrides: PathOverrides,
+    /// Where to look for the releases fetch installed, if they can be used
+    base_dir: Option<PathBuf>,
+    resolved: Mutex<HashMap<Binary, PathBuf>>,
+}
+
+impl Paths {
+    pub fn new(overrides: PathOverrides, base_dir: Option<&Path>) -> Self {
+        Paths {
+            overrides,
+            base_dir: base_dir.map(Path::to_owned),
+            resolved: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Probing which iptables variant is in use needs to run commands, hence the executor
+    pub fn get(&self, bin: Binary, ex: &dyn Executor) -> Result<PathBuf> {
+        if let Some(path) = self.resolved.lock().unwrap().get(&bin) {
+            return Ok(path.clone());
+        }
+        let path = if bin == Binary::Iptables && self.overrides.iptables.is_none() {
+            iptables::detect(ex)?
+        } else if bin == Binary::Ip6tables && self.overrides.ip6tables.is_none() {
+            // From the same backend as iptables, which lives next to it
+            let iptables = self.get(Binary::Iptables, ex)?;
+            let name = iptables.file_name().unwrap_or_default().to_string_lossy();
+            let ip6tables = iptables.with_file_name(name.replacen("iptables", "ip6tables", 1));
+            match is_executable(&ip6tables) {
+                true => ip6tables,
+                false => resolve(&self.overrides, self.base_dir.as_deref(), bin)?,
+            }
+        } else {
+            resolve(&self.overrides, self.base_dir.as_deref(), bin)?
+        };
+        self.resolved.lock().unwrap().insert(bin, path.clone());
+        Ok(path)
+    }
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.metadata()
+        .is_ok_and(|m| m.is_file() && m.permissions().mode() & 0o111 != 0)
+}
+
+/// Looks for an executable in $PATH, then in the usual sbin and bin directories
+pub fn find(name: &str) -> Option<PathBuf> {
+    let path_var = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path_var)
+        .chain(FALLBACK_DIRS.iter().map(PathBuf::from))
+        .map(|dir| dir.join(name))
+        .find(|candidate| is_executable(candidate))
+}
+
+/// `base_dir` has the releases fetch installed, if any
+pub fn resolve(overrides: &PathOverrides, base_dir: Option<&Path>, bin: Binary) -> Result<PathBuf> {
+    if let Some(path) = overrides.get(bin) {
+        if !is_executable(path) {
+            bail!(
+                "paths.{} is set to {}, but that is not an executable file",
+                bin.name().replace('_', "-"),
+                path.display()
+            );
+        }
+        return Ok(path.to_owned());
+    }
+    if matches!(bin, Binary::WarpSvc | Binary::WarpCli) {
+        if let Some(path) = base_dir.and_then(|dir| fetch::installed(dir, bin.name())) {
+            return Ok(path);
+        }
+    }
+
+    if let Some(path) = bin.file_names().iter().find_map(|name| find(name)) {
+        return Ok(path);
+    }
+    bail!(
+        "Could not find {} in $PATH or {}. Install the {} package, or set paths.{} in the config file{}",
+        bin.name(),
+        FALLBACK_DIRS.join(", "),
+        bin.package(),
+        bin.name().replace('_', "-"),
+        if matches!(bin, Binary::WarpSvc | Binary::WarpCli) { ". The fetch command can also download it" } else { "" },
+    )
+}
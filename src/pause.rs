@@ -0,0 +1,85 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::namespace::{run_inside_namespace, Mounts, Status, Type};
+use crate::paths::Binary;
+use crate::proxy::runs_danted;
+use crate::readiness::wait_for_proxy;
+use crate::service;
+use crate::up::{find_pid_ns_init_process, spawn_process_inside, Parts};
+use anyhow::{bail, Result};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Runs warp-cli against the container's warp-svc, whose socket is in the container's /run
+pub fn warp_cli(ex: &dyn Executor, base_dir: &Path, args: &[&str]) -> Result<String> {
+    let out = run_inside_namespace(
+        ex,
+        base_dir,
+        Type::Mount,
+        ex.command(Binary::WarpCli)?.arg("--accept-tos").args(args),
+    )?;
+    Ok(String::from_utf8(out.stdout)?)
+}
+
+/// The container's init PID, failing unless the container is up
+pub fn ensure_up(base_dir: &Path) -> Result<u32> {
+    if Mounts::snapshot()?.status(base_dir) != Status::Ready {
+        bail!("The container isn't up")
+    }
+    let Some(init_proc) = find_pid_ns_init_process(base_dir)? else {
+        bail!("The container's init process is dead, run down then up")
+    };
+    Ok(init_proc.pid as u32)
+}
+
+/// Kept while paused, so the relay resumes the container instead of waiting for its proxy
+fn paused_path(base_dir: &Path) -> PathBuf {
+    base_dir.join("paused")
+}
+
+pub fn is_paused(base_dir: &Path) -> bool {
+    paused_path(base_dir).exists()
+}
+
+pub fn forget(base_dir: &Path) -> Result<()> {
+    match std::fs::remove_file(paused_path(base_dir)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Disconnects the tunnel, keeping the container and its registration for a quick resume.
+/// With `stop_proxy`, danted stops too, so nothing uses the container's direct route meanwhile.
+pub fn pause(ex: &dyn Executor, config: &Config, stop_proxy: bool) -> Result<()> {
+    let base_dir = config.base_dir.as_path();
+    ensure_up(base_dir)?;
+    warp_cli(ex, base_dir, &["disconnect"])?;
+    if stop_proxy {
+        service::stop(ex, base_dir, Binary::Danted.name())?;
+    }
+    std::fs::write(paused_path(base_dir), "")?;
+    info!("WARP is paused, resume to reconnect");
+    Ok(())
+}
+
+/// Undoes pause, and returns once the proxy serves again
+pub fn resume(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let base_dir = config.base_dir.as_path();
+    let ns_init_pid = ensure_up(base_dir)?;
+    warp_cli(ex, base_dir, &["connect"])?;
+    forget(base_dir)?;
+    let parts = Parts::load(base_dir)?;
+    if !parts.proxy {
+        return Ok(());
+    }
+    if runs_danted(config, parts) && service::running(base_dir, Binary::Danted.name())?.is_none() {
+        spawn_process_inside(
+            ex,
+            base_dir,
+            Binary::Danted,
+            &config.services.get(Binary::Danted),
+            ns_init_pid,
+        )?;
+    }
+    wait_for_proxy(&config.network, config.timeouts.proxy_ready)
+}
@@ -0,0 +1,112 @@
+use nix::sys::signal::Signal;
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::time::Duration;
+
+/// A handle on a process. Unlike its PID, it keeps referring to that process after it exits,
+/// so signals can't reach an unrelated process that got the PID next.
+#[derive(Debug)]
+pub struct PidFd {
+    pid: i32,
+    fd: OwnedFd,
+}
+
+impl PidFd {
+    pub fn open(pid: i32) -> io::Result<Self> {
+        // SAFETY: pidfd_open takes no pointers, and we own the fd it returns
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as i32) };
+        Ok(PidFd { pid, fd })
+    }
+
+    /// Opens the process with this PID, unless it's not the one that started at `start_time`.
+    /// Checked after opening, so the PID can't get reused between the check and the open.
+    pub fn open_if_started_at(pid: i32, start_time: u64) -> io::Result<Option<Self>> {
+        let pidfd = match Self::open(pid) {
+            Err(e) if e.raw_os_error() == Some(libc::ESRCH) => return Ok(None),
+            pidfd => pidfd?,
+        };
+        Ok((self::start_time(pid) == Some(start_time)).then_some(pidfd))
+    }
+
+    pub fn pid(&self) -> i32 {
+        self.pid
+    }
+
+    pub fn send_signal(&self, signal: Signal) -> io::Result<()> {
+        // SAFETY: the siginfo pointer may be null, the kernel then fills it like kill() would
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                self.fd.as_raw_fd(),
+                signal as libc::c_int,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// The process' PID in each PID namespace it is in, from the outermost to its own
+    pub fn ns_pids(&self) -> io::Result<Vec<i32>> {
+        let fdinfo = std::fs::read_to_string(format!("/proc/self/fdinfo/{}", self.fd.as_raw_fd()))?;
+        let pids = fdinfo
+            .lines()
+            .find_map(|line| line.strip_prefix("NSpid:"))
+            .ok_or_else(|| io::Error::other("no NSpid in the pidfd's fdinfo"))?;
+        Ok(pids
+            .split_whitespace()
+            .filter_map(|pid| pid.parse().ok())
+            .collect())
+    }
+
+    /// Waits for the process to exit, returns whether it did within the timeout
+    pub fn wait_exit(&self, timeout: Duration) -> io::Result<bool> {
+        let mut poll_fd = libc::pollfd {
+            fd: self.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().try_into().unwrap_or(libc::c_int::MAX);
+        // SAFETY: poll_fd outlives the call
+        let ret = unsafe { libc::poll(&mut poll_fd, 1, timeout) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ret > 0)
+    }
+}
+
+/// When a process started, in clock ticks since boot. Together with the PID, that identifies
+/// a process across bubblewarp invocations.
+pub fn start_time(pid: i32) -> Option<u64> {
+    let stat = procfs::process::Process::new(pid).ok()?.stat().ok()?;
+    Some(stat.starttime)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracks_exit() {
+        let mut child = std::process::Command::new("sleep")
+            .arg("10")
+            .spawn()
+            .unwrap();
+        let pid = child.id() as i32;
+        let start = start_time(pid).unwrap();
+        assert!(PidFd::open_if_started_at(pid, start + 1).unwrap().is_none());
+        let pidfd = PidFd::open_if_started_at(pid, start).unwrap().unwrap();
+        assert!(!pidfd.wait_exit(Duration::ZERO).unwrap());
+        pidfd.send_signal(Signal::SIGKILL).unwrap();
+        assert!(pidfd.wait_exit(Duration::from_secs(5)).unwrap());
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,181 @@
+use crate::config::Config;
+use crate::lsm;
+use crate::namespace::Type;
+use crate::rootfs;
+use crate::up::Parts;
+use anyhow::{bail, Result};
+use std::path::Path;
+use strum::IntoEnumIterator;
+use tracing::debug;
+
+/// Overlayfs can be mounted in a user namespace's mount namespace since 5.11
+const OVERLAY_IN_USERNS: (u32, u32) = (5, 11);
+
+/// Whether a release like "6.1.0-13-amd64" is at least major.minor
+fn kernel_at_least(release: &str, (major, minor): (u32, u32)) -> bool {
+    let mut numbers = release
+        .split(|c: char| !c.is_ascii_digit())
+        .map(|n| n.parse::<u32>().unwrap_or(0));
+    let found = (numbers.next().unwrap_or(0), numbers.next().unwrap_or(0));
+    found >= (major, minor)
+}
+
+/// Whether modules.dep or modules.builtin has the module, which is then loaded on first use
+fn module_listed(list: &str, name: &str) -> bool {
+    list.lines().any(|line| {
+        let path = line.split(':').next().unwrap_or_default();
+        let file = path.rsplit('/').next().unwrap_or_default();
+        file.split('.').next() == Some(name)
+    })
+}
+
+fn module_available(release: &str, name: &str) -> bool {
+    if Path::new("/sys/module").join(name).exists() {
+        return true;
+    }
+    let modules = Path::new("/lib/modules").join(release);
+    let lists: Vec<String> = ["modules.builtin", "modules.dep"]
+        .iter()
+        .filter_map(|list| std::fs::read_to_string(modules.join(list)).ok())
+        .collect();
+    // Some distros keep modules elsewhere, don't refuse what we can't tell
+    lists.is_empty() || lists.iter().any(|list| module_listed(list, name))
+}
+
+/// What the kernel lacks for the container the config describes, each as a sentence
+fn problems(config: &Config, parts: Parts) -> Vec<String> {
+    let mut problems = Vec::new();
+    let release = std::fs::read_to_string("/proc/sys/kernel/osrelease").unwrap_or_default();
+    let release = release.trim();
+
+    for ns_type in Type::iter() {
+        if ns_type == Type::Time && config.container.time_offset.is_none() {
+            continue;
+        }
+        let path = Path::new("/proc/self/ns").join(ns_type.proc_name());
+        if !path.exists() {
+            problems.push(format!(
+                "The kernel has no {ns_type} namespaces, {} is missing",
+                path.display()
+            ));
+        }
+    }
+    match std::fs::read_to_string("/proc/sys/user/max_user_namespaces") {
+        Ok(max) if max.trim() == "0" => problems.push(
+            "user.max_user_namespaces is 0, set it higher with sysctl to allow user namespaces"
+                .to_owned(),
+        ),
+        _ => (),
+    }
+
+    if config.container.rootfs == rootfs::Kind::Host {
+        let filesystems = std::fs::read_to_string("/proc/filesystems").unwrap_or_default();
+        let has_overlay = filesystems
+            .lines()
+            .any(|line| line.split_whitespace().last() == Some("overlay"));
+        if !has_overlay && !module_available(release, "overlay") {
+            problems
+                .push("The kernel has no overlayfs, which the container's /etc needs".to_owned());
+        } else if !kernel_at_least(release, OVERLAY_IN_USERNS) {
+            problems.push(format!(
+                "Kernel {release} can't mount overlayfs in a user namespace, that needs {}.{} or later",
+                OVERLAY_IN_USERNS.0, OVERLAY_IN_USERNS.1
+            ));
+        }
+    }
+
+    let mut modules = vec![("veth", "the container's network link")];
+    if parts.external {
+        modules.push(("nf_nat", "NAT for the container's traffic"));
+    }
+    if config.gateway.enabled {
+        modules.push(("wireguard", "the WireGuard gateway"));
+    }
+    for (module, needed_for) in modules {
+        if !module_available(release, module) {
+            problems.push(format!(
+                "The {module} kernel module isn't available, it's needed for {needed_for}"
+            ));
+        }
+    }
+    problems
+}
+
+/// Fails with everything the kernel is missing at once, before setting anything up
+pub fn check_kernel(config: &Config, parts: Parts) -> Result<()> {
+    let problems = problems(config, parts);
+    if !problems.is_empty() {
+        bail!(
+            "This kernel can't run the container:\n  {}",
+            problems.join("\n  ")
+        )
+    }
+    debug!("The kernel has everything the container needs");
+    Ok(())
+}
+
+/// Denials shown by doctor, the latest ones are the relevant ones
+const SHOWN_DENIALS: usize = 10;
+
+/// Reports what could keep the container from working on this host, without changing anything
+pub fn doctor(config: &Config) -> Result<()> {
+    let problems = problems(config, config.parts);
+    if problems.is_empty() {
+        println!("[PASS] kernel features");
+    }
+    for problem in &problems {
+        println!("[FAIL] {problem}");
+    }
+
+    let lsms = lsm::active();
+    println!(
+        "Security modules: {}",
+        if lsms.is_empty() {
+            "none".to_owned()
+        } else {
+            lsms.join(", ")
+        }
+    );
+    if lsm::selinux_enabled() {
+        let mode = match lsm::selinux_enforcing() {
+            true => "enforcing",
+            false => "permissive",
+        };
+        println!("SELinux is {mode}, the container's files are labeled like the host's");
+    }
+    match lsm::recent_denials(SHOWN_DENIALS) {
+        Ok(denials) if denials.is_empty() => {
+            println!("No denials of bubblewarp's processes in the audit log")
+        }
+        Ok(denials) => {
+            println!("Latest denials of bubblewarp's processes:");
+            for denial in denials {
+                println!("  {denial}");
+            }
+        }
+        Err(e) => println!("Can't look for denials: {e:#}"),
+    }
+
+    if !problems.is_empty() {
+        bail!("This kernel can't run the container")
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_kernel_details() {
+        assert!(kernel_at_least("6.1.0-13-amd64", (5, 11)));
+        assert!(kernel_at_least("5.11.0", (5, 11)));
+        assert!(!kernel_at_least("5.4.0-150-generic", (5, 11)));
+
+        let deps = "kernel/drivers/net/veth.ko.zst:\nkernel/net/netfilter/nf_nat.ko: kernel/net/netfilter/nf_conntrack.ko\n";
+        assert!(module_listed(deps, "veth"));
+        assert!(module_listed(deps, "nf_nat"));
+        assert!(!module_listed(deps, "nf_conntrack"));
+        assert!(!module_listed(deps, "wireguard"));
+    }
+}
@@ -0,0 +1,112 @@
+use crate::timings;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::span;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+const SPINNER: &[char] = &['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const TICK: Duration = Duration::from_millis(100);
+
+/// When to show progress
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum When {
+    /// When stderr is a terminal
+    Auto,
+    Always,
+    Never,
+}
+
+impl When {
+    pub fn enabled(self) -> bool {
+        match self {
+            When::Auto => std::io::IsTerminal::is_terminal(&std::io::stderr()),
+            When::Always => true,
+            When::Never => false,
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    /// The phases running right now, by span ID
+    running: Vec<(u64, &'static str, Instant)>,
+    frame: usize,
+}
+
+/// Draws the phases of a command on stderr as they run: a spinner line for those in progress,
+/// and a line with its duration for each one that finished
+#[derive(Clone, Default)]
+pub struct Progress {
+    state: Arc<Mutex<State>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl State {
+    fn draw(&self, out: &mut impl Write) {
+        let _ = write!(out, "\r\x1b[2K");
+        if self.running.is_empty() {
+            return;
+        }
+        let names: Vec<&str> = self.running.iter().map(|(_, name, _)| *name).collect();
+        let _ = write!(
+            out,
+            "{} {}",
+            SPINNER[self.frame % SPINNER.len()],
+            names.join(", ")
+        );
+        let _ = out.flush();
+    }
+}
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> tracing_subscriber::Layer<S> for Progress {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, _ctx: Context<'_, S>) {
+        if attrs.metadata().target() != timings::TARGET {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let name = attrs.metadata().name();
+        state.running.push((id.into_u64(), name, Instant::now()));
+        state.draw(&mut std::io::stderr().lock());
+    }
+
+    fn on_close(&self, id: span::Id, _ctx: Context<'_, S>) {
+        let mut state = self.state.lock().unwrap();
+        let Some(pos) = state.running.iter().position(|(i, ..)| *i == id.into_u64()) else {
+            return;
+        };
+        let (_, name, started) = state.running.remove(pos);
+        let mut err = std::io::stderr().lock();
+        let _ = writeln!(
+            err,
+            "\r\x1b[2K✓ {name:<20} {:>8.1} ms",
+            started.elapsed().as_secs_f64() * 1000.
+        );
+        state.draw(&mut err);
+    }
+}
+
+impl Progress {
+    /// Keeps the spinner turning until finish
+    pub fn start(&self) {
+        let progress = self.clone();
+        std::thread::spawn(move || {
+            while !progress.stopped.load(Ordering::Relaxed) {
+                std::thread::sleep(TICK);
+                let mut state = progress.state.lock().unwrap();
+                state.frame += 1;
+                state.draw(&mut std::io::stderr().lock());
+            }
+        });
+    }
+
+    /// Stops the spinner and replaces it with a last line
+    pub fn finish(&self, summary: &str) {
+        self.stopped.store(true, Ordering::Relaxed);
+        let _state = self.state.lock().unwrap();
+        eprintln!("\r\x1b[2K{summary}");
+    }
+}
@@ -0,0 +1,517 @@
+use crate::compat::{warp_cli_op, Op};
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::net::{self, forward_to_loopback_inside, Network};
+use crate::paths::Binary;
+use crate::pause::warp_cli;
+use crate::readiness;
+use crate::service::ServiceConfig;
+use crate::up::{Parts, SOCKS_PORT};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// What serves the proxy port the host connects to
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Backend {
+    /// danted, sending its traffic out of WARP's tunnel interface
+    #[default]
+    Danted,
+    /// warp-svc's own SOCKS5 and HTTP proxy, in warp-cli's proxy mode. There's no tunnel
+    /// interface then, so the WireGuard gateway can't be used with it.
+    Warp,
+}
+
+/// The `[proxy]` section of the config file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ProxyConfig {
+    pub backend: Backend,
+    /// Where warp-svc listens in proxy mode, on the container's loopback
+    pub warp_port: u16,
+    /// Most SOCKS sessions danted serves at once, across all clients
+    pub max_connections: Option<u32>,
+    /// Most new connections per second from each client address
+    pub max_connection_rate: Option<u32>,
+    /// Client networks allowed to use the proxy, by default only the veth link's
+    pub allow: Option<Vec<Cidr>>,
+    /// An HTTP proxy like http://proxy.corp:3128 that warp-svc registers and fetches its settings
+    /// through, for networks that allow no other way out. Its tunnel still goes straight out.
+    pub upstream: Option<String>,
+}
+
+/// An address range like 192.168.1.0/24, normalized to its network address
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, len)) => (addr.parse::<IpAddr>()?, Some(len.parse::<u8>()?)),
+            None => (s.parse::<IpAddr>()?, None),
+        };
+        let addr = match addr {
+            IpAddr::V4(addr) => {
+                let len = prefix_len.unwrap_or(32);
+                if len > 32 {
+                    bail!("Invalid prefix length in {s}")
+                }
+                IpAddr::V4(Ipv4Addr::from(u32::from(addr) & net::netmask(len)))
+            }
+            IpAddr::V6(addr) => {
+                let len = prefix_len.unwrap_or(128);
+                if len > 128 {
+                    bail!("Invalid prefix length in {s}")
+                }
+                let mask = u128::MAX.checked_shl(128 - len as u32).unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(addr) & mask))
+            }
+        };
+        let prefix_len = prefix_len.unwrap_or(if addr.is_ipv4() { 32 } else { 128 });
+        Ok(Cidr { addr, prefix_len })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Cidr> for String {
+    fn from(cidr: Cidr) -> String {
+        cidr.to_string()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl ProxyConfig {
+    fn has_limits(&self) -> bool {
+        self.max_connections.is_some() || self.max_connection_rate.is_some()
+    }
+
+    /// Who may connect, as danted expects in its rules
+    fn allowed(&self, net: &Network) -> Vec<String> {
+        match &self.allow {
+            Some(allow) => allow.iter().map(Cidr::to_string).collect(),
+            None => vec![net.subnet()],
+        }
+    }
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        ProxyConfig {
+            backend: Backend::Danted,
+            warp_port: 40000,
+            max_connections: None,
+            max_connection_rate: None,
+            allow: None,
+            upstream: None,
+        }
+    }
+}
+
+/// Checks that an upstream proxy URL has a scheme warp-svc's HTTP client knows, a host and a port
+fn check_upstream(url: &str) -> Result<()> {
+    let Some((scheme, rest)) = url.split_once("://") else {
+        bail!("proxy.upstream {url:?} has no scheme, write it like http://proxy.corp:3128")
+    };
+    if scheme != "http" && scheme != "https" {
+        bail!("proxy.upstream must be an http:// or https:// URL, not {scheme}://")
+    }
+    let authority = rest.split('/').next().unwrap_or_default();
+    let host_port = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    match host_port.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => bail!("proxy.upstream {url:?} needs a host and a port"),
+    }
+}
+
+/// warp-svc's service settings, with the upstream proxy in its environment when there is one.
+/// Variables set in the service's own env win.
+pub fn warp_svc_service(config: &Config) -> ServiceConfig {
+    let mut service = config.services.get(Binary::WarpSvc);
+    if let Some(upstream) = &config.proxy.upstream {
+        let no_proxy = format!("localhost,127.0.0.1,::1,{}", config.network.subnet());
+        for (name, value) in [
+            ("HTTPS_PROXY", upstream.as_str()),
+            ("HTTP_PROXY", upstream),
+            ("https_proxy", upstream),
+            ("http_proxy", upstream),
+            ("NO_PROXY", &no_proxy),
+            ("no_proxy", &no_proxy),
+        ] {
+            service
+                .env
+                .entry(name.to_owned())
+                .or_insert_with(|| value.to_owned());
+        }
+    }
+    service
+}
+
+/// Whether the container's proxy is danted, which runs as one of its services
+pub fn runs_danted(config: &Config, parts: Parts) -> bool {
+    parts.proxy && config.proxy.backend == Backend::Danted
+}
+
+/// Fails for settings the configured backend can't honor
+pub fn check(config: &Config) -> Result<()> {
+    if config.proxy.backend == Backend::Warp && config.proxy.has_limits() {
+        bail!("Connection limits are enforced by danted, the warp proxy backend can't apply them")
+    }
+    if config.proxy.backend == Backend::Warp && config.proxy.allow.is_some() {
+        bail!("proxy.allow is enforced by danted, the warp proxy backend can't apply it")
+    }
+    if let Some(upstream) = &config.proxy.upstream {
+        check_upstream(upstream)?;
+    }
+    Ok(())
+}
+
+/// danted.conf's rules. Clients outside the allowed networks are turned away when they connect,
+/// which is also when their rate is limited. The total is limited when a SOCKS session starts,
+/// since danted keys a rule's limits on a single thing.
+pub fn danted_rules(proxy: &ProxyConfig, net: &Network) -> String {
+    let mut limits = String::new();
+    if let Some(rate) = proxy.max_connection_rate {
+        limits += &format!("\n    session.throttle: {rate}/1\n    session.state.key: from\n");
+    }
+    let mut rules: String = proxy
+        .allowed(net)
+        .iter()
+        .map(|from| format!("client pass {{ from: {from} to: 0.0.0.0/0 {limits}}}\n"))
+        .collect();
+    let mut socks = String::new();
+    if let Some(max) = proxy.max_connections {
+        socks += &format!("\n    session.max: {max}\n");
+    }
+    rules += &format!("socks pass {{ from: 0.0.0.0/0 to: 0.0.0.0/0 {socks}}}\n");
+    rules
+}
+
+/// Switches warp-svc to proxy mode, and forwards the proxy port to it
+pub fn setup_warp_proxy(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let base_dir = &config.base_dir;
+    // warp-cli fails until warp-svc opened its socket
+    readiness::wait_until(config.timeouts.warp_ready, || {
+        warp_cli_op(ex, base_dir, Op::Mode("proxy")).map(|_| ())
+    })?;
+    warp_cli_op(ex, base_dir, Op::ProxyPort(config.proxy.warp_port))?;
+    warp_cli(ex, base_dir, &["connect"])?;
+
+    let destination = SocketAddr::from((Ipv4Addr::LOCALHOST, config.proxy.warp_port));
+    forward_to_loopback_inside(
+        ex,
+        base_dir,
+        &config.network,
+        "tcp",
+        SOCKS_PORT,
+        destination,
+    )
+}
+
+/// How a request through the proxy went
+#[derive(Debug, PartialEq)]
+pub struct Fetched {
+    pub body: Vec<u8>,
+    pub status: u16,
+    pub connect: Duration,
+    pub first_byte: Duration,
+    pub total: Duration,
+}
+
+/// How long the proxy, and then the server, get to answer
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Fetches a plain HTTP URL through the proxy, which also resolves its name
+pub fn fetch(config: &Config, url: &str) -> Result<Fetched> {
+    if !Parts::load(&config.base_dir)?.proxy {
+        bail!("The container was brought up without a proxy")
+    }
+    fetch_via(SocketAddr::from((config.network.ns_addr, SOCKS_PORT)), url)
+}
+
+/// The host, port, and path with its query, of an http:// URL
+fn split_url(url: &str) -> Result<(&str, u16, &str)> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        bail!("Only http:// URLs can be fetched, there's no TLS client built in")
+    };
+    let rest = rest.split('#').next().unwrap_or_default();
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (
+            host,
+            port.parse().with_context(|| format!("Bad port in {url}"))?,
+        ),
+        _ => (authority, 80),
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    if host.is_empty() || host.len() > 255 {
+        bail!("Bad host in {url}")
+    }
+    Ok((host, port, path))
+}
+
+/// Asks the SOCKS5 proxy for a connection to the host, by name unless it's an address
+fn socks5_connect(stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+    stream.write_all(&[5, 1, 0])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [5, 0] {
+        bail!("The proxy refused the SOCKS5 greeting with {reply:?}")
+    }
+    let mut request = vec![5, 1, 0];
+    match host.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend(ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend(ip.octets());
+        }
+        Err(_) => {
+            request.extend([3, host.len() as u8]);
+            request.extend(host.as_bytes());
+        }
+    }
+    request.extend(port.to_be_bytes());
+    stream.write_all(&request)?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply)?;
+    if reply[1] != 0 {
+        bail!(
+            "The proxy couldn't connect to {host}:{port}, SOCKS5 reply {}",
+            reply[1]
+        )
+    }
+    // The address the proxy connected from, which we have no use for
+    let addr_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8];
+            stream.read_exact(&mut len)?;
+            len[0] as usize
+        }
+        atyp => bail!("The proxy replied with an unknown address type {atyp}"),
+    };
+    stream.read_exact(&mut vec![0u8; addr_len + 2])?;
+    Ok(())
+}
+
+/// A GET over HTTP/1.0, so the body comes as is until the server closes the connection
+fn fetch_via(proxy: SocketAddr, url: &str) -> Result<Fetched> {
+    let (host, port, path) = split_url(url)?;
+    let start = Instant::now();
+    let mut stream = TcpStream::connect_timeout(&proxy, FETCH_TIMEOUT)
+        .with_context(|| format!("Connecting to the proxy at {proxy}"))?;
+    stream.set_read_timeout(Some(FETCH_TIMEOUT))?;
+    stream.set_write_timeout(Some(FETCH_TIMEOUT))?;
+    socks5_connect(&mut stream, host, port)?;
+    let connect = start.elapsed();
+
+    let authority = match (port, host.contains(':')) {
+        (80, false) => host.to_owned(),
+        (80, true) => format!("[{host}]"),
+        (_, false) => format!("{host}:{port}"),
+        (_, true) => format!("[{host}]:{port}"),
+    };
+    let request = format!(
+        "GET {path} HTTP/1.0\r\nHost: {authority}\r\nUser-Agent: bubblewarp\r\nAccept: */*\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut response = vec![0u8; 8192];
+    let read = stream.read(&mut response)?;
+    let first_byte = start.elapsed();
+    response.truncate(read);
+    stream
+        .read_to_end(&mut response)
+        .context("Reading the response")?;
+    let total = start.elapsed();
+
+    let headers_end = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .context("The server's response has no end of headers")?;
+    let status_line = String::from_utf8_lossy(&response[..headers_end]);
+    let status = status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .with_context(|| {
+            format!(
+                "Bad status line from the server: {}",
+                status_line.lines().next().unwrap_or_default()
+            )
+        })?;
+    Ok(Fetched {
+        body: response.split_off(headers_end + 4),
+        status,
+        connect,
+        first_byte,
+        total,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::MockExecutor;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+
+    #[test]
+    fn forwards_proxy_port_to_warp() {
+        let ex = MockExecutor::without_rules();
+        let config = Config {
+            base_dir: PathBuf::from("/base"),
+            ..Default::default()
+        };
+        setup_warp_proxy(&ex, &config).unwrap();
+        let commands = ex.commands();
+        // The dialect is only probed once per run
+        let first = commands
+            .iter()
+            .position(|c| !c.ends_with("--help"))
+            .unwrap();
+        assert_eq!(
+            commands[first],
+            "nsenter --mount=/base/mount warp-cli --accept-tos mode proxy"
+        );
+        assert_eq!(
+            commands.last().unwrap(),
+            "nsenter --net=/base/net iptables -t nat -A PREROUTING -i veth-warp-ns -p tcp \
+             --dport 8080 -j DNAT --to-destination 127.0.0.1:40000"
+        );
+    }
+
+    #[test]
+    fn splits_urls() {
+        assert_eq!(
+            split_url("http://cloudflare.com/cdn-cgi/trace?x=1#top").unwrap(),
+            ("cloudflare.com", 80, "/cdn-cgi/trace?x=1")
+        );
+        assert_eq!(split_url("http://[::1]:8000").unwrap(), ("::1", 8000, "/"));
+        assert_eq!(split_url("http://[::1]").unwrap(), ("::1", 80, "/"));
+        assert!(split_url("https://cloudflare.com/").is_err());
+        assert!(split_url("http://:80/").is_err());
+    }
+
+    #[test]
+    fn fetches_through_socks5() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let proxy = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut greeting = [0u8; 3];
+            stream.read_exact(&mut greeting).unwrap();
+            stream.write_all(&[5, 0]).unwrap();
+            let mut connect = [0u8; 5 + 14 + 2];
+            stream.read_exact(&mut connect).unwrap();
+            stream.write_all(&[5, 0, 0, 1, 10, 0, 0, 2, 0, 80]).unwrap();
+            let mut request = Vec::new();
+            while !request.ends_with(b"\r\n\r\n") {
+                let mut byte = [0u8];
+                stream.read_exact(&mut byte).unwrap();
+                request.push(byte[0]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n\r\nwarp=on\n")
+                .unwrap();
+            (connect, String::from_utf8(request).unwrap())
+        });
+        let fetched = fetch_via(proxy, "http://cloudflare.com/cdn-cgi/trace").unwrap();
+        assert_eq!(fetched.status, 200);
+        assert_eq!(fetched.body, b"warp=on\n");
+        assert!(fetched.connect <= fetched.first_byte && fetched.first_byte <= fetched.total);
+        let (connect, request) = server.join().unwrap();
+        assert_eq!(&connect[..5], &[5, 1, 0, 3, 14]);
+        assert_eq!(&connect[5..19], b"cloudflare.com");
+        assert_eq!(&connect[19..], &[0, 80]);
+        assert!(request.starts_with("GET /cdn-cgi/trace HTTP/1.0\r\nHost: cloudflare.com\r\n"));
+    }
+
+    #[test]
+    fn passes_upstream_to_warp_svc() {
+        let mut config: Config = toml::from_str(
+            "[proxy]\nupstream = \"http://proxy.corp:3128\"\n\
+             [services.warp-svc]\nenv = { NO_PROXY = \"corp.example\" }\n",
+        )
+        .unwrap();
+        check(&config).unwrap();
+        let env = warp_svc_service(&config).env;
+        assert_eq!(env["HTTPS_PROXY"], "http://proxy.corp:3128");
+        assert_eq!(env["http_proxy"], "http://proxy.corp:3128");
+        assert_eq!(env["NO_PROXY"], "corp.example");
+        assert_eq!(env["no_proxy"], "localhost,127.0.0.1,::1,10.200.0.0/24");
+
+        for bad in [
+            "proxy.corp:3128",
+            "socks5://proxy.corp:1080",
+            "http://proxy.corp",
+        ] {
+            config.proxy.upstream = Some(bad.to_owned());
+            assert!(check(&config).is_err(), "{bad}");
+        }
+        config.proxy.upstream = Some("https://user:pw@proxy.corp:8443/".to_owned());
+        check(&config).unwrap();
+    }
+
+    #[test]
+    fn renders_connection_limits() {
+        assert_eq!(
+            danted_rules(&ProxyConfig::default(), &Network::default()),
+            "client pass { from: 10.200.0.0/24 to: 0.0.0.0/0 }\n\
+             socks pass { from: 0.0.0.0/0 to: 0.0.0.0/0 }\n"
+        );
+        let proxy: ProxyConfig =
+            toml::from_str("max-connections = 200\nmax-connection-rate = 20").unwrap();
+        assert_eq!(
+            danted_rules(&proxy, &Network::default()),
+            "client pass { from: 10.200.0.0/24 to: 0.0.0.0/0 \n    session.throttle: 20/1\n    \
+             session.state.key: from\n}\n\
+             socks pass { from: 0.0.0.0/0 to: 0.0.0.0/0 \n    session.max: 200\n}\n"
+        );
+    }
+
+    #[test]
+    fn renders_allowed_networks() {
+        let proxy: ProxyConfig =
+            toml::from_str("allow = [\"192.168.1.7/24\", \"10.200.0.1\", \"fd00::1/64\"]").unwrap();
+        assert_eq!(
+            danted_rules(&proxy, &Network::default()),
+            "client pass { from: 192.168.1.0/24 to: 0.0.0.0/0 }\n\
+             client pass { from: 10.200.0.1/32 to: 0.0.0.0/0 }\n\
+             client pass { from: fd00::/64 to: 0.0.0.0/0 }\n\
+             socks pass { from: 0.0.0.0/0 to: 0.0.0.0/0 }\n"
+        );
+        assert!(toml::from_str::<ProxyConfig>("allow = [\"10.0.0.0/33\"]").is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use crate::exec::Executor;
+use crate::namespace::{nsenter_child, spawn_inside_all_namespaces};
+use crate::net::Network;
+use crate::pidfd::PidFd;
+use crate::service::ServiceConfig;
+use crate::up::SOCKS_PORT;
+use anyhow::{bail, Context, Result};
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// The tunnel interface warp-svc creates, which danted sends its traffic out of
+pub const WARP_IFACE: &str = "CloudflareWARP";
+
+/// How often a condition that isn't met yet is checked again
+const RETRY_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The `[timeouts]` section of the config file, in seconds.
+/// Slow or loaded machines like a Raspberry Pi may need more than the defaults.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct TimeoutsConfig {
+    /// For the container's init to report that its namespaces exist
+    #[serde(deserialize_with = "seconds", serialize_with = "as_seconds")]
+    pub namespace_create: Duration,
+    /// For warp-svc to create its tunnel interface, before danted starts
+    #[serde(deserialize_with = "seconds", serialize_with = "as_seconds")]
+    pub warp_ready: Duration,
+    /// For the SOCKS proxy to answer
+    #[serde(deserialize_with = "seconds", serialize_with = "as_seconds")]
+    pub proxy_ready: Duration,
+    /// For an extra service's readiness check to pass, before those after it start
+    #[serde(deserialize_with = "seconds", serialize_with = "as_seconds")]
+    pub service_ready: Duration,
+}
+
+impl Default for TimeoutsConfig {
+    fn default() -> Self {
+        TimeoutsConfig {
+            namespace_create: Duration::from_secs(5),
+            warp_ready: Duration::from_secs(5),
+            proxy_ready: Duration::from_secs(3),
+            service_ready: Duration::from_secs(5),
+        }
+    }
+}
+
+impl TimeoutsConfig {
+    /// What --wait-timeout sets, for when all the defaults are too short
+    pub fn set_all(&mut self, timeout: Duration) {
+        self.namespace_create = timeout;
+        self.warp_ready = timeout;
+        self.proxy_ready = timeout;
+        self.service_ready = timeout;
+    }
+}
+
+/// Durations in the config file are in seconds, fractions allowed
+pub fn seconds<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    let secs = f64::deserialize(deserializer)?;
+    Duration::try_from_secs_f64(secs).map_err(serde::de::Error::custom)
+}
+
+/// And written back the same way
+pub fn as_seconds<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    duration.as_secs_f64().serialize(serializer)
+}
+
+/// Parses --wait-timeout
+pub fn parse_seconds(arg: &str) -> Result<Duration, String> {
+    let secs: f64 = arg.parse().map_err(|e| format!("{e}"))?;
+    Duration::try_from_secs_f64(secs).map_err(|e| format!("{e}"))
+}
+
+/// Checks until the condition holds, or returns its last error once the timeout passed
+pub fn wait_until(timeout: Duration, mut check: impl FnMut() -> Result<()>) -> Result<()> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let err = match check() {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        if Instant::now() + RETRY_INTERVAL > deadline {
+            return Err(err);
+        }
+        std::thread::sleep(RETRY_INTERVAL);
+    }
+}
+
+/// When a service counts as ready, for the services started after it. Set in the config like
+/// `ready = { port = 9100 }`, everything is looked at from inside the container.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Check {
+    /// Something listens on this TCP port
+    Port(u16),
+    /// The file exists
+    File(PathBuf),
+    /// The command exits successfully
+    Command(Vec<String>),
+    /// The network interface exists
+    Iface(String),
+}
+
+/// Whether a /proc/net/tcp table has a socket listening on the port
+fn listening(table: &str, port: u16) -> bool {
+    const TCP_LISTEN: &str = "0A";
+    table.lines().skip(1).any(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let local_port = fields
+            .get(1)
+            .and_then(|local| local.rsplit_once(':'))
+            .and_then(|(_, port)| u16::from_str_radix(port, 16).ok());
+        local_port == Some(port) && fields.get(3) == Some(&TCP_LISTEN)
+    })
+}
+
+impl Check {
+    /// Checks once. /proc/<pid>/net and /proc/<pid>/root are the namespaces of that process.
+    /// A command still running after the timeout is killed.
+    fn run(&self, ex: &dyn Executor, ns_pid: u32, timeout: Duration) -> Result<()> {
+        match self {
+            Check::Port(port) => {
+                for table in ["tcp", "tcp6"] {
+                    let table = std::fs::read_to_string(format!("/proc/{ns_pid}/net/{table}"))?;
+                    if listening(&table, *port) {
+                        return Ok(());
+                    }
+                }
+                bail!("nothing listens on port {port}")
+            }
+            Check::File(path) => {
+                let inside = Path::new(&format!("/proc/{ns_pid}/root"))
+                    .join(path.strip_prefix("/").unwrap_or(path));
+                if !inside.exists() {
+                    bail!("{} doesn't exist", path.display())
+                }
+                Ok(())
+            }
+            Check::Command(args) => {
+                let Some((program, args)) = args.split_first() else {
+                    bail!("the readiness command is empty")
+                };
+                let mut cmd = Command::new(program);
+                cmd.args(args);
+                let mut nsenter = spawn_inside_all_namespaces(ex, &cmd, ns_pid, None)?;
+                let process = PidFd::open(nsenter.id() as i32)?;
+                if !process.wait_exit(timeout)? {
+                    // nsenter forked to join the PID namespace, the command is its child
+                    if let Ok(child) = nsenter_child(&nsenter, Duration::ZERO) {
+                        if let Ok(child) = PidFd::open(child) {
+                            let _ = ex.kill(&child, Signal::SIGKILL);
+                        }
+                    }
+                    let _ = ex.kill(&process, Signal::SIGKILL);
+                    let _ = nsenter.wait();
+                    bail!("{program} still ran after {}s", timeout.as_secs_f64())
+                }
+                let status = nsenter.wait()?;
+                if !status.success() {
+                    bail!("{program} exited with {status}")
+                }
+                Ok(())
+            }
+            Check::Iface(iface) => {
+                let ifaces = std::fs::read_to_string(format!("/proc/{ns_pid}/net/dev"))?;
+                if !ifaces
+                    .lines()
+                    .any(|line| line.trim_start().starts_with(&format!("{iface}:")))
+                {
+                    bail!("there is no {iface} interface")
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Waits until the service's readiness check passes, if it has one
+pub fn wait_for_service(
+    ex: &dyn Executor,
+    name: &str,
+    service: &ServiceConfig,
+    ns_pid: u32,
+    timeout: Duration,
+) -> Result<()> {
+    let Some(check) = &service.ready else {
+        return Ok(());
+    };
+    let deadline = Instant::now() + timeout;
+    wait_until(timeout, || {
+        check.run(
+            ex,
+            ns_pid,
+            deadline.saturating_duration_since(Instant::now()),
+        )
+    })
+    .with_context(|| format!("Waited {}s for {name} to be ready", timeout.as_secs_f64()))?;
+    debug!("{name} is ready");
+    Ok(())
+}
+
+/// Does a SOCKS5 greeting until danted answers
+pub fn wait_for_proxy(net: &Network, timeout: Duration) -> Result<()> {
+    let addr = SocketAddr::from((net.ns_addr, SOCKS_PORT));
+    wait_until(timeout, || socks5_greeting(&addr))
+        .with_context(|| format!("SOCKS5 handshake with {addr}"))
+}
+
+fn socks5_greeting(addr: &SocketAddr) -> Result<()> {
+    let mut stream = TcpStream::connect_timeout(addr, Duration::from_secs(1))?;
+    stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+    stream.write_all(&[5, 1, 0])?;
+    let mut reply = [0u8; 2];
+    stream.read_exact(&mut reply)?;
+    if reply != [5, 0] {
+        bail!("unexpected reply {reply:?}")
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timeouts_in_seconds() {
+        let timeouts: TimeoutsConfig = toml::from_str("warp-ready = 12.5").unwrap();
+        assert_eq!(timeouts.warp_ready, Duration::from_millis(12500));
+        assert_eq!(timeouts.proxy_ready, Duration::from_secs(3));
+        let timeouts: TimeoutsConfig = toml::from_str("namespace-create = 10").unwrap();
+        assert_eq!(timeouts.namespace_create, Duration::from_secs(10));
+        assert!(toml::from_str::<TimeoutsConfig>("proxy-ready = -1.0").is_err());
+        assert!(parse_seconds("abc").is_err());
+    }
+
+    #[test]
+    fn readiness_checks() {
+        #[derive(Deserialize)]
+        struct Service {
+            ready: Check,
+        }
+        let parse = |toml: &str| toml::from_str::<Service>(toml).map(|service| service.ready);
+        assert_eq!(parse("ready = { port = 9100 }").unwrap(), Check::Port(9100));
+        assert_eq!(
+            parse("ready = { command = [\"test\", \"-S\", \"/run/x.sock\"] }").unwrap(),
+            Check::Command(vec!["test".into(), "-S".into(), "/run/x.sock".into()])
+        );
+        assert!(parse("ready = { socket = \"/run/x.sock\" }").is_err());
+
+        let table = "  sl  local_address rem_address   st tx_queue rx_queue tr tm->when retrnsmt   uid  timeout inode
+   0: 0A000002:1F90 00000000:0000 0A 00000000:00000000 00:00000000 00000000  1199        0 31337 1
+   1: 0A000002:1F90 0A000001:C350 01 00000000:00000000 00:00000000 00000000  1199        0 31338 1
+   2: 0100007F:238C 0100007F:9C40 01 00000000:00000000 00:00000000 00000000     0        0 31339 1
+";
+        assert!(listening(table, 8080));
+        assert!(!listening(table, 9100));
+    }
+}
@@ -0,0 +1,76 @@
+use crate::exec::Executor;
+use crate::namespace::{self, Status};
+use crate::paths::Binary;
+use crate::up::warp_state_dir;
+use anyhow::{bail, Context, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use tracing::{debug, info};
+
+/// Archives the instance's warp-svc state, which holds the device keys and account
+pub fn export(ex: &dyn Executor, base_dir: &Path, file: &Path) -> Result<()> {
+    let state_dir = warp_state_dir(base_dir);
+    let is_empty = match std::fs::read_dir(&state_dir) {
+        Ok(mut entries) => entries.next().is_none(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => true,
+        Err(e) => return Err(e.into()),
+    };
+    if is_empty {
+        bail!(
+            "There is no registration in {} to export",
+            state_dir.display()
+        )
+    }
+
+    debug!("Archiving {} to {}", state_dir.display(), file.display());
+    ex.status(
+        ex.command(Binary::Tar)?
+            .arg("-C")
+            .arg(&state_dir)
+            .arg("-czf")
+            .arg(file)
+            .arg("."),
+    )?
+    .exit_ok()
+    .context("Archiving the registration")?;
+    std::fs::set_permissions(file, std::fs::Permissions::from_mode(0o600))?;
+    info!("Exported the registration to {}", file.display());
+    Ok(())
+}
+
+/// Replaces the instance's warp-svc state with an archive made by export
+pub fn import(ex: &dyn Executor, base_dir: &Path, file: &Path) -> Result<()> {
+    if namespace::status(base_dir)? != Status::None {
+        bail!("warp-svc may still be using its state, run the down command before importing")
+    }
+    let state_dir = warp_state_dir(base_dir);
+    let staging = state_dir.with_extension("import");
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+    std::fs::set_permissions(&staging, std::fs::Permissions::from_mode(0o700))?;
+
+    debug!("Extracting {} to {}", file.display(), staging.display());
+    let extracted = ex
+        .status(
+            ex.command(Binary::Tar)?
+                .arg("-C")
+                .arg(&staging)
+                .arg("--no-same-owner")
+                .arg("-xzf")
+                .arg(file),
+        )?
+        .exit_ok();
+    if let Err(e) = extracted {
+        let _ = std::fs::remove_dir_all(&staging);
+        return Err(e).context("Extracting the registration");
+    }
+
+    if state_dir.exists() {
+        std::fs::remove_dir_all(&state_dir)?;
+    }
+    std::fs::rename(&staging, &state_dir)?;
+    info!("Imported the registration from {}", file.display());
+    Ok(())
+}
@@ -0,0 +1,262 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::namespace::{Mounts, Status};
+use crate::pause;
+use crate::readiness::{as_seconds, seconds, wait_for_proxy, WARP_IFACE};
+use crate::up::{find_pid_ns_init_process, up, SOCKS_PORT};
+use anyhow::{bail, Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use serde::{Deserialize, Serialize};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{FromRawFd, RawFd};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{debug, info, warn};
+
+/// The first fd systemd passes, see sd_listen_fds(3)
+const SD_LISTEN_FDS_START: RawFd = 3;
+/// How often the relay looks for traffic through the tunnel, when it suspends idle ones
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The `[relay]` section of the config file
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RelayConfig {
+    /// Seconds without relayed connections or traffic through WARP's tunnel after which the relay
+    /// pauses WARP, until the next connection resumes it. 0 never pauses, the default.
+    #[serde(deserialize_with = "seconds", serialize_with = "as_seconds")]
+    pub suspend_after: Duration,
+}
+
+/// The relay's connections, for the idle timer
+struct Activity {
+    open: usize,
+    last: Instant,
+}
+
+/// The listening socket systemd passed us, if it started us for socket activation
+fn activated_listener() -> Result<Option<TcpListener>> {
+    let for_us = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(std::process::id());
+    if !for_us {
+        return Ok(None);
+    }
+    let fds: u32 = std::env::var("LISTEN_FDS")?.parse()?;
+    // Not for the processes we start
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+    match fds {
+        0 => return Ok(None),
+        1 => (),
+        _ => bail!("systemd passed {fds} sockets, the relay listens on one"),
+    }
+    fcntl(SD_LISTEN_FDS_START, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+    // SAFETY: systemd passed this fd to us and nothing else took it
+    Ok(Some(unsafe {
+        TcpListener::from_raw_fd(SD_LISTEN_FDS_START)
+    }))
+}
+
+/// Bytes received and sent by an interface, from a /proc/net/dev table
+fn iface_bytes(net_dev: &str, iface: &str) -> Option<u64> {
+    let line = net_dev
+        .lines()
+        .find_map(|line| line.trim_start().strip_prefix(&format!("{iface}:")))?;
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    let rx: u64 = fields.first()?.parse().ok()?;
+    let tx: u64 = fields.get(8)?.parse().ok()?;
+    Some(rx + tx)
+}
+
+/// Pauses WARP once neither the relay nor anything routed through the container used the tunnel
+/// for `suspend_after`. Traffic forwarded to the container goes through the tunnel too, its
+/// counters cover it along with the proxy's. danted stops too, so that nothing reaches it
+/// around the relay while the tunnel is down.
+fn suspend_when_idle(
+    ex: &dyn Executor,
+    config: &Config,
+    bringing_up: &Mutex<()>,
+    activity: &Mutex<Activity>,
+) {
+    let suspend_after = config.relay.suspend_after;
+    let (mut tunnel_bytes, mut last_traffic) = (None, Instant::now());
+    loop {
+        std::thread::sleep(IDLE_CHECK_INTERVAL.min(suspend_after));
+        let _guard = bringing_up.lock().unwrap_or_else(|e| e.into_inner());
+        let base_dir = &config.base_dir;
+        if pause::is_paused(base_dir) {
+            continue;
+        }
+        // Nothing to suspend while the container is down
+        let Ok(ns_pid) = pause::ensure_up(base_dir) else {
+            continue;
+        };
+        let bytes = std::fs::read_to_string(format!("/proc/{ns_pid}/net/dev"))
+            .ok()
+            .and_then(|net_dev| iface_bytes(&net_dev, WARP_IFACE));
+        if bytes != tunnel_bytes {
+            (tunnel_bytes, last_traffic) = (bytes, Instant::now());
+        }
+        let (open, last_connection) = {
+            let activity = activity.lock().unwrap_or_else(|e| e.into_inner());
+            (activity.open, activity.last)
+        };
+        let idle_since = last_connection.max(last_traffic);
+        if open == 0 && idle_since.elapsed() >= suspend_after {
+            info!(
+                "Nothing used the tunnel for {} s, pausing WARP until the next connection",
+                idle_since.elapsed().as_secs()
+            );
+            if let Err(e) = pause::pause(ex, config, true) {
+                warn!("Pausing the idle container failed: {e:#}");
+            }
+        }
+    }
+}
+
+/// Brings the container up if it isn't, or resumes it if it's paused, one connection at a time
+fn ensure_up(ex: &dyn Executor, config: &Config, bringing_up: &Mutex<()>) -> Result<()> {
+    let _guard = bringing_up.lock().unwrap_or_else(|e| e.into_inner());
+    let base_dir = &config.base_dir;
+    let is_up = Mounts::snapshot()?.status(base_dir) == Status::Ready
+        && find_pid_ns_init_process(base_dir)?.is_some();
+    if !is_up {
+        info!("Bringing the container up for a connection");
+        up(ex, config)?;
+    } else if pause::is_paused(base_dir) {
+        info!("Resuming the container for a connection");
+        return pause::resume(ex, config);
+    }
+    wait_for_proxy(&config.network, config.timeouts.proxy_ready)
+}
+
+/// Connects to the proxy, waking the container first if it's paused. A paused container's proxy
+/// may still listen, and would serve without the tunnel.
+fn connect_upstream(
+    target: SocketAddr,
+    is_paused: bool,
+    mut wake: impl FnMut() -> Result<()>,
+) -> Result<TcpStream> {
+    if is_paused {
+        wake()?;
+    }
+    match TcpStream::connect(target) {
+        Ok(upstream) => Ok(upstream),
+        Err(e) => {
+            debug!("Connecting to {target} failed: {e}");
+            wake()?;
+            TcpStream::connect(target).with_context(|| format!("Connecting to {target}"))
+        }
+    }
+}
+
+fn serve(
+    ex: &dyn Executor,
+    config: &Config,
+    bringing_up: &Mutex<()>,
+    client: TcpStream,
+) -> Result<()> {
+    let target = SocketAddr::from((config.network.ns_addr, SOCKS_PORT));
+    let upstream = connect_upstream(target, pause::is_paused(&config.base_dir), || {
+        ensure_up(ex, config, bringing_up)
+    })?;
+    let (mut client_read, mut upstream_write) = (client.try_clone()?, upstream.try_clone()?);
+    std::thread::scope(|scope| {
+        scope.spawn(move || {
+            let _ = std::io::copy(&mut client_read, &mut upstream_write);
+            let _ = upstream_write.shutdown(Shutdown::Write);
+        });
+        let (mut upstream, mut client) = (upstream, client);
+        let _ = std::io::copy(&mut upstream, &mut client);
+        let _ = client.shutdown(Shutdown::Write);
+    });
+    Ok(())
+}
+
+/// Relays connections from a host port to the container's proxy, bringing the container up or
+/// resuming it on the first one. The port comes from systemd socket activation, or `listen`
+/// otherwise.
+pub fn relay(ex: &dyn Executor, config: &Config, listen: Option<SocketAddr>) -> Result<()> {
+    let listener = match activated_listener()? {
+        Some(listener) => listener,
+        None => {
+            let listen =
+                listen.context("Not started by systemd socket activation, pass --listen")?;
+            TcpListener::bind(listen).with_context(|| format!("Listening on {listen}"))?
+        }
+    };
+    info!(
+        "Relaying {} to the container's proxy",
+        listener.local_addr()?
+    );
+    let bringing_up = Mutex::new(());
+    let activity = Mutex::new(Activity {
+        open: 0,
+        last: Instant::now(),
+    });
+    std::thread::scope(|scope| {
+        if !config.relay.suspend_after.is_zero() {
+            scope.spawn(|| suspend_when_idle(ex, config, &bringing_up, &activity));
+        }
+        for client in listener.incoming() {
+            let client = match client {
+                Ok(client) => client,
+                Err(e) => {
+                    warn!("Accepting a connection failed: {e}");
+                    continue;
+                }
+            };
+            let (bringing_up, activity) = (&bringing_up, &activity);
+            scope.spawn(move || {
+                activity.lock().unwrap_or_else(|e| e.into_inner()).open += 1;
+                if let Err(e) = serve(ex, config, bringing_up, client) {
+                    warn!("Relaying a connection failed: {e:#}");
+                }
+                let mut activity = activity.lock().unwrap_or_else(|e| e.into_inner());
+                activity.open -= 1;
+                activity.last = Instant::now();
+            });
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_tunnel_bytes() {
+        let net_dev = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo:     120       2    0    0    0     0          0         0      120       2    0    0    0     0       0          0
+CloudflareWARP:  5000      10    0    0    0     0          0         0     700       9    0    0    0     0       0          0
+";
+        assert_eq!(iface_bytes(net_dev, WARP_IFACE), Some(5700));
+        assert_eq!(iface_bytes(net_dev, "lo"), Some(240));
+        assert_eq!(iface_bytes(net_dev, "eth0"), None);
+    }
+
+    #[test]
+    fn resumes_paused_container_with_listening_proxy() {
+        let proxy = TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = proxy.local_addr().unwrap();
+        let mut woken = 0;
+        connect_upstream(target, true, || {
+            woken += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(woken, 1);
+        connect_upstream(target, false, || {
+            woken += 1;
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(woken, 1);
+    }
+}
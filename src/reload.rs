@@ -0,0 +1,227 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::paths::Binary;
+use crate::pause::ensure_up;
+use crate::proxy::runs_danted;
+use crate::rootfs;
+use crate::service;
+use crate::up::{etc_overlay_dir, write_etc_files, Parts};
+use anyhow::{bail, Context, Result};
+use nix::sys::signal::Signal;
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
+
+/// Settings the running container picks up on reload, by section. Those with keys only have
+/// these keys applied, the others need a restart.
+const LIVE: &[(&str, &[&str])] = &[
+    ("dns", &["mode", "servers"]),
+    (
+        "proxy",
+        &["allow", "max-connections", "max-connection-rate"],
+    ),
+    ("hosts", &[]),
+];
+/// Read by each command as it runs, nothing in the container has them
+const NOT_IN_CONTAINER: &[&str] = &["health", "watchdog", "timeouts", "relay"];
+
+fn recorded_path(base_dir: &Path) -> PathBuf {
+    base_dir.join("applied-config.toml")
+}
+
+/// The config file the running container was set up from, as far as it was applied
+pub fn recorded(base_dir: &Path) -> Result<Option<toml::Table>> {
+    match std::fs::read_to_string(recorded_path(base_dir)) {
+        Ok(data) => Ok(Some(toml::from_str(&data)?)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn record(base_dir: &Path, file: &toml::Table) -> Result<()> {
+    std::fs::write(recorded_path(base_dir), toml::to_string(file)?)?;
+    Ok(())
+}
+
+pub fn forget(base_dir: &Path) -> Result<()> {
+    match std::fs::remove_file(recorded_path(base_dir)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+/// What changed between the applied config and the file, as sections or section.key
+#[derive(Debug, Default, PartialEq)]
+struct Changes {
+    live: Vec<String>,
+    not_in_container: Vec<String>,
+    restart: Vec<String>,
+}
+
+fn changed_keys(old: &toml::Table, new: &toml::Table) -> Vec<String> {
+    let mut keys: Vec<&String> = old.keys().chain(new.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    keys.into_iter()
+        .filter(|&key| old.get(key) != new.get(key))
+        .cloned()
+        .collect()
+}
+
+fn section<'a>(file: &'a toml::Table, name: &str) -> &'a toml::Table {
+    static EMPTY: std::sync::OnceLock<toml::Table> = std::sync::OnceLock::new();
+    match file.get(name) {
+        Some(toml::Value::Table(table)) => table,
+        _ => EMPTY.get_or_init(toml::Table::new),
+    }
+}
+
+/// Files in /etc are only rewritten with the host rootfs, a rootfs is assembled again on up.
+/// danted rereads its rules, warp-svc's proxy backend has none.
+fn classify(old: &toml::Table, new: &toml::Table, host_rootfs: bool, danted: bool) -> Changes {
+    let mut changes = Changes::default();
+    for name in changed_keys(old, new) {
+        if NOT_IN_CONTAINER.contains(&name.as_str()) {
+            changes.not_in_container.push(name);
+            continue;
+        }
+        let live_keys = LIVE
+            .iter()
+            .find(|(live, _)| *live == name)
+            .map(|(_, keys)| *keys)
+            .filter(|_| host_rootfs);
+        let (old, new) = (section(old, &name), section(new, &name));
+        match live_keys {
+            // A hosts file that comes or goes changes which layer of the overlay has it
+            Some([]) if name == "hosts" && (old.is_empty() || new.is_empty()) => {
+                changes.restart.push(name)
+            }
+            Some([]) => changes.live.push(name),
+            Some(keys) => {
+                for key in changed_keys(old, new) {
+                    let is_live = keys.contains(&key.as_str()) && (name != "proxy" || danted);
+                    let changed = format!("{name}.{key}");
+                    match is_live {
+                        true => changes.live.push(changed),
+                        false => changes.restart.push(changed),
+                    }
+                }
+            }
+            None => changes.restart.push(name),
+        }
+    }
+    changes
+}
+
+/// The applied config with the changes that were applied live
+fn applied(old: &toml::Table, new: &toml::Table, live: &[String]) -> toml::Table {
+    let mut applied = old.clone();
+    for change in live {
+        let (table, key, from) = match change.split_once('.') {
+            Some((name, key)) => {
+                let table = applied
+                    .entry(name)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()));
+                let Some(table) = table.as_table_mut() else {
+                    continue;
+                };
+                (table, key, section(new, name))
+            }
+            None => (&mut applied, change.as_str(), new),
+        };
+        match from.get(key) {
+            Some(value) => table.insert(key.to_owned(), value.clone()),
+            None => table.remove(key),
+        };
+    }
+    applied
+}
+
+/// Applies the config file's changes that don't need the container to go down: the DNS mode
+/// and servers, the hosts entries, and the proxy's client rules. Reports the others, which
+/// need a restart.
+pub fn reload(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let base_dir = config.base_dir.as_path();
+    ensure_up(base_dir)?;
+    let Some(old) = recorded(base_dir)? else {
+        bail!("The container was brought up without recording its config, restart it once to reload later")
+    };
+    config.check()?;
+    let parts = Parts::load(base_dir)?;
+    let host_rootfs = config.container.rootfs == rootfs::Kind::Host;
+    let danted = runs_danted(config, parts);
+    let changes = classify(&old, &config.file, host_rootfs, danted);
+
+    if !changes.live.is_empty() {
+        // Rewritten in place, the overlay shows them as long as nothing inside wrote over them
+        let extra_lower = etc_overlay_dir(base_dir).join("extra_lower");
+        write_etc_files(&extra_lower, config, Some(Path::new("/etc")))
+            .context("Rewriting the container's /etc files")?;
+        if danted && changes.live.iter().any(|c| c.starts_with("proxy.")) {
+            if let Some(process) = service::running(base_dir, Binary::Danted.name())? {
+                ex.kill(&process, Signal::SIGHUP)?;
+            }
+        }
+        record(base_dir, &applied(&old, &config.file, &changes.live))?;
+        info!("Applied {}", changes.live.join(", "));
+    }
+    if !changes.not_in_container.is_empty() {
+        info!(
+            "{} apply from the next command on, restart watch and relay for them to use them",
+            changes.not_in_container.join(", ")
+        );
+    }
+    if !changes.restart.is_empty() {
+        warn!(
+            "{} changed, restart the container to apply them",
+            changes.restart.join(", ")
+        );
+    }
+    if changes == Changes::default() {
+        info!("Nothing changed since the config was applied");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sorts_changes() {
+        let old: toml::Table = toml::from_str(
+            "[dns]\nmode = \"warp\"\n[proxy]\nmax-connections = 10\n[hosts]\nnas = \"10.0.0.2\"\n",
+        )
+        .unwrap();
+        let new: toml::Table = toml::from_str(
+            "[dns]\nmode = \"custom\"\nservers = [\"9.9.9.9\"]\nexpose = true\n\
+             [proxy]\nmax-connections = 20\nbackend = \"danted\"\n\
+             [hosts]\nnas = \"10.0.0.3\"\n[container]\nhostname = \"w\"\n[watchdog]\nfailures = 5\n",
+        )
+        .unwrap();
+        let changes = classify(&old, &new, true, true);
+        assert_eq!(
+            changes,
+            Changes {
+                live: vec![
+                    "dns.mode".to_owned(),
+                    "dns.servers".to_owned(),
+                    "hosts".to_owned(),
+                    "proxy.max-connections".to_owned(),
+                ],
+                not_in_container: vec!["watchdog".to_owned()],
+                restart: vec![
+                    "container".to_owned(),
+                    "dns.expose".to_owned(),
+                    "proxy.backend".to_owned(),
+                ],
+            }
+        );
+        let applied = applied(&old, &new, &changes.live);
+        assert_eq!(
+            toml::to_string(&applied).unwrap(),
+            "[dns]\nmode = \"custom\"\nservers = [\"9.9.9.9\"]\n\n\
+             [hosts]\nnas = \"10.0.0.3\"\n\n[proxy]\nmax-connections = 20\n"
+        );
+        assert!(classify(&old, &new, false, true).live.is_empty());
+    }
+}
@@ -0,0 +1,71 @@
+use crate::config::Config;
+use crate::down::down;
+use crate::exec::Executor;
+use crate::extra;
+use crate::namespace::{Mounts, Status};
+use crate::paths::Binary;
+use crate::proxy::{runs_danted, warp_svc_service};
+use crate::readiness::{wait_for_proxy, wait_for_service};
+use crate::service;
+use crate::up::{find_pid_ns_init_process, spawn_process_inside, up, Parts};
+use anyhow::{bail, Result};
+use tracing::{info, warn};
+
+/// Restarts warp, and returns once the proxy answers again.
+/// With `services_only`, warp-svc and danted are cycled inside the running container, which keeps
+/// its namespaces, networking and WARP registration. Otherwise the container is rebuilt.
+pub fn restart(ex: &dyn Executor, config: &Config, services_only: bool) -> Result<()> {
+    let parts = Parts::load(&config.base_dir)?;
+    if services_only {
+        restart_services(ex, config, parts)?;
+    } else {
+        down(ex, config)?;
+        // Comes back the way it was brought up
+        up(
+            ex,
+            &Config {
+                parts,
+                ..config.clone()
+            },
+        )?;
+    }
+    if !parts.proxy {
+        return Ok(());
+    }
+    wait_for_proxy(&config.network, config.timeouts.proxy_ready)
+}
+
+fn restart_services(ex: &dyn Executor, config: &Config, parts: Parts) -> Result<()> {
+    let base_dir = config.base_dir.as_path();
+    if Mounts::snapshot()?.status(base_dir) != Status::Ready {
+        bail!("The container isn't up, run up or restart without --services-only")
+    }
+    let Some(init_proc) = find_pid_ns_init_process(base_dir)? else {
+        bail!("The container's init process is dead, restart without --services-only")
+    };
+    let ns_init_pid = init_proc.pid as u32;
+
+    info!("Restarting the container's services");
+    extra::stop_all(ex, config)?;
+    // danted goes first, it has nothing to serve without warp-svc
+    service::stop(ex, base_dir, Binary::Danted.name())?;
+    service::stop(ex, base_dir, Binary::WarpSvc.name())?;
+    for bin in [Binary::WarpSvc, Binary::Danted] {
+        if bin == Binary::Danted && !runs_danted(config, parts) {
+            continue;
+        }
+        let service = match bin {
+            Binary::WarpSvc => warp_svc_service(config),
+            _ => config.services.get(bin),
+        };
+        spawn_process_inside(ex, base_dir, bin, &service, ns_init_pid)?;
+        let timeout = match bin {
+            Binary::WarpSvc => config.timeouts.warp_ready,
+            _ => config.timeouts.proxy_ready,
+        };
+        if let Err(e) = wait_for_service(ex, bin.name(), &service, ns_init_pid, timeout) {
+            warn!("{e:#}, starting the other services anyway");
+        }
+    }
+    extra::start_all(ex, config, ns_init_pid)
+}
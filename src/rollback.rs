@@ -0,0 +1,73 @@
+use anyhow::Result;
+use std::sync::Mutex;
+use tracing::warn;
+
+type Undo<'a> = Box<dyn FnOnce() -> Result<()> + Send + 'a>;
+
+/// Undoes the steps of a setup that didn't complete, newest first, when dropped before being
+/// disarmed. That covers errors returned with `?` as well as panics, so a failed up doesn't
+/// leave a half-built container behind. Steps can be added from several setup threads.
+pub struct Rollback<'a> {
+    steps: Mutex<Vec<(&'static str, Undo<'a>)>>,
+}
+
+impl<'a> Rollback<'a> {
+    pub fn new() -> Self {
+        Rollback {
+            steps: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Adds a step, before doing it, so what it did partially is undone too.
+    /// The undo must cope with the step not having happened at all.
+    pub fn push(&self, what: &'static str, undo: impl FnOnce() -> Result<()> + Send + 'a) {
+        // A panicking setup thread may have poisoned the lock, the steps are still valid
+        let mut steps = self.steps.lock().unwrap_or_else(|e| e.into_inner());
+        steps.push((what, Box::new(undo)));
+    }
+
+    /// The setup completed, keep everything it did
+    pub fn disarm(self) {
+        self.steps.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    }
+}
+
+impl Drop for Rollback<'_> {
+    fn drop(&mut self) {
+        let steps = std::mem::take(self.steps.get_mut().unwrap_or_else(|e| e.into_inner()));
+        if steps.is_empty() {
+            return;
+        }
+        warn!("Setup didn't complete, undoing what it did");
+        for (what, undo) in steps.into_iter().rev() {
+            if let Err(e) = undo() {
+                warn!("Failed to undo the {what}: {e:#}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn undoes_in_reverse_unless_disarmed() {
+        let undone = Mutex::new(Vec::new());
+        let undo = |what| {
+            undone.lock().unwrap().push(what);
+            Ok(())
+        };
+        {
+            let rollback = Rollback::new();
+            rollback.push("mount", || undo("mount"));
+            rollback.push("rules", || undo("rules"));
+        }
+        assert_eq!(*undone.lock().unwrap(), ["rules", "mount"]);
+
+        let rollback = Rollback::new();
+        rollback.push("mount", || undo("again"));
+        rollback.disarm();
+        assert_eq!(undone.lock().unwrap().len(), 2);
+    }
+}
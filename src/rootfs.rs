@@ -0,0 +1,317 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::image;
+use crate::namespace::spawn_inside_all_namespaces;
+use crate::paths::Binary;
+use crate::up::{
+    hosts_entries, warp_state_dir, write_etc_files, ROOT_GROUP, ROOT_PASSWD, WARP_STATE_DIR,
+};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use tracing::{debug, info, warn};
+
+/// Where the container's filesystem comes from
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Kind {
+    /// The host's filesystem, with a few private mounts on top
+    #[default]
+    Host,
+    /// A managed rootfs holding only warp-svc, warp-cli, danted, busybox and what they need to run
+    Minimal,
+    /// The unpacked layers of container.image, which must provide warp-svc, warp-cli, danted,
+    /// setpriv and umount
+    Image,
+}
+
+/// Only present in an assembled rootfs, so its presence under an init's root means we pivoted
+const MARKER: &str = ".bubblewarp-rootfs";
+const OLD_ROOT: &str = ".old_root";
+
+const DIRS: &[&str] = &[
+    "bin", "dev/net", "etc", "proc", "root", "run", "sys", "tmp", "var/lib",
+];
+const BUSYBOX_APPLETS: &[&str] = &[
+    "sh", "cat", "ls", "ps", "mount", "umount", "rmdir", "ip", "ping", "nslookup", "wget",
+];
+/// Bind mounted from the host, warp-svc needs the tun device
+const DEVICES: &[&str] = &[
+    "/dev/null",
+    "/dev/zero",
+    "/dev/full",
+    "/dev/random",
+    "/dev/urandom",
+    "/dev/tty",
+    "/dev/net/tun",
+];
+/// Copied when the host has them. warp-svc talks TLS to the Cloudflare API, hence the CA bundles.
+const HOST_FILES: &[&str] = &[
+    "/etc/ld.so.cache",
+    "/etc/localtime",
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+pub fn path(base_dir: &Path) -> PathBuf {
+    base_dir.join("rootfs")
+}
+
+/// Whether the container's init already runs inside the minimal rootfs
+pub fn is_entered(ns_init_pid: u32) -> bool {
+    Path::new(&format!("/proc/{ns_init_pid}/root"))
+        .join(MARKER)
+        .exists()
+}
+
+fn host_path_in(root: &Path, host_path: &Path) -> PathBuf {
+    root.join(host_path.strip_prefix("/").unwrap_or(host_path))
+}
+
+/// Copies a host file to the same path in the rootfs, following symlinks
+fn copy_in(root: &Path, host_path: &Path) -> Result<()> {
+    let dest = host_path_in(root, host_path);
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::copy(host_path, &dest)
+        .with_context(|| format!("Copying {} into the rootfs", host_path.display()))?;
+    Ok(())
+}
+
+/// Shared libraries out of ldd's output, the vDSO has no path and is skipped
+fn parse_ldd(out: &str) -> Vec<PathBuf> {
+    out.lines()
+        .filter_map(|line| {
+            let line = line.trim();
+            let target = line.split_once(" => ").map_or(line, |(_, target)| target);
+            let path = target.split_whitespace().next()?;
+            path.starts_with('/').then(|| PathBuf::from(path))
+        })
+        .collect()
+}
+
+/// Binaries keep their host path, so the paths we resolved on the host work inside
+fn copy_binary_in(ex: &dyn Executor, root: &Path, bin: Binary) -> Result<PathBuf> {
+    let path = ex.which(bin)?;
+    copy_in(root, &path)?;
+    let out = ex.output(ex.command(Binary::Ldd)?.arg(&path))?;
+    // ldd fails on static executables
+    if out.status.success() {
+        for lib in parse_ldd(&String::from_utf8_lossy(&out.stdout)) {
+            copy_in(root, &lib)?;
+        }
+    }
+    Ok(path)
+}
+
+/// Builds the rootfs from scratch, so it picks up updated binaries
+pub fn assemble(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let root = path(&config.base_dir);
+    if root.exists() {
+        std::fs::remove_dir_all(&root)?;
+    }
+    let image = match config.container.rootfs {
+        Kind::Image => Some(config.container.image.as_deref().ok_or_else(|| {
+            anyhow!("container.rootfs is \"image\", but container.image isn't set")
+        })?),
+        _ => None,
+    };
+    if let Some(reference) = image {
+        image::pull(ex, &config.base_dir, reference)?;
+        info!("Assembling rootfs from {reference} in {}", root.display());
+        image::unpack(ex, &config.base_dir, reference, &root)?;
+    } else {
+        info!("Assembling minimal rootfs in {}", root.display());
+    }
+    for dir in DIRS.iter().chain([&OLD_ROOT]) {
+        std::fs::create_dir_all(root.join(dir))?;
+    }
+    std::fs::create_dir_all(host_path_in(&root, Path::new(WARP_STATE_DIR)))?;
+
+    let etc = root.join("etc");
+    if image.is_some() {
+        // The image brings its own libraries, certificates and accounts
+        write_etc_files(&etc, config, Some(&etc))?;
+    } else {
+        populate_minimal(ex, &root)?;
+        write_etc_files(&etc, config, None)?;
+    }
+    std::fs::write(
+        etc.join("hosts"),
+        format!(
+            "127.0.0.1 localhost {0}\n::1 localhost {0}\n{1}",
+            config.container.hostname,
+            hosts_entries(config)?
+        ),
+    )?;
+
+    // Mount points for the host's device nodes
+    for dev in DEVICES {
+        File::create(host_path_in(&root, Path::new(dev)))?;
+    }
+    File::create(root.join(MARKER))?;
+    Ok(())
+}
+
+/// The binaries we run with their libraries, and the few files they read
+fn populate_minimal(ex: &dyn Executor, root: &Path) -> Result<()> {
+    for bin in [
+        Binary::WarpSvc,
+        Binary::WarpCli,
+        Binary::Danted,
+        Binary::Setpriv,
+    ] {
+        copy_binary_in(ex, root, bin)?;
+    }
+    let busybox = copy_binary_in(ex, root, Binary::Busybox)?;
+    for applet in BUSYBOX_APPLETS {
+        let link = root.join("bin").join(applet);
+        if !link.exists() {
+            std::os::unix::fs::symlink(&busybox, link)?;
+        }
+    }
+
+    for host_file in HOST_FILES.iter().map(Path::new) {
+        if host_file.exists() {
+            copy_in(root, host_file)?;
+        }
+    }
+    let etc = root.join("etc");
+    std::fs::write(etc.join("passwd"), ROOT_PASSWD)?;
+    std::fs::write(etc.join("group"), ROOT_GROUP)?;
+    std::fs::write(
+        etc.join("nsswitch.conf"),
+        "passwd: files\ngroup: files\nhosts: files dns\n",
+    )?;
+    Ok(())
+}
+
+fn mount_inside(ex: &dyn Executor, ns_init_pid: u32, cmd: &mut Command) -> Result<()> {
+    spawn_inside_all_namespaces(ex, cmd, ns_init_pid, None)?
+        .wait()?
+        .exit_ok()?;
+    Ok(())
+}
+
+/// Mounts what the rootfs needs from inside the container, then pivots the whole container into it.
+/// pivot_root moves every process whose root is the old one, our init included.
+pub fn enter(ex: &dyn Executor, base_dir: &Path, ns_init_pid: u32) -> Result<()> {
+    let root = path(base_dir);
+    let inside = |p: &str| host_path_in(&root, Path::new(p));
+
+    debug!("Mounting the rootfs inside namespace");
+    // pivot_root needs the new root to be a mount point
+    mount_inside(
+        ex,
+        ns_init_pid,
+        Command::new("mount").arg("--bind").arg(&root).arg(&root),
+    )
+    .context("Bind mounting the rootfs")?;
+    mount_inside(
+        ex,
+        ns_init_pid,
+        Command::new("mount")
+            .args(["-t", "proc", "proc"])
+            .arg(inside("/proc")),
+    )
+    .context("Mounting /proc in the rootfs")?;
+    if let Err(e) = mount_inside(
+        ex,
+        ns_init_pid,
+        Command::new("mount")
+            .args(["-t", "sysfs", "-o", "ro,nosuid,nodev,noexec", "sysfs"])
+            .arg(inside("/sys")),
+    ) {
+        warn!("Failed to mount /sys in the rootfs, continuing without it: {e}");
+    }
+    for (target, mode) in [("/tmp", "1777"), ("/run", "755")] {
+        mount_inside(
+            ex,
+            ns_init_pid,
+            Command::new("mount")
+                .args(["-t", "tmpfs", "tmpfs"])
+                .arg(format!("-omode={mode},nosuid,nodev"))
+                .arg(inside(target)),
+        )
+        .with_context(|| format!("Mounting tmpfs on {target} in the rootfs"))?;
+    }
+    for dev in DEVICES {
+        if !Path::new(dev).exists() {
+            debug!("{dev} doesn't exist on the host, leaving it out of the rootfs");
+            continue;
+        }
+        mount_inside(
+            ex,
+            ns_init_pid,
+            Command::new("mount")
+                .arg("--bind")
+                .arg(dev)
+                .arg(inside(dev)),
+        )
+        .with_context(|| format!("Bind mounting {dev} in the rootfs"))?;
+    }
+    let state_dir = warp_state_dir(base_dir);
+    std::fs::create_dir_all(&state_dir)?;
+    mount_inside(
+        ex,
+        ns_init_pid,
+        Command::new("mount")
+            .arg("--bind")
+            .arg(&state_dir)
+            .arg(inside(WARP_STATE_DIR)),
+    )
+    .with_context(|| format!("Bind mounting {WARP_STATE_DIR} in the rootfs"))?;
+
+    debug!("Pivoting the container into the rootfs");
+    mount_inside(
+        ex,
+        ns_init_pid,
+        ex.command(Binary::PivotRoot)?
+            .arg(&root)
+            .arg(root.join(OLD_ROOT)),
+    )
+    .context("Pivoting into the rootfs")?;
+    // From here on, commands run with the rootfs' busybox
+    mount_inside(
+        ex,
+        ns_init_pid,
+        Command::new("umount").args(["-l", &format!("/{OLD_ROOT}")]),
+    )
+    .context("Detaching the host filesystem from the container")?;
+    Ok(())
+}
+
+/// The mounts inside it went away with the container's mount namespace
+pub fn remove(base_dir: &Path) -> Result<()> {
+    let root = path(base_dir);
+    if root.exists() {
+        debug!("Removing rootfs {}", root.display());
+        std::fs::remove_dir_all(&root)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_ldd_output() {
+        let out = "\tlinux-vdso.so.1 (0x00007ffc6b5f2000)
+\tlibc.so.6 => /lib/x86_64-linux-gnu/libc.so.6 (0x00007f1d2c800000)
+\tlibmissing.so.1 => not found
+\t/lib64/ld-linux-x86-64.so.2 (0x00007f1d2cb0d000)
+";
+        assert_eq!(
+            parse_ldd(out),
+            [
+                PathBuf::from("/lib/x86_64-linux-gnu/libc.so.6"),
+                PathBuf::from("/lib64/ld-linux-x86-64.so.2"),
+            ]
+        );
+    }
+}
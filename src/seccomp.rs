@@ -0,0 +1,177 @@
+use libc::{c_long, sock_filter, sock_fprog};
+use serde::{Deserialize, Serialize};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use tracing::warn;
+
+/// Which seccomp filter a service runs under
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Blocks the syscalls typical container runtimes deny by default
+    #[default]
+    Default,
+    Unconfined,
+}
+
+/// The AUDIT_ARCH_* value the kernel reports for native syscalls, None where we don't know it
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_003e);
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00b7);
+#[cfg(target_arch = "x86")]
+const AUDIT_ARCH: Option<u32> = Some(0x4000_0003);
+#[cfg(target_arch = "arm")]
+const AUDIT_ARCH: Option<u32> = Some(0x4000_0028);
+#[cfg(target_arch = "riscv64")]
+const AUDIT_ARCH: Option<u32> = Some(0xc000_00f3);
+#[cfg(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "x86",
+    target_arch = "arm",
+    target_arch = "riscv64"
+)))]
+const AUDIT_ARCH: Option<u32> = None;
+
+/// x32 syscalls share the x86_64 audit arch, with this bit set in their number
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+/// Namespace root can reach all of these, none of which a VPN daemon or a SOCKS proxy needs.
+/// setns, unshare and the credential syscalls stay allowed, nsenter and setpriv run under the filter too.
+const DENIED: &[c_long] = &[
+    libc::SYS_acct,
+    libc::SYS_add_key,
+    libc::SYS_bpf,
+    libc::SYS_clock_adjtime,
+    libc::SYS_clock_settime,
+    libc::SYS_delete_module,
+    libc::SYS_finit_module,
+    libc::SYS_fsmount,
+    libc::SYS_fsopen,
+    libc::SYS_fspick,
+    libc::SYS_init_module,
+    libc::SYS_kexec_file_load,
+    libc::SYS_kexec_load,
+    libc::SYS_keyctl,
+    libc::SYS_lookup_dcookie,
+    libc::SYS_mount,
+    libc::SYS_move_mount,
+    libc::SYS_name_to_handle_at,
+    libc::SYS_open_by_handle_at,
+    libc::SYS_open_tree,
+    libc::SYS_perf_event_open,
+    libc::SYS_pivot_root,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_ptrace,
+    libc::SYS_quotactl,
+    libc::SYS_reboot,
+    libc::SYS_request_key,
+    libc::SYS_settimeofday,
+    libc::SYS_swapoff,
+    libc::SYS_swapon,
+    libc::SYS_syslog,
+    libc::SYS_umount2,
+    libc::SYS_userfaultfd,
+    libc::SYS_vhangup,
+];
+
+// Offsets into struct seccomp_data
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt: 0,
+        jf: 0,
+        k,
+    }
+}
+
+fn jump(code: u32, k: u32, jt: u8, jf: u8) -> sock_filter {
+    sock_filter {
+        code: code as u16,
+        jt,
+        jf,
+        k,
+    }
+}
+
+/// A classic BPF program returning EPERM for the denied syscalls, and for foreign syscall ABIs
+fn build_filter(arch: u32) -> Vec<sock_filter> {
+    use libc::{BPF_ABS, BPF_JEQ, BPF_JGE, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let n = DENIED.len();
+
+    let mut filter = vec![
+        stmt(BPF_LD | BPF_W | BPF_ABS, ARCH_OFFSET),
+        jump(BPF_JMP | BPF_JEQ | BPF_K, arch, 1, 0),
+        stmt(BPF_RET | BPF_K, deny),
+        stmt(BPF_LD | BPF_W | BPF_ABS, NR_OFFSET),
+        // Jump straight to the final deny
+        jump(BPF_JMP | BPF_JGE | BPF_K, X32_SYSCALL_BIT, n as u8 + 1, 0),
+    ];
+    for (i, nr) in DENIED.iter().enumerate() {
+        filter.push(jump(
+            BPF_JMP | BPF_JEQ | BPF_K,
+            *nr as u32,
+            (n - i) as u8,
+            0,
+        ));
+    }
+    filter.push(stmt(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW));
+    filter.push(stmt(BPF_RET | BPF_K, deny));
+    filter
+}
+
+/// Installs the filter between fork and exec. Filters are inherited, so this covers
+/// nsenter and everything it starts inside the container.
+pub fn apply_on_exec(cmd: &mut Command, profile: Profile) {
+    if profile == Profile::Unconfined {
+        return;
+    }
+    let Some(arch) = AUDIT_ARCH else {
+        warn!("No seccomp filter for this architecture, services run unconfined");
+        return;
+    };
+    let mut filter = build_filter(arch);
+    // SAFETY: prctl is async-signal-safe, and the program is built before forking
+    unsafe {
+        cmd.pre_exec(move || {
+            let prog = sock_fprog {
+                len: filter.len() as u16,
+                filter: filter.as_mut_ptr(),
+            };
+            let ret = libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &prog as *const sock_fprog,
+            );
+            if ret != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jumps_land_on_deny() {
+        let Some(arch) = AUDIT_ARCH else { return };
+        let filter = build_filter(arch);
+        let deny = filter.len() - 1;
+        for (pc, insn) in filter.iter().enumerate().skip(5) {
+            if insn.code as u32 == libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K {
+                assert_eq!(pc + 1 + insn.jt as usize, deny);
+            }
+        }
+        let x32_check = &filter[4];
+        assert_eq!(5 + x32_check.jt as usize, deny);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::cgroup;
+use crate::config::Config;
+use crate::down::down;
+use crate::exec::{Executor, RealExecutor};
+use crate::firewall;
+use crate::gateway::GatewayConfig;
+use crate::namespace::{self, run_inside_namespace, Status, Type};
+use crate::net::{default_route_iface_name, Network};
+use crate::paths::{Binary, PathOverrides, Paths};
+use crate::readiness::wait_for_proxy;
+use crate::up::up;
+use anyhow::{bail, Result};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::Command;
+use tracing::debug;
+
+/// Stands in for warp-svc, so that the scratch instance never touches the host's WARP registration.
+/// It only creates the interface danted uses as its external side.
+const STUB_WARP_SVC: &str = "#!/bin/sh
+ip link add CloudflareWARP type dummy
+ip link set CloudflareWARP up
+exec sleep infinity
+";
+
+struct Check {
+    name: &'static str,
+    result: Result<()>,
+}
+
+fn scratch_network() -> Network {
+    Network {
+        host_iface: "veth-warp-st".to_owned(),
+        ns_iface: "veth-warp-st-ns".to_owned(),
+        host_addr: "10.201.0.1".parse().unwrap(),
+        ns_addr: "10.201.0.2".parse().unwrap(),
+        prefix_len: 24,
+    }
+}
+
+pub fn selftest(host_config: &Config) -> Result<()> {
+    let base_dir = std::env::temp_dir().join(format!("bubblewarp-selftest-{}", std::process::id()));
+    std::fs::create_dir_all(&base_dir)?;
+    let stub_path = base_dir.join("stub-warp-svc");
+    std::fs::write(&stub_path, STUB_WARP_SVC)?;
+    std::fs::set_permissions(&stub_path, std::fs::Permissions::from_mode(0o755))?;
+
+    let config = Config {
+        base_dir: base_dir.clone(),
+        network: scratch_network(),
+        parts: Default::default(),
+        // The stub warp-svc has no version
+        strict: false,
+        egress_iface: None,
+        file: host_config.file.clone(),
+        paths: PathOverrides {
+            warp_svc: Some(stub_path),
+            ..host_config.paths.clone()
+        },
+        firewall: host_config.firewall.clone(),
+        nat: host_config.nat.clone(),
+        // Would clash with the real instance's listen port
+        gateway: GatewayConfig::default(),
+        container: host_config.container.clone(),
+        services: host_config.services.clone(),
+        // The stub warp-svc has no proxy mode
+        proxy: Default::default(),
+        dns: host_config.dns.clone(),
+        health: Default::default(),
+        watchdog: Default::default(),
+        timeouts: host_config.timeouts.clone(),
+        relay: Default::default(),
+        hosts: host_config.hosts.clone(),
+    };
+    let ex = &RealExecutor::new(
+        Paths::new(config.paths.clone(), Some(&config.base_dir)),
+        &config.base_dir,
+    );
+    debug!("Running self-test in {}", base_dir.display());
+
+    let mut checks = Vec::new();
+    let mut check = |name, result| checks.push(Check { name, result });
+
+    check("bring up scratch instance", up(ex, &config));
+    check("namespaces", check_namespaces(&base_dir));
+    check("/etc overlay", check_overlay(ex, &base_dir));
+    check("veth link", check_veth(ex, &base_dir, &config.network));
+    check("NAT rules", check_nat(ex, &config));
+    check(
+        "proxy port",
+        wait_for_proxy(&config.network, config.timeouts.proxy_ready),
+    );
+    check("tear down scratch instance", down(ex, &config));
+    check(
+        "nothing left behind",
+        check_clean(&base_dir, &config.network),
+    );
+
+    if let Err(e) = std::fs::remove_dir_all(&base_dir) {
+        debug!("Failed to remove {}: {e}", base_dir.display());
+    }
+
+    println!("bubblewarp self-test:");
+    let mut failures = 0;
+    for check in &checks {
+        match &check.result {
+            Ok(()) => println!("  [PASS] {}", check.name),
+            Err(e) => {
+                failures += 1;
+                println!("  [FAIL] {}: {:#}", check.name, e);
+            }
+        }
+    }
+    if failures > 0 {
+        bail!("{failures} of {} self-test checks failed", checks.len());
+    }
+    println!("All {} checks passed", checks.len());
+    Ok(())
+}
+
+fn check_namespaces(base_dir: &Path) -> Result<()> {
+    match namespace::status(base_dir)? {
+        Status::Ready => Ok(()),
+        Status::Partial(mounted) => bail!("only {mounted:?} are mounted"),
+        Status::None => bail!("no namespace is mounted"),
+    }
+}
+
+fn check_overlay(ex: &dyn Executor, base_dir: &Path) -> Result<()> {
+    let out = run_inside_namespace(
+        ex,
+        base_dir,
+        Type::Mount,
+        Command::new("cat").arg("/etc/resolv.conf"),
+    )?;
+    if !String::from_utf8_lossy(&out.stdout).contains("127.0.2.2") {
+        bail!("resolv.conf inside the container is not the overlay's")
+    }
+    Ok(())
+}
+
+fn check_veth(ex: &dyn Executor, base_dir: &Path, net: &Network) -> Result<()> {
+    if !nix::ifaddrs::getifaddrs()?.any(|dev| dev.interface_name == net.host_iface) {
+        bail!("{} is missing on the host", net.host_iface)
+    }
+    let out = run_inside_namespace(
+        ex,
+        base_dir,
+        Type::Net,
+        ex.command(Binary::Ip)?
+            .args(["-o", "addr", "show", "dev", &net.ns_iface]),
+    )?;
+    if !String::from_utf8_lossy(&out.stdout).contains(&net.ns_cidr()) {
+        bail!("{} has no {} address", net.ns_iface, net.ns_cidr())
+    }
+    Ok(())
+}
+
+fn check_nat(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let iface_name = default_route_iface_name(ex)?;
+    let net = &config.network;
+    if !firewall::has_forward(ex, config.firewall.backend, &config.nat, net, &iface_name)? {
+        bail!("no NAT for {} on {iface_name}", net.subnet())
+    }
+    Ok(())
+}
+
+fn check_clean(base_dir: &Path, net: &Network) -> Result<()> {
+    if namespace::status(base_dir)? != Status::None {
+        bail!("namespaces are still mounted")
+    }
+    if nix::ifaddrs::getifaddrs()?.any(|dev| dev.interface_name == net.host_iface) {
+        bail!("{} still exists", net.host_iface)
+    }
+    if cgroup::path(base_dir).exists() {
+        bail!("cgroup {} still exists", cgroup::path(base_dir).display())
+    }
+    Ok(())
+}
@@ -0,0 +1,343 @@
+use crate::exec::Executor;
+use crate::extra::ExtraService;
+use crate::namespace::ID_MAP_SIZE;
+use crate::paths::Binary;
+use crate::pidfd::{self, PidFd};
+use crate::readiness::{Check, WARP_IFACE};
+use crate::seccomp;
+use crate::up::SOCKS_PORT;
+use anyhow::{bail, Result};
+use nix::sys::signal::Signal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::time::Duration;
+use tracing::{debug, warn};
+
+/// Capability names as setpriv takes them, see capabilities(7)
+const CAPABILITIES: &[&str] = &[
+    "all",
+    "audit_control",
+    "audit_read",
+    "audit_write",
+    "block_suspend",
+    "bpf",
+    "checkpoint_restore",
+    "chown",
+    "dac_override",
+    "dac_read_search",
+    "fowner",
+    "fsetid",
+    "ipc_lock",
+    "ipc_owner",
+    "kill",
+    "lease",
+    "linux_immutable",
+    "mac_admin",
+    "mac_override",
+    "mknod",
+    "net_admin",
+    "net_bind_service",
+    "net_broadcast",
+    "net_raw",
+    "perfmon",
+    "setfcap",
+    "setgid",
+    "setpcap",
+    "setuid",
+    "sys_admin",
+    "sys_boot",
+    "sys_chroot",
+    "sys_module",
+    "sys_nice",
+    "sys_pacct",
+    "sys_ptrace",
+    "sys_rawio",
+    "sys_resource",
+    "sys_time",
+    "sys_tty_config",
+    "syslog",
+    "wake_alarm",
+];
+
+/// The `[services.<name>]` sections of the config file
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ServicesConfig {
+    pub warp_svc: ServiceConfig,
+    pub danted: ServiceConfig,
+    pub extra: Vec<ExtraService>,
+}
+
+impl ServicesConfig {
+    /// The service's settings, with its defaults filled in. Only warp-svc and danted have them.
+    pub fn get(&self, bin: Binary) -> ServiceConfig {
+        let (mut service, ready) = match bin {
+            Binary::WarpSvc => (self.warp_svc.clone(), Check::Iface(WARP_IFACE.to_owned())),
+            Binary::Danted => (self.danted.clone(), Check::Port(SOCKS_PORT)),
+            _ => panic!("{} isn't configured under [services]", bin.name()),
+        };
+        service
+            .capabilities
+            .get_or_insert_with(|| default_capabilities(bin));
+        service.ready.get_or_insert(ready);
+        if bin == Binary::Danted && service.user.is_none() {
+            service.user = Some(DANTED_ID);
+            service.group.get_or_insert(DANTED_ID);
+        }
+        service
+    }
+}
+
+/// Mapped to the same host ID, so picked at the end of the range, away from regular users
+pub const DANTED_ID: u32 = ID_MAP_SIZE - 1;
+
+/// warp-svc manages its tunnel interface and routes, danted only needs to open sockets
+fn default_capabilities(bin: Binary) -> Vec<String> {
+    match bin {
+        Binary::WarpSvc => vec!["net_admin".to_owned(), "net_raw".to_owned()],
+        _ => Vec::new(),
+    }
+}
+
+/// Keeps namespace root from regaining capabilities through exec, they only come from the ambient set
+const SECUREBITS: &str =
+    "+noroot,+noroot_locked,+no_setuid_fixup,+no_setuid_fixup_locked,+keep_caps_locked";
+
+/// How one of the long-running processes inside the container is started
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub seccomp: seccomp::Profile,
+    /// Capabilities the service keeps, everything else is dropped before exec
+    pub capabilities: Option<Vec<String>>,
+    /// Sets no_new_privs and locks the securebits, so setuid binaries can't re-escalate
+    pub no_new_privs: bool,
+    /// IDs inside the container. danted gets its own unless set to 0, the rest runs as root
+    pub user: Option<u32>,
+    pub group: Option<u32>,
+    /// Passed to the service's binary
+    pub args: Vec<String>,
+    /// Set on top of the environment everything inside the container gets
+    pub env: BTreeMap<String, String>,
+    /// What the services started after this one wait for
+    pub ready: Option<Check>,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        ServiceConfig {
+            seccomp: Default::default(),
+            capabilities: None,
+            no_new_privs: true,
+            user: None,
+            group: None,
+            args: Vec::new(),
+            env: BTreeMap::new(),
+            ready: None,
+        }
+    }
+}
+
+impl ServiceConfig {
+    /// Arguments for setpriv, which applies what has to happen after nsenter joined the namespaces
+    pub fn setpriv_args(&self) -> Result<Vec<String>> {
+        let caps = self.capabilities.as_deref().unwrap_or_default();
+        let mut set = "-all".to_owned();
+        for cap in caps {
+            let cap = cap.to_lowercase();
+            let cap = cap.strip_prefix("cap_").unwrap_or(&cap);
+            if !CAPABILITIES.contains(&cap) {
+                bail!("Unknown capability {cap} in the services config")
+            }
+            set += &format!(",+{cap}");
+        }
+        let mut args = Vec::new();
+        for (flag, id) in [("--reuid", self.user), ("--regid", self.group)] {
+            let Some(id) = id else { continue };
+            if id >= ID_MAP_SIZE {
+                bail!("Only IDs below {ID_MAP_SIZE} are mapped in the container, got {id}")
+            }
+            args.push(format!("{flag}={id}"));
+        }
+        if !args.is_empty() {
+            args.push("--clear-groups".to_owned());
+        }
+        if self.no_new_privs {
+            args.push("--no-new-privs".to_owned());
+            args.push(format!("--securebits={SECUREBITS}"));
+        }
+        args.push(format!("--bounding-set={set}"));
+        args.push(format!("--inh-caps={set}"));
+        args.push(format!("--ambient-caps={set}"));
+        Ok(args)
+    }
+}
+
+fn pid_dir(base_dir: &Path) -> PathBuf {
+    base_dir.join("services")
+}
+
+/// Remembers which process runs the service, so later invocations can find it again
+pub fn record(base_dir: &Path, name: &str, pid: i32) -> Result<()> {
+    let Some(start_time) = pidfd::start_time(pid) else {
+        bail!("{name} exited right after starting")
+    };
+    std::fs::create_dir_all(pid_dir(base_dir))?;
+    std::fs::write(
+        pid_dir(base_dir).join(name),
+        format!("{pid} {start_time}\n"),
+    )?;
+    Ok(())
+}
+
+/// The process running the service, if it's still alive
+pub fn running(base_dir: &Path, name: &str) -> Result<Option<PidFd>> {
+    let data = match std::fs::read_to_string(pid_dir(base_dir).join(name)) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let Some((pid, start_time)) = data.trim().split_once(' ') else {
+        return Ok(None);
+    };
+    Ok(PidFd::open_if_started_at(
+        pid.parse()?,
+        start_time.parse()?,
+    )?)
+}
+
+/// How long a service gets to exit on SIGTERM before it is killed
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Stops the service if it runs, and waits until it's gone so it can be started again
+pub fn stop(ex: &dyn Executor, base_dir: &Path, name: &str) -> Result<()> {
+    let Some(process) = running(base_dir, name)? else {
+        debug!("{name} isn't running");
+        return Ok(());
+    };
+    debug!("Stopping {name} (pid {})", process.pid());
+    ex.kill(&process, Signal::SIGTERM)?;
+    if !process.wait_exit(STOP_TIMEOUT)? {
+        warn!("{name} didn't exit on SIGTERM, killing it");
+        ex.kill(&process, Signal::SIGKILL)?;
+        process.wait_exit(STOP_TIMEOUT)?;
+    }
+    Ok(())
+}
+
+/// Reaps the service's nsenter once it exits, so long-running invocations don't pile up zombies.
+/// nsenter exits with its command's status, which is logged and kept next to the service's PID.
+/// Only the process that started the service can wait for it, so that's only recorded when it
+/// outlives the service, as watch does. Services started by up or restart exit unrecorded.
+pub fn supervise(base_dir: &Path, name: &str, mut nsenter: Child) {
+    let exit_file = pid_dir(base_dir).join(format!("{name}.exit"));
+    let _ = std::fs::remove_file(&exit_file);
+    let name = name.to_owned();
+    std::thread::spawn(move || {
+        let status = match nsenter.wait() {
+            Ok(status) => status,
+            Err(e) => return warn!("Failed to wait for {name}: {e}"),
+        };
+        if status.success() {
+            debug!("{name} exited");
+        } else {
+            warn!("{name} exited with {status}");
+        }
+        let _ = std::fs::create_dir_all(exit_file.parent().unwrap());
+        let _ = std::fs::write(&exit_file, format!("{status}\n"));
+    });
+}
+
+/// Whether the service's last exit was a failure, when supervise saw it
+pub fn exited_with_failure(base_dir: &Path, name: &str) -> bool {
+    std::fs::read_to_string(pid_dir(base_dir).join(format!("{name}.exit")))
+        .is_ok_and(|status| status.trim() != "exit status: 0")
+}
+
+/// Drops the records of services that went away with their container
+pub fn forget_all(base_dir: &Path) -> Result<()> {
+    match std::fs::remove_dir_all(pid_dir(base_dir)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn per_service_default_capabilities() {
+        let services = ServicesConfig::default();
+        assert_eq!(
+            services.get(Binary::WarpSvc).setpriv_args().unwrap()[2..],
+            [
+                "--bounding-set=-all,+net_admin,+net_raw",
+                "--inh-caps=-all,+net_admin,+net_raw",
+                "--ambient-caps=-all,+net_admin,+net_raw",
+            ]
+        );
+        assert_eq!(
+            services.get(Binary::Danted).setpriv_args().unwrap()[..3],
+            ["--reuid=1199", "--regid=1199", "--clear-groups"]
+        );
+        assert_eq!(
+            services.get(Binary::Danted).setpriv_args().unwrap()[5..],
+            [
+                "--bounding-set=-all",
+                "--inh-caps=-all",
+                "--ambient-caps=-all"
+            ]
+        );
+    }
+
+    #[test]
+    fn configured_args_and_env() {
+        let services: ServicesConfig =
+            toml::from_str("[warp-svc]\nargs = [\"--accept-tos\"]\nenv = { WARP_DEBUG = \"1\" }\n")
+                .unwrap();
+        let warp_svc = services.get(Binary::WarpSvc);
+        assert_eq!(warp_svc.args, ["--accept-tos"]);
+        assert_eq!(warp_svc.env["WARP_DEBUG"], "1");
+        assert!(services.get(Binary::Danted).args.is_empty());
+    }
+
+    #[test]
+    fn configured_capabilities() {
+        let service = ServiceConfig {
+            capabilities: Some(vec!["CAP_NET_ADMIN".to_owned()]),
+            ..Default::default()
+        };
+        assert_eq!(
+            service.setpriv_args().unwrap()[2],
+            "--bounding-set=-all,+net_admin"
+        );
+        let service = ServiceConfig {
+            capabilities: Some(vec!["net_admn".to_owned()]),
+            ..Default::default()
+        };
+        assert!(service.setpriv_args().is_err());
+    }
+
+    #[test]
+    fn unmapped_user_is_an_error() {
+        let service = ServiceConfig {
+            user: Some(65534),
+            ..Default::default()
+        };
+        assert!(service.setpriv_args().is_err());
+    }
+
+    #[test]
+    fn no_new_privs_opt_out() {
+        let service = ServiceConfig::default();
+        assert_eq!(service.setpriv_args().unwrap()[0], "--no-new-privs");
+        let service = ServiceConfig {
+            no_new_privs: false,
+            ..Default::default()
+        };
+        assert_eq!(service.setpriv_args().unwrap()[0], "--bounding-set=-all");
+    }
+}
@@ -0,0 +1,170 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::paths::Binary;
+use crate::summary::proxy_url;
+use anyhow::{bail, Context, Result};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use std::fmt::Write;
+use std::fs::File;
+use std::os::fd::FromRawFd;
+use std::time::Duration;
+use tracing::{debug, info};
+
+/// Cloudflare's speed test endpoints, which are close to WARP's own servers
+const DOWN_URL: &str = "https://speed.cloudflare.com/__down";
+const UP_URL: &str = "https://speed.cloudflare.com/__up";
+
+const DOWN_BYTES: u64 = 25_000_000;
+const UP_BYTES: u64 = 10_000_000;
+const LATENCY_SAMPLES: usize = 5;
+
+#[derive(Debug, PartialEq)]
+struct Measurement {
+    latency: Duration,
+    /// In bits per second
+    download: f64,
+    upload: f64,
+}
+
+/// Zeroes to upload, kept in memory so an interrupted test leaves no file behind
+fn upload_data() -> Result<File> {
+    let fd = memfd_create(c"speedtest-upload", MemFdCreateFlag::MFD_CLOEXEC)?;
+    // SAFETY: memfd_create just returned this fd, nothing else owns it
+    let data = unsafe { File::from_raw_fd(fd) };
+    data.set_len(UP_BYTES)?;
+    Ok(data)
+}
+
+/// Runs curl without keeping the body, returns the numbers it writes out.
+/// curl reads what it sends from stdin when there is one.
+fn curl(
+    ex: &dyn Executor,
+    proxy: Option<&str>,
+    args: &[&str],
+    write_out: &str,
+    stdin: Option<File>,
+) -> Result<Vec<f64>> {
+    let mut cmd = ex.command(Binary::Curl)?;
+    cmd.args([
+        "-sS",
+        "-o",
+        "/dev/null",
+        "--max-time",
+        "30",
+        "--write-out",
+        write_out,
+    ]);
+    if let Some(proxy) = proxy {
+        cmd.args(["--proxy", proxy]);
+    }
+    if let Some(stdin) = stdin {
+        cmd.stdin(stdin);
+    }
+    let out = ex.output(cmd.args(args))?;
+    if !out.status.success() {
+        bail!(
+            "curl failed: {}",
+            String::from_utf8_lossy(&out.stderr).trim()
+        )
+    }
+    String::from_utf8(out.stdout)?
+        .split_whitespace()
+        .map(|value| Ok(value.parse()?))
+        .collect()
+}
+
+fn measure(ex: &dyn Executor, proxy: Option<&str>) -> Result<Measurement> {
+    // Time to first byte of an empty response, less the connection setup
+    let mut latencies = Vec::new();
+    for _ in 0..LATENCY_SAMPLES {
+        let url = format!("{DOWN_URL}?bytes=0");
+        let times = curl(
+            ex,
+            proxy,
+            &[&url],
+            "%{time_pretransfer} %{time_starttransfer}",
+            None,
+        )?;
+        let [pretransfer, starttransfer] = times[..] else {
+            bail!("Unexpected curl output {times:?}")
+        };
+        latencies.push(starttransfer - pretransfer);
+    }
+    latencies.sort_by(f64::total_cmp);
+
+    let url = format!("{DOWN_URL}?bytes={DOWN_BYTES}");
+    let download = curl(ex, proxy, &[&url], "%{speed_download}", None)?;
+    let upload = curl(
+        ex,
+        proxy,
+        &["--data-binary", "@-", UP_URL],
+        "%{speed_upload}",
+        Some(upload_data()?),
+    )?;
+    Ok(Measurement {
+        latency: Duration::from_secs_f64(latencies[LATENCY_SAMPLES / 2]),
+        download: download.first().context("No download speed")? * 8.0,
+        upload: upload.first().context("No upload speed")? * 8.0,
+    })
+}
+
+/// Formats one of the measurements
+type Column = fn(&Measurement) -> String;
+
+fn mbits(bits_per_sec: f64) -> String {
+    format!("{:.1} Mbit/s", bits_per_sec / 1e6)
+}
+
+/// Measures through the proxy, then directly from the host, to show what the tunnel costs
+pub fn speedtest(ex: &dyn Executor, config: &Config) -> Result<String> {
+    info!("Measuring through WARP");
+    let warp = measure(ex, Some(&proxy_url(config)));
+    info!("Measuring directly from the host");
+    let direct = measure(ex, None);
+    let warp = warp.context("Measuring through WARP")?;
+    let direct = direct
+        .map_err(|e| debug!("Direct measurement failed: {e:#}"))
+        .ok();
+
+    let mut report = format!("{:<10}{:>16}{:>16}\n", "", "WARP", "direct");
+    let rows: [(&str, Column); 3] = [
+        ("latency", |m| format!("{} ms", m.latency.as_millis())),
+        ("download", |m| mbits(m.download)),
+        ("upload", |m| mbits(m.upload)),
+    ];
+    for (name, value) in rows {
+        let direct = direct.as_ref().map_or("failed".to_owned(), value);
+        let _ = writeln!(report, "{name:<10}{:>16}{direct:>16}", value(&warp));
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::mock::{MockExecutor, Reply};
+
+    #[test]
+    fn measures_with_curl() {
+        let ex = MockExecutor::with_handler(|args| {
+            let write_out = &args[args.iter().position(|arg| arg == "--write-out").unwrap() + 1];
+            Reply::ok(match write_out.as_str() {
+                "%{speed_download}" => "12500000",
+                "%{speed_upload}" => "2500000",
+                _ => "0.5 0.75",
+            })
+        });
+        let measurement = measure(&ex, Some("socks5h://10.200.0.2:8080")).unwrap();
+        assert_eq!(
+            measurement,
+            Measurement {
+                latency: Duration::from_millis(250),
+                download: 100e6,
+                upload: 20e6,
+            }
+        );
+        assert!(ex.commands()[0].ends_with(
+            "--proxy socks5h://10.200.0.2:8080 https://speed.cloudflare.com/__down?bytes=0"
+        ));
+    }
+}
@@ -0,0 +1,97 @@
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// The layout of the base dir this bubblewarp uses. Changing the layout means bumping it and
+/// adding a migration that upgrades directories in place.
+pub const STATE_VERSION: u32 = 1;
+
+struct Migration {
+    /// The version the base dir is at once it ran
+    to: u32,
+    what: &'static str,
+    run: fn(&Path) -> Result<()>,
+}
+
+/// In order, one for each version after 0
+const MIGRATIONS: &[Migration] = &[Migration {
+    to: 1,
+    what: "record the layout's version",
+    // Base dirs from before versioning already have the first versioned layout
+    run: |_| Ok(()),
+}];
+
+fn version_path(base_dir: &Path) -> PathBuf {
+    base_dir.join("STATE_VERSION")
+}
+
+/// The base dir's layout version. Without a version file, an empty directory is new and one
+/// with files in it is from before versioning.
+fn version(base_dir: &Path) -> Result<u32> {
+    match std::fs::read_to_string(version_path(base_dir)) {
+        Ok(version) => version
+            .trim()
+            .parse()
+            .with_context(|| format!("Invalid {}", version_path(base_dir).display())),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            let is_empty = std::fs::read_dir(base_dir)?.next().is_none();
+            Ok(if is_empty { STATE_VERSION } else { 0 })
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn set_version(base_dir: &Path, version: u32) -> Result<()> {
+    std::fs::write(version_path(base_dir), format!("{version}\n"))?;
+    Ok(())
+}
+
+/// Upgrades the base dir to the current layout, if it exists. Only up and down run it, the
+/// commands that only read the base dir leave it as they find it. A running container
+/// is upgraded in place too, so migrations must leave its namespaces and processes be.
+pub fn migrate(base_dir: &Path) -> Result<()> {
+    if !base_dir.exists() {
+        return Ok(());
+    }
+    let found = version(base_dir)?;
+    if found > STATE_VERSION {
+        bail!(
+            "{} was set up by a newer bubblewarp (layout version {found}, this one knows {STATE_VERSION}), \
+             use that version or run its down command and remove the directory",
+            base_dir.display()
+        )
+    }
+    for migration in MIGRATIONS.iter().filter(|m| m.to > found) {
+        info!(
+            "Upgrading {} to layout version {}: {}",
+            base_dir.display(),
+            migration.to,
+            migration.what
+        );
+        (migration.run)(base_dir)
+            .with_context(|| format!("Upgrading to layout version {}", migration.to))?;
+        set_version(base_dir, migration.to)?;
+    }
+    if !version_path(base_dir).exists() {
+        set_version(base_dir, STATE_VERSION)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn versions_base_dir() {
+        let dir = TempDir::new("state");
+        std::fs::write(dir.join("operations.log"), "").unwrap();
+        assert_eq!(version(&dir).unwrap(), 0);
+        migrate(&dir).unwrap();
+        assert_eq!(version(&dir).unwrap(), STATE_VERSION);
+        set_version(&dir, STATE_VERSION + 1).unwrap();
+        assert!(migrate(&dir).is_err());
+        assert_eq!(MIGRATIONS.last().map(|m| m.to), Some(STATE_VERSION));
+    }
+}
@@ -0,0 +1,271 @@
+use crate::config::Config;
+use crate::dns;
+use crate::exec::Executor;
+use crate::firewall;
+use crate::mtu;
+use crate::namespace::{self, run_inside_namespace, Type};
+use crate::net::{self, container_has_default_route, recorded_uplinks, uplinks};
+use crate::oplog;
+use crate::paths::Binary;
+use crate::pause;
+use crate::proxy::runs_danted;
+use crate::rootfs;
+use crate::service;
+use crate::summary;
+use crate::up::{etc_overlay_dir, find_pid_ns_init_process, Parts};
+use anyhow::{bail, Context, Result};
+
+/// What each part of the container differs in from what up set up. Parts the container was
+/// brought up without have no result.
+type Checked = Vec<(&'static str, Option<Result<Vec<String>>>)>;
+
+/// How far back in the operations log to look for a command that was cut short
+const INTERRUPTED_LINES: usize = 2000;
+
+fn link_mtu(link: &str) -> Option<u16> {
+    let mut words = link.split_whitespace();
+    words.find(|&word| word == "mtu")?;
+    words.next()?.parse().ok()
+}
+
+/// How one end of the veth differs, from what `ip -o link` and `ip -o -4 addr` show for it
+fn veth_end(iface: &str, cidr: &str, mtu: u16, link: &str, addrs: &str) -> Vec<String> {
+    let mut drifted = Vec::new();
+    if !net::link_is_up(link) {
+        drifted.push(format!("{iface} is down"));
+    }
+    match link_mtu(link) {
+        Some(found) if found != mtu => {
+            drifted.push(format!("{iface} has MTU {found} instead of {mtu}"))
+        }
+        Some(_) => (),
+        None => drifted.push(format!("ip shows no MTU for {iface}")),
+    }
+    let has_addr = addrs.lines().any(|line| {
+        let mut words = line.split_whitespace().skip_while(|&word| word != "inet");
+        words.nth(1) == Some(cidr)
+    });
+    if !has_addr {
+        drifted.push(format!("{iface} lost its address {cidr}"));
+    }
+    drifted
+}
+
+fn namespaces(config: &Config) -> Result<Vec<String>> {
+    let base_dir = config.base_dir.as_path();
+    let mut drifted = Vec::new();
+    if let namespace::Status::Partial(mounted) = namespace::status(base_dir)? {
+        let created = namespace::created(base_dir)?;
+        for ns_type in created.into_iter().filter(|t| !mounted.contains(t)) {
+            drifted.push(format!("the {ns_type} namespace isn't mounted"));
+        }
+    }
+    if find_pid_ns_init_process(base_dir)?.is_none() {
+        drifted.push("the container's init isn't running".to_owned());
+    }
+    Ok(drifted)
+}
+
+fn veth(ex: &dyn Executor, config: &Config) -> Result<Vec<String>> {
+    let base_dir = config.base_dir.as_path();
+    let net = &config.network;
+    let mtu = mtu::expected(base_dir)?;
+    let ip = |inside: bool, args: &[&str]| -> Result<String> {
+        let mut cmd = ex.command(Binary::Ip)?;
+        cmd.args(args);
+        let out = match inside {
+            true => run_inside_namespace(ex, base_dir, Type::Net, &cmd)?,
+            false => {
+                let out = ex.output(&mut cmd)?;
+                out.status.exit_ok()?;
+                out
+            }
+        };
+        Ok(String::from_utf8(out.stdout)?)
+    };
+    let ends = [
+        (&net.host_iface, net.host_cidr(), false),
+        (&net.ns_iface, net.ns_cidr(), true),
+    ];
+    let mut drifted = Vec::new();
+    for (iface, cidr, inside) in ends {
+        let Ok(link) = ip(inside, &["-o", "link", "show", "dev", iface]) else {
+            drifted.push(format!("{iface} is missing"));
+            continue;
+        };
+        let addrs = ip(inside, &["-o", "-4", "addr", "show", "dev", iface])?;
+        drifted.extend(veth_end(iface, &cidr, mtu, &link, &addrs));
+    }
+    Ok(drifted)
+}
+
+fn route(config: &Config) -> Result<Vec<String>> {
+    Ok(match container_has_default_route(&config.base_dir)? {
+        true => Vec::new(),
+        false => vec!["the container has no default route".to_owned()],
+    })
+}
+
+fn forward(ex: &dyn Executor, config: &Config) -> Result<Vec<String>> {
+    let mut forwarded = recorded_uplinks(&config.base_dir)?;
+    if forwarded.is_empty() {
+        forwarded = uplinks(ex, &config.firewall)?;
+    }
+    let missing = firewall::missing_forward_rules(
+        ex,
+        &config.firewall,
+        &config.nat,
+        &config.network,
+        &forwarded,
+    )?;
+    Ok(missing
+        .into_iter()
+        .map(|rule| format!("missing {rule}"))
+        .collect())
+}
+
+fn ipv6(ex: &dyn Executor, config: &Config) -> Result<Vec<String>> {
+    let missing = firewall::missing_ipv6_rules(ex, &config.network)?;
+    Ok(missing
+        .into_iter()
+        .map(|rule| format!("missing {rule}"))
+        .collect())
+}
+
+/// Compares what the container sees with what up wrote, or would write for the config now
+fn resolv_conf(config: &Config) -> Result<Vec<String>> {
+    let base_dir = config.base_dir.as_path();
+    let init = find_pid_ns_init_process(base_dir)?.context("The container's init isn't running")?;
+    let seen = std::fs::read_to_string(format!("/proc/{}/root/etc/resolv.conf", init.pid))?;
+    // The host mode copies the host's when the container is created
+    let expected = match (config.dns.mode, config.container.rootfs) {
+        (dns::Mode::Host, rootfs::Kind::Host) => {
+            let written = etc_overlay_dir(base_dir).join("extra_lower/resolv.conf");
+            std::fs::read_to_string(&written)
+                .with_context(|| format!("Reading {}", written.display()))?
+        }
+        (dns::Mode::Host, _) => return Ok(Vec::new()),
+        _ => dns::container_resolv_conf(&config.dns)?,
+    };
+    Ok(match seen == expected {
+        true => Vec::new(),
+        false => vec!["/etc/resolv.conf differs from what the DNS config gives".to_owned()],
+    })
+}
+
+fn services(config: &Config, parts: Parts) -> Result<Vec<String>> {
+    let mut names = vec![Binary::WarpSvc.name()];
+    if runs_danted(config, parts) {
+        names.push(Binary::Danted.name());
+    }
+    names.extend(
+        config
+            .services
+            .extra
+            .iter()
+            .map(|extra| extra.name.as_str()),
+    );
+    let mut drifted = Vec::new();
+    for name in names {
+        if service::running(&config.base_dir, name)?.is_none() {
+            drifted.push(format!("{name} isn't running"));
+        }
+    }
+    Ok(drifted)
+}
+
+/// Checks each resource up manages against the config and what up recorded
+pub fn check(ex: &dyn Executor, config: &Config) -> Result<Checked> {
+    let parts = Parts::load(&config.base_dir)?;
+    Ok(vec![
+        ("namespaces", Some(namespaces(config))),
+        ("veth", Some(veth(ex, config))),
+        ("route", Some(route(config))),
+        ("forward", parts.external.then(|| forward(ex, config))),
+        ("ipv6", (!config.firewall.ipv6).then(|| ipv6(ex, config))),
+        ("resolv.conf", Some(resolv_conf(config))),
+        ("services", Some(services(config, parts))),
+    ])
+}
+
+/// Prints whether the container is up, and with `verify` what drifted from what up set up.
+/// Fails if anything did, for monitoring.
+pub fn status(ex: &dyn Executor, config: &Config, verify: bool) -> Result<()> {
+    let status = namespace::status(&config.base_dir)?;
+    let state = match &status {
+        namespace::Status::Ready if pause::is_paused(&config.base_dir) => "up, paused",
+        namespace::Status::Ready => "up",
+        namespace::Status::Partial(_) => "partially up",
+        namespace::Status::None => "down",
+    };
+    println!("Container      {state}");
+    // What a crash left halfway explains a partial container, or drift
+    let interrupted = oplog::unfinished(&config.base_dir, INTERRUPTED_LINES)?;
+    for line in &interrupted {
+        println!("Interrupted    {line}");
+    }
+    if !interrupted.is_empty() {
+        println!("               see `bubblewarp history` for what led up to it");
+    }
+    if !verify {
+        if status == namespace::Status::Ready {
+            print!("{}", summary::summary(ex, config));
+        }
+        return Ok(());
+    }
+    if status == namespace::Status::None {
+        bail!("The container is down, there's nothing to verify")
+    }
+
+    let checked = check(ex, config)?;
+    let mut drifted = 0;
+    for (what, result) in &checked {
+        match result {
+            Some(Ok(found)) if found.is_empty() => println!("[OK]    {what}"),
+            Some(Ok(found)) => {
+                drifted += 1;
+                for drift in found {
+                    println!("[DRIFT] {what}: {drift}");
+                }
+            }
+            Some(Err(e)) => {
+                drifted += 1;
+                println!("[DRIFT] {what}: can't check, {e:#}");
+            }
+            None => println!("[SKIP]  {what}: not part of this container"),
+        }
+    }
+    if drifted > 0 {
+        bail!(
+            "{drifted} of {} resources drifted, up again to restore them",
+            checked.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_veth_drift() {
+        let link =
+            "5: veth-warp@if4: <BROADCAST,MULTICAST,UP,LOWER_UP> mtu 1500 qdisc noqueue state UP";
+        let addrs =
+            "5: veth-warp    inet 10.200.0.1/24 scope global veth-warp\\       valid_lft forever";
+        assert!(veth_end("veth-warp", "10.200.0.1/24", 1500, link, addrs).is_empty());
+        assert_eq!(
+            veth_end("veth-warp", "10.200.0.1/24", 1280, link, ""),
+            [
+                "veth-warp has MTU 1500 instead of 1280",
+                "veth-warp lost its address 10.200.0.1/24",
+            ]
+        );
+        let down = "5: veth-warp@if4: <BROADCAST,MULTICAST> mtu 1500 qdisc noqueue state DOWN";
+        assert_eq!(
+            veth_end("veth-warp", "10.200.0.1/24", 1500, down, addrs),
+            ["veth-warp is down"]
+        );
+    }
+}
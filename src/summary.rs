@@ -0,0 +1,157 @@
+use crate::compat::{warp_cli_op, Op};
+use crate::config::Config;
+use crate::dns::{DnsConfig, Mode};
+use crate::exec::Executor;
+use crate::gateway;
+use crate::paths::Binary;
+use crate::up::{Parts, SOCKS_PORT};
+use anyhow::{Context, Result};
+use std::fmt::Write;
+use tracing::debug;
+
+/// Where Cloudflare echoes back how it sees a request, including the colo that served it
+const TRACE_URL: &str = "https://www.cloudflare.com/cdn-cgi/trace";
+
+/// Names resolve on the proxy's side with socks5h, so lookups go through WARP too
+pub fn proxy_url(config: &Config) -> String {
+    format!("socks5h://{}:{SOCKS_PORT}", config.network.ns_addr)
+}
+
+/// Where the container sends lookups, by DNS mode
+fn container_resolvers(dns: &DnsConfig) -> String {
+    match dns.mode {
+        Mode::Warp => "at 127.0.2.2 and 127.0.2.3 in the container".to_owned(),
+        Mode::Host => "to the host's resolvers through the tunnel".to_owned(),
+        Mode::Custom => {
+            let servers: Vec<String> = dns.servers.iter().map(|s| s.to_string()).collect();
+            format!("to {} through the tunnel", servers.join(", "))
+        }
+    }
+}
+
+fn account_type(registration: &str) -> Option<&str> {
+    registration
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("Account type:"))
+        .map(str::trim)
+}
+
+fn trace_field<'a>(trace: &'a str, key: &str) -> Option<&'a str> {
+    trace
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+}
+
+fn warp_account(ex: &dyn Executor, config: &Config) -> Result<String> {
+    let registration = warp_cli_op(ex, &config.base_dir, Op::ShowRegistration)?;
+    Ok(account_type(&registration)
+        .context("warp-cli didn't show an account type")?
+        .to_owned())
+}
+
+/// The colo serving requests through the proxy, if WARP carries them already
+pub fn warp_colo(ex: &dyn Executor, config: &Config) -> Result<Option<String>> {
+    let out = ex.output(
+        ex.command(Binary::Curl)?
+            .args(["-fsS", "--max-time", "3", "--proxy", &proxy_url(config)])
+            .arg(TRACE_URL),
+    )?;
+    out.status.exit_ok()?;
+    let trace = String::from_utf8(out.stdout)?;
+    if trace_field(&trace, "warp") == Some("off") {
+        return Ok(None);
+    }
+    Ok(trace_field(&trace, "colo").map(str::to_owned))
+}
+
+/// What up tells the user once the container runs. WARP may still be connecting,
+/// so the details it asks WARP for are best effort.
+pub fn summary(ex: &dyn Executor, config: &Config) -> String {
+    let account = warp_account(ex, config).unwrap_or_else(|e| {
+        debug!("Couldn't get the WARP account type: {e:#}");
+        "unknown".to_owned()
+    });
+    let mut summary = String::new();
+    if !Parts::load(&config.base_dir).unwrap_or_default().proxy {
+        let _ = writeln!(summary, "SOCKS5 proxy   not started, as asked");
+        let _ = writeln!(
+            summary,
+            "WARP           {account} account, see warp-cli status"
+        );
+        return summary;
+    }
+    let connection = match warp_colo(ex, config) {
+        Ok(Some(colo)) => format!("connected via {colo}"),
+        Ok(None) => "not connected yet, see warp-cli status".to_owned(),
+        Err(e) => {
+            debug!("Couldn't get the WARP colo: {e:#}");
+            "not reachable yet".to_owned()
+        }
+    };
+
+    let _ = writeln!(summary, "SOCKS5 proxy   {}", proxy_url(config));
+    if config.dns.exposed() {
+        let _ = writeln!(
+            summary,
+            "DNS            at {} for the host, see `bubblewarp client-config resolved`",
+            config.network.ns_addr
+        );
+    } else {
+        let _ = writeln!(
+            summary,
+            "DNS            through the proxy with socks5h, {}",
+            container_resolvers(&config.dns)
+        );
+    }
+    let _ = writeln!(summary, "WARP           {account} account, {connection}");
+    if config.gateway.enabled {
+        let port = gateway::listen_port(config).map_or("unknown".to_owned(), |p| p.to_string());
+        let _ = writeln!(
+            summary,
+            "WireGuard      port {port}, peers use {} for DNS",
+            config.gateway.dns
+        );
+    }
+    let _ = writeln!(
+        summary,
+        "Run `eval \"$(bubblewarp env)\"` to send your shell's traffic through WARP"
+    );
+    summary
+}
+
+/// Shell exports pointing proxy-aware programs at the container
+pub fn env(config: &Config) -> String {
+    let url = proxy_url(config);
+    ["ALL_PROXY", "all_proxy"]
+        .iter()
+        .map(|var| format!("export {var}={url}\n"))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_warp_details() {
+        let registration = "Account type: Free\nDevice ID: 0123\nPublic key: abcd\n";
+        assert_eq!(account_type(registration), Some("Free"));
+        assert_eq!(account_type("Missing registration\n"), None);
+
+        let trace = "fl=123\nh=www.cloudflare.com\ncolo=CDG\nwarp=on\n";
+        assert_eq!(trace_field(trace, "colo"), Some("CDG"));
+        assert_eq!(trace_field(trace, "warp"), Some("on"));
+        assert_eq!(trace_field(trace, "loc"), None);
+    }
+
+    #[test]
+    fn resolvers_by_dns_mode() {
+        assert!(container_resolvers(&DnsConfig::default()).contains("127.0.2.2"));
+        let custom: DnsConfig =
+            toml::from_str("mode = \"custom\"\nservers = [\"9.9.9.9\", \"1.1.1.1\"]").unwrap();
+        assert_eq!(
+            container_resolvers(&custom),
+            "to 9.9.9.9, 1.1.1.1 through the tunnel"
+        );
+    }
+}
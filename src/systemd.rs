@@ -0,0 +1,317 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::paths::Binary;
+use anyhow::{bail, Context, Result};
+use chrono::{Datelike, NaiveTime, Timelike, Weekday};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tracing::{debug, info};
+
+const UNIT_DIR: &str = "/etc/systemd/system";
+const SERVICE: &str = "bubblewarp.service";
+/// Scheduled units, which only exist with a schedule
+const SCHEDULE_UNITS: &[&str] = &[
+    "bubblewarp-start.timer",
+    "bubblewarp-stop.timer",
+    "bubblewarp-stop.service",
+];
+/// The socket-activated relay, which only exists when asked for
+const RELAY_UNITS: &[&str] = &["bubblewarp-relay.socket", "bubblewarp-relay.service"];
+
+/// When the container should be up, like "Mon..Fri 09:00-18:00". The days use systemd's
+/// calendar syntax, and are every day when left out. The window can't go past midnight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    days: Option<String>,
+    /// Indexed from Monday
+    on_days: [bool; 7],
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+fn parse_weekday(day: &str) -> Result<Weekday> {
+    Weekday::from_str(day).map_err(|_| anyhow::anyhow!("Unknown day {day}"))
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(spec: &str) -> Result<Self> {
+        let (days, window) = match spec.trim().rsplit_once(' ') {
+            Some((days, window)) => (Some(days.trim()), window),
+            None => (None, spec.trim()),
+        };
+        let (start, end) = window
+            .split_once('-')
+            .context("Expected a time window like 09:00-18:00")?;
+        let start = NaiveTime::parse_from_str(start, "%H:%M")?;
+        let end = NaiveTime::parse_from_str(end, "%H:%M")?;
+        if start >= end {
+            bail!("The window must end after it starts, on the same day")
+        }
+        let mut on_days = [days.is_none(); 7];
+        for item in days.into_iter().flat_map(|days| days.split(',')) {
+            let (first, last) = match item.split_once("..") {
+                Some((first, last)) => (parse_weekday(first)?, parse_weekday(last)?),
+                None => (parse_weekday(item)?, parse_weekday(item)?),
+            };
+            let mut day = first;
+            loop {
+                on_days[day.num_days_from_monday() as usize] = true;
+                if day == last {
+                    break;
+                }
+                day = day.succ();
+            }
+        }
+        Ok(Schedule {
+            days: days.map(str::to_owned),
+            on_days,
+            start,
+            end,
+        })
+    }
+}
+
+impl Schedule {
+    pub fn contains(&self, day: Weekday, time: NaiveTime) -> bool {
+        self.on_days[day.num_days_from_monday() as usize] && self.start <= time && time < self.end
+    }
+
+    fn on_calendar(&self, time: NaiveTime) -> String {
+        let time = format!("{:02}:{:02}", time.hour(), time.minute());
+        match &self.days {
+            Some(days) => format!("{days} {time}"),
+            None => format!("*-*-* {time}"),
+        }
+    }
+}
+
+fn unit_path(name: &str) -> PathBuf {
+    Path::new(UNIT_DIR).join(name)
+}
+
+/// Runs up and down, with the same config and base dir as the install command
+fn service_unit(exe: &Path, common_args: &str, schedule: Option<(&str, &Schedule)>) -> String {
+    let exe = exe.display();
+    // Starting at boot outside the window would keep WARP up until the next stop
+    let condition = schedule.map_or(String::new(), |(spec, _)| {
+        format!("ExecCondition={exe}{common_args} systemd check-schedule \"{spec}\"\n")
+    });
+    format!(
+        "[Unit]
+Description=WARP in a bubblewarp container
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=oneshot
+RemainAfterExit=yes
+{condition}ExecStart={exe}{common_args} up --progress=never
+ExecStop={exe}{common_args} down
+
+[Install]
+WantedBy=multi-user.target
+"
+    )
+}
+
+fn timer_unit(what: &str, on_calendar: &str, unit: &str) -> String {
+    format!(
+        "[Unit]
+Description={what} bubblewarp on schedule
+
+[Timer]
+OnCalendar={on_calendar}
+Unit={unit}
+
+[Install]
+WantedBy=timers.target
+"
+    )
+}
+
+fn relay_units(exe: &Path, common_args: &str, port: u16) -> [String; 2] {
+    let exe = exe.display();
+    [
+        format!(
+            "[Unit]
+Description=Host port relaying to the bubblewarp container's proxy
+
+[Socket]
+ListenStream=127.0.0.1:{port}
+
+[Install]
+WantedBy=sockets.target
+"
+        ),
+        format!(
+            "[Unit]
+Description=Relay to the bubblewarp container's proxy, bringing it up on the first connection
+Requires=bubblewarp-relay.socket
+
+[Service]
+ExecStart={exe}{common_args} relay
+"
+        ),
+    ]
+}
+
+fn systemctl(ex: &dyn Executor, args: &[&str]) -> Result<()> {
+    ex.status(ex.command(Binary::Systemctl)?.args(args))?
+        .exit_ok()?;
+    Ok(())
+}
+
+/// Installs a service bringing the container up at boot, or only during the schedule's window
+pub fn install(
+    ex: &dyn Executor,
+    config: &Config,
+    config_file: Option<&Path>,
+    schedule: Option<&str>,
+    relay_port: Option<u16>,
+) -> Result<()> {
+    let parsed = schedule.map(Schedule::from_str).transpose()?;
+    let exe = std::env::current_exe().context("Finding our own executable")?;
+    let mut common_args = format!(" --base-dir {}", config.base_dir.display());
+    if let Some(config_file) = config_file {
+        common_args += &format!(" --config {}", config_file.canonicalize()?.display());
+    }
+
+    let mut units = vec![(
+        SERVICE,
+        service_unit(&exe, &common_args, schedule.zip(parsed.as_ref())),
+    )];
+    if let Some(schedule) = &parsed {
+        units.push((
+            "bubblewarp-start.timer",
+            timer_unit("Start", &schedule.on_calendar(schedule.start), SERVICE),
+        ));
+        units.push((
+            "bubblewarp-stop.timer",
+            timer_unit(
+                "Stop",
+                &schedule.on_calendar(schedule.end),
+                "bubblewarp-stop.service",
+            ),
+        ));
+        units.push((
+            "bubblewarp-stop.service",
+            format!(
+                "[Unit]\nDescription=Stop bubblewarp on schedule\n\n[Service]\nType=oneshot\nExecStart={} stop {SERVICE}\n",
+                ex.command(Binary::Systemctl)?.get_program().to_string_lossy()
+            ),
+        ));
+    } else {
+        remove_units(ex, SCHEDULE_UNITS)?;
+    }
+    if let Some(port) = relay_port {
+        units.extend(
+            RELAY_UNITS
+                .iter()
+                .copied()
+                .zip(relay_units(&exe, &common_args, port)),
+        );
+    } else {
+        remove_units(ex, RELAY_UNITS)?;
+    }
+    for (name, unit) in &units {
+        debug!("Writing {}", unit_path(name).display());
+        std::fs::write(unit_path(name), unit)?;
+    }
+    systemctl(ex, &["daemon-reload"])?;
+    systemctl(ex, &["enable", SERVICE])?;
+    if parsed.is_some() {
+        systemctl(
+            ex,
+            &[
+                "enable",
+                "--now",
+                "bubblewarp-start.timer",
+                "bubblewarp-stop.timer",
+            ],
+        )?;
+        info!("Installed {SERVICE}, started and stopped on schedule");
+    } else {
+        info!("Installed {SERVICE}, start it with systemctl start {SERVICE}");
+    }
+    if let Some(port) = relay_port {
+        systemctl(ex, &["enable", "--now", RELAY_UNITS[0]])?;
+        info!("Relaying 127.0.0.1:{port} to the container's proxy, which comes up on the first connection");
+    }
+    Ok(())
+}
+
+fn remove_units(ex: &dyn Executor, units: &[&str]) -> Result<()> {
+    for unit in units {
+        if unit_path(unit).exists() {
+            let _ = systemctl(ex, &["disable", "--now", unit]);
+            std::fs::remove_file(unit_path(unit))?;
+        }
+    }
+    Ok(())
+}
+
+/// Removes what install added. The container itself stays up if it is.
+pub fn uninstall(ex: &dyn Executor) -> Result<()> {
+    remove_units(ex, SCHEDULE_UNITS)?;
+    remove_units(ex, RELAY_UNITS)?;
+    if unit_path(SERVICE).exists() {
+        let _ = systemctl(ex, &["disable", SERVICE]);
+        std::fs::remove_file(unit_path(SERVICE))?;
+    }
+    systemctl(ex, &["daemon-reload"])
+}
+
+/// The service's ExecCondition, which skips starting outside the window
+pub fn check_schedule(spec: &str) -> Result<()> {
+    let schedule = Schedule::from_str(spec)?;
+    let now = chrono::Local::now();
+    if !schedule.contains(now.weekday(), now.time()) {
+        bail!("Outside of the scheduled window {spec}")
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn parses_schedule() {
+        let schedule: Schedule = "Mon..Fri 09:00-18:00".parse().unwrap();
+        assert!(schedule.contains(Weekday::Wed, at(9, 0)));
+        assert!(!schedule.contains(Weekday::Wed, at(18, 0)));
+        assert!(!schedule.contains(Weekday::Sat, at(12, 0)));
+        assert_eq!(schedule.on_calendar(schedule.start), "Mon..Fri 09:00");
+
+        let schedule: Schedule = "Sat,Sun 10:30-12:00".parse().unwrap();
+        assert!(schedule.contains(Weekday::Sun, at(11, 0)));
+        assert!(!schedule.contains(Weekday::Mon, at(11, 0)));
+
+        let schedule: Schedule = "07:00-08:00".parse().unwrap();
+        assert!(schedule.contains(Weekday::Tue, at(7, 59)));
+        assert_eq!(schedule.on_calendar(schedule.end), "*-*-* 08:00");
+
+        assert!("Mon..Fri 18:00-09:00".parse::<Schedule>().is_err());
+        assert!("Someday 09:00-18:00".parse::<Schedule>().is_err());
+    }
+
+    #[test]
+    fn scheduled_service_checks_window() {
+        let schedule: Schedule = "Mon..Fri 09:00-18:00".parse().unwrap();
+        let unit = service_unit(
+            Path::new("/usr/bin/bubblewarp"),
+            " --base-dir /run/bubblewarp",
+            Some(("Mon..Fri 09:00-18:00", &schedule)),
+        );
+        assert!(unit.contains(
+            "ExecCondition=/usr/bin/bubblewarp --base-dir /run/bubblewarp systemd check-schedule \"Mon..Fri 09:00-18:00\"\n\
+             ExecStart=/usr/bin/bubblewarp --base-dir /run/bubblewarp up --progress=never\n"
+        ));
+    }
+}
@@ -0,0 +1,32 @@
+//! Fixtures shared by the unit tests, of the library and of the binary
+
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+/// A fresh directory under the system's temporary directory, removed with what's in it once
+/// dropped, so it goes away when the test fails too
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Tests run in parallel, so each needs a name of its own
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("bubblewarp-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        TempDir(dir)
+    }
+}
+
+impl Deref for TempDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
@@ -0,0 +1,103 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::span;
+use tracing::Subscriber;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+
+/// Spans with this target are the phases of a command, timed by a Recorder
+pub const TARGET: &str = "bubblewarp::phase";
+
+struct Phase {
+    name: &'static str,
+    started: Instant,
+    elapsed: Duration,
+}
+
+/// Keeps how long each phase span took, in the order they finished
+#[derive(Clone, Default)]
+pub struct Recorder {
+    phases: Arc<Mutex<Vec<Phase>>>,
+}
+
+struct Started(Instant);
+
+impl<S: Subscriber + for<'a> LookupSpan<'a>> tracing_subscriber::Layer<S> for Recorder {
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if attrs.metadata().target() != TARGET {
+            return;
+        }
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(Started(Instant::now()));
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(started) = span.extensions().get::<Started>().map(|s| s.0) else {
+            return;
+        };
+        self.phases.lock().unwrap().push(Phase {
+            name: span.name(),
+            started,
+            elapsed: started.elapsed(),
+        });
+    }
+}
+
+impl Recorder {
+    /// A table of the phases that ran, slowest stand out by their share of the total.
+    /// Phases can run side by side, so the total is the wall time from first to last.
+    pub fn report(&self) -> String {
+        let phases = self.phases.lock().unwrap();
+        let first = phases.iter().map(|p| p.started).min();
+        let last = phases.iter().map(|p| p.started + p.elapsed).max();
+        let total = match (first, last) {
+            (Some(first), Some(last)) => last - first,
+            _ => Duration::ZERO,
+        };
+        let width = phases
+            .iter()
+            .map(|p| p.name.len())
+            .chain(["total".len()])
+            .max()
+            .unwrap_or_default();
+        let mut report = String::new();
+        for Phase { name, elapsed, .. } in phases.iter() {
+            let share = elapsed.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON) * 100.;
+            report += &format!(
+                "{name:<width$}  {:>8.1} ms  {share:>5.1}%\n",
+                elapsed.as_secs_f64() * 1000.
+            );
+        }
+        report += &format!(
+            "{:<width$}  {:>8.1} ms\n",
+            "total",
+            total.as_secs_f64() * 1000.
+        );
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[test]
+    fn times_phase_spans() {
+        let recorder = Recorder::default();
+        let subscriber = tracing_subscriber::registry().with(recorder.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            tracing::info_span!(target: TARGET, "networking").in_scope(|| {
+                std::thread::sleep(Duration::from_millis(10));
+            });
+            tracing::info_span!("not_a_phase").in_scope(|| {});
+        });
+        let report = recorder.report();
+        let lines: Vec<&str> = report.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("networking"));
+        assert!(lines[1].starts_with("total"));
+    }
+}
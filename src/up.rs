@@ -1,32 +1,162 @@
+use crate::cgroup;
+use crate::compat;
+use crate::config::{Config, ContainerConfig};
+use crate::dns;
+use crate::down::{cleanup_external_networking, kill_ns_processes, unmount_namespaces};
+use crate::exec::Executor;
+use crate::extra;
+use crate::firewall;
+use crate::gateway;
+use crate::init;
+use crate::lsm;
 use crate::namespace;
-use crate::namespace::{all_ns_processes, mount_point, spawn_inside_all_namespaces, Status, Type};
-use crate::net::{setup_external_networking, setup_private_networking};
+use crate::namespace::{
+    all_ns_processes, mount_point, nsenter_child, run_inside_namespace,
+    spawn_inside_all_namespaces, which_inside, with_mount_namespace, Mounts, Status, Type,
+    ID_MAP_SIZE,
+};
+use crate::net::{
+    check_egress, container_has_default_route, loosen_rp_filter, remove_egress_route,
+    restore_rp_filter, route_through_egress, setup_external_networking, setup_private_networking,
+    uplinks,
+};
+use crate::paths::Binary;
+use crate::preflight;
+use crate::proxy;
+use crate::readiness;
+use crate::reload;
+use crate::rollback::Rollback;
+use crate::rootfs;
+use crate::service::{self, ServiceConfig};
+use crate::state;
+use crate::timings;
 use anyhow::{bail, Context, Result};
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::mount::{MntFlags, MsFlags};
 use std::fs::File;
-use std::io::Write;
-use std::path::Path;
-use std::process::Command;
-use std::time::Duration;
+use std::io::{Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread::ScopedJoinHandle;
+use std::time::{Duration, Instant};
 use strum::IntoEnumIterator;
-use tracing::{debug, info, trace, warn};
+use tracing::{debug, info, info_span, trace, warn};
 
-pub fn up() -> Result<()> {
+/// Port danted listens on, on the container side of the veth link
+pub const SOCKS_PORT: u16 = 8080;
+
+pub const WARP_STATE_DIR: &str = "/var/lib/cloudflare-warp";
+pub const VAR_LIB: &str = "/var/lib";
+
+pub const ROOT_PASSWD: &str = "root:x:0:0:root:/root:/bin/sh\n";
+pub const ROOT_GROUP: &str = "root:x:0:\n";
+
+/// The account danted runs as inside the container, unless configured to run as root
+const DANTED_USER: &str = "bubblewarp-danted";
+
+/// Which parts of the stack up sets up, the others are left to the user
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Parts {
+    /// danted, for users of warp-cli's own proxy or of commands run inside
+    pub proxy: bool,
+    /// The NAT and forward rules, for hosts whose firewall is managed separately
+    pub external: bool,
+}
+
+impl Default for Parts {
+    fn default() -> Self {
+        Parts {
+            proxy: true,
+            external: true,
+        }
+    }
+}
+
+impl Parts {
+    fn path(base_dir: &Path) -> PathBuf {
+        base_dir.join("skipped-parts")
+    }
+
+    /// The parts the container was created with, so later commands know what's absent on purpose
+    pub fn load(base_dir: &Path) -> Result<Self> {
+        let skipped = match std::fs::read_to_string(Self::path(base_dir)) {
+            Ok(skipped) => skipped,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Parts::default()),
+            Err(e) => return Err(e.into()),
+        };
+        let skipped: Vec<&str> = skipped.lines().collect();
+        Ok(Parts {
+            proxy: !skipped.contains(&"proxy"),
+            external: !skipped.contains(&"external"),
+        })
+    }
+
+    fn save(&self, base_dir: &Path) -> Result<()> {
+        let mut skipped = String::new();
+        for (part, enabled) in [("proxy", self.proxy), ("external", self.external)] {
+            if !enabled {
+                skipped += part;
+                skipped += "\n";
+            }
+        }
+        Ok(std::fs::write(Self::path(base_dir), skipped)?)
+    }
+
+    pub fn forget(base_dir: &Path) -> Result<()> {
+        match std::fs::remove_file(Self::path(base_dir)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn up(ex: &dyn Executor, config: &Config) -> Result<()> {
     // TODO: Check that /etc/subuid and /etc/subgid contain a suitable range, and if not warn about it...
-    let base_dir = namespace::base_dir()?;
+    let base_dir = config.base_dir.as_path();
+    let net = &config.network;
+    config.check()?;
+    config.nat.check()?;
+    if let Some(iface) = &config.egress_iface {
+        check_egress(ex, iface)?;
+    }
+    compat::check_versions(ex, config)?;
     if !base_dir.exists() {
-        std::fs::create_dir_all(&base_dir)?;
+        std::fs::create_dir_all(base_dir)?;
     }
+    state::migrate(base_dir)?;
 
-    if base_dir_has_private_self_bind_mount(&base_dir)? {
+    let rollback = Rollback::new();
+    let phase = info_span!(target: timings::TARGET, "mounting").entered();
+    let mounts = Mounts::snapshot()?;
+    if mounts.has_self_bind_mount(base_dir) {
         warn!("Persistent namespace base directory is still bind-mounted, continuing...")
     } else {
-        private_self_bind_mount_base_dir(&base_dir)?;
+        rollback.push("base dir bind mount", || {
+            Ok(ex.umount(base_dir, MntFlags::MNT_DETACH)?)
+        });
+        private_self_bind_mount_base_dir(ex, base_dir)?;
     }
 
-    let init_proc = match namespace::status(&base_dir)? {
+    let status = mounts.status(base_dir);
+    let created = status == Status::None;
+    drop(phase);
+    let mut parts = config.parts;
+    let init_proc = match status {
         Status::Ready => {
-            if let Some(proc) = find_pid_ns_init_process(&base_dir)? {
+            if let Some(proc) = find_pid_ns_init_process(base_dir)? {
                 info!("Namespaces already mounted, continuing");
+                let created_with = Parts::load(base_dir)?;
+                if created_with != parts {
+                    warn!("Keeping the parts the container was created with, run down first to change them");
+                    parts = created_with;
+                }
+                if config.container.time_offset.is_some()
+                    && !mounts.is_mounted(base_dir, Type::Time)
+                {
+                    warn!("The container was created without a time namespace, run down first to get one");
+                }
                 proc
             } else {
                 bail!(
@@ -34,93 +164,231 @@ pub fn up() -> Result<()> {
                 );
             }
         }
-        Status::Partial(_mounted_set) => {
-            bail!("Namespaces partially mounted! Try calling the down command first");
+        Status::Partial(mounted_set) => {
+            let Some(proc) = find_init_process(base_dir)? else {
+                bail!("Namespaces partially mounted and the container's init is gone! Try calling the down command first");
+            };
+            info!("Namespaces partially mounted, mounting the rest from the container's init");
+            namespace::mount_missing(ex, base_dir, &mounted_set, &proc)
+                .context("Namespaces partially mounted, try calling the down command first")?;
+            parts = Parts::load(base_dir)?;
+            proc
+        }
+        Status::None => {
+            let _phase = info_span!(target: timings::TARGET, "namespaces").entered();
+            // Everything else set up inside goes away with the container's processes and namespaces
+            preflight::check_kernel(config, parts)?;
+            dns::restore_stale_host(config)?;
+            rollback.push("container", || remove_container(ex, base_dir));
+            parts.save(base_dir)?;
+            create_namespaces(
+                ex,
+                base_dir,
+                &config.container,
+                config.timeouts.namespace_create,
+            )?
         }
-        Status::None => create_namespaces(&base_dir)?,
     };
     let ns_init_pid = init_proc.pid as u32;
 
-    create_etc_overlay_inside(&base_dir, ns_init_pid)?;
-    setup_private_networking(&base_dir)?;
-    setup_external_networking(&base_dir)?;
-    spawn_process_inside("warp-svc", ns_init_pid)?;
+    // The container's filesystem and its network don't depend on each other. warp-svc needs both,
+    // but not the NAT rules, which are slow to add on small devices and can go on meanwhile.
+    std::thread::scope(|scope| -> Result<()> {
+        let filesystem = scope.spawn(|| {
+            let _phase = info_span!(target: timings::TARGET, "overlay").entered();
+            setup_filesystem(ex, config, ns_init_pid)
+        });
+        let phase = info_span!(target: timings::TARGET, "private_networking").entered();
+        setup_private_networking(ex, base_dir, net)?;
+        if !config.firewall.ipv6 {
+            rollback.push("IPv6 rules", || firewall::unblock_ipv6(ex, net));
+            firewall::block_ipv6_leaks(ex, base_dir, net)?;
+        }
+        drop(phase);
+        let external = scope.spawn(|| {
+            let _phase = info_span!(target: timings::TARGET, "external_networking").entered();
+            // Rules a working container already had aren't ours to remove
+            if parts.external && !container_has_default_route(base_dir)? {
+                rollback.push("NAT rules", || {
+                    cleanup_external_networking(ex, base_dir, net)
+                });
+            }
+            let (firewall, nat) = (&config.firewall, &config.nat);
+            setup_external_networking(ex, base_dir, net, firewall, nat, parts.external)?;
+            if parts.external {
+                rollback.push("rp_filter", || restore_rp_filter(ex, base_dir));
+                loosen_rp_filter(ex, base_dir, net, &uplinks(ex, firewall)?)?;
+            }
+            if let Some(iface) = config.egress_iface.as_deref().filter(|_| parts.external) {
+                rollback.push("egress route", || remove_egress_route(ex, base_dir));
+                route_through_egress(ex, base_dir, net, iface)?;
+            }
+            Ok(())
+        });
+        join(filesystem)?;
+
+        let phase = info_span!(target: timings::TARGET, "warp").entered();
+        let warp_svc = proxy::warp_svc_service(config);
+        spawn_process_inside(ex, base_dir, Binary::WarpSvc, &warp_svc, ns_init_pid)?;
+
+        // TODO: Try starting danted every 250ms for ~2s max and check that it's still running 250ms later
+        if proxy::runs_danted(config, parts) || !config.services.extra.is_empty() {
+            let timeout = config.timeouts.warp_ready;
+            if let Err(e) =
+                readiness::wait_for_service(ex, "warp-svc", &warp_svc, ns_init_pid, timeout)
+            {
+                warn!("{e:#}, starting the other services anyway");
+            }
+        }
+        drop(phase);
+        join(external)
+    })?;
+
+    if proxy::runs_danted(config, parts) {
+        let _phase = info_span!(target: timings::TARGET, "danted").entered();
+        let danted = config.services.get(Binary::Danted);
+        spawn_process_inside(ex, base_dir, Binary::Danted, &danted, ns_init_pid)?;
+        if !config.services.extra.is_empty() {
+            let timeout = config.timeouts.proxy_ready;
+            if let Err(e) = readiness::wait_for_service(ex, "danted", &danted, ns_init_pid, timeout)
+            {
+                warn!("{e:#}, starting the extra services anyway");
+            }
+        }
+    } else if parts.proxy {
+        let _phase = info_span!(target: timings::TARGET, "warp_proxy").entered();
+        proxy::setup_warp_proxy(ex, config)?;
+    }
+
+    if !config.services.extra.is_empty() {
+        let _phase = info_span!(target: timings::TARGET, "extra_services").entered();
+        extra::start_all(ex, config, ns_init_pid)?;
+    }
 
-    // TODO: Wait for warp interface to be up inside the container instead of a hard sleep..
-    //       Also, try starting danted every 250ms for ~2s max and check that it's still running 250ms later
-    std::thread::sleep(Duration::from_millis(1000));
+    if config.dns.exposed() {
+        let _phase = info_span!(target: timings::TARGET, "dns").entered();
+        dns::expose(ex, config)?;
+        if config.dns.manage_host {
+            rollback.push("host DNS", || dns::restore_host(ex, config));
+            dns::manage_host(ex, config)?;
+        }
+    }
 
-    spawn_process_inside("/usr/sbin/danted", ns_init_pid)?;
+    if config.gateway.enabled {
+        let _phase = info_span!(target: timings::TARGET, "gateway").entered();
+        gateway::setup(ex, config)?;
+    }
+
+    if !config.firewall.open.is_empty() {
+        rollback.push("firewall openings", || {
+            firewall::close_recorded(ex, base_dir)
+        });
+        firewall::open_configured(ex, base_dir, &config.firewall)?;
+    }
 
+    // What reload compares against, a config changed for an up that kept the container wasn't
+    // all applied
+    if created || reload::recorded(base_dir)?.is_none() {
+        reload::record(base_dir, &config.file)?;
+    }
+    rollback.disarm();
     Ok(())
 }
 
-fn find_pid_ns_init_process(base_dir: &Path) -> Result<Option<procfs::process::Process>> {
-    let ns_procs = all_ns_processes(base_dir)?;
-    for proc in ns_procs {
-        let cmdline = proc.cmdline()?;
-        if !cmdline.is_empty() && cmdline[0] == "tini" {
-            return Ok(Some(proc));
-        }
+/// Kills the container's processes and unmounts its namespaces, which takes what was set up
+/// inside with them
+fn remove_container(ex: &dyn Executor, base_dir: &Path) -> Result<()> {
+    let mounts = Mounts::snapshot()?;
+    if mounts.is_mounted(base_dir, Type::Pid) {
+        kill_ns_processes(ex, base_dir)?;
     }
-    Ok(None)
+    cgroup::remove(ex, base_dir)?;
+    unmount_namespaces(ex, base_dir, &mounts)?;
+    init::remove_id(base_dir)?;
+    namespace::forget_created(base_dir)?;
+    Parts::forget(base_dir)?;
+    service::forget_all(base_dir)
 }
 
-pub fn base_dir_has_private_self_bind_mount(base_dir: &Path) -> Result<bool> {
-    use procfs::process::Process;
+/// Joins a setup thread, passing its panic on if it had one
+fn join<T>(thread: ScopedJoinHandle<'_, Result<T>>) -> Result<T> {
+    thread
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+}
 
-    let base_dir = base_dir.canonicalize()?;
-    for mount in Process::myself()?.mountinfo()? {
-        let root = if let Ok(root) = std::fs::canonicalize(&mount.root) {
-            root
-        } else {
-            continue;
-        };
-        if root != base_dir {
-            continue;
+/// Gives the container its hostname, and either the host's filesystem with a private /etc
+/// or a rootfs of its own
+fn setup_filesystem(ex: &dyn Executor, config: &Config, ns_init_pid: u32) -> Result<()> {
+    let base_dir = config.base_dir.as_path();
+    set_hostname(ex, base_dir, &config.container.hostname)?;
+    match config.container.rootfs {
+        rootfs::Kind::Host => {
+            create_etc_overlay_inside(ex, config, ns_init_pid)?;
+            mount_private_tmpfs_inside(ex, ns_init_pid)?;
+            mount_warp_state_inside(ex, base_dir, ns_init_pid)?;
+            mask_paths_inside(ex, base_dir, &config.container.mask_paths, ns_init_pid)?;
+            if config.container.read_only {
+                remount_read_only_inside(ex, ns_init_pid)?;
+            }
         }
-
-        let dst = if let Ok(dst) = std::fs::canonicalize(&mount.mount_point) {
-            dst
-        } else {
-            continue;
-        };
-        if dst != base_dir {
-            continue;
+        rootfs::Kind::Minimal | rootfs::Kind::Image => {
+            if rootfs::is_entered(ns_init_pid) {
+                debug!("Container already runs in its rootfs");
+            } else {
+                rootfs::assemble(ex, config)?;
+                rootfs::enter(ex, base_dir, ns_init_pid)?;
+            }
         }
-        trace!("Found base dir self bind mount point: {:#?}", mount);
-        return Ok(true);
     }
-    Ok(false)
+    Ok(())
 }
 
-pub fn private_self_bind_mount_base_dir(base_dir: &Path) -> Result<()> {
-    use nix::mount::MsFlags;
-
-    debug!("Creating base dir private self bind mount");
-    nix::mount::mount(
-        Some(base_dir),
+/// Makes warp-svc's registration and logs distinguishable from the host's
+fn set_hostname(ex: &dyn Executor, base_dir: &Path, hostname: &str) -> Result<()> {
+    debug!("Setting container hostname to {hostname}");
+    run_inside_namespace(
+        ex,
         base_dir,
-        None::<&Path>,
-        MsFlags::MS_BIND,
-        None::<&Path>,
+        Type::Uts,
+        ex.command(Binary::Sysctl)?
+            .args(["-w", &format!("kernel.hostname={hostname}")]),
     )?;
+    Ok(())
+}
 
-    if let Err(e) = nix::mount::mount(
-        None::<&Path>,
-        base_dir,
-        None::<&Path>,
-        MsFlags::MS_PRIVATE,
-        None::<&Path>,
-    ) {
-        let _ = nix::mount::umount(base_dir);
+pub fn find_pid_ns_init_process(base_dir: &Path) -> Result<Option<procfs::process::Process>> {
+    Ok(all_ns_processes(base_dir)?
+        .find(|proc| init::is_init(base_dir, proc) || init::is_legacy_init(base_dir, proc)))
+}
+
+/// The container's init, found by its ID since its PID namespace may not be mounted to look in
+fn find_init_process(base_dir: &Path) -> Result<Option<procfs::process::Process>> {
+    Ok(procfs::process::all_processes()?
+        .filter_map(Result::ok)
+        .find(|proc| init::is_init(base_dir, proc)))
+}
+
+pub fn private_self_bind_mount_base_dir(ex: &dyn Executor, base_dir: &Path) -> Result<()> {
+    use nix::mount::{MntFlags, MsFlags};
+
+    debug!("Creating base dir private self bind mount");
+    ex.mount(Some(base_dir), base_dir, None, MsFlags::MS_BIND, None)?;
+
+    if let Err(e) = ex.mount(None, base_dir, None, MsFlags::MS_PRIVATE, None) {
+        let _ = ex.umount(base_dir, MntFlags::empty());
         return Err(e.into());
     }
 
     Ok(())
 }
 
-pub fn create_namespaces(base_dir: &Path) -> Result<procfs::process::Process> {
+pub fn create_namespaces(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    container: &ContainerConfig,
+    timeout: Duration,
+) -> Result<procfs::process::Process> {
     use namespace::Type::*;
 
     debug!("Creating mount points for persistent namespaces");
@@ -131,146 +399,641 @@ pub fn create_namespaces(base_dir: &Path) -> Result<procfs::process::Process> {
         }
     }
 
+    let cgroup = cgroup::create(base_dir, container)?;
+
     debug!("Calling unshare to create persistent namespaces");
-    let unshare_handle = Command::new("unshare")
+    let init = std::env::current_exe().context("Finding our own executable to run as init")?;
+    let mut unshare = ex.command(Binary::Unshare)?;
+    // pivot_root only moves processes whose cwd is the old root, and nsenter -a inherits init's
+    unshare.current_dir("/");
+    // The cgroup namespace is rooted at unshare's cgroup, so it must be in ours before it runs
+    if let Some(cgroup) = &cgroup {
+        cgroup::join_on_exec(&mut unshare, cgroup)?;
+    }
+    unshare
         .arg("--fork")
         .arg("-r")
         .arg("--mount-proc")
-        .arg("--map-users=0,0,1200")
-        .arg("--map-groups=0,0,1200")
+        .arg(format!("--map-users=0,0,{ID_MAP_SIZE}"))
+        .arg(format!("--map-groups=0,0,{ID_MAP_SIZE}"))
         .arg(format!("--pid={}", mount_point(base_dir, Pid).display()))
         .arg(format!("--user={}", mount_point(base_dir, User).display()))
         .arg(format!("--net={}", mount_point(base_dir, Net).display()))
+        .arg(format!("--uts={}", mount_point(base_dir, Uts).display()))
+        .arg(format!("--ipc={}", mount_point(base_dir, Ipc).display()))
+        .arg(format!(
+            "--cgroup={}",
+            mount_point(base_dir, Cgroup).display()
+        ))
         .arg(format!(
             "--mount={}",
             mount_point(base_dir, Mount).display()
-        ))
-        .args(["--", "tini", "--", "sleep", "infinity"])
-        .spawn()?;
-
-    std::thread::sleep(Duration::from_millis(25));
-    let unshare_proc = procfs::process::Process::new(unshare_handle.id() as i32)?;
-
-    let start_time = std::time::Instant::now();
-    let unshare_child_pid = loop {
-        if !unshare_proc.is_alive() {
-            let output = unshare_handle.wait_with_output()?;
-            bail!(
-                "Failed to create namespaces, unshare exited with {}\nstdout: {}\nstderr: {}",
-                output.status,
-                String::from_utf8_lossy(&output.stdout),
-                String::from_utf8_lossy(&output.stderr),
-            )
-        }
+        ));
+    if let Some(offset) = container.time_offset {
+        debug!("Creating a time namespace with clocks offset by {offset}s");
+        unshare
+            .arg(format!("--time={}", mount_point(base_dir, Time).display()))
+            .arg(format!("--monotonic={offset}"))
+            .arg(format!("--boottime={offset}"));
+    }
+    namespace::record_created(
+        base_dir,
+        Type::iter()
+            .filter(|t| !t.is_optional() || container.time_offset.is_some())
+            .collect(),
+    )?;
+    unshare.env(init::ID_ENV, init::new_id(base_dir)?);
+    // The init reports on this pipe once it runs, so there's nothing to poll for
+    let (ready_read, ready_write) = nix::unistd::pipe()?;
+    // SAFETY: pipe() just returned these fds, nothing else owns them
+    let (mut ready_read, ready_write) = unsafe {
+        (
+            File::from_raw_fd(ready_read),
+            OwnedFd::from_raw_fd(ready_write),
+        )
+    };
+    fcntl(
+        ready_read.as_raw_fd(),
+        FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC),
+    )?;
+    unshare
+        .env(init::READY_FD_ENV, ready_write.as_raw_fd().to_string())
+        .stderr(Stdio::piped());
+    let mut unshare_handle = ex.spawn(unshare.arg("--").arg(init).arg(init::MARKER))?;
+    drop(ready_write);
 
-        let mut unshare_tasks: Vec<_> = unshare_proc.tasks()?.collect();
-        if unshare_tasks.len() != 1 {
-            bail!(
-                "Unshare process has {} tasks, expected 1!",
-                unshare_tasks.len()
-            )
-        }
-        let unshare_task = unshare_tasks.remove(0)?;
-        let unshare_children = unshare_task.children()?;
-        if !unshare_children.is_empty() {
-            if unshare_children.len() != 1 {
-                for p in &unshare_children {
-                    warn!("{}", p);
+    let mut ready_poll = libc::pollfd {
+        fd: ready_read.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let deadline = Instant::now() + timeout;
+    let ready = loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        // SAFETY: ready_poll outlives the call
+        match unsafe { libc::poll(&mut ready_poll, 1, left.as_millis() as libc::c_int) } {
+            0 => break false,
+            n if n > 0 => break true,
+            _ => {
+                let e = std::io::Error::last_os_error();
+                if e.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(e).context("Waiting for namespace creation");
                 }
-                bail!(
-                    "Unshare process has {} children, expected 1!",
-                    unshare_children.len()
-                )
             }
-            break unshare_children[0];
         }
-
-        if std::time::Instant::now().duration_since(start_time) > Duration::from_secs(1) {
-            bail!("Timed out waiting for namespace creation")
+    };
+    // Nothing to read means unshare or the init exited without reporting
+    if !ready || ready_read.read(&mut [0])? == 0 {
+        if !ready {
+            unshare_handle.kill()?;
         }
-        std::thread::sleep(Duration::from_millis(25));
+        let output = unshare_handle.wait_with_output()?;
+        bail!(
+            "Failed to create namespaces, {}\nstderr: {}",
+            match ready {
+                true => format!("unshare exited with {}", output.status),
+                false => "timed out waiting for the init".to_owned(),
+            },
+            String::from_utf8_lossy(&output.stderr),
+        )
+    }
+    // Nothing reads it from here on, so writes fail instead of filling the pipe up
+    drop(unshare_handle.stderr.take());
+    let unshare_children = procfs::process::Process::new(unshare_handle.id() as i32)?
+        .task_main_thread()?
+        .children()?;
+    let [unshare_child_pid] = unshare_children[..] else {
+        bail!(
+            "Unshare process has {} children, expected 1!",
+            unshare_children.len()
+        )
     };
 
-    let tini_proc = procfs::process::Process::new(unshare_child_pid as i32)?;
-    if !tini_proc.is_alive() {
+    service::supervise(base_dir, "init", unshare_handle);
+    let init_proc = procfs::process::Process::new(unshare_child_pid as i32)?;
+    if !init_proc.is_alive() {
         bail!("namespace init process died!")
     }
     trace!(
-        "tini proc running with namespaces {:?}",
-        tini_proc.namespaces()
+        "init proc running with namespaces {:?}",
+        init_proc.namespaces()
     );
-    Ok(tini_proc)
+    Ok(init_proc)
 }
 
-pub fn create_etc_overlay_inside(base_dir: &Path, ns_init_pid: u32) -> Result<()> {
-    let overlay_dir = base_dir.join("etc_overlay");
+pub fn create_etc_overlay_inside(
+    ex: &dyn Executor,
+    config: &Config,
+    ns_init_pid: u32,
+) -> Result<()> {
+    let base_dir = &config.base_dir;
+    let overlay_dir = etc_overlay_dir(base_dir);
     let extra_lower = overlay_dir.join("extra_lower");
     let upper = overlay_dir.join("upper");
     let work = overlay_dir.join("work");
 
-    let mount_child = spawn_inside_all_namespaces(&Command::new("mount"), ns_init_pid)?;
-    let mount_out = mount_child.wait_with_output()?;
-    if String::from_utf8_lossy(&mount_out.stdout).contains("overlay on /etc type overlay") {
-        debug!("/etc overlay appears already mounted, not mounting it again");
+    let mounts = procfs::process::Process::new(ns_init_pid as i32)?.mountinfo()?;
+    if mounts
+        .iter()
+        .any(|m| m.mount_point == Path::new("/etc") && m.fs_type == "overlay")
+    {
+        debug!("/etc overlay is already mounted, not mounting it again");
         return Ok(());
     }
 
-    std::fs::create_dir_all(&extra_lower)?;
     std::fs::create_dir_all(&upper)?;
+    // Overlayfs refuses a work dir still holding what a mount that didn't go away cleanly left
+    if work.exists() {
+        debug!("Clearing the /etc overlay's stale work dir");
+        std::fs::remove_dir_all(&work)?;
+    }
     std::fs::create_dir_all(&work)?;
+    write_etc_files(&extra_lower, config, Some(Path::new("/etc")))?;
+
+    debug!("Mount read-only /etc overlay inside namespace");
+    let opt_lower = format!("lowerdir={}:/etc", extra_lower.to_string_lossy());
+    let opt_upper = format!("upperdir={}", upper.to_string_lossy());
+    let opt_work = format!("workdir={}", work.to_string_lossy());
+    let mut options = format!("{opt_lower},{opt_upper},{opt_work}");
+    if let Some(context) = lsm::overlay_context(Path::new("/etc"))? {
+        options += &format!(",{context}");
+    }
+    with_mount_namespace(base_dir, || {
+        ex.mount(
+            Some(Path::new("overlay")),
+            Path::new("/etc"),
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(&options),
+        )
+        .context("Mounting the /etc overlay")
+    })?;
+
+    Ok(())
+}
 
+/// Keeps warp-svc's runtime sockets and temp files away from the host, and from a host WARP install
+fn mount_private_tmpfs_inside(ex: &dyn Executor, ns_init_pid: u32) -> Result<()> {
+    let mounts = procfs::process::Process::new(ns_init_pid as i32)?.mountinfo()?;
+    for (target, mode) in [("/tmp", "1777"), ("/run", "755")] {
+        if mounts
+            .iter()
+            .any(|m| m.mount_point == Path::new(target) && m.fs_type == "tmpfs")
+        {
+            debug!("{target} already has a private tmpfs, not mounting it again");
+            continue;
+        }
+        debug!("Mounting a private tmpfs on {target} inside namespace");
+        let mut cmd = Command::new("mount");
+        cmd.args(["-t", "tmpfs", "tmpfs"])
+            .arg(format!("-omode={mode},nosuid,nodev"))
+            .arg(target);
+        spawn_inside_all_namespaces(ex, &cmd, ns_init_pid, None)?
+            .wait()?
+            .exit_ok()
+            .with_context(|| format!("Mounting tmpfs on {target}"))?;
+    }
+    Ok(())
+}
+
+/// Source of the tmpfs masks, so we can tell them apart from the host's own mounts
+const MASK_SOURCE: &str = "bubblewarp-mask";
+
+/// Hides user secrets from the container, the host filesystem is otherwise shared with it
+fn mask_paths_inside(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    paths: &[PathBuf],
+    ns_init_pid: u32,
+) -> Result<()> {
+    let mounts = procfs::process::Process::new(ns_init_pid as i32)?.mountinfo()?;
+    for path in paths {
+        // Masks apply to where symlinks lead, and mountinfo lists resolved paths
+        let Ok(path) = path.canonicalize() else {
+            debug!("{} doesn't exist, nothing to mask", path.display());
+            continue;
+        };
+        if base_dir.starts_with(&path) {
+            warn!(
+                "Not masking {}, the instance's base directory {} is inside",
+                path.display(),
+                base_dir.display()
+            );
+            continue;
+        }
+        if mounts.iter().any(|m| {
+            m.mount_point == path
+                && (m.mount_source.as_deref() == Some(MASK_SOURCE) || m.root == "/null")
+        }) {
+            debug!("{} is already masked", path.display());
+            continue;
+        }
+
+        debug!("Masking {} inside namespace", path.display());
+        let mut cmd = Command::new("mount");
+        if path.is_dir() {
+            cmd.args([
+                "-t",
+                "tmpfs",
+                "-o",
+                "ro,nosuid,nodev,noexec,size=0,mode=755",
+            ])
+            .arg(MASK_SOURCE);
+        } else {
+            cmd.args(["--bind", "/dev/null"]);
+        }
+        cmd.arg(&path);
+        spawn_inside_all_namespaces(ex, &cmd, ns_init_pid, None)?
+            .wait()?
+            .exit_ok()
+            .with_context(|| format!("Masking {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Kernel filesystems keep their own permission checks, and device nodes stay writable on a read-only mount
+const PSEUDO_FS_TYPES: &[&str] = &[
+    "proc",
+    "sysfs",
+    "cgroup",
+    "cgroup2",
+    "devtmpfs",
+    "devpts",
+    "mqueue",
+    "debugfs",
+    "tracefs",
+    "securityfs",
+    "pstore",
+    "bpf",
+    "configfs",
+    "fusectl",
+    "hugetlbfs",
+    "binfmt_misc",
+    "autofs",
+];
+
+/// Flags a remount has to repeat, the kernel refuses to clear the ones the host locked
+const KEPT_MOUNT_FLAGS: &[&str] = &[
+    "nosuid",
+    "nodev",
+    "noexec",
+    "noatime",
+    "nodiratime",
+    "relatime",
+];
+
+/// Makes every mount the container shares with the host read-only, except for our private ones
+fn remount_read_only_inside(ex: &dyn Executor, ns_init_pid: u32) -> Result<()> {
+    let root = PathBuf::from(format!("/proc/{ns_init_pid}/root"));
+    let writable = ["/etc", "/tmp", "/run", WARP_STATE_DIR].map(Path::new);
+    // Only the last mount on a path is visible, so it's the one that matters
+    let mut mounts = procfs::process::Process::new(ns_init_pid as i32)?.mountinfo()?;
+    mounts.reverse();
+    let mut seen = Vec::new();
+    for mount in mounts {
+        let target = mount.mount_point;
+        if seen.contains(&target) {
+            continue;
+        }
+        // Hidden under a mount made later on one of its parents, remounting its path would
+        // remount that one instead
+        let hidden = seen.iter().any(|later: &PathBuf| target.starts_with(later));
+        seen.push(target.clone());
+        if hidden
+            || mount.mount_options.contains_key("ro")
+            || PSEUDO_FS_TYPES.contains(&mount.fs_type.as_str())
+            || writable.iter().any(|w| target.starts_with(w))
+            // Its mount point was removed since, e.g. deleted on the host
+            || !root.join(target.strip_prefix("/").unwrap_or(&target)).exists()
+        {
+            continue;
+        }
+
+        let mut options = "remount,bind,ro".to_owned();
+        for flag in KEPT_MOUNT_FLAGS {
+            if mount.mount_options.contains_key(*flag) {
+                options += &format!(",{flag}");
+            }
+        }
+        debug!("Remounting {} read-only inside namespace", target.display());
+        let mut cmd = Command::new("mount");
+        cmd.arg("-o").arg(options).arg(&target);
+        spawn_inside_all_namespaces(ex, &cmd, ns_init_pid, None)?
+            .wait()?
+            .exit_ok()
+            .with_context(|| format!("Remounting {} read-only", target.display()))?;
+    }
+    Ok(())
+}
+
+/// The /etc overlay's upper, work and extra lower dirs. The upper dir keeps what was written
+/// to /etc inside the container across runs.
+pub fn etc_overlay_dir(base_dir: &Path) -> PathBuf {
+    base_dir.join("etc_overlay")
+}
+
+/// Where warp-svc keeps its registration, per instance so they don't clobber the host's or each other's
+pub fn warp_state_dir(base_dir: &Path) -> PathBuf {
+    base_dir.join("warp-state")
+}
+
+fn mount_warp_state_inside(ex: &dyn Executor, base_dir: &Path, ns_init_pid: u32) -> Result<()> {
+    let mounts = procfs::process::Process::new(ns_init_pid as i32)?.mountinfo()?;
+    if mounts
+        .iter()
+        .any(|m| m.mount_point == Path::new(WARP_STATE_DIR))
     {
-        let resolv_path = extra_lower.join("resolv.conf");
-        let resolv_file_data = b"# This is a hardcoded overlay of resolv.conf in the WARP container
-nameserver 127.0.2.2
-nameserver 127.0.2.3
-nameserver fd01:db8:1111::2
-nameserver fd01:db8:1111::3
-";
-        let mut f = File::create(resolv_path)?;
-        f.write_all(resolv_file_data)?;
+        debug!("{WARP_STATE_DIR} is already mounted, not mounting it again");
+        return Ok(());
+    }
+
+    let state_dir = warp_state_dir(base_dir);
+    std::fs::create_dir_all(&state_dir)?;
+    std::fs::set_permissions(&state_dir, std::fs::Permissions::from_mode(0o700))?;
+    // The mount point has to exist, but the container shares the host's /var
+    let label_reference = if Path::new(WARP_STATE_DIR).exists() {
+        Path::new(WARP_STATE_DIR)
+    } else {
+        mount_var_lib_overlay_inside(ex, base_dir, &mounts)?;
+        Path::new(VAR_LIB)
+    };
+    lsm::label_like(&state_dir, label_reference)?;
+
+    debug!(
+        "Bind mounting {} on {WARP_STATE_DIR} inside namespace",
+        state_dir.display()
+    );
+    let mut cmd = Command::new("mount");
+    cmd.arg("--bind").arg(&state_dir).arg(WARP_STATE_DIR);
+    spawn_inside_all_namespaces(ex, &cmd, ns_init_pid, None)?
+        .wait()?
+        .exit_ok()
+        .with_context(|| format!("Bind mounting {WARP_STATE_DIR}"))?;
+    Ok(())
+}
+
+/// The overlay giving the container the mount point of its WARP state dir, on hosts without one
+fn var_lib_overlay_dir(base_dir: &Path) -> PathBuf {
+    base_dir.join("var_lib_overlay")
+}
+
+/// Lays an overlay over /var/lib whose upper dir has the mount point, so nothing is created in
+/// the host's /var/lib. What the container writes to /var/lib then stays in the upper dir too.
+fn mount_var_lib_overlay_inside(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    mounts: &[procfs::process::MountInfo],
+) -> Result<()> {
+    if mounts
+        .iter()
+        .any(|m| m.mount_point == Path::new(VAR_LIB) && m.fs_type == "overlay")
+    {
+        debug!("{VAR_LIB} overlay is already mounted, not mounting it again");
+        return Ok(());
     }
+    let overlay_dir = var_lib_overlay_dir(base_dir);
+    if base_dir.canonicalize()?.starts_with(VAR_LIB) {
+        bail!(
+            "{WARP_STATE_DIR} doesn't exist and the base dir is under {VAR_LIB}, which an overlay \
+             can't cover, create {WARP_STATE_DIR} or move the base dir"
+        )
+    }
+    let upper = overlay_dir.join("upper");
+    let work = overlay_dir.join("work");
+    let state_name = Path::new(WARP_STATE_DIR).strip_prefix(VAR_LIB)?;
+    std::fs::create_dir_all(upper.join(state_name))?;
+    if work.exists() {
+        debug!("Clearing the {VAR_LIB} overlay's stale work dir");
+        std::fs::remove_dir_all(&work)?;
+    }
+    std::fs::create_dir_all(&work)?;
+
+    debug!("Mount {VAR_LIB} overlay inside namespace");
+    let mut options = format!(
+        "lowerdir={VAR_LIB},upperdir={},workdir={}",
+        upper.to_string_lossy(),
+        work.to_string_lossy()
+    );
+    if let Some(context) = lsm::overlay_context(Path::new(VAR_LIB))? {
+        options += &format!(",{context}");
+    }
+    with_mount_namespace(base_dir, || {
+        ex.mount(
+            Some(Path::new("overlay")),
+            Path::new(VAR_LIB),
+            Some("overlay"),
+            MsFlags::empty(),
+            Some(&options),
+        )
+        .with_context(|| format!("Mounting the {VAR_LIB} overlay"))
+    })
+}
+
+/// Files the container gets in /etc, over the host's or in a rootfs.
+/// Accounts the container adds are appended to those of base_accounts' passwd and group, or to just root's.
+pub fn write_etc_files(
+    etc_dir: &Path,
+    config: &Config,
+    base_accounts: Option<&Path>,
+) -> Result<()> {
+    std::fs::create_dir_all(etc_dir)?;
+
+    std::fs::write(
+        etc_dir.join("resolv.conf"),
+        dns::container_resolv_conf(&config.dns)?,
+    )?;
 
     {
-        let danted_path = extra_lower.join("danted.conf");
-        let file_data = b"internal: 10.200.0.2 port = 8080
+        let danted_path = etc_dir.join("danted.conf");
+        let mut file_data = format!(
+            "internal: {} port = {SOCKS_PORT}
 external: CloudflareWARP
 socksmethod: none
 clientmethod: none
-client pass { from: 0.0.0.0/0 to: 0.0.0.0/0 }
-socks pass { from: 0.0.0.0/0 to: 0.0.0.0/0 }
-";
+{}",
+            config.network.ns_addr,
+            proxy::danted_rules(&config.proxy, &config.network)
+        );
+        let danted = config.services.get(Binary::Danted);
+        if let Some(uid) = danted.user.filter(|&uid| uid != 0) {
+            add_danted_user(etc_dir, uid, danted.group.unwrap_or(uid), base_accounts)?;
+            file_data +=
+                &format!("user.privileged: {DANTED_USER}\nuser.unprivileged: {DANTED_USER}\n");
+        }
         let mut f = File::create(danted_path)?;
-        f.write_all(file_data)?;
+        f.write_all(file_data.as_bytes())?;
     }
 
-    debug!("Mount read-only /etc overlay inside namespace");
-    let opt_lower = format!("lowerdir={}:/etc", extra_lower.to_string_lossy());
-    let opt_upper = format!("upperdir={}", upper.to_string_lossy());
-    let opt_work = format!("workdir={}", work.to_string_lossy());
-    let mut cmd = Command::new("mount");
-    cmd.args(["-t", "overlay", "overlay"])
-        .arg(format!("-o{opt_lower},{opt_upper},{opt_work}"))
-        .arg("/etc");
-    spawn_inside_all_namespaces(&cmd, ns_init_pid)?.wait()?;
+    // glibc in a rootfs without an nsswitch.conf would use the same sources
+    let base_nsswitch = match base_accounts {
+        Some(base) => match std::fs::read_to_string(base.join("nsswitch.conf")) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            nsswitch => nsswitch?,
+        },
+        None => String::new(),
+    };
+    match dns::container_nsswitch(&base_nsswitch) {
+        Some(nsswitch) => {
+            if dns::host_uses_stub_resolver() {
+                debug!("The host resolves through systemd-resolved, the container uses WARP's resolver instead");
+            }
+            std::fs::write(etc_dir.join("nsswitch.conf"), nsswitch)?;
+        }
+        None if base_accounts.is_some_and(|base| base != etc_dir) => {
+            match std::fs::remove_file(etc_dir.join("nsswitch.conf")) {
+                Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                _ => (),
+            }
+        }
+        None => (),
+    }
 
+    // Otherwise the base's hosts file is left to show through
+    if !config.hosts.is_empty() {
+        let base = match base_accounts.map(|base| std::fs::read_to_string(base.join("hosts"))) {
+            Some(Ok(base)) => base,
+            Some(Err(e)) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => String::new(),
+        };
+        std::fs::write(
+            etc_dir.join("hosts"),
+            format!("{base}{}", hosts_entries(config)?),
+        )?;
+    } else if base_accounts.is_some_and(|base| base != etc_dir) {
+        // Left by a run that had entries, the overlay's extra lower dir is kept across runs
+        match std::fs::remove_file(etc_dir.join("hosts")) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
     Ok(())
 }
 
-pub fn spawn_process_inside(name: &str, ns_pid: u32) -> Result<()> {
+/// The `[hosts]` entries of the config, as /etc/hosts lines
+pub fn hosts_entries(config: &Config) -> Result<String> {
+    let mut entries = String::new();
+    for (name, addr) in &config.hosts {
+        let is_valid = !name.is_empty()
+            && name.split('.').all(|label| {
+                !label.is_empty() && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            });
+        if !is_valid {
+            bail!("Invalid host name '{name}' in the hosts config")
+        }
+        entries += &format!("{addr}\t{name}\n");
+    }
+    Ok(entries)
+}
+
+/// danted resolves its user.* settings by name, so the container needs passwd and group entries
+fn add_danted_user(etc_dir: &Path, uid: u32, gid: u32, base_accounts: Option<&Path>) -> Result<()> {
+    let (passwd, group) = match base_accounts {
+        Some(base) => (
+            std::fs::read_to_string(base.join("passwd"))?,
+            std::fs::read_to_string(base.join("group"))?,
+        ),
+        None => (ROOT_PASSWD.to_owned(), ROOT_GROUP.to_owned()),
+    };
+    std::fs::write(
+        etc_dir.join("passwd"),
+        format!("{passwd}{DANTED_USER}:x:{uid}:{gid}::/nonexistent:/usr/sbin/nologin\n"),
+    )?;
+    std::fs::write(
+        etc_dir.join("group"),
+        format!("{group}{DANTED_USER}:x:{gid}:\n"),
+    )?;
+    Ok(())
+}
+
+pub fn spawn_process_inside(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    bin: Binary,
+    service: &ServiceConfig,
+    ns_pid: u32,
+) -> Result<()> {
+    let path = which_inside(ex, ns_pid, bin)?;
+    let name = path
+        .file_name()
+        .map_or(bin.name().into(), |n| n.to_string_lossy());
+    if let Some(process) = service::running(base_dir, bin.name())? {
+        warn!(
+            "{name} is already running as pid {}, not starting another",
+            process.pid()
+        );
+        return Ok(());
+    }
+    // Also catches instances we didn't start ourselves
+    let runs_service = |proc: &procfs::process::Process| {
+        proc.cmdline()
+            .is_ok_and(|cmdline| !cmdline.is_empty() && cmdline[0].ends_with(name.as_ref()))
+    };
+    let ns_pids: Vec<i32> = all_ns_processes(base_dir)?.map(|p| p.pid).collect();
     for proc in procfs::process::all_processes()? {
         let Ok(proc) = proc else { continue };
-        let Ok(cmdline) = proc.cmdline() else {
-            continue;
-        };
-        if cmdline.is_empty() || !cmdline[0].ends_with(name) {
+        if !runs_service(&proc) {
             continue;
         }
-        warn!("There appears to already be a {name} process running, not starting another");
-        return Ok(());
+        if ns_pids.contains(&proc.pid) {
+            warn!(
+                "There appears to already be a {name} process running in the container, not starting another"
+            );
+            return Ok(());
+        }
+        if bin == Binary::WarpSvc {
+            warn!(
+                "A {name} is also running on the host as pid {}, outside the container. \
+                 The container gets its own regardless, but they must not share a registration",
+                proc.pid
+            );
+        }
     }
 
+    spawn_service_inside(ex, base_dir, bin.name(), &path, service, ns_pid)
+}
+
+/// Starts a service's program inside, recorded under `name`
+pub fn spawn_service_inside(
+    ex: &dyn Executor,
+    base_dir: &Path,
+    name: &str,
+    path: &Path,
+    service: &ServiceConfig,
+    ns_pid: u32,
+) -> Result<()> {
     debug!("Spawning {name} process inside namespaces");
-    spawn_inside_all_namespaces(&Command::new(name), ns_pid)?;
+    let mut cmd = Command::new(path);
+    cmd.args(&service.args).envs(&service.env);
+    let nsenter = spawn_inside_all_namespaces(ex, &cmd, ns_pid, Some(service))?;
+    let pid = nsenter_child(&nsenter, Duration::from_secs(1))
+        .with_context(|| format!("Starting {name}"))?;
+    service::record(base_dir, name, pid)?;
+    service::supervise(base_dir, name, nsenter);
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn configured_hosts() {
+        let dir = TempDir::new("hosts");
+        let base = dir.join("base");
+        std::fs::create_dir_all(&base).unwrap();
+        std::fs::write(base.join("hosts"), "127.0.0.1\tlocalhost\n").unwrap();
+        std::fs::write(base.join("passwd"), ROOT_PASSWD).unwrap();
+        std::fs::write(base.join("group"), ROOT_GROUP).unwrap();
+        let mut config: Config =
+            toml::from_str("[hosts]\n\"git.corp.example\" = \"10.0.0.5\"\n").unwrap();
+        write_etc_files(&dir.join("etc"), &config, Some(&base)).unwrap();
+        let hosts = std::fs::read_to_string(dir.join("etc/hosts")).unwrap();
+        config
+            .hosts
+            .insert("bad name".to_owned(), "10.0.0.6".parse().unwrap());
+        let invalid = hosts_entries(&config);
+        assert_eq!(hosts, "127.0.0.1\tlocalhost\n10.0.0.5\tgit.corp.example\n");
+        assert!(invalid.is_err());
+    }
+}
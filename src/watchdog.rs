@@ -0,0 +1,193 @@
+use crate::config::Config;
+use crate::exec::Executor;
+use crate::extra;
+use crate::health;
+use crate::pause::warp_cli;
+use crate::readiness::{as_seconds, seconds};
+use crate::restart::restart;
+use crate::up::find_pid_ns_init_process;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{error, info, warn};
+
+/// The `[watchdog]` section of the config file
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(default, rename_all = "kebab-case", deny_unknown_fields)]
+pub struct WatchdogConfig {
+    /// Seconds between health checks
+    #[serde(deserialize_with = "seconds", serialize_with = "as_seconds")]
+    pub interval: Duration,
+    /// Consecutive failed checks before trying to recover
+    pub failures: u32,
+    /// The wait after a recovery attempt doubles each time, up to this many seconds
+    #[serde(deserialize_with = "seconds", serialize_with = "as_seconds")]
+    pub max_backoff: Duration,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        WatchdogConfig {
+            interval: Duration::from_secs(30),
+            failures: 3,
+            max_backoff: Duration::from_secs(600),
+        }
+    }
+}
+
+/// Ways to recover, from the least disruptive
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Step {
+    /// warp-cli disconnect and connect
+    Reconnect,
+    /// Restart warp-svc and danted in the container
+    RestartServices,
+    /// Take the container down and up again
+    Recreate,
+}
+
+impl Step {
+    fn name(self) -> &'static str {
+        match self {
+            Step::Reconnect => "reconnect",
+            Step::RestartServices => "restart-services",
+            Step::Recreate => "recreate",
+        }
+    }
+}
+
+/// Decides when to recover and how. Each recovery that doesn't help escalates to the next step,
+/// and the container being healthy again starts over from the first one.
+struct Escalation {
+    needed: u32,
+    failed: u32,
+    next: Step,
+}
+
+impl Escalation {
+    fn new(needed: u32) -> Self {
+        Escalation {
+            needed: needed.max(1),
+            failed: 0,
+            next: Step::Reconnect,
+        }
+    }
+
+    /// What to do after a health check, if anything
+    fn observe(&mut self, healthy: bool) -> Option<Step> {
+        if healthy {
+            self.failed = 0;
+            self.next = Step::Reconnect;
+            return None;
+        }
+        self.failed += 1;
+        if self.failed < self.needed {
+            return None;
+        }
+        self.failed = 0;
+        let step = self.next;
+        self.next = match step {
+            Step::Reconnect => Step::RestartServices,
+            _ => Step::Recreate,
+        };
+        Some(step)
+    }
+}
+
+fn recover(ex: &dyn Executor, config: &Config, step: Step) -> Result<()> {
+    match step {
+        Step::Reconnect => {
+            warp_cli(ex, &config.base_dir, &["disconnect"])?;
+            warp_cli(ex, &config.base_dir, &["connect"])?;
+            Ok(())
+        }
+        Step::RestartServices => restart(ex, config, true),
+        Step::Recreate => restart(ex, config, false),
+    }
+}
+
+/// Checks the container's health until killed, and recovers it when WARP stops working.
+/// Meant to run as a service, each failure and recovery step is logged as an event.
+pub fn watch(ex: &dyn Executor, config: &Config) -> Result<()> {
+    let watchdog = &config.watchdog;
+    let mut escalation = Escalation::new(watchdog.failures);
+    let mut backoff = watchdog.interval;
+    let latest = Arc::new(Mutex::new(health::Latest::default()));
+    if let Some(listen) = config.health.listen {
+        // Recovering sleeps through the backoff, which isn't being stuck
+        let max_age = watchdog.interval * 3 + watchdog.max_backoff;
+        health::serve(listen, latest.clone(), max_age)?;
+    }
+    info!(
+        "Checking the container's health every {:?}",
+        watchdog.interval
+    );
+    loop {
+        if let Some(init) = find_pid_ns_init_process(&config.base_dir)? {
+            extra::restart_exited(ex, config, init.pid as u32)?;
+        }
+        let results = health::check(ex, config)?;
+        latest.lock().unwrap().record(&results);
+        let failed: Vec<String> = results
+            .into_iter()
+            .filter_map(|(probe, result)| match result {
+                Some(Err(e)) => Some(format!("{}: {e:#}", probe.name())),
+                _ => None,
+            })
+            .collect();
+        let healthy = failed.is_empty();
+        if healthy {
+            if escalation.failed > 0 || escalation.next != Step::Reconnect {
+                info!(event = "recovered", "The container is healthy again");
+            }
+            backoff = watchdog.interval;
+        } else {
+            warn!(
+                event = "unhealthy",
+                probes = failed.join(", "),
+                "Health check failed"
+            );
+        }
+
+        let Some(step) = escalation.observe(healthy) else {
+            std::thread::sleep(watchdog.interval);
+            continue;
+        };
+        warn!(
+            event = "escalation",
+            step = step.name(),
+            "Trying to recover the container"
+        );
+        if let Err(e) = recover(ex, config, step) {
+            error!(
+                event = "escalation_failed",
+                step = step.name(),
+                "Recovery failed: {e:#}"
+            );
+        }
+        std::thread::sleep(backoff);
+        backoff = (backoff * 2).min(watchdog.max_backoff);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escalates_until_healthy() {
+        let mut escalation = Escalation::new(2);
+        assert_eq!(escalation.observe(false), None);
+        assert_eq!(escalation.observe(false), Some(Step::Reconnect));
+        assert_eq!(escalation.observe(false), None);
+        assert_eq!(escalation.observe(false), Some(Step::RestartServices));
+        assert_eq!(escalation.observe(false), None);
+        assert_eq!(escalation.observe(false), Some(Step::Recreate));
+        assert_eq!(escalation.observe(false), None);
+        assert_eq!(escalation.observe(false), Some(Step::Recreate));
+        assert_eq!(escalation.observe(true), None);
+        assert_eq!(escalation.observe(false), None);
+        assert_eq!(escalation.observe(false), Some(Step::Reconnect));
+    }
+}